@@ -1,3 +1,6 @@
+use std::error::Error;
+use std::fmt;
+
 use anyhow::Result;
 
 use crate::command::Command;
@@ -21,6 +24,19 @@ pub trait Auth {
     }
 }
 
+/// The error returned when the server rejects the credentials, as opposed to the connection itself
+/// failing
+#[derive(Debug)]
+pub struct AuthError(pub ResponseDone);
+
+impl fmt::Display for AuthError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "unable to login: {:?}", self.0)
+    }
+}
+
+impl Error for AuthError {}
+
 pub struct Plain {
     pub username: String,
     pub password: String,
@@ -41,7 +57,7 @@ impl Auth for Plain {
         let done = done.unwrap();
 
         if done.status != Status::Ok {
-            bail!("unable to login: {:?}", done);
+            return Err(AuthError(done).into());
         }
 
         // if !matches!(
@@ -0,0 +1,235 @@
+//! Stream compression for the COMPRESS=DEFLATE extension (RFC 4978)
+
+use std::io;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+use flate2::{Compress, Compression, Decompress, FlushCompress, FlushDecompress, Status};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+
+const BUF_SIZE: usize = 8192;
+
+/// A stream that transparently compresses everything written to it and decompresses everything
+/// read from it using raw DEFLATE (RFC 1951), as required by RFC 4978.
+pub struct DeflateStream<C> {
+    inner: C,
+
+    compress: Compress,
+    decompress: Decompress,
+
+    /// Compressed bytes that have been read from the inner stream but not decompressed yet
+    read_buf: Box<[u8]>,
+    read_pos: usize,
+    read_len: usize,
+
+    /// Compressed bytes that haven't been written to the inner stream yet
+    write_buf: Vec<u8>,
+
+    /// Whether data has been written since the last sync flush
+    needs_flush: bool,
+}
+
+impl<C> DeflateStream<C> {
+    /// Wraps the given stream. This should only be done directly after the server has accepted the
+    /// COMPRESS command, since both sides start compressing immediately after that.
+    pub fn new(inner: C) -> Self {
+        DeflateStream {
+            inner,
+            compress: Compress::new(Compression::default(), false),
+            decompress: Decompress::new(false),
+            read_buf: vec![0; BUF_SIZE].into_boxed_slice(),
+            read_pos: 0,
+            read_len: 0,
+            write_buf: Vec::with_capacity(BUF_SIZE),
+            needs_flush: false,
+        }
+    }
+
+    /// Returns the underlying stream
+    pub fn into_inner(self) -> C {
+        self.inner
+    }
+}
+
+impl<C> DeflateStream<C>
+where
+    C: AsyncWrite + Unpin,
+{
+    /// Writes out all of the compressed data that is still buffered
+    fn poll_write_buf(&mut self, cx: &mut Context) -> Poll<io::Result<()>> {
+        while !self.write_buf.is_empty() {
+            let n = ready!(Pin::new(&mut self.inner).poll_write(cx, &self.write_buf))?;
+            if n == 0 {
+                return Poll::Ready(Err(io::ErrorKind::WriteZero.into()));
+            }
+            self.write_buf.drain(..n);
+        }
+        Poll::Ready(Ok(()))
+    }
+
+    fn compress(&mut self, data: &[u8], flush: FlushCompress) -> io::Result<()> {
+        let start = self.compress.total_in();
+        loop {
+            let consumed = (self.compress.total_in() - start) as usize;
+            self.write_buf.reserve(data.len() - consumed + 64);
+            self.compress
+                .compress_vec(&data[consumed..], &mut self.write_buf, flush)
+                .map_err(|err| io::Error::new(io::ErrorKind::Other, err))?;
+
+            // the compressor is done once it's consumed all the input and didn't fill up the
+            // output buffer, otherwise it might still have more to give us
+            let consumed = (self.compress.total_in() - start) as usize;
+            if consumed == data.len() && self.write_buf.len() < self.write_buf.capacity() {
+                return Ok(());
+            }
+        }
+    }
+}
+
+impl<C> AsyncRead for DeflateStream<C>
+where
+    C: AsyncRead + Unpin,
+{
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context,
+        buf: &mut ReadBuf,
+    ) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        if buf.remaining() == 0 {
+            return Poll::Ready(Ok(()));
+        }
+
+        loop {
+            if this.read_pos == this.read_len {
+                let mut read_buf = ReadBuf::new(&mut this.read_buf);
+                ready!(Pin::new(&mut this.inner).poll_read(cx, &mut read_buf))?;
+                let n = read_buf.filled().len();
+                if n == 0 {
+                    // EOF
+                    return Poll::Ready(Ok(()));
+                }
+                this.read_pos = 0;
+                this.read_len = n;
+            }
+
+            let (in_before, out_before) = (this.decompress.total_in(), this.decompress.total_out());
+            let status = this
+                .decompress
+                .decompress(
+                    &this.read_buf[this.read_pos..this.read_len],
+                    buf.initialize_unfilled(),
+                    FlushDecompress::None,
+                )
+                .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))?;
+            let consumed = (this.decompress.total_in() - in_before) as usize;
+            let produced = (this.decompress.total_out() - out_before) as usize;
+            this.read_pos += consumed;
+            buf.advance(produced);
+
+            // going around again with the same input would do the same thing forever
+            if consumed == 0 && produced == 0 && this.read_pos < this.read_len {
+                return Poll::Ready(Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "decompressor stopped making progress",
+                )));
+            }
+
+            // if nothing came out of the decompressor, it needs more input before it can continue
+            if produced > 0 || status == Status::StreamEnd {
+                return Poll::Ready(Ok(()));
+            }
+        }
+    }
+}
+
+impl<C> AsyncWrite for DeflateStream<C>
+where
+    C: AsyncWrite + Unpin,
+{
+    fn poll_write(self: Pin<&mut Self>, cx: &mut Context, data: &[u8]) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+
+        // don't let the buffer grow without bound if the other side isn't reading
+        if this.write_buf.len() >= BUF_SIZE {
+            ready!(this.poll_write_buf(cx))?;
+        }
+
+        this.compress(data, FlushCompress::None)?;
+        this.needs_flush = true;
+        Poll::Ready(Ok(data.len()))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+
+        // a sync flush makes the compressor emit everything it's been holding on to, so the server
+        // can actually see the end of the command
+        if this.needs_flush {
+            this.compress(&[], FlushCompress::Sync)?;
+            this.needs_flush = false;
+        }
+
+        ready!(this.poll_write_buf(cx))?;
+        Pin::new(&mut this.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<io::Result<()>> {
+        ready!(self.as_mut().poll_flush(cx))?;
+        Pin::new(&mut self.get_mut().inner).poll_shutdown(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    use super::*;
+
+    #[tokio::test]
+    async fn test_roundtrip() {
+        let (client, server) = tokio::io::duplex(4096);
+        let mut client = DeflateStream::new(client);
+        let mut server = DeflateStream::new(server);
+
+        let line = b"ptag4 UID FETCH 1:* (UID FLAGS)\r\n";
+        client.write_all(line).await.unwrap();
+        client.flush().await.unwrap();
+
+        let mut buf = vec![0; line.len()];
+        server.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, line);
+    }
+
+    /// A stream that only gives out one byte at a time
+    struct OneByte<'a>(&'a [u8]);
+
+    impl AsyncRead for OneByte<'_> {
+        fn poll_read(
+            self: Pin<&mut Self>,
+            _: &mut Context,
+            buf: &mut ReadBuf,
+        ) -> Poll<io::Result<()>> {
+            let this = self.get_mut();
+            if let Some((first, rest)) = this.0.split_first() {
+                buf.put_slice(&[*first]);
+                this.0 = rest;
+            }
+            Poll::Ready(Ok(()))
+        }
+    }
+
+    #[tokio::test]
+    async fn test_one_byte_reads() {
+        let text = b"* 1 FETCH (UID 1 FLAGS (\\Seen))\r\n".repeat(100);
+        let mut compressed = DeflateStream::new(Vec::new());
+        compressed.write_all(&text).await.unwrap();
+        compressed.flush().await.unwrap();
+        let compressed = compressed.into_inner();
+
+        let mut stream = DeflateStream::new(OneByte(&compressed));
+        let mut buf = Vec::new();
+        stream.read_to_end(&mut buf).await.unwrap();
+        assert_eq!(buf, text);
+    }
+}
@@ -1,3 +1,4 @@
+use std::collections::VecDeque;
 use std::pin::Pin;
 use std::sync::Arc;
 use std::task::{Context, Poll};
@@ -23,10 +24,12 @@ use tokio_rustls::{
 use tokio_util::codec::FramedRead;
 
 use crate::codec::ImapCodec;
-use crate::command::Command;
+use crate::command::{split_literals, Command};
 use crate::parser::{parse_capability, parse_response};
-use crate::response::{Response, ResponseDone};
+use crate::response::{Response, ResponseDone, Status};
 
+#[cfg(feature = "rfc4978-compress")]
+use super::compress::DeflateStream;
 use super::ClientConfig;
 
 pub const TAG_PREFIX: &str = "ptag";
@@ -36,7 +39,7 @@ pub struct Client<C> {
     ctr: usize,
     config: ClientConfig,
     // conn: WriteHalf<C>,
-    pub(crate) write_tx: mpsc::UnboundedSender<String>,
+    pub(crate) write_tx: mpsc::UnboundedSender<Vec<u8>>,
     cmd_tx: mpsc::UnboundedSender<Command2>,
     greeting_rx: Option<oneshot::Receiver<()>>,
     writer_exit_tx: oneshot::Sender<()>,
@@ -55,7 +58,7 @@ where
         let (greeting_tx, greeting_rx) = oneshot::channel();
 
         let (writer_exit_tx, exit_rx) = oneshot::channel();
-        let (write_tx, mut write_rx) = mpsc::unbounded_channel::<String>();
+        let (write_tx, mut write_rx) = mpsc::unbounded_channel::<Vec<u8>>();
         let writer_handle = tokio::spawn(write(write_half, write_rx, exit_rx).map_err(|err| {
             error!("Help, the writer loop died: {}", err);
             err
@@ -160,6 +163,40 @@ where
 
         Ok(Client::new(stream, self.config))
     }
+
+    /// Negotiates COMPRESS=DEFLATE (RFC 4978), re-wrapping the connection so everything sent and
+    /// received afterwards is compressed.
+    #[cfg(feature = "rfc4978-compress")]
+    pub async fn compress(mut self) -> Result<Client<DeflateStream<C>>> {
+        if !self.has_capability("COMPRESS=DEFLATE").await? {
+            bail!("server doesn't support this capability");
+        }
+
+        let resp = self.execute(Command::Compress).await?;
+        let done = resp.done().await?;
+        debug!("server response to compress: {:?}", done);
+        match done {
+            Some(ResponseDone {
+                status: Status::Ok, ..
+            }) => {}
+            done => bail!("server refused to compress the connection: {:?}", done),
+        }
+
+        // the server starts compressing right after the OK, so the connection needs to be taken
+        // back from the reader and writer before anything else gets sent
+        debug!("sending exit for compress");
+        self.listener_exit_tx.send(()).unwrap();
+        self.writer_exit_tx.send(()).unwrap();
+        let (reader, writer) = future::join(self.listener_handle, self.writer_handle).await;
+        let reader = reader??;
+        let writer = writer??;
+
+        let conn = reader.unsplit(writer);
+        let stream = DeflateStream::new(conn);
+        debug!("compressed, stream is using DEFLATE now");
+
+        Ok(Client::new(stream, self.config))
+    }
 }
 
 pub struct ResponseStream {
@@ -203,7 +240,7 @@ impl Stream for ResponseStream {
 #[allow(unreachable_code)]
 async fn write<C>(
     mut conn: WriteHalf<C>,
-    mut write_rx: mpsc::UnboundedReceiver<String>,
+    mut write_rx: mpsc::UnboundedReceiver<Vec<u8>>,
     exit_rx: oneshot::Receiver<()>,
 ) -> Result<WriteHalf<C>>
 where
@@ -221,9 +258,9 @@ where
 
             line = write_fut => {
                 if let Some(line) = line {
-                    conn.write_all(line.as_bytes()).await?;
+                    conn.write_all(&line).await?;
                     conn.flush().await?;
-                    trace!("C>>>S: {:?}", line);
+                    trace!("C>>>S: {:?}", String::from_utf8_lossy(&line));
                 }
             }
         }
@@ -236,7 +273,7 @@ where
 async fn listen<C>(
     conn: ReadHalf<C>,
     mut cmd_rx: mpsc::UnboundedReceiver<Command2>,
-    mut write_tx: mpsc::UnboundedSender<String>,
+    mut write_tx: mpsc::UnboundedSender<Vec<u8>>,
     greeting_tx: oneshot::Sender<()>,
     exit_rx: oneshot::Receiver<()>,
 ) -> Result<ReadHalf<C>>
@@ -247,6 +284,8 @@ where
     let mut framed = FramedRead::new(conn, codec);
     let mut greeting_tx = Some(greeting_tx);
     let mut curr_cmd: Option<Command2> = None;
+    // pieces of the current command that are waiting for the server to accept a literal
+    let mut literal_rest: VecDeque<Vec<u8>> = VecDeque::new();
     let mut exit_rx = exit_rx.map_err(|_| ()).shared();
 
     loop {
@@ -275,7 +314,10 @@ where
                 if curr_cmd.is_none() {
                     if let Some((ref tag, ref cmd, _)) = cmd {
                         let cmd_str = format!("{} {}\r\n", tag, cmd);
-                        write_tx.send(cmd_str);
+                        literal_rest = split_literals(&cmd_str).into();
+                        if let Some(first) = literal_rest.pop_front() {
+                            write_tx.send(first);
+                        }
                     }
                     curr_cmd = cmd;
                 }
@@ -294,8 +336,14 @@ where
                     greeting_tx.send(()).unwrap();
                 }
 
-                if let Response::Done(_) = resp {
+                if matches!(resp, Response::Continue { .. }) && !literal_rest.is_empty() {
+                    // the server is ready for the rest of a command that contains a literal
+                    if let Some(next) = literal_rest.pop_front() {
+                        write_tx.send(next);
+                    }
+                } else if let Response::Done(_) = resp {
                     // since this is the DONE message, clear curr_cmd so another one can be sent
+                    literal_rest.clear();
                     if let Some((_, _, cmd_tx)) = curr_cmd.take() {
                         let res = cmd_tx.send(resp);
                         // debug!("res0: {:?}", res);
@@ -34,6 +34,8 @@
 //! ```
 
 pub mod auth;
+#[cfg(feature = "rfc4978-compress")]
+mod compress;
 mod inner;
 
 use std::pin::Pin;
@@ -54,12 +56,16 @@ use tokio_rustls::{
     client::TlsStream, rustls::ClientConfig as RustlsConfig, webpki::DNSNameRef, TlsConnector,
 };
 
-use crate::command::{Command, FetchItems, SearchCriteria};
+use crate::command::{
+    Command, FetchItems, SearchCriteria, SortCriterion, StoreAction, ThreadAlgorithm,
+};
 use crate::response::{
-    AttributeValue, Envelope, MailboxData, MailboxFlag, Response, ResponseCode, ResponseData,
-    ResponseDone, Status,
+    AttributeValue, Envelope, MailboxData, MailboxFlag, MailboxListFlag, Metadata, Namespaces,
+    Quota, Response, ResponseCode, ResponseData, ResponseDone, SpecialUse, Status, ThreadNode,
 };
 
+#[cfg(feature = "rfc4978-compress")]
+pub use self::compress::DeflateStream;
 pub use self::inner::{Client, ResponseStream};
 
 /// Struct used to start building the config for a client.
@@ -148,6 +154,14 @@ impl ClientUnauthenticated {
 pub enum ClientAuthenticated {
     Encrypted(Client<TlsStream<TcpStream>>),
     Unencrypted(Client<TcpStream>),
+
+    #[cfg(feature = "rfc4978-compress")]
+    #[cfg_attr(docsrs, doc(cfg(feature = "rfc4978-compress")))]
+    EncryptedCompressed(Client<DeflateStream<TlsStream<TcpStream>>>),
+
+    #[cfg(feature = "rfc4978-compress")]
+    #[cfg_attr(docsrs, doc(cfg(feature = "rfc4978-compress")))]
+    UnencryptedCompressed(Client<DeflateStream<TcpStream>>),
 }
 
 impl ClientAuthenticated {
@@ -156,13 +170,21 @@ impl ClientAuthenticated {
         match self {
             ClientAuthenticated::Encrypted(e) => e.execute(cmd).await,
             ClientAuthenticated::Unencrypted(e) => e.execute(cmd).await,
+            #[cfg(feature = "rfc4978-compress")]
+            ClientAuthenticated::EncryptedCompressed(e) => e.execute(cmd).await,
+            #[cfg(feature = "rfc4978-compress")]
+            ClientAuthenticated::UnencryptedCompressed(e) => e.execute(cmd).await,
         }
     }
 
-    fn sender(&self) -> mpsc::UnboundedSender<String> {
+    fn sender(&self) -> mpsc::UnboundedSender<Vec<u8>> {
         match self {
             ClientAuthenticated::Encrypted(e) => e.write_tx.clone(),
             ClientAuthenticated::Unencrypted(e) => e.write_tx.clone(),
+            #[cfg(feature = "rfc4978-compress")]
+            ClientAuthenticated::EncryptedCompressed(e) => e.write_tx.clone(),
+            #[cfg(feature = "rfc4978-compress")]
+            ClientAuthenticated::UnencryptedCompressed(e) => e.write_tx.clone(),
         }
     }
 
@@ -171,11 +193,32 @@ impl ClientAuthenticated {
         match self {
             ClientAuthenticated::Encrypted(e) => e.has_capability(cap).await,
             ClientAuthenticated::Unencrypted(e) => e.has_capability(cap).await,
+            #[cfg(feature = "rfc4978-compress")]
+            ClientAuthenticated::EncryptedCompressed(e) => e.has_capability(cap).await,
+            #[cfg(feature = "rfc4978-compress")]
+            ClientAuthenticated::UnencryptedCompressed(e) => e.has_capability(cap).await,
+        }
+    }
+
+    /// Turns on COMPRESS=DEFLATE (RFC 4978) for the rest of the connection. This is a no-op if the
+    /// connection is already compressed.
+    #[cfg(feature = "rfc4978-compress")]
+    #[cfg_attr(docsrs, doc(cfg(feature = "rfc4978-compress")))]
+    pub async fn compress(self) -> Result<ClientAuthenticated> {
+        match self {
+            ClientAuthenticated::Encrypted(e) => Ok(ClientAuthenticated::EncryptedCompressed(
+                e.compress().await?,
+            )),
+            ClientAuthenticated::Unencrypted(e) => Ok(ClientAuthenticated::UnencryptedCompressed(
+                e.compress().await?,
+            )),
+            ClientAuthenticated::EncryptedCompressed(_)
+            | ClientAuthenticated::UnencryptedCompressed(_) => Ok(self),
         }
     }
 
     /// Runs the LIST command
-    pub async fn list(&mut self) -> Result<Vec<String>> {
+    pub async fn list(&mut self) -> Result<Vec<ListEntry>> {
         let cmd = Command::List {
             reference: "".to_owned(),
             mailbox: "*".to_owned(),
@@ -186,20 +229,76 @@ impl ClientAuthenticated {
 
         let mut folders = Vec::new();
         for resp in data {
-            if let Response::MailboxData(MailboxData::List { name, .. }) = resp {
-                folders.push(name.to_owned());
+            if let Response::MailboxData(MailboxData::List {
+                flags,
+                delimiter,
+                name,
+            }) = resp
+            {
+                folders.push(ListEntry {
+                    name,
+                    delimiter,
+                    flags,
+                });
             }
         }
 
         Ok(folders)
     }
 
+    /// Runs the NAMESPACE command
+    #[cfg(feature = "rfc2342-namespace")]
+    #[cfg_attr(docsrs, doc(cfg(feature = "rfc2342-namespace")))]
+    pub async fn namespace(&mut self) -> Result<Namespaces> {
+        let stream = self.execute(Command::Namespace).await?;
+        let (_, data) = stream.wait().await?;
+        for resp in data {
+            if let Response::Namespace(namespaces) = resp {
+                return Ok(namespaces);
+            }
+        }
+        bail!("could not find the NAMESPACE response")
+    }
+
+    /// Runs the ID command, sending the given client parameters and returning the parameters the
+    /// server identified itself with
+    #[cfg(feature = "rfc2971-id")]
+    #[cfg_attr(docsrs, doc(cfg(feature = "rfc2971-id")))]
+    pub async fn id(
+        &mut self,
+        parameters: Option<Vec<(String, String)>>,
+    ) -> Result<Option<Vec<(String, Option<String>)>>> {
+        let cmd = Command::Id { parameters };
+        let stream = self.execute(cmd).await?;
+        let (_, data) = stream.wait().await?;
+        for resp in data {
+            if let Response::Id(server_parameters) = resp {
+                return Ok(server_parameters);
+            }
+        }
+        Ok(None)
+    }
+
     /// Runs the SELECT command
     pub async fn select(&mut self, mailbox: impl AsRef<str>) -> Result<SelectResponse> {
         let cmd = Command::Select {
             mailbox: mailbox.as_ref().to_owned(),
         };
+        self.run_select(cmd).await
+    }
+
+    /// Runs the SELECT command with CONDSTORE turned on, so that the response has the mailbox's
+    /// highest mod-sequence in it
+    #[cfg(feature = "rfc7162-condstore")]
+    #[cfg_attr(docsrs, doc(cfg(feature = "rfc7162-condstore")))]
+    pub async fn select_condstore(&mut self, mailbox: impl AsRef<str>) -> Result<SelectResponse> {
+        let cmd = Command::SelectCondstore {
+            mailbox: mailbox.as_ref().to_owned(),
+        };
+        self.run_select(cmd).await
+    }
 
+    async fn run_select(&mut self, cmd: Command) -> Result<SelectResponse> {
         let stream = self.execute(cmd).await?;
         let (_, data) = stream.wait().await?;
 
@@ -217,6 +316,7 @@ impl ClientAuthenticated {
                     ResponseCode::Unseen(value) => select.unseen = Some(value),
                     ResponseCode::UidNext(value) => select.uid_next = Some(value),
                     ResponseCode::UidValidity(value) => select.uid_validity = Some(value),
+                    ResponseCode::HighestModSeq(value) => select.highest_modseq = Some(value),
                     _ => {}
                 },
                 _ => {}
@@ -226,11 +326,9 @@ impl ClientAuthenticated {
         Ok(select)
     }
 
-    /// Runs the SEARCH command
-    pub async fn uid_search(&mut self) -> Result<Vec<u32>> {
-        let cmd = Command::UidSearch {
-            criteria: SearchCriteria::All,
-        };
+    /// Runs the UID SEARCH command
+    pub async fn uid_search(&mut self, criteria: SearchCriteria) -> Result<Vec<u32>> {
+        let cmd = Command::UidSearch { criteria };
         let stream = self.execute(cmd).await?;
         let (_, data) = stream.wait().await?;
         for resp in data {
@@ -241,6 +339,134 @@ impl ClientAuthenticated {
         bail!("could not find the SEARCH response")
     }
 
+    /// Runs the UID SORT command, returning the UIDs of the matching messages in sorted order
+    #[cfg(feature = "rfc5256-sort")]
+    #[cfg_attr(docsrs, doc(cfg(feature = "rfc5256-sort")))]
+    pub async fn uid_sort(
+        &mut self,
+        criteria: Vec<SortCriterion>,
+        search: SearchCriteria,
+    ) -> Result<Vec<u32>> {
+        let cmd = Command::UidSort {
+            criteria,
+            charset: "UTF-8".to_owned(),
+            search,
+        };
+        let stream = self.execute(cmd).await?;
+        let (_, data) = stream.wait().await?;
+        for resp in data {
+            if let Response::MailboxData(MailboxData::Sort(uids)) = resp {
+                return Ok(uids);
+            }
+        }
+        bail!("could not find the SORT response")
+    }
+
+    /// Runs the UID THREAD command, returning a tree of UIDs for each thread
+    #[cfg(feature = "rfc5256-thread")]
+    #[cfg_attr(docsrs, doc(cfg(feature = "rfc5256-thread")))]
+    pub async fn uid_thread(
+        &mut self,
+        algorithm: ThreadAlgorithm,
+        search: SearchCriteria,
+    ) -> Result<Vec<ThreadNode>> {
+        let cmd = Command::UidThread {
+            algorithm,
+            charset: "UTF-8".to_owned(),
+            search,
+        };
+        let stream = self.execute(cmd).await?;
+        let (_, data) = stream.wait().await?;
+        for resp in data {
+            if let Response::MailboxData(MailboxData::Thread(threads)) = resp {
+                return Ok(threads);
+            }
+        }
+        bail!("could not find the THREAD response")
+    }
+
+    /// Runs the GETQUOTAROOT command, returning the quota roots of the mailbox along with the
+    /// usage and limits of each of them
+    #[cfg(feature = "rfc9208-quota")]
+    #[cfg_attr(docsrs, doc(cfg(feature = "rfc9208-quota")))]
+    pub async fn get_quota_root(&mut self, mailbox: impl AsRef<str>) -> Result<QuotaRootResponse> {
+        let cmd = Command::GetQuotaRoot {
+            mailbox: mailbox.as_ref().to_owned(),
+        };
+        let stream = self.execute(cmd).await?;
+        let (_, data) = stream.wait().await?;
+
+        let mut quota_root = QuotaRootResponse::default();
+        for resp in data {
+            match resp {
+                Response::MailboxData(MailboxData::QuotaRoot { roots, .. }) => {
+                    quota_root.roots = roots
+                }
+                Response::MailboxData(MailboxData::Quota(quota)) => quota_root.quotas.push(quota),
+                _ => {}
+            }
+        }
+        Ok(quota_root)
+    }
+
+    /// Runs the GETQUOTA command
+    #[cfg(feature = "rfc9208-quota")]
+    #[cfg_attr(docsrs, doc(cfg(feature = "rfc9208-quota")))]
+    pub async fn get_quota(&mut self, root: impl AsRef<str>) -> Result<Quota> {
+        let cmd = Command::GetQuota {
+            root: root.as_ref().to_owned(),
+        };
+        let stream = self.execute(cmd).await?;
+        let (_, data) = stream.wait().await?;
+        for resp in data {
+            if let Response::MailboxData(MailboxData::Quota(quota)) = resp {
+                return Ok(quota);
+            }
+        }
+        bail!("could not find the QUOTA response")
+    }
+
+    /// Runs the GETMETADATA command, returning the values of the requested entries
+    ///
+    /// An empty mailbox name refers to server-wide metadata.
+    #[cfg(feature = "rfc5464-metadata")]
+    #[cfg_attr(docsrs, doc(cfg(feature = "rfc5464-metadata")))]
+    pub async fn get_metadata(
+        &mut self,
+        mailbox: impl AsRef<str>,
+        entries: Vec<String>,
+    ) -> Result<Vec<Metadata>> {
+        let cmd = Command::GetMetadata {
+            mailbox: mailbox.as_ref().to_owned(),
+            entries,
+        };
+        let stream = self.execute(cmd).await?;
+        let (_, data) = stream.wait().await?;
+
+        let mut metadata = Vec::new();
+        for resp in data {
+            if let Response::MailboxData(MailboxData::MetadataSolicited { values, .. }) = resp {
+                metadata.extend(values);
+            }
+        }
+        Ok(metadata)
+    }
+
+    /// Runs the SETMETADATA command, a value of `None` removes the entry
+    #[cfg(feature = "rfc5464-metadata")]
+    #[cfg_attr(docsrs, doc(cfg(feature = "rfc5464-metadata")))]
+    pub async fn set_metadata(
+        &mut self,
+        mailbox: impl AsRef<str>,
+        values: Vec<(String, Option<String>)>,
+    ) -> Result<()> {
+        let cmd = Command::SetMetadata {
+            mailbox: mailbox.as_ref().to_owned(),
+            values,
+        };
+        self.execute_ok(cmd).await
+    }
+
     /// Runs the FETCH command
     pub async fn fetch(
         &mut self,
@@ -256,8 +482,30 @@ impl ClientAuthenticated {
         // let (done, data) = stream.wait().await?;
         Ok(stream.filter_map(|resp| match resp {
             Response::Fetch(n, attrs) => future::ready(Some((n, attrs))).boxed(),
-            Response::Done(_) => future::ready(None).boxed(),
-            _ => future::pending().boxed(),
+            _ => future::ready(None).boxed(),
+        }))
+    }
+
+    /// Runs the FETCH command with the CHANGEDSINCE modifier, which only returns the messages that
+    /// changed after the given mod-sequence
+    #[cfg(feature = "rfc7162-condstore")]
+    #[cfg_attr(docsrs, doc(cfg(feature = "rfc7162-condstore")))]
+    pub async fn fetch_changed_since(
+        &mut self,
+        uids: &[u32],
+        items: FetchItems,
+        modseq: u64,
+    ) -> Result<impl Stream<Item = (u32, Vec<AttributeValue>)>> {
+        let cmd = Command::FetchChangedSince {
+            uids: uids.to_vec(),
+            items,
+            modseq,
+        };
+        debug!("fetch: {}", cmd);
+        let stream = self.execute(cmd).await?;
+        Ok(stream.filter_map(|resp| match resp {
+            Response::Fetch(n, attrs) => future::ready(Some((n, attrs))).boxed(),
+            _ => future::ready(None).boxed(),
         }))
     }
 
@@ -276,11 +524,140 @@ impl ClientAuthenticated {
         // let (done, data) = stream.wait().await?;
         Ok(stream.filter_map(|resp| match resp {
             Response::Fetch(n, attrs) => future::ready(Some((n, attrs))).boxed(),
-            Response::Done(_) => future::ready(None).boxed(),
-            _ => future::pending().boxed(),
+            _ => future::ready(None).boxed(),
         }))
     }
 
+    /// Runs an arbitrary command, returning the server's final response along with all of the
+    /// untagged data that was sent before it
+    pub async fn run(&mut self, cmd: Command) -> Result<(Option<ResponseDone>, Vec<Response>)> {
+        let stream = self.execute(cmd).await?;
+        stream.wait().await
+    }
+
+    /// Runs a command that doesn't return any data, failing if the server didn't respond with OK
+    async fn execute_ok(&mut self, cmd: Command) -> Result<()> {
+        let name = format!("{:?}", cmd);
+        let stream = self.execute(cmd).await?;
+        let (done, _) = stream.wait().await?;
+        match done {
+            Some(done) if done.status == Status::Ok => Ok(()),
+            done => bail!("{} failed: {:?}", name, done),
+        }
+    }
+
+    /// Runs the UID STORE command, changing the flags on the given messages
+    pub async fn uid_store(
+        &mut self,
+        uids: &[u32],
+        action: StoreAction,
+        flags: Vec<MailboxFlag>,
+    ) -> Result<()> {
+        let cmd = Command::UidStore {
+            uids: uids.to_vec(),
+            action,
+            flags,
+        };
+        debug!("uid store: {}", cmd);
+        self.execute_ok(cmd).await
+    }
+
+    /// Runs the UID COPY command
+    pub async fn uid_copy(&mut self, uids: &[u32], mailbox: impl AsRef<str>) -> Result<()> {
+        let cmd = Command::UidCopy {
+            uids: uids.to_vec(),
+            mailbox: mailbox.as_ref().to_owned(),
+        };
+        self.execute_ok(cmd).await
+    }
+
+    /// Runs the UID MOVE command
+    #[cfg(feature = "rfc6851-move")]
+    #[cfg_attr(docsrs, doc(cfg(feature = "rfc6851-move")))]
+    pub async fn uid_move(&mut self, uids: &[u32], mailbox: impl AsRef<str>) -> Result<()> {
+        let cmd = Command::UidMove {
+            uids: uids.to_vec(),
+            mailbox: mailbox.as_ref().to_owned(),
+        };
+        self.execute_ok(cmd).await
+    }
+
+    /// Runs the EXPUNGE command, permanently removing every message with the \Deleted flag
+    /// from the selected mailbox
+    pub async fn expunge(&mut self) -> Result<()> {
+        self.execute_ok(Command::Expunge).await
+    }
+
+    /// Runs the UID EXPUNGE command, which only removes the given messages
+    #[cfg(feature = "rfc4315-uidplus")]
+    #[cfg_attr(docsrs, doc(cfg(feature = "rfc4315-uidplus")))]
+    pub async fn uid_expunge(&mut self, uids: &[u32]) -> Result<()> {
+        let cmd = Command::UidExpunge {
+            uids: uids.to_vec(),
+        };
+        self.execute_ok(cmd).await
+    }
+
+    /// Runs the APPEND command, uploading a message to the given mailbox
+    pub async fn append(
+        &mut self,
+        mailbox: impl AsRef<str>,
+        flags: Vec<MailboxFlag>,
+        message: &[u8],
+    ) -> Result<()> {
+        let cmd = Command::Append {
+            mailbox: mailbox.as_ref().to_owned(),
+            flags,
+            length: message.len(),
+        };
+        let mut stream = self.execute(cmd).await?;
+
+        // the message can only be sent after the server says it's ready for it
+        loop {
+            match stream.next().await {
+                Some(Response::Continue { .. }) => break,
+                Some(Response::Done(done)) => bail!("server refused the message: {:?}", done),
+                Some(_) => {}
+                None => bail!("connection closed during APPEND"),
+            }
+        }
+        // the message is sent as it is, since it doesn't have to be UTF-8
+        let mut data = message.to_vec();
+        data.extend_from_slice(b"\r\n");
+        self.sender().send(data)?;
+
+        let (done, _) = stream.wait().await?;
+        match done {
+            Some(done) if done.status == Status::Ok => Ok(()),
+            done => bail!("unable to append message: {:?}", done),
+        }
+    }
+
+    /// Runs the CREATE command
+    pub async fn create(&mut self, mailbox: impl AsRef<str>) -> Result<()> {
+        let cmd = Command::Create {
+            mailbox: mailbox.as_ref().to_owned(),
+        };
+        self.execute_ok(cmd).await
+    }
+
+    /// Runs the DELETE command
+    pub async fn delete(&mut self, mailbox: impl AsRef<str>) -> Result<()> {
+        let cmd = Command::Delete {
+            mailbox: mailbox.as_ref().to_owned(),
+        };
+        self.execute_ok(cmd).await
+    }
+
+    /// Runs the RENAME command
+    pub async fn rename(&mut self, from: impl AsRef<str>, to: impl AsRef<str>) -> Result<()> {
+        let cmd = Command::Rename {
+            from: from.as_ref().to_owned(),
+            to: to.as_ref().to_owned(),
+        };
+        self.execute_ok(cmd).await
+    }
+
     /// Runs the IDLE command
     #[cfg(feature = "rfc2177-idle")]
     #[cfg_attr(docsrs, doc(cfg(feature = "rfc2177-idle")))]
@@ -292,6 +669,38 @@ impl ClientAuthenticated {
     }
 }
 
+/// A single mailbox returned by the LIST command
+#[derive(Clone, Debug)]
+pub struct ListEntry {
+    pub name: String,
+    pub delimiter: Option<String>,
+    pub flags: Vec<MailboxListFlag>,
+}
+
+impl ListEntry {
+    /// Returns the special-use attribute (RFC 6154) the server advertised for this mailbox, if any
+    pub fn special_use(&self) -> Option<SpecialUse> {
+        self.flags.iter().find_map(MailboxListFlag::special_use)
+    }
+
+    /// Whether or not this mailbox can be selected
+    pub fn is_selectable(&self) -> bool {
+        !self.flags.contains(&MailboxListFlag::NoSelect)
+    }
+}
+
+/// The response to the GETQUOTAROOT command
+#[cfg(feature = "rfc9208-quota")]
+#[cfg_attr(docsrs, doc(cfg(feature = "rfc9208-quota")))]
+#[derive(Clone, Debug, Default)]
+pub struct QuotaRootResponse {
+    /// The quota roots that the mailbox belongs to
+    pub roots: Vec<String>,
+
+    /// Usage and limits of each of the quota roots
+    pub quotas: Vec<Quota>,
+}
+
 #[derive(Debug, Default)]
 pub struct SelectResponse {
     pub flags: Vec<MailboxFlag>,
@@ -300,6 +709,9 @@ pub struct SelectResponse {
     pub uid_next: Option<u32>,
     pub uid_validity: Option<u32>,
     pub unseen: Option<u32>,
+
+    /// Only sent by servers with CONDSTORE (RFC 7162), for mailboxes that keep mod-sequences
+    pub highest_modseq: Option<u64>,
 }
 
 /// A token that represents an idling connection.
@@ -310,7 +722,7 @@ pub struct SelectResponse {
 #[cfg_attr(docsrs, doc(cfg(feature = "rfc2177-idle")))]
 pub struct IdleToken {
     pub stream: ResponseStream,
-    sender: mpsc::UnboundedSender<String>,
+    sender: mpsc::UnboundedSender<Vec<u8>>,
 }
 
 #[cfg(feature = "rfc2177-idle")]
@@ -318,7 +730,7 @@ pub struct IdleToken {
 impl Drop for IdleToken {
     fn drop(&mut self) {
         // TODO: should ignore this?
-        self.sender.send(format!("DONE\r\n")).unwrap();
+        self.sender.send(b"DONE\r\n".to_vec()).unwrap();
     }
 }
 
@@ -1,5 +1,8 @@
 use std::fmt;
 
+use crate::response::MailboxFlag;
+use crate::utf7;
+
 /// Commands, without the tag part.
 #[derive(Clone)]
 pub enum Command {
@@ -32,11 +35,143 @@ pub enum Command {
         uids: Vec<u32>,
         items: FetchItems,
     },
+    UidStore {
+        uids: Vec<u32>,
+        action: StoreAction,
+        flags: Vec<MailboxFlag>,
+    },
+    UidCopy {
+        uids: Vec<u32>,
+        mailbox: String,
+    },
+    Expunge,
+
+    /// Uploads a message, which is sent separately as a literal of the given length once the
+    /// server has asked for it
+    Append {
+        mailbox: String,
+        flags: Vec<MailboxFlag>,
+        length: usize,
+    },
+    Create {
+        mailbox: String,
+    },
+    Delete {
+        mailbox: String,
+    },
+    Rename {
+        from: String,
+        to: String,
+    },
+
+    #[cfg(feature = "rfc4315-uidplus")]
+    #[cfg_attr(docsrs, doc(cfg(feature = "rfc4315-uidplus")))]
+    UidExpunge {
+        uids: Vec<u32>,
+    },
+
+    #[cfg(feature = "rfc6851-move")]
+    #[cfg_attr(docsrs, doc(cfg(feature = "rfc6851-move")))]
+    UidMove {
+        uids: Vec<u32>,
+        mailbox: String,
+    },
 
     #[cfg(feature = "rfc2177-idle")]
     #[cfg_attr(docsrs, doc(cfg(feature = "rfc2177-idle")))]
     Idle,
 
+    /// Selects a mailbox and turns on CONDSTORE, so that the server keeps track of which
+    /// messages changed when
+    #[cfg(feature = "rfc7162-condstore")]
+    #[cfg_attr(docsrs, doc(cfg(feature = "rfc7162-condstore")))]
+    SelectCondstore {
+        mailbox: String,
+    },
+
+    /// Fetches only the messages whose mod-sequence is higher than the given one
+    #[cfg(feature = "rfc7162-condstore")]
+    #[cfg_attr(docsrs, doc(cfg(feature = "rfc7162-condstore")))]
+    FetchChangedSince {
+        // TODO: do sequence-set
+        uids: Vec<u32>,
+        items: FetchItems,
+        modseq: u64,
+    },
+
+    #[cfg(feature = "rfc2342-namespace")]
+    #[cfg_attr(docsrs, doc(cfg(feature = "rfc2342-namespace")))]
+    Namespace,
+
+    #[cfg(feature = "rfc4978-compress")]
+    #[cfg_attr(docsrs, doc(cfg(feature = "rfc4978-compress")))]
+    Compress,
+
+    #[cfg(feature = "rfc2971-id")]
+    #[cfg_attr(docsrs, doc(cfg(feature = "rfc2971-id")))]
+    Id {
+        parameters: Option<Vec<(String, String)>>,
+    },
+
+    #[cfg(feature = "rfc5256-sort")]
+    #[cfg_attr(docsrs, doc(cfg(feature = "rfc5256-sort")))]
+    Sort {
+        criteria: Vec<SortCriterion>,
+        charset: String,
+        search: SearchCriteria,
+    },
+
+    #[cfg(feature = "rfc5256-sort")]
+    #[cfg_attr(docsrs, doc(cfg(feature = "rfc5256-sort")))]
+    UidSort {
+        criteria: Vec<SortCriterion>,
+        charset: String,
+        search: SearchCriteria,
+    },
+
+    #[cfg(feature = "rfc5256-thread")]
+    #[cfg_attr(docsrs, doc(cfg(feature = "rfc5256-thread")))]
+    Thread {
+        algorithm: ThreadAlgorithm,
+        charset: String,
+        search: SearchCriteria,
+    },
+
+    #[cfg(feature = "rfc5256-thread")]
+    #[cfg_attr(docsrs, doc(cfg(feature = "rfc5256-thread")))]
+    UidThread {
+        algorithm: ThreadAlgorithm,
+        charset: String,
+        search: SearchCriteria,
+    },
+
+    #[cfg(feature = "rfc5464-metadata")]
+    #[cfg_attr(docsrs, doc(cfg(feature = "rfc5464-metadata")))]
+    GetMetadata {
+        mailbox: String,
+        entries: Vec<String>,
+    },
+
+    /// Sets metadata entries on a mailbox, a value of `None` removes the entry
+    #[cfg(feature = "rfc5464-metadata")]
+    #[cfg_attr(docsrs, doc(cfg(feature = "rfc5464-metadata")))]
+    SetMetadata {
+        mailbox: String,
+        values: Vec<(String, Option<String>)>,
+    },
+
+    #[cfg(feature = "rfc9208-quota")]
+    #[cfg_attr(docsrs, doc(cfg(feature = "rfc9208-quota")))]
+    GetQuota {
+        root: String,
+    },
+
+    #[cfg(feature = "rfc9208-quota")]
+    #[cfg_attr(docsrs, doc(cfg(feature = "rfc9208-quota")))]
+    GetQuotaRoot {
+        mailbox: String,
+    },
+
     #[cfg(feature = "rfc2177-idle")]
     #[cfg_attr(docsrs, doc(cfg(feature = "rfc2177-idle")))]
     Done,
@@ -59,40 +194,347 @@ impl fmt::Display for Command {
             Capability => write!(f, "CAPABILITY"),
             Starttls => write!(f, "STARTTLS"),
             Login { username, password } => write!(f, "LOGIN {:?} {:?}", username, password),
-            Select { mailbox } => write!(f, "SELECT {}", mailbox),
+            Select { mailbox } => write!(f, "SELECT {}", Mailbox(mailbox)),
             Search { criteria } => write!(f, "SEARCH {}", criteria),
             UidSearch { criteria } => write!(f, "UID SEARCH {}", criteria),
-            List { reference, mailbox } => write!(f, "LIST {:?} {:?}", reference, mailbox),
-            Fetch { uids, items } => write!(
+            List { reference, mailbox } => {
+                write!(f, "LIST {} {}", Mailbox(reference), Mailbox(mailbox))
+            }
+            Fetch { uids, items } => write!(f, "FETCH {} {}", SequenceSet(uids), items),
+            UidFetch { uids, items } => write!(f, "UID FETCH {} {}", SequenceSet(uids), items),
+            UidStore {
+                uids,
+                action,
+                flags,
+            } => write!(
                 f,
-                "FETCH {} {}",
-                uids.iter()
-                    .map(|s| s.to_string())
+                "UID STORE {} {}FLAGS.SILENT ({})",
+                SequenceSet(uids),
+                action,
+                flags
+                    .iter()
+                    .map(|flag| flag.to_string())
                     .collect::<Vec<_>>()
-                    .join(","),
-                items
+                    .join(" ")
             ),
-            UidFetch { uids, items } => write!(
+            UidCopy { uids, mailbox } => {
+                write!(f, "UID COPY {} {}", SequenceSet(uids), Mailbox(mailbox))
+            }
+            Expunge => write!(f, "EXPUNGE"),
+            Append {
+                mailbox,
+                flags,
+                length,
+            } => write!(
                 f,
-                "UID FETCH {} {}",
-                uids.iter()
-                    .map(|s| s.to_string())
+                "APPEND {} ({}) {{{}}}",
+                Mailbox(mailbox),
+                flags
+                    .iter()
+                    .map(|flag| flag.to_string())
                     .collect::<Vec<_>>()
-                    .join(","),
-                items
+                    .join(" "),
+                length
             ),
+            Create { mailbox } => write!(f, "CREATE {}", Mailbox(mailbox)),
+            Delete { mailbox } => write!(f, "DELETE {}", Mailbox(mailbox)),
+            Rename { from, to } => write!(f, "RENAME {} {}", Mailbox(from), Mailbox(to)),
+
+            #[cfg(feature = "rfc4315-uidplus")]
+            UidExpunge { uids } => write!(f, "UID EXPUNGE {}", SequenceSet(uids)),
+
+            #[cfg(feature = "rfc6851-move")]
+            UidMove { uids, mailbox } => {
+                write!(f, "UID MOVE {} {}", SequenceSet(uids), Mailbox(mailbox))
+            }
 
             #[cfg(feature = "rfc2177-idle")]
             Idle => write!(f, "IDLE"),
             #[cfg(feature = "rfc2177-idle")]
             Done => write!(f, "DONE"),
+
+            #[cfg(feature = "rfc2342-namespace")]
+            Namespace => write!(f, "NAMESPACE"),
+
+            #[cfg(feature = "rfc7162-condstore")]
+            SelectCondstore { mailbox } => write!(f, "SELECT {} (CONDSTORE)", Mailbox(mailbox)),
+            #[cfg(feature = "rfc7162-condstore")]
+            FetchChangedSince {
+                uids,
+                items,
+                modseq,
+            } => write!(
+                f,
+                "FETCH {} {} (CHANGEDSINCE {})",
+                SequenceSet(uids),
+                items,
+                modseq
+            ),
+
+            #[cfg(feature = "rfc4978-compress")]
+            Compress => write!(f, "COMPRESS DEFLATE"),
+
+            #[cfg(feature = "rfc2971-id")]
+            Id { parameters: None } => write!(f, "ID NIL"),
+            #[cfg(feature = "rfc2971-id")]
+            Id {
+                parameters: Some(parameters),
+            } => write!(
+                f,
+                "ID ({})",
+                parameters
+                    .iter()
+                    .map(|(key, value)| format!("{} {}", IString(key), IString(value)))
+                    .collect::<Vec<_>>()
+                    .join(" ")
+            ),
+
+            #[cfg(feature = "rfc5256-sort")]
+            Sort {
+                criteria,
+                charset,
+                search,
+            } => write!(
+                f,
+                "SORT ({}) {} {}",
+                criteria
+                    .iter()
+                    .map(|s| s.to_string())
+                    .collect::<Vec<_>>()
+                    .join(" "),
+                charset,
+                search
+            ),
+            #[cfg(feature = "rfc5256-sort")]
+            UidSort {
+                criteria,
+                charset,
+                search,
+            } => write!(
+                f,
+                "UID SORT ({}) {} {}",
+                criteria
+                    .iter()
+                    .map(|s| s.to_string())
+                    .collect::<Vec<_>>()
+                    .join(" "),
+                charset,
+                search
+            ),
+
+            #[cfg(feature = "rfc5256-thread")]
+            Thread {
+                algorithm,
+                charset,
+                search,
+            } => write!(f, "THREAD {} {} {}", algorithm, charset, search),
+            #[cfg(feature = "rfc5256-thread")]
+            UidThread {
+                algorithm,
+                charset,
+                search,
+            } => write!(f, "UID THREAD {} {} {}", algorithm, charset, search),
+
+            #[cfg(feature = "rfc5464-metadata")]
+            GetMetadata { mailbox, entries } => write!(
+                f,
+                "GETMETADATA {} ({})",
+                Mailbox(mailbox),
+                entries
+                    .iter()
+                    .map(|entry| AString(entry).to_string())
+                    .collect::<Vec<_>>()
+                    .join(" ")
+            ),
+            #[cfg(feature = "rfc5464-metadata")]
+            SetMetadata { mailbox, values } => write!(
+                f,
+                "SETMETADATA {} ({})",
+                Mailbox(mailbox),
+                values
+                    .iter()
+                    .map(|(entry, value)| match value {
+                        Some(value) => format!("{} {}", AString(entry), Literal(value)),
+                        None => format!("{} NIL", AString(entry)),
+                    })
+                    .collect::<Vec<_>>()
+                    .join(" ")
+            ),
+
+            #[cfg(feature = "rfc9208-quota")]
+            GetQuota { root } => write!(f, "GETQUOTA {}", AString(root)),
+            #[cfg(feature = "rfc9208-quota")]
+            GetQuotaRoot { mailbox } => write!(f, "GETQUOTAROOT {}", Mailbox(mailbox)),
+        }
+    }
+}
+
+/// How the flags given to the STORE command are applied to the messages
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum StoreAction {
+    /// Add the flags to the ones the message already has
+    Add,
+
+    /// Remove the flags from the message
+    Remove,
+
+    /// Replace all of the message's flags
+    Replace,
+}
+
+impl fmt::Display for StoreAction {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            StoreAction::Add => write!(f, "+"),
+            StoreAction::Remove => write!(f, "-"),
+            StoreAction::Replace => Ok(()),
+        }
+    }
+}
+
+/// Formats a list of numbers as a sequence set, collapsing consecutive runs into ranges so that
+/// fetching a whole mailbox doesn't produce a gigantic command
+struct SequenceSet<'a>(&'a [u32]);
+
+impl fmt::Display for SequenceSet<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let mut iter = self.0.iter().copied().peekable();
+        let mut first = true;
+        while let Some(start) = iter.next() {
+            let mut end = start;
+            while iter.peek() == Some(&(end + 1)) {
+                end = iter.next().unwrap();
+            }
+
+            if !first {
+                write!(f, ",")?;
+            }
+            first = false;
+
+            if start == end {
+                write!(f, "{}", start)?;
+            } else {
+                write!(f, "{}:{}", start, end)?;
+            }
         }
+        Ok(())
     }
 }
 
+/// Formats an argument as an IMAP `string`: a quoted string when every character may appear
+/// between quotes, and a synchronizing literal otherwise (for 8-bit text or line breaks)
+pub struct IString<'a>(pub &'a str);
+
+impl fmt::Display for IString<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let quotable = self
+            .0
+            .bytes()
+            .all(|b| b.is_ascii() && b != b'\0' && b != b'\r' && b != b'\n');
+        if !quotable {
+            return write!(f, "{}", Literal(self.0));
+        }
+
+        write!(f, "\"")?;
+        for c in self.0.chars() {
+            if c == '"' || c == '\\' {
+                write!(f, "\\")?;
+            }
+            write!(f, "{}", c)?;
+        }
+        write!(f, "\"")
+    }
+}
+
+/// Formats an argument as a synchronizing literal, which can carry any text at all
+pub struct Literal<'a>(pub &'a str);
+
+impl fmt::Display for Literal<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{{{}}}\r\n{}", self.0.len(), self.0)
+    }
+}
+
+/// Formats an argument as an IMAP `astring`, which goes out as a bare atom when none of its
+/// characters need quoting
+pub struct AString<'a>(pub &'a str);
+
+impl fmt::Display for AString<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let is_atom = !self.0.is_empty()
+            && self.0.bytes().all(|b| {
+                b.is_ascii_graphic()
+                    && !matches!(b, b'(' | b')' | b'{' | b'%' | b'*' | b'"' | b'\\')
+            });
+        if is_atom {
+            write!(f, "{}", self.0)
+        } else {
+            write!(f, "{}", IString(self.0))
+        }
+    }
+}
+
+/// Formats a mailbox name, which is encoded as modified UTF-7 before being sent as an `astring`
+pub struct Mailbox<'a>(pub &'a str);
+
+impl fmt::Display for Mailbox<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", AString(&utf7::encode(self.0)))
+    }
+}
+
+/// Splits a formatted command line into the pieces that have to be sent separately: everything up
+/// to and including each literal's `{n}\r\n` header goes out on its own, and the server has to
+/// answer with a continuation before the next piece may follow
+pub(crate) fn split_literals(line: &str) -> Vec<Vec<u8>> {
+    let bytes = line.as_bytes();
+    let mut chunks = Vec::new();
+    let mut start = 0;
+    let mut pos = 0;
+    while let Some(found) = bytes
+        .get(pos..)
+        .and_then(|rest| rest.windows(2).position(|w| w == b"\r\n"))
+    {
+        let end = pos + found + 2;
+        if end == bytes.len() {
+            break;
+        }
+
+        // everything that isn't literal data is a single line, so any CRLF before the end has to
+        // be the one following a literal header
+        let header = &line[..pos + found];
+        let length = header
+            .rfind('{')
+            .filter(|_| header.ends_with('}'))
+            .and_then(|open| header[open + 1..header.len() - 1].parse::<usize>().ok());
+        match length {
+            Some(length) => {
+                chunks.push(bytes[start..end].to_vec());
+                start = end;
+                pos = end + length;
+            }
+            None => pos = end,
+        }
+    }
+    chunks.push(bytes[start..].to_vec());
+    chunks
+}
+
 #[derive(Clone, Debug)]
 pub enum SearchCriteria {
     All,
+
+    /// Messages with UIDs in the given range, an `end` of `None` means up to the last message
+    Uid {
+        start: u32,
+        end: Option<u32>,
+    },
+
+    /// Messages with a header that contains the given value
+    Header {
+        name: String,
+        value: String,
+    },
 }
 
 impl fmt::Display for SearchCriteria {
@@ -100,6 +542,58 @@ impl fmt::Display for SearchCriteria {
         use SearchCriteria::*;
         match self {
             All => write!(f, "ALL"),
+            Uid {
+                start,
+                end: Some(end),
+            } => write!(f, "UID {}:{}", start, end),
+            Uid { start, end: None } => write!(f, "UID {}:*", start),
+            Header { name, value } => write!(f, "HEADER {} {}", AString(name), AString(value)),
+        }
+    }
+}
+
+/// Sort criteria for the SORT command (RFC 5256)
+#[derive(Clone, Debug)]
+pub enum SortCriterion {
+    Arrival,
+    Cc,
+    Date,
+    From,
+    Size,
+    Subject,
+    To,
+    Reverse(Box<SortCriterion>),
+}
+
+impl fmt::Display for SortCriterion {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        use SortCriterion::*;
+        match self {
+            Arrival => write!(f, "ARRIVAL"),
+            Cc => write!(f, "CC"),
+            Date => write!(f, "DATE"),
+            From => write!(f, "FROM"),
+            Size => write!(f, "SIZE"),
+            Subject => write!(f, "SUBJECT"),
+            To => write!(f, "TO"),
+            Reverse(criterion) => write!(f, "REVERSE {}", criterion),
+        }
+    }
+}
+
+/// Threading algorithms for the THREAD command (RFC 5256)
+#[derive(Clone, Debug)]
+pub enum ThreadAlgorithm {
+    OrderedSubject,
+    References,
+}
+
+impl fmt::Display for ThreadAlgorithm {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        use ThreadAlgorithm::*;
+        match self {
+            OrderedSubject => write!(f, "ORDEREDSUBJECT"),
+            References => write!(f, "REFERENCES"),
         }
     }
 }
@@ -114,6 +608,19 @@ pub enum FetchItems {
 
     /// item set that panorama uses, TODO: remove when FetchItems has a builder
     PanoramaAll,
+
+    /// item set that panorama uses for messages whose bodies aren't kept, which only has the
+    /// header instead of the whole message
+    PanoramaHeaders,
+
+    /// item set that panorama uses to identify messages without downloading their bodies
+    PanoramaEnvelope,
+
+    /// item set that panorama uses to store messages whose bodies it already has
+    PanoramaSummary,
+
+    /// item set that panorama uses to synchronize flags
+    PanoramaFlags,
 }
 
 #[derive(Clone, Debug)]
@@ -128,7 +635,182 @@ impl fmt::Display for FetchItems {
             Full => write!(f, "FULL"),
             BodyPeek => write!(f, "(BODY.PEEK[])"),
             PanoramaAll => write!(f, "(FLAGS INTERNALDATE RFC822.SIZE ENVELOPE BODY.PEEK[])"),
+            PanoramaHeaders => write!(
+                f,
+                "(FLAGS INTERNALDATE RFC822.SIZE ENVELOPE BODY.PEEK[HEADER])"
+            ),
+            PanoramaEnvelope => write!(f, "(UID ENVELOPE)"),
+            PanoramaSummary => write!(f, "(UID FLAGS INTERNALDATE ENVELOPE)"),
+            PanoramaFlags => write!(f, "(UID FLAGS)"),
             Items(attrs) => write!(f, ""),
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::{split_literals, AString, Command, FetchItems, IString, Literal, SearchCriteria};
+
+    #[test]
+    fn test_istring() {
+        assert_eq!(IString("").to_string(), "\"\"");
+        assert_eq!(IString("a\"b\\c").to_string(), "\"a\\\"b\\\\c\"");
+        assert_eq!(IString("Entwürfe").to_string(), "{9}\r\nEntwürfe");
+        assert_eq!(IString("a\r\nb").to_string(), "{4}\r\na\r\nb");
+        assert_eq!(Literal("abc").to_string(), "{3}\r\nabc");
+    }
+
+    #[test]
+    fn test_astring() {
+        assert_eq!(AString("INBOX").to_string(), "INBOX");
+        assert_eq!(AString("[Gmail]/Sent").to_string(), "[Gmail]/Sent");
+        assert_eq!(AString("").to_string(), "\"\"");
+        assert_eq!(AString("Sent Items").to_string(), "\"Sent Items\"");
+        assert_eq!(AString("*").to_string(), "\"*\"");
+    }
+
+    #[test]
+    fn test_split_literals() {
+        assert_eq!(split_literals("a1 NOOP\r\n"), vec![b"a1 NOOP\r\n".to_vec()]);
+        assert_eq!(
+            split_literals("a1 APPEND INBOX () {5}\r\n"),
+            vec![b"a1 APPEND INBOX () {5}\r\n".to_vec()]
+        );
+        assert_eq!(
+            split_literals("a1 ID (\"name\" {4}\r\na\r\nb \"os\" {2}\r\nüx)\r\n"),
+            vec![
+                b"a1 ID (\"name\" {4}\r\n".to_vec(),
+                b"a\r\nb \"os\" {2}\r\n".to_vec(),
+                "üx)\r\n".as_bytes().to_vec(),
+            ]
+        );
+    }
+
+    #[cfg(feature = "rfc2971-id")]
+    #[test]
+    fn id() {
+        let cmd = Command::Id {
+            parameters: Some(vec![
+                ("name".to_owned(), "panorama".to_owned()),
+                ("os".to_owned(), "Gentoo \"Linux\"".to_owned()),
+                ("vendor".to_owned(), "Müller".to_owned()),
+            ]),
+        };
+        assert_eq!(
+            cmd.to_string(),
+            "ID (\"name\" \"panorama\" \"os\" \"Gentoo \\\"Linux\\\"\" \"vendor\" {7}\r\nMüller)"
+        );
+        assert_eq!(Command::Id { parameters: None }.to_string(), "ID NIL");
+    }
+
+    #[test]
+    fn mailbox_names() {
+        let cmd = Command::Select {
+            mailbox: "INBOX".to_owned(),
+        };
+        assert_eq!(cmd.to_string(), "SELECT INBOX");
+        let cmd = Command::Create {
+            mailbox: "Entwürfe".to_owned(),
+        };
+        assert_eq!(cmd.to_string(), "CREATE Entw&APw-rfe");
+        let cmd = Command::Rename {
+            from: "Sent Items".to_owned(),
+            to: "AT&T/Gesendete Objekte".to_owned(),
+        };
+        assert_eq!(
+            cmd.to_string(),
+            "RENAME \"Sent Items\" \"AT&-T/Gesendete Objekte\""
+        );
+        let cmd = Command::List {
+            reference: "".to_owned(),
+            mailbox: "*".to_owned(),
+        };
+        assert_eq!(cmd.to_string(), "LIST \"\" \"*\"");
+        let cmd = Command::Append {
+            mailbox: "Brouillons d'été".to_owned(),
+            flags: vec![],
+            length: 10,
+        };
+        assert_eq!(
+            cmd.to_string(),
+            "APPEND \"Brouillons d'&AOk-t&AOk-\" () {10}"
+        );
+    }
+
+    #[test]
+    fn search_header() {
+        let cmd = Command::UidSearch {
+            criteria: SearchCriteria::Header {
+                name: "Message-ID".to_owned(),
+                value: "<a\"b@example.com>".to_owned(),
+            },
+        };
+        assert_eq!(
+            cmd.to_string(),
+            "UID SEARCH HEADER Message-ID \"<a\\\"b@example.com>\""
+        );
+        let criteria = SearchCriteria::Header {
+            name: "Subject".to_owned(),
+            value: "Grüße".to_owned(),
+        };
+        assert_eq!(criteria.to_string(), "HEADER Subject {7}\r\nGrüße");
+    }
+
+    #[cfg(feature = "rfc5464-metadata")]
+    #[test]
+    fn metadata() {
+        let cmd = Command::GetMetadata {
+            mailbox: "".to_owned(),
+            entries: vec!["/shared/comment".to_owned(), "/private/x y".to_owned()],
+        };
+        assert_eq!(
+            cmd.to_string(),
+            "GETMETADATA \"\" (/shared/comment \"/private/x y\")"
+        );
+
+        let cmd = Command::SetMetadata {
+            mailbox: "INBOX".to_owned(),
+            values: vec![
+                (
+                    "/private/comment".to_owned(),
+                    Some("Grüße \"aus\"\r\nBerlin".to_owned()),
+                ),
+                ("/shared/comment".to_owned(), None),
+            ],
+        };
+        assert_eq!(
+            cmd.to_string(),
+            "SETMETADATA INBOX (/private/comment {21}\r\nGrüße \"aus\"\r\nBerlin /shared/comment NIL)"
+        );
+    }
+
+    #[cfg(feature = "rfc9208-quota")]
+    #[test]
+    fn quota() {
+        let cmd = Command::GetQuota {
+            root: "".to_owned(),
+        };
+        assert_eq!(cmd.to_string(), "GETQUOTA \"\"");
+        let cmd = Command::GetQuotaRoot {
+            mailbox: "Sent Items".to_owned(),
+        };
+        assert_eq!(cmd.to_string(), "GETQUOTAROOT \"Sent Items\"");
+    }
+
+    #[test]
+    fn condstore() {
+        let cmd = Command::SelectCondstore {
+            mailbox: "INBOX".to_owned(),
+        };
+        assert_eq!(cmd.to_string(), "SELECT INBOX (CONDSTORE)");
+        let cmd = Command::FetchChangedSince {
+            uids: vec![1, 2, 3],
+            items: FetchItems::PanoramaFlags,
+            modseq: 12345,
+        };
+        assert_eq!(
+            cmd.to_string(),
+            "FETCH 1:3 (UID FLAGS) (CHANGEDSINCE 12345)"
+        );
+    }
+}
@@ -10,7 +10,16 @@
 //!
 //! - RFC3501 (IMAP4) : work-in-progress
 //! - RFC2177 (IDLE) : implemented
-//! - RFC5256 (SORT / THREAD) : planned
+//! - RFC2342 (NAMESPACE) : implemented
+//! - RFC2971 (ID) : implemented
+//! - RFC4315 (UIDPLUS) : UID EXPUNGE only
+//! - RFC4978 (COMPRESS=DEFLATE) : implemented
+//! - RFC5256 (SORT / THREAD) : implemented
+//! - RFC5464 (METADATA) : implemented
+//! - RFC6154 (SPECIAL-USE) : LIST attributes only
+//! - RFC6851 (MOVE) : implemented
+//! - RFC7162 (CONDSTORE) : SELECT (CONDSTORE) and FETCH (CHANGEDSINCE) only
+//! - RFC9208 (QUOTA) : GETQUOTA / GETQUOTAROOT only
 
 #[macro_use]
 extern crate anyhow;
@@ -30,3 +39,4 @@ pub mod codec;
 pub mod command;
 pub mod parser;
 pub mod response;
+pub mod utf7;
@@ -12,6 +12,7 @@ use chrono::{DateTime, FixedOffset, TimeZone};
 use pest::{error::Error, iterators::Pair, Parser};
 
 use crate::response::*;
+use crate::utf7;
 
 use self::literal::literal_internal;
 
@@ -105,6 +106,8 @@ fn build_response_data(pair: Pair<Rule>) -> Response {
         }
         Rule::mailbox_data => Response::MailboxData(build_mailbox_data(pair)),
         Rule::capability_data => Response::Capabilities(build_capabilities(pair)),
+        Rule::id_response => Response::Id(build_id_params_list(unwrap1(pair))),
+        Rule::namespace_response => Response::Namespace(build_namespace_response(pair)),
         Rule::message_data => {
             let mut pairs = pair.into_inner();
             let pair = pairs.next().unwrap();
@@ -160,6 +163,7 @@ fn build_msg_att(pair: Pair<Rule>) -> AttributeValue {
     match pair.as_rule() {
         Rule::msg_att_dynamic => AttributeValue::Flags(pair.into_inner().map(build_flag).collect()),
         Rule::msg_att_static => build_msg_att_static(pair),
+        Rule::msg_att_modseq => AttributeValue::ModSeq(build_number(unwrap1(unwrap1(pair)))),
         _ => unreachable!("{:#?}", pair),
     }
 }
@@ -184,8 +188,7 @@ fn build_msg_att_static(pair: Pair<Rule>) -> AttributeValue {
         }),
         Rule::msg_att_static_body_section => {
             let mut pairs = pair.into_inner();
-            let section = None;
-            pairs.next();
+            let section = build_section(pairs.next().unwrap());
             let index = match pairs.peek().unwrap().as_rule() {
                 Rule::number => Some(build_number(unwrap1(pairs.next().unwrap()))),
                 _ => None,
@@ -202,8 +205,43 @@ fn build_msg_att_static(pair: Pair<Rule>) -> AttributeValue {
     }
 }
 
-fn build_section(pair: Pair<Rule>) -> () {
+fn build_section(pair: Pair<Rule>) -> Option<SectionPath> {
     assert!(matches!(pair.as_rule(), Rule::section));
+    let mut pairs = pair.into_inner().next()?.into_inner();
+    let first = pairs.next().unwrap();
+    Some(match first.as_rule() {
+        Rule::section_msgtext => SectionPath::Full(build_section_msgtext(first)),
+        Rule::section_part => {
+            let part = first.into_inner().map(build_number).collect();
+            let text = pairs.next().map(|pair| match pair.into_inner().next() {
+                Some(msgtext) => build_section_msgtext(msgtext),
+                None => MessageSection::Mime,
+            });
+            SectionPath::Part(part, text)
+        }
+        _ => unreachable!("{:#?}", first),
+    })
+}
+
+fn build_section_msgtext(pair: Pair<Rule>) -> MessageSection {
+    assert!(matches!(pair.as_rule(), Rule::section_msgtext));
+    let upper = pair.as_str().to_ascii_uppercase();
+    let fields = || -> Vec<String> {
+        let header_list = pair.clone().into_inner().next().unwrap();
+        header_list
+            .into_inner()
+            .map(|field| build_astring(unwrap1(field)))
+            .collect()
+    };
+    if upper.starts_with("HEADER.FIELDS.NOT") {
+        MessageSection::HeaderFieldsNot(fields())
+    } else if upper.starts_with("HEADER.FIELDS") {
+        MessageSection::HeaderFields(fields())
+    } else if upper.starts_with("HEADER") {
+        MessageSection::Header
+    } else {
+        MessageSection::Text
+    }
 }
 
 fn build_envelope(pair: Pair<Rule>) -> Envelope {
@@ -282,6 +320,9 @@ fn build_resp_text_code(pair: Pair<Rule>) -> Option<ResponseCode> {
         Rule::resp_text_code_uidvalidity => ResponseCode::UidValidity(build_number(unwrap1(pair))),
         Rule::resp_text_code_uidnext => ResponseCode::UidNext(build_number(unwrap1(pair))),
         Rule::resp_text_code_unseen => ResponseCode::Unseen(build_number(unwrap1(pair))),
+        Rule::resp_text_code_highestmodseq => {
+            ResponseCode::HighestModSeq(build_number(unwrap1(unwrap1(pair))))
+        }
         // TODO: maybe have an actual type for these flags instead of just string
         Rule::resp_text_code_permanentflags => {
             ResponseCode::PermanentFlags(pair.into_inner().map(|p| p.as_str().to_owned()).collect())
@@ -358,16 +399,7 @@ fn build_flag(mut pair: Pair<Rule>) -> MailboxFlag {
         unreachable!("{:#?}", pair);
     }
 
-    match pair.as_str() {
-        "\\Answered" => MailboxFlag::Answered,
-        "\\Flagged" => MailboxFlag::Flagged,
-        "\\Deleted" => MailboxFlag::Deleted,
-        "\\Seen" => MailboxFlag::Seen,
-        "\\Draft" => MailboxFlag::Draft,
-        // s if s.starts_with("\\") => MailboxFlag::Ext(s.to_owned()),
-        // TODO: what??
-        s => MailboxFlag::Ext(s.to_owned()),
-    }
+    MailboxFlag::from(pair.as_str())
 }
 
 fn build_mailbox_data(pair: Pair<Rule>) -> MailboxData {
@@ -398,11 +430,27 @@ fn build_mailbox_data(pair: Pair<Rule>) -> MailboxData {
             let uids = pair.into_inner().map(build_number).collect();
             MailboxData::Search(uids)
         }
+        Rule::mailbox_data_sort => {
+            let uids = pair.into_inner().map(build_number).collect();
+            MailboxData::Sort(uids)
+        }
+        Rule::mailbox_data_thread => {
+            let threads = pair.into_inner().map(build_thread_list).collect();
+            MailboxData::Thread(threads)
+        }
+        Rule::mailbox_data_metadata => build_metadata(pair),
+        Rule::mailbox_data_quota => MailboxData::Quota(build_quota(pair)),
+        Rule::mailbox_data_quotaroot => {
+            let mut pairs = pair.into_inner();
+            let mailbox = build_mailbox(pairs.next().unwrap());
+            let roots = pairs.map(|pair| build_astring(unwrap1(pair))).collect();
+            MailboxData::QuotaRoot { mailbox, roots }
+        }
         _ => unreachable!("{:#?}", pair),
     }
 }
 
-fn build_mailbox_list(pair: Pair<Rule>) -> (Vec<String>, Option<String>, String) {
+fn build_mailbox_list(pair: Pair<Rule>) -> (Vec<MailboxListFlag>, Option<String>, String) {
     assert!(matches!(pair.as_rule(), Rule::mailbox_list));
 
     let mut pairs = pair.into_inner();
@@ -425,21 +473,204 @@ fn build_mailbox_list(pair: Pair<Rule>) -> (Vec<String>, Option<String>, String)
     let s = build_nstring(unwrap1(pair));
 
     pair = pairs.next().unwrap();
+    let mailbox = build_mailbox(pair);
+
+    (flags, s, mailbox)
+}
+
+fn build_mailbox(pair: Pair<Rule>) -> String {
     assert!(matches!(pair.as_rule(), Rule::mailbox));
-    let mailbox = if pair.as_str().to_lowercase() == "inbox" {
+    if pair.as_str().to_lowercase() == "inbox" {
         pair.as_str().to_owned()
     } else {
-        build_astring(unwrap1(pair))
-    };
+        let name = build_astring(unwrap1(pair));
+        utf7::decode(&name).unwrap_or(name)
+    }
+}
 
-    (flags, s, mailbox)
+fn build_metadata(pair: Pair<Rule>) -> MailboxData {
+    assert!(matches!(pair.as_rule(), Rule::mailbox_data_metadata));
+
+    let mut pairs = pair.into_inner();
+    let mailbox = build_mailbox(pairs.next().unwrap());
+    let pair = pairs.next().unwrap();
+    match pair.as_rule() {
+        Rule::metadata_entry_values => {
+            let values = pair
+                .into_inner()
+                .map(|pair| {
+                    let mut pairs = pair.into_inner();
+                    let entry = build_astring(unwrap1(pairs.next().unwrap()));
+                    let value = build_nstring(pairs.next().unwrap());
+                    Metadata { entry, value }
+                })
+                .collect();
+            MailboxData::MetadataSolicited { mailbox, values }
+        }
+        Rule::metadata_entry_list => {
+            let values = pair
+                .into_inner()
+                .map(|pair| build_astring(unwrap1(pair)))
+                .collect();
+            MailboxData::MetadataUnsolicited { mailbox, values }
+        }
+        _ => unreachable!("{:#?}", pair),
+    }
+}
+
+fn build_quota(pair: Pair<Rule>) -> Quota {
+    assert!(matches!(pair.as_rule(), Rule::mailbox_data_quota));
+
+    let mut pairs = pair.into_inner();
+    let root = build_astring(unwrap1(pairs.next().unwrap()));
+    let resources = pairs
+        .next()
+        .unwrap()
+        .into_inner()
+        .map(build_quota_resource)
+        .collect();
+    Quota { root, resources }
+}
+
+fn build_quota_resource(pair: Pair<Rule>) -> QuotaResource {
+    assert!(matches!(pair.as_rule(), Rule::quota_resource));
+
+    let mut pairs = pair.into_inner();
+    let name = match pairs.next().unwrap().as_str().to_uppercase().as_str() {
+        "STORAGE" => QuotaResourceName::Storage,
+        "MESSAGE" => QuotaResourceName::Message,
+        "MAILBOX" => QuotaResourceName::Mailbox,
+        "ANNOTATION-STORAGE" => QuotaResourceName::AnnotationStorage,
+        s => QuotaResourceName::Other(s.to_owned()),
+    };
+    let usage = build_number(pairs.next().unwrap());
+    let limit = build_number(pairs.next().unwrap());
+    QuotaResource { name, usage, limit }
 }
 
-fn build_mbx_list_flags(pair: Pair<Rule>) -> Vec<String> {
+fn build_mbx_list_flags(pair: Pair<Rule>) -> Vec<MailboxListFlag> {
     assert!(matches!(pair.as_rule(), Rule::mbx_list_flags));
-    pair.into_inner()
-        .map(|pair| pair.as_str().to_owned())
-        .collect()
+    pair.into_inner().map(build_mbx_list_flag).collect()
+}
+
+fn build_mbx_list_flag(pair: Pair<Rule>) -> MailboxListFlag {
+    assert!(matches!(
+        pair.as_rule(),
+        Rule::mbx_list_oflag | Rule::mbx_list_sflag
+    ));
+
+    // flags are case-insensitive, and servers don't agree on the casing of \Noselect
+    match pair.as_str().to_ascii_lowercase().as_str() {
+        "\\noinferiors" => MailboxListFlag::NoInferiors,
+        "\\noselect" => MailboxListFlag::NoSelect,
+        "\\marked" => MailboxListFlag::Marked,
+        "\\unmarked" => MailboxListFlag::Unmarked,
+        "\\haschildren" => MailboxListFlag::HasChildren,
+        "\\hasnochildren" => MailboxListFlag::HasNoChildren,
+        "\\all" => MailboxListFlag::SpecialUse(SpecialUse::All),
+        "\\archive" => MailboxListFlag::SpecialUse(SpecialUse::Archive),
+        "\\drafts" => MailboxListFlag::SpecialUse(SpecialUse::Drafts),
+        "\\flagged" => MailboxListFlag::SpecialUse(SpecialUse::Flagged),
+        "\\junk" => MailboxListFlag::SpecialUse(SpecialUse::Junk),
+        "\\sent" => MailboxListFlag::SpecialUse(SpecialUse::Sent),
+        "\\trash" => MailboxListFlag::SpecialUse(SpecialUse::Trash),
+        _ => MailboxListFlag::Ext(pair.as_str().to_owned()),
+    }
+}
+
+fn build_thread_list(pair: Pair<Rule>) -> ThreadNode {
+    assert!(matches!(pair.as_rule(), Rule::thread_list));
+
+    let pair = unwrap1(pair);
+    match pair.as_rule() {
+        Rule::thread_members => build_thread_members(pair),
+        // a list that starts with nested lists has a parent that the server doesn't know about
+        Rule::thread_nested => ThreadNode {
+            id: None,
+            children: pair.into_inner().map(build_thread_list).collect(),
+        },
+        _ => unreachable!("{:#?}", pair),
+    }
+}
+
+fn build_thread_members(pair: Pair<Rule>) -> ThreadNode {
+    assert!(matches!(pair.as_rule(), Rule::thread_members));
+
+    let mut ids = Vec::new();
+    let mut children = Vec::new();
+    for pair in pair.into_inner() {
+        match pair.as_rule() {
+            Rule::nz_number => ids.push(build_number(pair)),
+            Rule::thread_nested => children = pair.into_inner().map(build_thread_list).collect(),
+            _ => unreachable!("{:#?}", pair),
+        }
+    }
+
+    // each message in the list is the only child of the one before it, and the nested lists are
+    // the children of the last one, so build the chain from the bottom up
+    let mut node = None;
+    for id in ids.into_iter().rev() {
+        let children = match node.take() {
+            Some(child) => vec![child],
+            None => std::mem::take(&mut children),
+        };
+        node = Some(ThreadNode {
+            id: Some(id),
+            children,
+        });
+    }
+    node.unwrap()
+}
+
+fn build_namespace_response(pair: Pair<Rule>) -> Namespaces {
+    assert!(matches!(pair.as_rule(), Rule::namespace_response));
+
+    let mut pairs = pair.into_inner();
+    let personal = build_namespace(pairs.next().unwrap());
+    let other_users = build_namespace(pairs.next().unwrap());
+    let shared = build_namespace(pairs.next().unwrap());
+    Namespaces {
+        personal,
+        other_users,
+        shared,
+    }
+}
+
+fn build_namespace(pair: Pair<Rule>) -> Vec<Namespace> {
+    assert!(matches!(pair.as_rule(), Rule::namespace));
+
+    let mut namespaces = Vec::new();
+    for pair in pair.into_inner() {
+        if let Rule::nil = pair.as_rule() {
+            break;
+        }
+
+        // extensions are dropped, since nothing uses them yet
+        let mut pairs = pair.into_inner();
+        let prefix = build_string(pairs.next().unwrap());
+        let delimiter = build_nstring(pairs.next().unwrap());
+        namespaces.push(Namespace { prefix, delimiter });
+    }
+    namespaces
+}
+
+fn build_id_params_list(pair: Pair<Rule>) -> Option<Vec<(String, Option<String>)>> {
+    assert!(matches!(pair.as_rule(), Rule::id_params_list));
+
+    let mut pairs = pair.into_inner().peekable();
+    if let Some(Rule::nil) = pairs.peek().map(|p| p.as_rule()) {
+        return None;
+    }
+
+    let params = pairs
+        .map(|pair| {
+            let mut pairs = pair.into_inner();
+            let key = build_string(pairs.next().unwrap());
+            let value = build_nstring(pairs.next().unwrap());
+            (key, value)
+        })
+        .collect();
+    Some(params)
 }
 
 /// Unwraps a singleton pair (a pair that only has one element in its `inner` list)
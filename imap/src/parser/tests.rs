@@ -207,3 +207,324 @@ fn test_section_8() {
         ))
     );
 }
+
+#[test]
+fn test_body_sections() {
+    let body_section = |section, data: &str| {
+        AttributeValue::BodySection(BodySection {
+            section,
+            index: None,
+            data: Some(data.to_owned()),
+        })
+    };
+    assert_eq!(
+        parse_response(concat!(
+            "* 3 FETCH (UID 7 BODY[HEADER] \"Subject: hi\" ",
+            "BODY[1.2.MIME] \"Content-Type: text/plain\" BODY[2.TEXT] \"hello\" BODY[] \"x\")\r\n",
+        )),
+        Ok(Response::Fetch(
+            3,
+            vec![
+                AttributeValue::Uid(7),
+                body_section(
+                    Some(SectionPath::Full(MessageSection::Header)),
+                    "Subject: hi"
+                ),
+                body_section(
+                    Some(SectionPath::Part(vec![1, 2], Some(MessageSection::Mime))),
+                    "Content-Type: text/plain"
+                ),
+                body_section(
+                    Some(SectionPath::Part(vec![2], Some(MessageSection::Text))),
+                    "hello"
+                ),
+                body_section(None, "x"),
+            ]
+        ))
+    );
+
+    assert_eq!(
+        parse_response(concat!(
+            "* 4 FETCH (BODY[HEADER.FIELDS (SUBJECT \"Message-ID\")] \"Subject: hi\" ",
+            "BODY[1.HEADER.FIELDS.NOT (DATE)] \"From: me\")\r\n",
+        )),
+        Ok(Response::Fetch(
+            4,
+            vec![
+                body_section(
+                    Some(SectionPath::Full(MessageSection::HeaderFields(vec![
+                        "SUBJECT".to_owned(),
+                        "Message-ID".to_owned(),
+                    ]))),
+                    "Subject: hi"
+                ),
+                body_section(
+                    Some(SectionPath::Part(
+                        vec![1],
+                        Some(MessageSection::HeaderFieldsNot(vec!["DATE".to_owned()]))
+                    )),
+                    "From: me"
+                ),
+            ]
+        ))
+    );
+}
+
+#[test]
+fn test_list_special_use() {
+    // example from https://tools.ietf.org/html/rfc6154#section-5.1
+    assert_eq!(
+        parse_response("* LIST (\\Sent \\HasNoChildren) \"/\" \"Sent Mail\"\r\n"),
+        Ok(Response::MailboxData(MailboxData::List {
+            flags: vec![
+                MailboxListFlag::SpecialUse(SpecialUse::Sent),
+                MailboxListFlag::HasNoChildren,
+            ],
+            delimiter: Some("/".to_owned()),
+            name: "Sent Mail".to_owned(),
+        }))
+    );
+
+    assert_eq!(
+        parse_response("* LIST (\\Noselect \\HasChildren) \"/\" \"[Gmail]\"\r\n"),
+        Ok(Response::MailboxData(MailboxData::List {
+            flags: vec![MailboxListFlag::NoSelect, MailboxListFlag::HasChildren],
+            delimiter: Some("/".to_owned()),
+            name: "[Gmail]".to_owned(),
+        }))
+    );
+
+    assert_eq!(
+        parse_response("* LIST (\\Drafts) \"/\" \"AT&-T/Entw&APw-rfe\"\r\n"),
+        Ok(Response::MailboxData(MailboxData::List {
+            flags: vec![MailboxListFlag::SpecialUse(SpecialUse::Drafts)],
+            delimiter: Some("/".to_owned()),
+            name: "AT&T/Entwürfe".to_owned(),
+        }))
+    );
+}
+
+#[test]
+fn test_namespace() {
+    // examples from https://tools.ietf.org/html/rfc2342#section-5
+    assert_eq!(
+        parse_response("* NAMESPACE ((\"\" \"/\")) NIL NIL\r\n"),
+        Ok(Response::Namespace(Namespaces {
+            personal: vec![Namespace {
+                prefix: "".to_owned(),
+                delimiter: Some("/".to_owned()),
+            }],
+            other_users: vec![],
+            shared: vec![],
+        }))
+    );
+
+    assert_eq!(
+        parse_response(concat!(
+            r##"* NAMESPACE (("" "/")("#mh/" "/" "X-PARAM" ("FLAG1" "FLAG2"))) "##,
+            r##"(("~" "/")) (("#shared/" "/")("#public/" "/"))"##,
+            "\r\n",
+        )),
+        Ok(Response::Namespace(Namespaces {
+            personal: vec![
+                Namespace {
+                    prefix: "".to_owned(),
+                    delimiter: Some("/".to_owned()),
+                },
+                Namespace {
+                    prefix: "#mh/".to_owned(),
+                    delimiter: Some("/".to_owned()),
+                },
+            ],
+            other_users: vec![Namespace {
+                prefix: "~".to_owned(),
+                delimiter: Some("/".to_owned()),
+            }],
+            shared: vec![
+                Namespace {
+                    prefix: "#shared/".to_owned(),
+                    delimiter: Some("/".to_owned()),
+                },
+                Namespace {
+                    prefix: "#public/".to_owned(),
+                    delimiter: Some("/".to_owned()),
+                },
+            ],
+        }))
+    );
+}
+
+#[test]
+fn test_id() {
+    // examples from https://tools.ietf.org/html/rfc2971#section-3.3
+    assert_eq!(parse_response("* ID NIL\r\n"), Ok(Response::Id(None)));
+
+    assert_eq!(
+        parse_response(
+            "* ID (\"name\" \"Cyrus\" \"version\" \"1.5\" \"os\" \"sunos\" \"support-url\" NIL)\r\n"
+        ),
+        Ok(Response::Id(Some(vec![
+            ("name".to_owned(), Some("Cyrus".to_owned())),
+            ("version".to_owned(), Some("1.5".to_owned())),
+            ("os".to_owned(), Some("sunos".to_owned())),
+            ("support-url".to_owned(), None),
+        ])))
+    );
+}
+
+#[test]
+fn test_sort() {
+    // example from https://tools.ietf.org/html/rfc5256#section-4
+    assert_eq!(
+        parse_response("* SORT 2 84 882\r\n"),
+        Ok(Response::MailboxData(MailboxData::Sort(vec![2, 84, 882])))
+    );
+    assert_eq!(
+        parse_response("* SORT\r\n"),
+        Ok(Response::MailboxData(MailboxData::Sort(vec![])))
+    );
+}
+
+#[test]
+fn test_thread() {
+    let leaf = |id| ThreadNode {
+        id: Some(id),
+        children: vec![],
+    };
+    let node = |id, children| ThreadNode {
+        id: Some(id),
+        children,
+    };
+
+    // example from https://tools.ietf.org/html/rfc5256#section-4
+    assert_eq!(
+        parse_response("* THREAD (2)(3 6 (4 23)(44 7 96))\r\n"),
+        Ok(Response::MailboxData(MailboxData::Thread(vec![
+            leaf(2),
+            node(
+                3,
+                vec![node(
+                    6,
+                    vec![
+                        node(4, vec![leaf(23)]),
+                        node(44, vec![node(7, vec![leaf(96)])])
+                    ]
+                )]
+            ),
+        ])))
+    );
+
+    // a thread whose root is missing
+    assert_eq!(
+        parse_response("* THREAD ((3)(5))\r\n"),
+        Ok(Response::MailboxData(MailboxData::Thread(vec![
+            ThreadNode {
+                id: None,
+                children: vec![leaf(3), leaf(5)],
+            }
+        ])))
+    );
+
+    assert_eq!(
+        parse_response("* THREAD\r\n"),
+        Ok(Response::MailboxData(MailboxData::Thread(vec![])))
+    );
+}
+
+#[test]
+fn test_quota() {
+    // examples from https://tools.ietf.org/html/rfc9208#section-4
+    assert_eq!(
+        parse_response("* QUOTAROOT INBOX \"\"\r\n"),
+        Ok(Response::MailboxData(MailboxData::QuotaRoot {
+            mailbox: "INBOX".to_owned(),
+            roots: vec!["".to_owned()],
+        }))
+    );
+
+    assert_eq!(
+        parse_response("* QUOTAROOT comp.mail.mime\r\n"),
+        Ok(Response::MailboxData(MailboxData::QuotaRoot {
+            mailbox: "comp.mail.mime".to_owned(),
+            roots: vec![],
+        }))
+    );
+
+    assert_eq!(
+        parse_response("* QUOTA \"\" (STORAGE 10 512 MESSAGE 3 100)\r\n"),
+        Ok(Response::MailboxData(MailboxData::Quota(Quota {
+            root: "".to_owned(),
+            resources: vec![
+                QuotaResource {
+                    name: QuotaResourceName::Storage,
+                    usage: 10,
+                    limit: 512,
+                },
+                QuotaResource {
+                    name: QuotaResourceName::Message,
+                    usage: 3,
+                    limit: 100,
+                },
+            ],
+        })))
+    );
+}
+
+#[test]
+fn test_condstore() {
+    // examples from https://tools.ietf.org/html/rfc7162#section-3.1
+    assert_eq!(
+        parse_response("* OK [HIGHESTMODSEQ 715194045007] Highest\r\n"),
+        Ok(Response::Data(ResponseData {
+            status: Status::Ok,
+            code: Some(ResponseCode::HighestModSeq(715194045007)),
+            information: Some("Highest".to_owned()),
+        }))
+    );
+
+    assert_eq!(
+        parse_response(
+            "* OK [NOMODSEQ] Sorry, this mailbox format doesn't support modsequences\r\n"
+        ),
+        Ok(Response::Data(ResponseData {
+            status: Status::Ok,
+            code: Some(ResponseCode::Other("NOMODSEQ".to_owned(), None)),
+            information: Some("Sorry, this mailbox format doesn't support modsequences".to_owned()),
+        }))
+    );
+
+    assert_eq!(
+        parse_response("* 1 FETCH (UID 4 MODSEQ (65402) FLAGS (\\Seen))\r\n"),
+        Ok(Response::Fetch(
+            1,
+            vec![
+                AttributeValue::Uid(4),
+                AttributeValue::ModSeq(65402),
+                AttributeValue::Flags(vec![MailboxFlag::Seen]),
+            ]
+        ))
+    );
+}
+
+#[test]
+fn test_metadata() {
+    // examples from https://tools.ietf.org/html/rfc5464#section-4.4
+    assert_eq!(
+        parse_response("* METADATA \"INBOX\" (/private/comment \"My own comment\")\r\n"),
+        Ok(Response::MailboxData(MailboxData::MetadataSolicited {
+            mailbox: "INBOX".to_owned(),
+            values: vec![Metadata {
+                entry: "/private/comment".to_owned(),
+                value: Some("My own comment".to_owned()),
+            }],
+        }))
+    );
+
+    assert_eq!(
+        parse_response("* METADATA \"\" /shared/comment /private/comment\r\n"),
+        Ok(Response::MailboxData(MailboxData::MetadataUnsolicited {
+            mailbox: "".to_owned(),
+            values: vec!["/shared/comment".to_owned(), "/private/comment".to_owned()],
+        }))
+    );
+}
@@ -21,6 +21,8 @@ pub enum Response {
     },
     Fetch(u32, Vec<AttributeValue>),
     MailboxData(MailboxData),
+    Id(Option<Vec<(String, Option<String>)>>), // RFC 2971
+    Namespace(Namespaces),                     // RFC 2342
 }
 
 #[derive(Clone, Debug, PartialEq, Eq)]
@@ -221,6 +223,10 @@ pub enum Attribute {
 #[derive(Clone, Debug, Eq, PartialEq)]
 pub enum MessageSection {
     Header,
+    /// Only the header fields with the given names (`HEADER.FIELDS`)
+    HeaderFields(Vec<String>),
+    /// Every header field except the ones with the given names (`HEADER.FIELDS.NOT`)
+    HeaderFieldsNot(Vec<String>),
     Mime,
     Text,
 }
@@ -236,11 +242,13 @@ pub enum MailboxData {
     Exists(u32),
     Flags(Vec<MailboxFlag>),
     List {
-        flags: Vec<String>,
+        flags: Vec<MailboxListFlag>,
         delimiter: Option<String>,
         name: String,
     },
     Search(Vec<u32>),
+    Sort(Vec<u32>),          // RFC 5256
+    Thread(Vec<ThreadNode>), // RFC 5256
     Status {
         mailbox: String,
         status: Vec<StatusAttribute>,
@@ -254,6 +262,11 @@ pub enum MailboxData {
         mailbox: String,
         values: Vec<String>,
     },
+    Quota(Quota), // RFC 9208
+    QuotaRoot {
+        mailbox: String,
+        roots: Vec<String>,
+    }, // RFC 9208
 }
 
 #[derive(Debug, Eq, PartialEq, Clone, Hash)]
@@ -267,6 +280,139 @@ pub enum MailboxFlag {
     Ext(String),
 }
 
+impl<'a> From<&'a str> for MailboxFlag {
+    fn from(s: &'a str) -> Self {
+        match s {
+            "\\Answered" => MailboxFlag::Answered,
+            "\\Flagged" => MailboxFlag::Flagged,
+            "\\Deleted" => MailboxFlag::Deleted,
+            "\\Seen" => MailboxFlag::Seen,
+            "\\Draft" => MailboxFlag::Draft,
+            "\\Recent" => MailboxFlag::Recent,
+            s => MailboxFlag::Ext(s.to_owned()),
+        }
+    }
+}
+
+impl fmt::Display for MailboxFlag {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            MailboxFlag::Answered => write!(f, "\\Answered"),
+            MailboxFlag::Flagged => write!(f, "\\Flagged"),
+            MailboxFlag::Deleted => write!(f, "\\Deleted"),
+            MailboxFlag::Seen => write!(f, "\\Seen"),
+            MailboxFlag::Draft => write!(f, "\\Draft"),
+            MailboxFlag::Recent => write!(f, "\\Recent"),
+            MailboxFlag::Ext(s) => write!(f, "{}", s),
+        }
+    }
+}
+
+/// Mailbox name attributes returned by LIST and LSUB
+#[derive(Debug, Eq, PartialEq, Clone, Hash)]
+pub enum MailboxListFlag {
+    NoInferiors,
+    NoSelect,
+    Marked,
+    Unmarked,
+    HasChildren,   // RFC 3348
+    HasNoChildren, // RFC 3348
+    SpecialUse(SpecialUse),
+    Ext(String),
+}
+
+impl MailboxListFlag {
+    /// Returns the special-use attribute if this flag is one
+    pub fn special_use(&self) -> Option<SpecialUse> {
+        match self {
+            MailboxListFlag::SpecialUse(special_use) => Some(*special_use),
+            _ => None,
+        }
+    }
+}
+
+/// Special-use mailbox attributes, RFC 6154
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub enum SpecialUse {
+    All,
+    Archive,
+    Drafts,
+    Flagged,
+    Junk,
+    Sent,
+    Trash,
+}
+
+/// Namespaces returned by the NAMESPACE command, RFC 2342
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Namespaces {
+    pub personal: Vec<Namespace>,
+    pub other_users: Vec<Namespace>,
+    pub shared: Vec<Namespace>,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Namespace {
+    pub prefix: String,
+    pub delimiter: Option<String>,
+}
+
+/// A node in a thread tree returned by the THREAD command, RFC 5256
+///
+/// The id is missing if the server knows that a message is missing from the thread (for example,
+/// a parent message that was deleted). The children are ordered as the server sent them.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct ThreadNode {
+    pub id: Option<u32>,
+    pub children: Vec<ThreadNode>,
+}
+
+impl ThreadNode {
+    /// Returns the ids of every message in this thread in depth-first order
+    pub fn ids(&self) -> Vec<u32> {
+        let mut ids = Vec::new();
+        let mut stack = vec![self];
+        while let Some(node) = stack.pop() {
+            ids.extend(node.id);
+            stack.extend(node.children.iter().rev());
+        }
+        ids
+    }
+}
+
+/// The usage and limits of a quota root, RFC 9208
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Quota {
+    pub root: String,
+    pub resources: Vec<QuotaResource>,
+}
+
+impl Quota {
+    /// Returns the usage and limit of the given resource, if the quota root has a limit on it
+    pub fn get(&self, name: &QuotaResourceName) -> Option<&QuotaResource> {
+        self.resources
+            .iter()
+            .find(|resource| &resource.name == name)
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct QuotaResource {
+    pub name: QuotaResourceName,
+    pub usage: u64,
+    pub limit: u64,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub enum QuotaResourceName {
+    /// Sum of messages' sizes, in units of 1024 octets
+    Storage,
+    Message,
+    Mailbox,
+    AnnotationStorage,
+    Other(String),
+}
+
 #[derive(Debug, Eq, PartialEq, Clone)]
 pub struct Metadata {
     pub entry: String,
@@ -0,0 +1,121 @@
+//! The modified UTF-7 encoding that IMAP uses for mailbox names (RFC 3501, section 5.1.3).
+
+const ALPHABET: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+,";
+
+/// Encodes a mailbox name so that it can be sent to the server
+pub fn encode(name: &str) -> String {
+    let mut out = String::with_capacity(name.len());
+    let mut pending = Vec::new();
+    for c in name.chars() {
+        if (' '..='~').contains(&c) {
+            flush(&mut out, &mut pending);
+            if c == '&' {
+                out.push_str("&-");
+            } else {
+                out.push(c);
+            }
+        } else {
+            let mut buf = [0; 2];
+            pending.extend(c.encode_utf16(&mut buf).iter().copied());
+        }
+    }
+    flush(&mut out, &mut pending);
+    out
+}
+
+/// Writes out a run of non-ASCII characters as a base64 section
+fn flush(out: &mut String, pending: &mut Vec<u16>) {
+    if pending.is_empty() {
+        return;
+    }
+
+    let bytes = pending
+        .drain(..)
+        .flat_map(|unit| {
+            let [hi, lo] = unit.to_be_bytes();
+            vec![hi, lo]
+        })
+        .collect::<Vec<_>>();
+
+    out.push('&');
+    for chunk in bytes.chunks(3) {
+        let n = chunk
+            .iter()
+            .enumerate()
+            .fold(0u32, |n, (i, b)| n | ((*b as u32) << (16 - 8 * i)));
+        for i in 0..=chunk.len() {
+            out.push(ALPHABET[((n >> (18 - 6 * i)) & 0x3f) as usize] as char);
+        }
+    }
+    out.push('-');
+}
+
+/// Decodes a mailbox name the server sent, returning `None` if it isn't valid modified UTF-7
+pub fn decode(name: &str) -> Option<String> {
+    let mut out = String::with_capacity(name.len());
+    let mut chars = name.chars();
+    while let Some(c) = chars.next() {
+        if c != '&' {
+            out.push(c);
+            continue;
+        }
+
+        let mut bits = 0u32;
+        let mut nbits = 0;
+        let mut units = Vec::new();
+        let mut empty = true;
+        loop {
+            let c = chars.next()?;
+            if c == '-' {
+                break;
+            }
+            empty = false;
+            let value = ALPHABET.iter().position(|a| *a as char == c)? as u32;
+            bits = (bits << 6) | value;
+            nbits += 6;
+            if nbits >= 16 {
+                nbits -= 16;
+                units.push((bits >> nbits) as u16);
+                bits &= (1 << nbits) - 1;
+            }
+        }
+
+        if empty {
+            out.push('&');
+        } else {
+            out.push_str(&String::from_utf16(&units).ok()?);
+        }
+    }
+    Some(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{decode, encode};
+
+    #[test]
+    fn test_encode() {
+        assert_eq!(encode("INBOX"), "INBOX");
+        assert_eq!(encode("Entwürfe"), "Entw&APw-rfe");
+        assert_eq!(encode("AT&T"), "AT&-T");
+        assert_eq!(
+            encode("~peter/mail/台北/日本語"),
+            "~peter/mail/&U,BTFw-/&ZeVnLIqe-"
+        );
+        assert_eq!(encode("😀"), "&2D3eAA-");
+    }
+
+    #[test]
+    fn test_decode() {
+        assert_eq!(decode("INBOX").as_deref(), Some("INBOX"));
+        assert_eq!(decode("Entw&APw-rfe").as_deref(), Some("Entwürfe"));
+        assert_eq!(decode("AT&-T").as_deref(), Some("AT&T"));
+        assert_eq!(
+            decode("~peter/mail/&U,BTFw-/&ZeVnLIqe-").as_deref(),
+            Some("~peter/mail/台北/日本語")
+        );
+        assert_eq!(decode("&2D3eAA-").as_deref(), Some("😀"));
+        assert_eq!(decode("broken&APw"), None);
+        assert_eq!(decode("bad&A!w-"), None);
+    }
+}
@@ -0,0 +1,18 @@
+-- set up the first time the mail store is opened with encryption turned on. the key is derived
+-- from the passphrase and the salt, and the check value is a known string encrypted with it, so a
+-- wrong passphrase can be caught before anything gets written with the wrong key
+CREATE TABLE IF NOT EXISTS "encryption" (
+    "id" INTEGER PRIMARY KEY CHECK ("id" = 1),
+    "salt" TEXT NOT NULL,
+    "check_value" TEXT NOT NULL,
+    -- whether everything that was stored before encryption was turned on has been encrypted
+    "converted" INTEGER NOT NULL DEFAULT 0
+);
+
+-- what threads are looked up by when matching replies by subject, since the subject itself can't
+-- be compared once it's encrypted. this is the lowercased subject, or a keyed hash of it when the
+-- store is encrypted
+ALTER TABLE "threads" ADD COLUMN "subject_key" TEXT;
+UPDATE "threads" SET subject_key = lower(subject);
+DROP INDEX IF EXISTS "threads_subject";
+CREATE INDEX IF NOT EXISTS "threads_subject_key" ON "threads" ("account", "subject_key");
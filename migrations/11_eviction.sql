@@ -0,0 +1,7 @@
+-- bodies that were removed from the mail directory to save space. the messages that had them are
+-- still listed, and the body is downloaded again the next time one of them is opened
+ALTER TABLE "contents" ADD COLUMN "evicted" INTEGER NOT NULL DEFAULT 0;
+
+-- bodies that are only a message's header, from folders that are set to only keep headers. they
+-- aren't evicted, since they're all there is, and the whole message is downloaded when opened
+ALTER TABLE "contents" ADD COLUMN "headers_only" INTEGER NOT NULL DEFAULT 0;
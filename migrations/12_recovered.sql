@@ -0,0 +1,8 @@
+-- message bodies that were found in the mail directory after the database was lost or rebuilt.
+-- nothing is known about them except what's in the body itself, so they're matched back up with
+-- messages by Message-ID as accounts are synced, instead of being downloaded again
+CREATE TABLE IF NOT EXISTS "recovered_bodies" (
+    "hash" TEXT PRIMARY KEY,
+    "message_id" TEXT NOT NULL
+);
+CREATE INDEX IF NOT EXISTS "recovered_bodies_message_id" ON "recovered_bodies" ("message_id");
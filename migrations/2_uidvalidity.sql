@@ -0,0 +1,3 @@
+-- used for matching up messages by Message-ID after the UIDVALIDITY of a folder changes
+CREATE INDEX IF NOT EXISTS "mail_message_id" ON "mail" ("account", "folder", "message_id");
+CREATE INDEX IF NOT EXISTS "mail_filename" ON "mail" ("filename");
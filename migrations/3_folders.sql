@@ -0,0 +1,9 @@
+-- sync state of each folder, used to only fetch the messages that arrived since the last sync
+CREATE TABLE IF NOT EXISTS "folders" (
+    "account" TEXT,
+    "folder" TEXT,
+    "uidvalidity" INTEGER,
+    -- every message with a UID lower than this has already been synced
+    "uidnext" INTEGER,
+    PRIMARY KEY ("account", "folder")
+);
@@ -0,0 +1,10 @@
+-- flags of each message separated by spaces (ex. "\Seen \Flagged")
+ALTER TABLE "mail" ADD COLUMN "flags" TEXT NOT NULL DEFAULT '';
+-- when the flags were last synced with the server (unix millis), local changes that are newer
+-- than this win over the flags on the server
+ALTER TABLE "mail" ADD COLUMN "flags_synced_at" INTEGER;
+-- what each folder looked like the last time its flags were synced, so that a folder that hasn't
+-- changed since doesn't need every message's flags fetched again. the highest mod-sequence is
+-- only known for servers with CONDSTORE. both are cleared whenever the folder's row is replaced
+ALTER TABLE "folders" ADD COLUMN "message_count" INTEGER;
+ALTER TABLE "folders" ADD COLUMN "highestmodseq" INTEGER;
@@ -0,0 +1,19 @@
+-- journal of changes made locally that still need to be applied on the server, in the order they
+-- were made
+CREATE TABLE IF NOT EXISTS "operations" (
+    "id" INTEGER PRIMARY KEY,
+    "account" TEXT,
+    "kind" TEXT,
+    -- message the operation applies to, if any
+    "folder" TEXT,
+    "uidvalidity" INTEGER,
+    "uid" INTEGER,
+    -- the operation itself, serialized as JSON
+    "payload" TEXT,
+    -- unix millis
+    "created_at" INTEGER,
+    "attempts" INTEGER NOT NULL DEFAULT 0,
+    "last_error" TEXT,
+    -- set once the operation has failed too many times to keep retrying
+    "failed" INTEGER NOT NULL DEFAULT 0
+);
@@ -0,0 +1,32 @@
+-- metadata parsed out of each message when it's stored, so the message list doesn't have to open
+-- the files. rows that were stored before this have a NULL size until they're filled in
+ALTER TABLE "mail" ADD COLUMN "date" TEXT;
+ALTER TABLE "mail" ADD COLUMN "size" INTEGER;
+ALTER TABLE "mail" ADD COLUMN "in_reply_to" TEXT;
+-- Message-IDs from the References header separated by spaces
+ALTER TABLE "mail" ADD COLUMN "references" TEXT;
+ALTER TABLE "mail" ADD COLUMN "has_attachment" INTEGER NOT NULL DEFAULT 0;
+-- the start of the plain text body, for previews
+ALTER TABLE "mail" ADD COLUMN "snippet" TEXT;
+
+-- used for listing a folder newest first
+CREATE INDEX IF NOT EXISTS "mail_internaldate" ON "mail" ("account", "folder", "internaldate");
+
+-- senders and recipients of each message
+CREATE TABLE IF NOT EXISTS "addresses" (
+    "id" INTEGER PRIMARY KEY,
+    "mail_id" INTEGER NOT NULL,
+    -- which header the address came from (ex. "from", "to", "cc")
+    "kind" TEXT NOT NULL,
+    -- order of the address within its header
+    "position" INTEGER NOT NULL,
+    "name" TEXT,
+    "address" TEXT NOT NULL
+);
+CREATE INDEX IF NOT EXISTS "addresses_mail_id" ON "addresses" ("mail_id", "kind");
+CREATE INDEX IF NOT EXISTS "addresses_address" ON "addresses" ("address");
+
+CREATE TRIGGER IF NOT EXISTS "mail_delete_addresses" AFTER DELETE ON "mail"
+BEGIN
+    DELETE FROM "addresses" WHERE mail_id = OLD.id;
+END;
@@ -0,0 +1,47 @@
+-- conversations that messages are grouped into
+CREATE TABLE IF NOT EXISTS "threads" (
+    "id" INTEGER PRIMARY KEY,
+    "account" TEXT NOT NULL,
+    -- subject of the conversation with any "Re:" or "Fwd:" taken off, used to pick up replies
+    -- that lost their References header
+    "subject" TEXT NOT NULL
+);
+CREATE INDEX IF NOT EXISTS "threads_subject" ON "threads" ("account", "subject" COLLATE NOCASE);
+
+-- messages stored before this have a NULL thread until the threads are rebuilt
+ALTER TABLE "mail" ADD COLUMN "thread_id" INTEGER;
+CREATE INDEX IF NOT EXISTS "mail_thread_id" ON "mail" ("account", "thread_id");
+
+-- used for finding messages by Message-ID without knowing the folder
+CREATE INDEX IF NOT EXISTS "mail_account_message_id" ON "mail" ("account", "message_id");
+
+-- the Message-IDs each message refers to in In-Reply-To and References, used for finding replies
+-- to a message that came in before it did
+CREATE TABLE IF NOT EXISTS "message_references" (
+    "mail_id" INTEGER NOT NULL,
+    "message_id" TEXT NOT NULL
+);
+CREATE INDEX IF NOT EXISTS "message_references_message_id" ON "message_references" ("message_id");
+CREATE INDEX IF NOT EXISTS "message_references_mail_id" ON "message_references" ("mail_id");
+
+CREATE TRIGGER IF NOT EXISTS "mail_delete_references" AFTER DELETE ON "mail"
+BEGIN
+    DELETE FROM "message_references" WHERE mail_id = OLD.id;
+END;
+
+-- a conversation goes away along with the last message in it
+CREATE TRIGGER IF NOT EXISTS "mail_delete_thread" AFTER DELETE ON "mail"
+WHEN OLD.thread_id IS NOT NULL
+BEGIN
+    DELETE FROM "threads" WHERE id = OLD.thread_id AND NOT EXISTS (
+        SELECT 1 FROM "mail" WHERE account = OLD.account AND thread_id = OLD.thread_id
+    );
+END;
+
+CREATE TRIGGER IF NOT EXISTS "mail_update_thread" AFTER UPDATE OF "thread_id" ON "mail"
+WHEN OLD.thread_id IS NOT NULL AND OLD.thread_id IS NOT NEW.thread_id
+BEGIN
+    DELETE FROM "threads" WHERE id = OLD.thread_id AND NOT EXISTS (
+        SELECT 1 FROM "mail" WHERE account = OLD.account AND thread_id = OLD.thread_id
+    );
+END;
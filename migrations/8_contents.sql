@@ -0,0 +1,37 @@
+-- message bodies in the mail directory, which are named by the SHA-256 of their contents. the same
+-- body is shared by every copy of a message (ex. Gmail's "All Mail" has a copy of everything), so
+-- a file can only be removed once nothing refers to it anymore
+CREATE TABLE IF NOT EXISTS "contents" (
+    "hash" TEXT PRIMARY KEY,
+    -- number of rows in "mail" that refer to this body, kept up to date by the triggers below
+    "refcount" INTEGER NOT NULL DEFAULT 0
+);
+
+ALTER TABLE "mail" ADD COLUMN "content_hash" TEXT;
+UPDATE "mail" SET content_hash = substr(filename, 1, length(filename) - length('.mail'))
+WHERE filename LIKE '%.mail';
+CREATE INDEX IF NOT EXISTS "mail_content_hash" ON "mail" ("content_hash");
+
+INSERT OR REPLACE INTO "contents" (hash, refcount)
+SELECT content_hash, COUNT(*) FROM "mail" WHERE content_hash IS NOT NULL GROUP BY content_hash;
+
+CREATE TRIGGER IF NOT EXISTS "mail_insert_content" AFTER INSERT ON "mail"
+WHEN NEW.content_hash IS NOT NULL
+BEGIN
+    INSERT OR IGNORE INTO "contents" (hash) VALUES (NEW.content_hash);
+    UPDATE "contents" SET refcount = refcount + 1 WHERE hash = NEW.content_hash;
+END;
+
+CREATE TRIGGER IF NOT EXISTS "mail_delete_content" AFTER DELETE ON "mail"
+WHEN OLD.content_hash IS NOT NULL
+BEGIN
+    UPDATE "contents" SET refcount = refcount - 1 WHERE hash = OLD.content_hash;
+END;
+
+CREATE TRIGGER IF NOT EXISTS "mail_update_content" AFTER UPDATE OF content_hash ON "mail"
+WHEN NEW.content_hash IS NOT OLD.content_hash
+BEGIN
+    UPDATE "contents" SET refcount = refcount - 1 WHERE hash = OLD.content_hash;
+    INSERT OR IGNORE INTO "contents" (hash) VALUES (NEW.content_hash);
+    UPDATE "contents" SET refcount = refcount + 1 WHERE hash = NEW.content_hash;
+END;
@@ -0,0 +1,11 @@
+-- accounts that read a local Maildir or mbox file don't have a server to give messages UIDs, so
+-- they're given out here the first time each message is seen. the key is what identifies the
+-- message locally: the unique part of a Maildir file name, or the hash of a message in an mbox
+CREATE TABLE IF NOT EXISTS "local_uids" (
+    "account" TEXT NOT NULL,
+    "folder" TEXT NOT NULL,
+    "key" TEXT NOT NULL,
+    "uid" INTEGER NOT NULL,
+    PRIMARY KEY ("account", "folder", "key")
+);
+CREATE INDEX IF NOT EXISTS "local_uids_uid" ON "local_uids" ("account", "folder", "uid");
@@ -0,0 +1,132 @@
+use std::error::Error;
+use std::fmt;
+
+use anyhow::Result;
+
+use crate::response::{AuthMechanism, Reply};
+
+use super::{ClientAuthenticated, ClientUnauthenticated};
+
+#[async_trait]
+pub trait Auth {
+    /// Performs authentication, consuming the client
+    async fn perform_auth(self, client: ClientUnauthenticated) -> Result<ClientAuthenticated>;
+
+    /// Converts the wrappers around the client once the authentication has happened. Should only
+    /// be called by the `perform_auth` function.
+    fn convert_client(client: ClientUnauthenticated) -> ClientAuthenticated {
+        match client {
+            ClientUnauthenticated::Encrypted(e) => ClientAuthenticated::Encrypted(e),
+            ClientUnauthenticated::Unencrypted(e) => ClientAuthenticated::Unencrypted(e),
+        }
+    }
+}
+
+/// The error returned when the server rejects the credentials, as opposed to the connection itself
+/// failing
+#[derive(Debug)]
+pub struct AuthError(pub Reply);
+
+impl fmt::Display for AuthError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "unable to login: {}", self.0)
+    }
+}
+
+impl Error for AuthError {}
+
+/// Logs in without authenticating, for servers that take mail from anyone who can connect (ex. a
+/// relay on the same machine)
+pub struct Anonymous;
+
+#[async_trait]
+impl Auth for Anonymous {
+    async fn perform_auth(self, client: ClientUnauthenticated) -> Result<ClientAuthenticated> {
+        Ok(<Self as Auth>::convert_client(client))
+    }
+}
+
+/// AUTH PLAIN (RFC 4616)
+pub struct Plain {
+    pub username: String,
+    pub password: String,
+}
+
+#[async_trait]
+impl Auth for Plain {
+    async fn perform_auth(self, mut client: ClientUnauthenticated) -> Result<ClientAuthenticated> {
+        let credentials = format!("\0{}\0{}", self.username, self.password).into_bytes();
+
+        // the credentials are sent along with the command, but they might be asked for again if
+        // the server didn't expect them there
+        let response = credentials.clone();
+        client
+            .authenticate(AuthMechanism::Plain, Some(&credentials), move |_| {
+                Ok(response.clone())
+            })
+            .await?;
+
+        Ok(<Self as Auth>::convert_client(client))
+    }
+}
+
+/// AUTH LOGIN, which isn't standardized but is still the only mechanism on some servers
+pub struct Login {
+    pub username: String,
+    pub password: String,
+}
+
+#[async_trait]
+impl Auth for Login {
+    async fn perform_auth(self, mut client: ClientUnauthenticated) -> Result<ClientAuthenticated> {
+        let username = self.username;
+        let password = self.password;
+
+        // the server asks for the username and then the password, but the prompts it uses aren't
+        // the same everywhere, so only the order is relied on
+        let mut step = 0;
+        client
+            .authenticate(AuthMechanism::Login, None, move |_| {
+                step += 1;
+                match step {
+                    1 => Ok(username.clone().into_bytes()),
+                    2 => Ok(password.clone().into_bytes()),
+                    _ => bail!("server kept asking for more after the password"),
+                }
+            })
+            .await?;
+
+        Ok(<Self as Auth>::convert_client(client))
+    }
+}
+
+/// AUTH XOAUTH2, which logs in with an OAuth 2.0 access token instead of a password
+pub struct XOAuth2 {
+    pub username: String,
+    pub access_token: String,
+}
+
+#[async_trait]
+impl Auth for XOAuth2 {
+    async fn perform_auth(self, mut client: ClientUnauthenticated) -> Result<ClientAuthenticated> {
+        let initial_response = format!(
+            "user={}\x01auth=Bearer {}\x01\x01",
+            self.username, self.access_token
+        );
+
+        // if the token is refused, the server sends the details as a challenge and waits for an
+        // empty response before it fails the command
+        client
+            .authenticate(
+                AuthMechanism::XOAuth2,
+                Some(initial_response.as_bytes()),
+                |challenge| {
+                    debug!("XOAUTH2 error: {}", String::from_utf8_lossy(challenge));
+                    Ok(Vec::new())
+                },
+            )
+            .await?;
+
+        Ok(<Self as Auth>::convert_client(client))
+    }
+}
@@ -0,0 +1,495 @@
+use std::sync::Arc;
+use std::time::Duration;
+
+use anyhow::Result;
+use futures::stream::StreamExt;
+use tokio::{
+    io::{self, AsyncRead, AsyncWrite, AsyncWriteExt, ReadHalf, WriteHalf},
+    time,
+};
+use tokio_rustls::{
+    client::TlsStream, rustls::ClientConfig as RustlsConfig, webpki::DNSNameRef, TlsConnector,
+};
+use tokio_util::codec::FramedRead;
+
+use crate::codec::{check_parameter, encode_data, SmtpCodec};
+use crate::command::Command;
+use crate::parser::parse_ehlo;
+use crate::response::{AuthMechanism, Ehlo, Extension, Reply, ReplyCode, ReplyError, Severity};
+
+use super::auth::AuthError;
+use super::ClientConfig;
+
+pub struct Client<C> {
+    config: ClientConfig,
+    reader: FramedRead<ReadHalf<C>, SmtpCodec>,
+    writer: WriteHalf<C>,
+    ehlo: Option<Ehlo>,
+}
+
+impl<C> Client<C>
+where
+    C: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    pub fn new(conn: C, config: ClientConfig) -> Self {
+        let (read_half, write_half) = io::split(conn);
+        Client {
+            config,
+            reader: FramedRead::new(read_half, SmtpCodec),
+            writer: write_half,
+            ehlo: None,
+        }
+    }
+
+    /// Waits for the server to say it's ready, failing if it doesn't want the connection
+    pub async fn wait_for_greeting(&mut self) -> Result<Reply> {
+        let reply = self.read_reply().await?;
+        if reply.code != ReplyCode::SERVICE_READY {
+            return Err(ReplyError {
+                command: "connection",
+                reply,
+            }
+            .into());
+        }
+        Ok(reply)
+    }
+
+    /// What the server said about itself in its reply to the last EHLO
+    pub fn ehlo_info(&self) -> Option<&Ehlo> {
+        self.ehlo.as_ref()
+    }
+
+    /// Checks if the server advertised the given extension in its reply to EHLO
+    pub fn has_extension(&self, extension: &Extension) -> bool {
+        matches!(&self.ehlo, Some(ehlo) if ehlo.has_extension(extension))
+    }
+
+    pub async fn read_reply(&mut self) -> Result<Reply> {
+        let reply = match time::timeout(self.config.timeout, self.reader.next()).await {
+            Ok(Some(reply)) => reply?,
+            Ok(None) => bail!("connection closed"),
+            Err(_) => bail!("timed out waiting for the server to reply"),
+        };
+        trace!("S>>>C: {:?}", reply);
+        Ok(reply)
+    }
+
+    async fn write_line(&mut self, line: &str) -> Result<()> {
+        trace!("C>>>S: {:?}", line);
+        self.write_raw(format!("{}\r\n", line).as_bytes()).await
+    }
+
+    /// Same as `write_line`, but for lines with credentials in them that shouldn't be logged
+    async fn write_secret_line(&mut self, line: &str) -> Result<()> {
+        trace!("C>>>S: <hidden>");
+        self.write_raw(format!("{}\r\n", line).as_bytes()).await
+    }
+
+    async fn write_raw(&mut self, data: &[u8]) -> Result<()> {
+        let writer = &mut self.writer;
+        let write = async move {
+            writer.write_all(data).await?;
+            writer.flush().await
+        };
+        match time::timeout(self.config.timeout, write).await {
+            Ok(result) => Ok(result?),
+            Err(_) => bail!("timed out sending to the server"),
+        }
+    }
+
+    /// Sends a command and waits for the reply, whatever it is
+    pub async fn execute(&mut self, cmd: Command) -> Result<Reply> {
+        match cmd {
+            Command::Auth { .. } => self.write_secret_line(&cmd.to_string()).await?,
+            _ => self.write_line(&cmd.to_string()).await?,
+        }
+        self.read_reply().await
+    }
+
+    /// Sends a command, failing with a [`ReplyError`] if the server doesn't reply with the given
+    /// severity
+    async fn execute_expect(&mut self, cmd: Command, severity: Severity) -> Result<Reply> {
+        let command = cmd.name();
+        let reply = self.execute(cmd).await?;
+        if reply.code.severity() != severity {
+            return Err(ReplyError { command, reply }.into());
+        }
+        Ok(reply)
+    }
+
+    /// Sends a command, failing with a [`ReplyError`] if the server doesn't accept it
+    pub async fn execute_ok(&mut self, cmd: Command) -> Result<Reply> {
+        self.execute_expect(cmd, Severity::PositiveCompletion).await
+    }
+
+    /// Runs the EHLO command, falling back to HELO for servers that are too old to know it
+    pub async fn ehlo(&mut self) -> Result<&Ehlo> {
+        let hello_name = self.config.hello_name.clone();
+        let reply = self.execute(Command::Ehlo(hello_name.clone())).await?;
+
+        let ehlo = if reply.code.severity() == Severity::PositiveCompletion {
+            parse_ehlo(&reply)
+        } else if reply.code.severity() == Severity::PermanentNegative {
+            debug!("server doesn't know EHLO, using HELO: {}", reply);
+            let reply = self.execute_ok(Command::Helo(hello_name)).await?;
+            Ehlo {
+                domain: parse_ehlo(&reply).domain,
+                extensions: Vec::new(),
+            }
+        } else {
+            return Err(ReplyError {
+                command: "EHLO",
+                reply,
+            }
+            .into());
+        };
+
+        debug!("server extensions: {:?}", ehlo.extensions);
+        self.ehlo = Some(ehlo);
+        Ok(self.ehlo.as_ref().unwrap())
+    }
+
+    pub async fn upgrade(mut self) -> Result<Client<TlsStream<C>>> {
+        if !self.has_extension(&Extension::StartTls) {
+            bail!("server doesn't support STARTTLS");
+        }
+
+        let resp = self.execute_ok(Command::Starttls).await?;
+        debug!("server response to starttls: {}", resp);
+
+        // anything the server sent between its reply and the handshake would be trusted as if it
+        // came over TLS, so it's safer to give up (see CVE-2011-0411)
+        if !self.reader.read_buffer().is_empty() {
+            bail!("server sent data before the TLS handshake");
+        }
+
+        let conn = self.reader.into_inner().unsplit(self.writer);
+        let stream = connect_tls(&self.config.hostname, conn, self.config.timeout).await?;
+        debug!("upgraded, stream is using TLS now");
+
+        // the server forgets everything from before the handshake, so it has to be greeted again
+        let mut client = Client::new(stream, self.config);
+        client.ehlo().await?;
+        Ok(client)
+    }
+
+    /// Runs the AUTH command (RFC 4954). Every time the server sends a challenge, it's decoded and
+    /// passed to `respond`, and whatever that returns is encoded and sent back.
+    pub async fn authenticate(
+        &mut self,
+        mechanism: AuthMechanism,
+        initial_response: Option<&[u8]>,
+        mut respond: impl FnMut(&[u8]) -> Result<Vec<u8>> + Send,
+    ) -> Result<()> {
+        let ehlo = match &self.ehlo {
+            Some(ehlo) => ehlo,
+            None => bail!("EHLO hasn't been sent yet"),
+        };
+        if !ehlo.auth_mechanisms().contains(&mechanism) {
+            bail!("server doesn't support AUTH {}", mechanism);
+        }
+
+        let cmd = Command::Auth {
+            mechanism,
+            initial_response: initial_response.map(base64::encode),
+        };
+        let mut reply = self.execute(cmd).await?;
+        loop {
+            match reply.code {
+                ReplyCode::AUTH_SUCCEEDED => return Ok(()),
+                ReplyCode::AUTH_CONTINUE => {
+                    let challenge = reply.lines.first().map(|s| s.trim()).unwrap_or("");
+                    let response = base64::decode(challenge)
+                        .map_err(|err| anyhow!("server sent an invalid challenge: {}", err))
+                        .and_then(|challenge| respond(&challenge));
+                    let response = match response {
+                        Ok(v) => v,
+                        Err(err) => {
+                            // "*" cancels the exchange (RFC 4954, section 4), so the connection
+                            // can still be used afterwards
+                            self.write_line("*").await?;
+                            self.read_reply().await?;
+                            return Err(err);
+                        }
+                    };
+                    self.write_secret_line(&base64::encode(response)).await?;
+                    reply = self.read_reply().await?;
+                }
+                _ => return Err(AuthError(reply).into()),
+            }
+        }
+    }
+
+    /// Sends a message to the given recipients, returning the server's reply once it has taken
+    /// responsibility for it
+    pub async fn send_mail(
+        &mut self,
+        from: &str,
+        recipients: &[String],
+        message: &[u8],
+    ) -> Result<Reply> {
+        if recipients.is_empty() {
+            bail!("message has no recipients");
+        }
+
+        let mut parameters = Vec::new();
+        if let Some(ehlo) = &self.ehlo {
+            if let Some(max_size) = ehlo.max_size() {
+                if message.len() > max_size {
+                    bail!(
+                        "message is {} bytes, but the server only takes up to {}",
+                        message.len(),
+                        max_size
+                    );
+                }
+            }
+            let has_size = ehlo
+                .extensions
+                .iter()
+                .any(|extension| matches!(extension, Extension::Size(_)));
+            if has_size {
+                parameters.push(format!("SIZE={}", message.len()));
+            }
+        }
+
+        // a server without these would mangle or bounce anything that isn't plain ASCII, so it's
+        // better to fail here than to have the message show up wrong
+        if !message.is_ascii() {
+            if !self.has_extension(&Extension::EightBitMime) {
+                bail!("message has 8-bit data, but the server doesn't support 8BITMIME");
+            }
+            parameters.push("BODY=8BITMIME".to_owned());
+        }
+        if !from.is_ascii() || recipients.iter().any(|to| !to.is_ascii()) {
+            if !self.has_extension(&Extension::SmtpUtf8) {
+                bail!("addresses aren't ASCII, but the server doesn't support SMTPUTF8");
+            }
+            parameters.push("SMTPUTF8".to_owned());
+        }
+
+        self.mail(from, parameters).await?;
+        for to in recipients {
+            if let Err(err) = self.rcpt(to).await {
+                // the refused recipient is what the caller needs to know about, even if the
+                // connection went bad afterwards
+                if let Err(reset_err) = self.reset().await {
+                    warn!(
+                        "couldn't reset after a recipient was refused: {}",
+                        reset_err
+                    );
+                }
+                return Err(err);
+            }
+        }
+        self.data(message).await
+    }
+
+    /// Runs the MAIL command, which starts a new message
+    pub async fn mail(&mut self, from: &str, parameters: Vec<String>) -> Result<Reply> {
+        check_parameter("address", from)?;
+        for parameter in parameters.iter() {
+            check_parameter("parameter", parameter)?;
+        }
+        let cmd = Command::Mail {
+            from: from.to_owned(),
+            parameters,
+        };
+        self.execute_ok(cmd).await
+    }
+
+    /// Runs the RCPT command, adding a recipient to the message
+    pub async fn rcpt(&mut self, to: &str) -> Result<Reply> {
+        check_parameter("address", to)?;
+        let cmd = Command::Rcpt { to: to.to_owned() };
+        self.execute_ok(cmd).await
+    }
+
+    /// Runs the DATA command and sends the message, which finishes it
+    pub async fn data(&mut self, message: &[u8]) -> Result<Reply> {
+        if let Err(err) = self
+            .execute_expect(Command::Data, Severity::PositiveIntermediate)
+            .await
+        {
+            // same as with a refused recipient, the refusal is what matters
+            if let Err(reset_err) = self.reset().await {
+                warn!("couldn't reset after DATA was refused: {}", reset_err);
+            }
+            return Err(err);
+        }
+
+        trace!("C>>>S: <{} bytes of message data>", message.len());
+        self.write_raw(&encode_data(message)).await?;
+        let reply = self.read_reply().await?;
+        if reply.code.severity() != Severity::PositiveCompletion {
+            return Err(ReplyError {
+                command: "DATA",
+                reply,
+            }
+            .into());
+        }
+        Ok(reply)
+    }
+
+    /// Runs the RSET command, throwing away the message that's being sent
+    pub async fn reset(&mut self) -> Result<()> {
+        self.execute_ok(Command::Rset).await?;
+        Ok(())
+    }
+
+    /// Runs the NOOP command
+    pub async fn noop(&mut self) -> Result<()> {
+        self.execute_ok(Command::Noop).await?;
+        Ok(())
+    }
+
+    /// Runs the QUIT command, closing the connection
+    pub async fn quit(mut self) -> Result<()> {
+        self.execute_ok(Command::Quit).await?;
+        let mut conn = self.reader.into_inner().unsplit(self.writer);
+        conn.shutdown().await?;
+        Ok(())
+    }
+}
+
+/// Starts TLS on a connection, checking the server's certificate against the given hostname
+pub(crate) async fn connect_tls<C>(
+    hostname: &str,
+    conn: C,
+    timeout: Duration,
+) -> Result<TlsStream<C>>
+where
+    C: AsyncRead + AsyncWrite + Unpin,
+{
+    let mut tls_config = RustlsConfig::new();
+    tls_config
+        .root_store
+        .add_server_trust_anchors(&webpki_roots::TLS_SERVER_ROOTS);
+    let tls_config = TlsConnector::from(Arc::new(tls_config));
+    let dnsname = DNSNameRef::try_from_ascii_str(hostname)
+        .map_err(|_| anyhow!("{:?} isn't a valid hostname for TLS", hostname))?;
+    match time::timeout(timeout, tls_config.connect(dnsname, conn)).await {
+        Ok(stream) => Ok(stream?),
+        Err(_) => bail!("timed out during the TLS handshake with {}", hostname),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::io::{AsyncReadExt, DuplexStream};
+    use tokio::task::JoinHandle;
+
+    use super::*;
+    use crate::client::ClientConfigBuilder;
+
+    /// Starts a client talking to a server that expects each of the given lines from the client in
+    /// order, answering each one with the reply next to it. The server hangs up once it gets to the
+    /// end, or if the client sends something else.
+    fn scripted(
+        script: Vec<(&'static str, &'static str)>,
+    ) -> (Client<DuplexStream>, JoinHandle<()>) {
+        let (client, mut server) = io::duplex(4096);
+        let server = tokio::spawn(async move {
+            for (expected, reply) in script {
+                let mut received = vec![0; expected.len()];
+                server.read_exact(&mut received).await.unwrap();
+                assert_eq!(String::from_utf8_lossy(&received), expected);
+                server.write_all(reply.as_bytes()).await.unwrap();
+            }
+        });
+        let config = ClientConfigBuilder::default()
+            .hostname("localhost".to_owned())
+            .port(587)
+            .tls(false)
+            .build()
+            .unwrap();
+        (Client::new(client, config), server)
+    }
+
+    fn refused_code(err: &anyhow::Error) -> Option<(&'static str, ReplyCode)> {
+        err.downcast_ref::<ReplyError>()
+            .map(|err| (err.command, err.reply.code))
+    }
+
+    #[tokio::test]
+    async fn test_send_mail() -> Result<()> {
+        let (mut client, server) = scripted(vec![
+            ("MAIL FROM:<me@example.com>\r\n", "250 OK\r\n"),
+            ("RCPT TO:<you@example.com>\r\n", "250 OK\r\n"),
+            ("DATA\r\n", "354 go ahead\r\n"),
+            (
+                "Subject: hi\r\n\r\n..one\r\n...two\r\n.\r\n",
+                "250 2.0.0 queued\r\n",
+            ),
+        ]);
+        let reply = client
+            .send_mail(
+                "me@example.com",
+                &["you@example.com".to_owned()],
+                b"Subject: hi\n\n.one\n..two\n",
+            )
+            .await?;
+        assert_eq!(reply.code, ReplyCode::OK);
+        server.await?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_refused_recipient() -> Result<()> {
+        let (mut client, server) = scripted(vec![
+            ("MAIL FROM:<me@example.com>\r\n", "250 OK\r\n"),
+            ("RCPT TO:<you@example.com>\r\n", "250 OK\r\n"),
+            (
+                "RCPT TO:<nobody@example.com>\r\n",
+                "550 5.1.1 no such user\r\n",
+            ),
+            ("RSET\r\n", "250 OK\r\n"),
+        ]);
+        let recipients = [
+            "you@example.com".to_owned(),
+            "nobody@example.com".to_owned(),
+        ];
+        let err = client
+            .send_mail("me@example.com", &recipients, b"hello\r\n")
+            .await
+            .unwrap_err();
+        assert_eq!(
+            refused_code(&err),
+            Some(("RCPT", ReplyCode::MAILBOX_UNAVAILABLE))
+        );
+        server.await?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_refused_data() -> Result<()> {
+        let (mut client, server) = scripted(vec![
+            ("MAIL FROM:<me@example.com>\r\n", "250 OK\r\n"),
+            ("RCPT TO:<you@example.com>\r\n", "250 OK\r\n"),
+            ("DATA\r\n", "554 no valid recipients\r\n"),
+            ("RSET\r\n", "250 OK\r\n"),
+        ]);
+        let err = client
+            .send_mail(
+                "me@example.com",
+                &["you@example.com".to_owned()],
+                b"hello\r\n",
+            )
+            .await
+            .unwrap_err();
+        assert_eq!(
+            refused_code(&err),
+            Some(("DATA", ReplyCode::TRANSACTION_FAILED))
+        );
+        server.await?;
+
+        // the refusal is still what comes back when the reset fails too
+        let (mut client, server) = scripted(vec![("DATA\r\n", "554 no valid recipients\r\n")]);
+        let err = client.data(b"hello\r\n").await.unwrap_err();
+        assert_eq!(
+            refused_code(&err),
+            Some(("DATA", ReplyCode::TRANSACTION_FAILED))
+        );
+        server.await?;
+        Ok(())
+    }
+}
@@ -0,0 +1,267 @@
+//! SMTP Client
+//! ===
+//!
+//! Like the IMAP client, the SMTP client is implemented as a state machine in the type system:
+//! messages can only be sent once the client has been through authentication, even if that's just
+//! [`Anonymous`][self::auth::Anonymous] for a server that doesn't need it.
+//!
+//! Because there's many client types for the different types of clients, you'll want to start
+//! here:
+//!
+//! - [`ClientBuilder`][self::ClientBuilder] : Constructs the config for the SMTP client
+//!
+//! If you choose not to use the high-level type-safe features of `ClientBuilder`, then you can
+//! also choose to access the lower level [`Client`][self::inner::Client] directly.
+//!
+//! Example
+//! ---
+//!
+//! The following example connects to `mywebsite.com:587` using STARTTLS, logs in, and sends a
+//! message.
+//!
+//! ```no_run
+//! # use anyhow::Result;
+//! # use panorama_smtp::client::{ClientConfigBuilder, auth::{Auth, Plain}};
+//! # async fn test() -> Result<()> {
+//! let config = ClientConfigBuilder::default()
+//!     .hostname("mywebsite.com".to_owned())
+//!     .port(587)
+//!     .tls(false)
+//!     .build().unwrap();
+//! let insecure = config.open().await?;
+//! let unauth = insecure.upgrade().await?;
+//! let auth = Plain {
+//!     username: "me@mywebsite.com".to_owned(),
+//!     password: "hunter2".to_owned(),
+//! };
+//! let mut client = auth.perform_auth(unauth).await?;
+//! let message = b"From: me@mywebsite.com\r\nTo: you@example.com\r\nSubject: hi\r\n\r\nhello\r\n";
+//! client.send_mail("me@mywebsite.com", &["you@example.com".to_owned()], message).await?;
+//! client.quit().await?;
+//! # Ok(())
+//! # }
+//! ```
+
+pub mod auth;
+mod inner;
+
+use std::time::Duration;
+
+use anyhow::Result;
+use tokio::{net::TcpStream, time};
+use tokio_rustls::client::TlsStream;
+
+use crate::codec::check_parameter;
+use crate::response::{AuthMechanism, Ehlo, Extension, Reply};
+
+pub use self::inner::Client;
+
+/// Struct used to start building the config for a client.
+///
+/// Call [`.build`][1] to _build_ the config, then run [`.open`][2] to actually start opening
+/// the connection to the server.
+///
+/// [1]: self::ClientConfigBuilder::build
+/// [2]: self::ClientConfig::open
+pub type ClientBuilder = ClientConfigBuilder;
+
+/// An SMTP client that hasn't been connected yet.
+#[derive(Builder, Clone, Debug)]
+#[builder(build_fn(validate = "Self::validate"))]
+pub struct ClientConfig {
+    /// The hostname of the SMTP server. If using TLS, must be an address
+    hostname: String,
+
+    /// The port of the SMTP server, usually 587 for STARTTLS or 465 for implicit TLS.
+    port: u16,
+
+    /// Whether or not the client is using an encrypted stream from the start (implicit TLS).
+    ///
+    /// To upgrade the connection later with STARTTLS, use the upgrade method.
+    tls: bool,
+
+    /// The name the client gives for itself in EHLO, which should be the client's own hostname.
+    #[builder(default = "\"localhost\".to_owned()")]
+    hello_name: String,
+
+    /// How long to wait on the server before giving up, for each read and write.
+    #[builder(default = "Duration::from_secs(60)")]
+    timeout: Duration,
+}
+
+impl ClientConfigBuilder {
+    fn validate(&self) -> Result<(), String> {
+        if let Some(hello_name) = &self.hello_name {
+            if hello_name.is_empty() || hello_name.contains(' ') {
+                return Err(format!("invalid hello name {:?}", hello_name));
+            }
+            check_parameter("hello name", hello_name).map_err(|err| err.to_string())?;
+        }
+        Ok(())
+    }
+}
+
+impl ClientConfig {
+    pub async fn open(self) -> Result<ClientUnauthenticated> {
+        let addr = (self.hostname.as_ref(), self.port);
+        let conn = match time::timeout(self.timeout, TcpStream::connect(addr)).await {
+            Ok(conn) => conn?,
+            Err(_) => bail!("timed out connecting to {}:{}", self.hostname, self.port),
+        };
+
+        if self.tls {
+            let conn = inner::connect_tls(&self.hostname, conn, self.timeout).await?;
+            let mut inner = Client::new(conn, self);
+            inner.wait_for_greeting().await?;
+            inner.ehlo().await?;
+            Ok(ClientUnauthenticated::Encrypted(inner))
+        } else {
+            let mut inner = Client::new(conn, self);
+            inner.wait_for_greeting().await?;
+            inner.ehlo().await?;
+            Ok(ClientUnauthenticated::Unencrypted(inner))
+        }
+    }
+}
+
+pub enum ClientUnauthenticated {
+    Encrypted(Client<TlsStream<TcpStream>>),
+    Unencrypted(Client<TcpStream>),
+}
+
+impl ClientUnauthenticated {
+    pub async fn upgrade(self) -> Result<ClientUnauthenticated> {
+        match self {
+            // this is a no-op, we don't need to upgrade
+            ClientUnauthenticated::Encrypted(_) => Ok(self),
+            ClientUnauthenticated::Unencrypted(e) => {
+                Ok(ClientUnauthenticated::Encrypted(e.upgrade().await?))
+            }
+        }
+    }
+
+    /// What the server said about itself in its reply to EHLO
+    pub fn ehlo_info(&self) -> Option<&Ehlo> {
+        match self {
+            ClientUnauthenticated::Encrypted(e) => e.ehlo_info(),
+            ClientUnauthenticated::Unencrypted(e) => e.ehlo_info(),
+        }
+    }
+
+    /// Checks if the server that the client is talking to has support for the given extension.
+    pub fn has_extension(&self, extension: &Extension) -> bool {
+        match self {
+            ClientUnauthenticated::Encrypted(e) => e.has_extension(extension),
+            ClientUnauthenticated::Unencrypted(e) => e.has_extension(extension),
+        }
+    }
+
+    /// Exposing low-level authenticate, for implementations of [`Auth`][self::auth::Auth]
+    pub async fn authenticate(
+        &mut self,
+        mechanism: AuthMechanism,
+        initial_response: Option<&[u8]>,
+        respond: impl FnMut(&[u8]) -> Result<Vec<u8>> + Send,
+    ) -> Result<()> {
+        match self {
+            ClientUnauthenticated::Encrypted(e) => {
+                e.authenticate(mechanism, initial_response, respond).await
+            }
+            ClientUnauthenticated::Unencrypted(e) => {
+                e.authenticate(mechanism, initial_response, respond).await
+            }
+        }
+    }
+}
+
+pub enum ClientAuthenticated {
+    Encrypted(Client<TlsStream<TcpStream>>),
+    Unencrypted(Client<TcpStream>),
+}
+
+impl ClientAuthenticated {
+    /// What the server said about itself in its reply to EHLO
+    pub fn ehlo_info(&self) -> Option<&Ehlo> {
+        match self {
+            ClientAuthenticated::Encrypted(e) => e.ehlo_info(),
+            ClientAuthenticated::Unencrypted(e) => e.ehlo_info(),
+        }
+    }
+
+    /// Checks if the server that the client is talking to has support for the given extension.
+    pub fn has_extension(&self, extension: &Extension) -> bool {
+        match self {
+            ClientAuthenticated::Encrypted(e) => e.has_extension(extension),
+            ClientAuthenticated::Unencrypted(e) => e.has_extension(extension),
+        }
+    }
+
+    /// Sends a message, running MAIL, RCPT for each of the recipients, and DATA. If any of the
+    /// recipients are refused, nothing is sent.
+    ///
+    /// The message should be a complete RFC 5322 message, with its headers. Dot-stuffing and line
+    /// endings are taken care of here.
+    pub async fn send_mail(
+        &mut self,
+        from: impl AsRef<str>,
+        recipients: &[String],
+        message: &[u8],
+    ) -> Result<Reply> {
+        match self {
+            ClientAuthenticated::Encrypted(e) => {
+                e.send_mail(from.as_ref(), recipients, message).await
+            }
+            ClientAuthenticated::Unencrypted(e) => {
+                e.send_mail(from.as_ref(), recipients, message).await
+            }
+        }
+    }
+
+    /// Runs the MAIL command
+    pub async fn mail(&mut self, from: impl AsRef<str>, parameters: Vec<String>) -> Result<Reply> {
+        match self {
+            ClientAuthenticated::Encrypted(e) => e.mail(from.as_ref(), parameters).await,
+            ClientAuthenticated::Unencrypted(e) => e.mail(from.as_ref(), parameters).await,
+        }
+    }
+
+    /// Runs the RCPT command
+    pub async fn rcpt(&mut self, to: impl AsRef<str>) -> Result<Reply> {
+        match self {
+            ClientAuthenticated::Encrypted(e) => e.rcpt(to.as_ref()).await,
+            ClientAuthenticated::Unencrypted(e) => e.rcpt(to.as_ref()).await,
+        }
+    }
+
+    /// Runs the DATA command, sending the message
+    pub async fn data(&mut self, message: &[u8]) -> Result<Reply> {
+        match self {
+            ClientAuthenticated::Encrypted(e) => e.data(message).await,
+            ClientAuthenticated::Unencrypted(e) => e.data(message).await,
+        }
+    }
+
+    /// Runs the RSET command
+    pub async fn reset(&mut self) -> Result<()> {
+        match self {
+            ClientAuthenticated::Encrypted(e) => e.reset().await,
+            ClientAuthenticated::Unencrypted(e) => e.reset().await,
+        }
+    }
+
+    /// Runs the NOOP command
+    pub async fn noop(&mut self) -> Result<()> {
+        match self {
+            ClientAuthenticated::Encrypted(e) => e.noop().await,
+            ClientAuthenticated::Unencrypted(e) => e.noop().await,
+        }
+    }
+
+    /// Runs the QUIT command
+    pub async fn quit(self) -> Result<()> {
+        match self {
+            ClientAuthenticated::Encrypted(e) => e.quit().await,
+            ClientAuthenticated::Unencrypted(e) => e.quit().await,
+        }
+    }
+}
@@ -0,0 +1,111 @@
+use anyhow::Result;
+use bytes::{Buf, BytesMut};
+use tokio_util::codec::Decoder;
+
+use crate::parser::parse_reply;
+use crate::response::Reply;
+
+#[derive(Default)]
+pub struct SmtpCodec;
+
+impl Decoder for SmtpCodec {
+    type Item = Reply;
+    type Error = anyhow::Error;
+
+    fn decode(&mut self, src: &mut BytesMut) -> Result<Option<Self::Item>, Self::Error> {
+        // a reply is only complete once the line without a "-" after the code has come in
+        let mut start = 0;
+        while let Some(pos) = src[start..].iter().position(|b| *b == b'\n') {
+            let line = &src[start..start + pos];
+            start += pos + 1;
+
+            if line.get(3) != Some(&b'-') {
+                let reply = parse_reply(String::from_utf8_lossy(&src[..start]))?;
+                src.advance(start);
+                return Ok(Some(reply));
+            }
+        }
+
+        Ok(None)
+    }
+}
+
+/// Turns a message into what gets sent after DATA: every line ends with CRLF, lines that start
+/// with a dot get another one in front (RFC 5321, section 4.5.2), and a line with just a dot marks
+/// the end.
+pub fn encode_data(message: &[u8]) -> Vec<u8> {
+    let mut data = Vec::with_capacity(message.len() + 5);
+    let mut lines = message.split(|b| *b == b'\n').peekable();
+    while let Some(line) = lines.next() {
+        // if the message ends with a newline, there's an empty piece after it that isn't a line
+        if line.is_empty() && lines.peek().is_none() {
+            break;
+        }
+
+        let line = if line.ends_with(b"\r") {
+            &line[..line.len() - 1]
+        } else {
+            line
+        };
+        if line.starts_with(b".") {
+            data.push(b'.');
+        }
+        data.extend_from_slice(line);
+        data.extend_from_slice(b"\r\n");
+    }
+    data.extend_from_slice(b".\r\n");
+    data
+}
+
+/// Checks that a value can be put into a command line as it is. A CR or LF would end the line
+/// early and let whatever comes after it be read as another command, and angle brackets would end
+/// an address in the middle.
+pub fn check_parameter(kind: &str, value: &str) -> Result<()> {
+    if let Some(c) = value.chars().find(|c| matches!(c, '\r' | '\n' | '<' | '>')) {
+        bail!("{} {:?} can't contain {:?}", kind, value, c);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::response::ReplyCode;
+
+    #[test]
+    fn test_decode_partial() {
+        let mut codec = SmtpCodec;
+        let mut buf = BytesMut::from(&b"250-mail.example.com\r\n250-SIZE 1000\r\n250 8BIT"[..]);
+        assert_eq!(codec.decode(&mut buf).unwrap(), None);
+
+        buf.extend_from_slice(b"MIME\r\n220 next");
+        let reply = codec.decode(&mut buf).unwrap().unwrap();
+        assert_eq!(reply.code, ReplyCode::OK);
+        assert_eq!(
+            reply.lines,
+            vec!["mail.example.com", "SIZE 1000", "8BITMIME"]
+        );
+        assert_eq!(&buf[..], b"220 next");
+    }
+
+    #[test]
+    fn test_encode_data() {
+        assert_eq!(encode_data(b""), b".\r\n".to_vec());
+        assert_eq!(
+            encode_data(b"Subject: hi\n\nhello\n.\n..two\r\nend"),
+            b"Subject: hi\r\n\r\nhello\r\n..\r\n...two\r\nend\r\n.\r\n".to_vec()
+        );
+        assert_eq!(encode_data(b"a\r\n.b\r\n"), b"a\r\n..b\r\n.\r\n".to_vec());
+    }
+
+    #[test]
+    fn test_check_parameter() {
+        assert!(check_parameter("address", "me@example.com").is_ok());
+        assert!(check_parameter("address", "").is_ok());
+        assert!(check_parameter("address", "me@example.com\r\nRCPT TO:<you@example.com").is_err());
+        assert!(check_parameter("address", "me@example.com\nDATA").is_err());
+        assert!(check_parameter("address", "me@example.com> SIZE=1").is_err());
+        assert!(check_parameter("address", "<me@example.com").is_err());
+        assert!(check_parameter("hello name", "client.example.com\r").is_err());
+    }
+}
@@ -0,0 +1,81 @@
+//! Commands that can be sent to the server.
+
+use std::fmt;
+
+use crate::response::AuthMechanism;
+
+#[derive(Clone, Debug)]
+pub enum Command {
+    Ehlo(String),
+    Helo(String),
+    Starttls,
+    Auth {
+        mechanism: AuthMechanism,
+        initial_response: Option<String>,
+    },
+    Mail {
+        from: String,
+        parameters: Vec<String>,
+    },
+    Rcpt {
+        to: String,
+    },
+    Data,
+    Rset,
+    Noop,
+    Quit,
+}
+
+impl Command {
+    /// The name of the command, which is safe to log and put in errors
+    pub fn name(&self) -> &'static str {
+        match self {
+            Command::Ehlo(_) => "EHLO",
+            Command::Helo(_) => "HELO",
+            Command::Starttls => "STARTTLS",
+            Command::Auth { .. } => "AUTH",
+            Command::Mail { .. } => "MAIL",
+            Command::Rcpt { .. } => "RCPT",
+            Command::Data => "DATA",
+            Command::Rset => "RSET",
+            Command::Noop => "NOOP",
+            Command::Quit => "QUIT",
+        }
+    }
+}
+
+impl fmt::Display for Command {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        use Command::*;
+
+        match self {
+            Ehlo(domain) => write!(f, "EHLO {}", domain),
+            Helo(domain) => write!(f, "HELO {}", domain),
+            Starttls => write!(f, "STARTTLS"),
+            Auth {
+                mechanism,
+                initial_response,
+            } => {
+                write!(f, "AUTH {}", mechanism)?;
+                if let Some(response) = initial_response {
+                    // an empty initial response has to be sent as "=" (RFC 4954, section 4)
+                    let response = if response.is_empty() { "=" } else { response };
+                    write!(f, " {}", response)?;
+                }
+                Ok(())
+            }
+            Mail { from, parameters } => {
+                write!(f, "MAIL FROM:<{}>", from)?;
+                for parameter in parameters {
+                    write!(f, " {}", parameter)?;
+                }
+                Ok(())
+            }
+            Rcpt { to } => write!(f, "RCPT TO:<{}>", to),
+            Data => write!(f, "DATA"),
+            Rset => write!(f, "RSET"),
+            Noop => write!(f, "NOOP"),
+            Quit => write!(f, "QUIT"),
+        }
+    }
+}
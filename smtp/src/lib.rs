@@ -1,7 +1,33 @@
-#[cfg(test)]
-mod tests {
-    #[test]
-    fn it_works() {
-        assert_eq!(2 + 2, 4);
-    }
-}
+//! Panorama/SMTP
+//! ===
+//!
+//! This is a library that implements the client side of SMTP mail submission according to RFC 5321
+//! and the extensions that are needed to talk to most submission servers. See the
+//! [client][crate::client] module for more information on how to get started with a client
+//! quickly.
+//!
+//! RFCs:
+//!
+//! - RFC5321 (SMTP) : implemented, except for VRFY / EXPN / HELP
+//! - RFC1870 (SIZE) : implemented
+//! - RFC2034 (ENHANCEDSTATUSCODES) : implemented
+//! - RFC3207 (STARTTLS) : implemented
+//! - RFC4954 (AUTH) : PLAIN, LOGIN, and XOAUTH2
+//! - RFC6152 (8BITMIME) : implemented
+//! - RFC6531 (SMTPUTF8) : implemented
+//! - RFC8314 (implicit TLS) : implemented
+
+#[macro_use]
+extern crate anyhow;
+#[macro_use]
+extern crate async_trait;
+#[macro_use]
+extern crate derive_builder;
+#[macro_use]
+extern crate log;
+
+pub mod client;
+pub mod codec;
+pub mod command;
+pub mod parser;
+pub mod response;
@@ -0,0 +1,172 @@
+//! Module that implements parsers for replies from the server.
+//!
+//! The grammar for replies (RFC 5321, section 4.2) is small enough that these are written by hand
+//! instead of with a parser generator.
+
+#[cfg(test)]
+mod tests;
+
+use anyhow::Result;
+
+use crate::response::*;
+
+/// Parses a complete reply, which is every line up to and including the one without a `-` after
+/// the code
+pub fn parse_reply(s: impl AsRef<str>) -> Result<Reply> {
+    let mut code = None;
+    let mut texts = Vec::new();
+    let mut finished = false;
+
+    for line in s.as_ref().lines() {
+        if finished {
+            bail!("reply continues after its last line: {:?}", line);
+        }
+
+        let (line_code, last, text) = parse_reply_line(line)?;
+        match code {
+            Some(code) if code != line_code => {
+                bail!(
+                    "reply has lines with different codes: {} and {}",
+                    code,
+                    line_code
+                )
+            }
+            _ => code = Some(line_code),
+        }
+        texts.push(text);
+        finished = last;
+    }
+
+    let code = match code {
+        Some(code) if finished => code,
+        _ => bail!("reply is incomplete: {:?}", s.as_ref()),
+    };
+
+    // servers that support ENHANCEDSTATUSCODES put the same kind of code in front of every line,
+    // but only the one on the first line counts
+    let enhanced_code = texts
+        .first()
+        .and_then(|text| parse_enhanced_code(text))
+        .filter(|(enhanced_code, _)| enhanced_code.class as u16 == code.0 / 100)
+        .map(|(enhanced_code, _)| enhanced_code);
+    let lines = texts
+        .into_iter()
+        .map(|text| match (enhanced_code, parse_enhanced_code(text)) {
+            (Some(_), Some((_, rest))) => rest.to_owned(),
+            _ => text.to_owned(),
+        })
+        .collect();
+
+    Ok(Reply {
+        code,
+        enhanced_code,
+        lines,
+    })
+}
+
+/// Parses a single line of a reply, returning the code, whether it's the last line, and the text
+/// after the code
+pub fn parse_reply_line(line: &str) -> Result<(ReplyCode, bool, &str)> {
+    let line = line.trim_end_matches(&['\r', '\n'][..]);
+    let bytes = line.as_bytes();
+    if bytes.len() < 3 || !bytes[..3].iter().all(u8::is_ascii_digit) {
+        bail!("reply line doesn't start with a code: {:?}", line);
+    }
+    if !(b'2'..=b'5').contains(&bytes[0]) || !(b'0'..=b'5').contains(&bytes[1]) {
+        bail!("invalid reply code: {:?}", &line[..3]);
+    }
+    let code = ReplyCode(line[..3].parse()?);
+
+    match bytes.get(3) {
+        None => Ok((code, true, "")),
+        Some(b' ') => Ok((code, true, &line[4..])),
+        Some(b'-') => Ok((code, false, &line[4..])),
+        Some(_) => bail!("invalid separator after reply code: {:?}", line),
+    }
+}
+
+/// Parses the enhanced status code (RFC 2034) at the start of the text of a reply line, returning
+/// it along with the rest of the text
+pub fn parse_enhanced_code(text: &str) -> Option<(EnhancedCode, &str)> {
+    let (code, rest) = match text.find(' ') {
+        Some(idx) => (&text[..idx], &text[idx + 1..]),
+        None => (text, ""),
+    };
+
+    let is_number = |s: &str, max_len: usize| {
+        !s.is_empty() && s.len() <= max_len && s.bytes().all(|b| b.is_ascii_digit())
+    };
+    let mut parts = code.split('.');
+    let (class, subject, detail) = match (parts.next(), parts.next(), parts.next(), parts.next()) {
+        (Some(class), Some(subject), Some(detail), None)
+            if is_number(class, 1) && is_number(subject, 3) && is_number(detail, 3) =>
+        {
+            (class, subject, detail)
+        }
+        _ => return None,
+    };
+
+    let enhanced_code = EnhancedCode {
+        class: class.parse().ok()?,
+        subject: subject.parse().ok()?,
+        detail: detail.parse().ok()?,
+    };
+    if !matches!(enhanced_code.class, 2 | 4 | 5) {
+        return None;
+    }
+    Some((enhanced_code, rest))
+}
+
+/// Parses the reply to EHLO, where the first line has the server's name and every line after it
+/// is an extension
+pub fn parse_ehlo(reply: &Reply) -> Ehlo {
+    let mut lines = reply.lines.iter();
+    let domain = lines
+        .next()
+        .and_then(|line| line.split_whitespace().next())
+        .unwrap_or("")
+        .to_owned();
+    let extensions = lines
+        .filter(|line| !line.trim().is_empty())
+        .map(|line| parse_extension(line))
+        .collect();
+    Ehlo { domain, extensions }
+}
+
+/// Parses a single extension from the reply to EHLO, ex. `SIZE 35882577`
+pub fn parse_extension(line: &str) -> Extension {
+    let mut words = line.split_whitespace();
+    let keyword = words.next().unwrap_or("").to_ascii_uppercase();
+    let params = words.map(|word| word.to_owned()).collect::<Vec<_>>();
+
+    // some older servers send "AUTH=LOGIN PLAIN" for clients that followed an early draft of
+    // RFC 4954, which means the same thing as the usual line
+    if let Some(first) = keyword.strip_prefix("AUTH=") {
+        let mechanisms = std::iter::once(first)
+            .chain(params.iter().map(|param| param.as_str()))
+            .filter(|mechanism| !mechanism.is_empty())
+            .map(parse_auth_mechanism)
+            .collect();
+        return Extension::Auth(mechanisms);
+    }
+
+    match keyword.as_str() {
+        "STARTTLS" => Extension::StartTls,
+        "AUTH" => Extension::Auth(params.iter().map(|p| parse_auth_mechanism(p)).collect()),
+        "SIZE" => Extension::Size(params.first().and_then(|size| size.parse().ok())),
+        "8BITMIME" => Extension::EightBitMime,
+        "SMTPUTF8" => Extension::SmtpUtf8,
+        "PIPELINING" => Extension::Pipelining,
+        "ENHANCEDSTATUSCODES" => Extension::EnhancedStatusCodes,
+        _ => Extension::Other(keyword, params),
+    }
+}
+
+pub fn parse_auth_mechanism(s: &str) -> AuthMechanism {
+    match s.to_ascii_uppercase().as_str() {
+        "PLAIN" => AuthMechanism::Plain,
+        "LOGIN" => AuthMechanism::Login,
+        "XOAUTH2" => AuthMechanism::XOAuth2,
+        other => AuthMechanism::Other(other.to_owned()),
+    }
+}
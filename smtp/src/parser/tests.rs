@@ -0,0 +1,122 @@
+use anyhow::Result;
+
+use super::*;
+
+#[test]
+fn test_reply_line() -> Result<()> {
+    assert_eq!(
+        parse_reply_line("220 mail.example.com ESMTP\r\n")?,
+        (ReplyCode::SERVICE_READY, true, "mail.example.com ESMTP")
+    );
+    assert_eq!(
+        parse_reply_line("250-PIPELINING")?,
+        (ReplyCode::OK, false, "PIPELINING")
+    );
+    assert_eq!(
+        parse_reply_line("354")?,
+        (ReplyCode::START_MAIL_INPUT, true, "")
+    );
+    assert!(parse_reply_line("25").is_err());
+    assert!(parse_reply_line("abc hello").is_err());
+    assert!(parse_reply_line("650 nope").is_err());
+    assert!(parse_reply_line("250_what").is_err());
+    Ok(())
+}
+
+#[test]
+fn test_reply() -> Result<()> {
+    assert_eq!(
+        parse_reply("550-5.1.1 The email account that you tried to reach does not exist.\r\n550 5.1.1 Please check the address.\r\n")?,
+        Reply {
+            code: ReplyCode::MAILBOX_UNAVAILABLE,
+            enhanced_code: Some(EnhancedCode {
+                class: 5,
+                subject: 1,
+                detail: 1,
+            }),
+            lines: vec![
+                "The email account that you tried to reach does not exist.".to_owned(),
+                "Please check the address.".to_owned(),
+            ],
+        }
+    );
+
+    // a class that doesn't match the reply code isn't an enhanced code
+    let reply = parse_reply("250 4.0.0 ok\r\n")?;
+    assert_eq!(reply.enhanced_code, None);
+    assert_eq!(reply.lines, vec!["4.0.0 ok"]);
+
+    assert!(parse_reply("250-one\r\n").is_err());
+    assert!(parse_reply("250-one\r\n251 two\r\n").is_err());
+    assert!(parse_reply("250 one\r\n250 two\r\n").is_err());
+    Ok(())
+}
+
+#[test]
+fn test_reply_code() {
+    assert_eq!(ReplyCode::OK.severity(), Severity::PositiveCompletion);
+    assert_eq!(
+        ReplyCode::AUTH_CONTINUE.severity(),
+        Severity::PositiveIntermediate
+    );
+    assert_eq!(
+        ReplyCode::MAILBOX_BUSY.severity(),
+        Severity::TransientNegative
+    );
+    assert_eq!(
+        ReplyCode::AUTH_FAILED.severity(),
+        Severity::PermanentNegative
+    );
+    assert_eq!(ReplyCode::SERVICE_READY.category(), Category::Connections);
+    assert_eq!(
+        ReplyCode::MAILBOX_UNAVAILABLE.category(),
+        Category::MailSystem
+    );
+}
+
+#[test]
+fn test_ehlo() -> Result<()> {
+    let reply = parse_reply(
+        "250-smtp.example.com at your service, [203.0.113.5]\r\n\
+         250-SIZE 35882577\r\n\
+         250-8BITMIME\r\n\
+         250-AUTH LOGIN PLAIN XOAUTH2 OAUTHBEARER\r\n\
+         250-AUTH=LOGIN\r\n\
+         250-enhancedstatuscodes\r\n\
+         250-PIPELINING\r\n\
+         250-CHUNKING\r\n\
+         250 SMTPUTF8\r\n",
+    )?;
+    let ehlo = parse_ehlo(&reply);
+    assert_eq!(ehlo.domain, "smtp.example.com");
+    assert_eq!(
+        ehlo.extensions,
+        vec![
+            Extension::Size(Some(35882577)),
+            Extension::EightBitMime,
+            Extension::Auth(vec![
+                AuthMechanism::Login,
+                AuthMechanism::Plain,
+                AuthMechanism::XOAuth2,
+                AuthMechanism::Other("OAUTHBEARER".to_owned()),
+            ]),
+            Extension::Auth(vec![AuthMechanism::Login]),
+            Extension::EnhancedStatusCodes,
+            Extension::Pipelining,
+            Extension::Other("CHUNKING".to_owned(), vec![]),
+            Extension::SmtpUtf8,
+        ]
+    );
+    assert_eq!(ehlo.max_size(), Some(35882577));
+    assert!(!ehlo.has_starttls());
+    assert_eq!(
+        ehlo.auth_mechanisms(),
+        vec![
+            AuthMechanism::Login,
+            AuthMechanism::Plain,
+            AuthMechanism::XOAuth2,
+            AuthMechanism::Other("OAUTHBEARER".to_owned()),
+        ]
+    );
+    Ok(())
+}
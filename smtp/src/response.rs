@@ -0,0 +1,233 @@
+//! Structs and enums that have to do with replies from the server.
+
+use std::error::Error;
+use std::fmt;
+
+/// A complete reply from the server, which may have been sent over multiple lines
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Reply {
+    pub code: ReplyCode,
+
+    /// The enhanced status code (RFC 2034) in front of the text, if the server sent one
+    pub enhanced_code: Option<EnhancedCode>,
+
+    /// The text of each line, without the codes in front of it
+    pub lines: Vec<String>,
+}
+
+impl Reply {
+    /// Whether the server accepted the command
+    pub fn is_positive(&self) -> bool {
+        matches!(
+            self.code.severity(),
+            Severity::PositiveCompletion | Severity::PositiveIntermediate
+        )
+    }
+
+    /// The text of the reply, with the lines joined back together
+    pub fn message(&self) -> String {
+        self.lines.join("\n")
+    }
+}
+
+impl fmt::Display for Reply {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.code)?;
+        if let Some(enhanced_code) = &self.enhanced_code {
+            write!(f, " {}", enhanced_code)?;
+        }
+        write!(f, " {}", self.lines.join(" "))
+    }
+}
+
+/// The three digit code that every reply starts with (RFC 5321, section 4.2)
+#[derive(Clone, Copy, Debug, Hash, PartialEq, Eq)]
+pub struct ReplyCode(pub u16);
+
+impl ReplyCode {
+    pub const SERVICE_READY: ReplyCode = ReplyCode(220);
+    pub const SERVICE_CLOSING: ReplyCode = ReplyCode(221);
+    pub const AUTH_SUCCEEDED: ReplyCode = ReplyCode(235);
+    pub const OK: ReplyCode = ReplyCode(250);
+    pub const USER_NOT_LOCAL: ReplyCode = ReplyCode(251);
+    pub const AUTH_CONTINUE: ReplyCode = ReplyCode(334);
+    pub const START_MAIL_INPUT: ReplyCode = ReplyCode(354);
+    pub const SERVICE_UNAVAILABLE: ReplyCode = ReplyCode(421);
+    pub const MAILBOX_BUSY: ReplyCode = ReplyCode(450);
+    pub const INSUFFICIENT_STORAGE: ReplyCode = ReplyCode(452);
+    pub const SYNTAX_ERROR: ReplyCode = ReplyCode(500);
+    pub const PARAMETER_SYNTAX_ERROR: ReplyCode = ReplyCode(501);
+    pub const NOT_IMPLEMENTED: ReplyCode = ReplyCode(502);
+    pub const BAD_SEQUENCE: ReplyCode = ReplyCode(503);
+    pub const AUTH_REQUIRED: ReplyCode = ReplyCode(530);
+    pub const AUTH_FAILED: ReplyCode = ReplyCode(535);
+    pub const MAILBOX_UNAVAILABLE: ReplyCode = ReplyCode(550);
+    pub const EXCEEDED_STORAGE: ReplyCode = ReplyCode(552);
+    pub const TRANSACTION_FAILED: ReplyCode = ReplyCode(554);
+
+    /// Whether the command succeeded, failed, or needs more from the client, from the first digit
+    pub fn severity(self) -> Severity {
+        match self.0 / 100 {
+            2 => Severity::PositiveCompletion,
+            3 => Severity::PositiveIntermediate,
+            4 => Severity::TransientNegative,
+            _ => Severity::PermanentNegative,
+        }
+    }
+
+    /// What the reply is about, from the second digit
+    pub fn category(self) -> Category {
+        match self.0 / 10 % 10 {
+            0 => Category::Syntax,
+            1 => Category::Information,
+            2 => Category::Connections,
+            5 => Category::MailSystem,
+            _ => Category::Unspecified,
+        }
+    }
+}
+
+impl fmt::Display for ReplyCode {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+#[derive(Clone, Copy, Debug, Hash, PartialEq, Eq)]
+pub enum Severity {
+    /// 2yz, the command was accepted
+    PositiveCompletion,
+
+    /// 3yz, the command was accepted but the server is waiting for more (ex. the body after DATA)
+    PositiveIntermediate,
+
+    /// 4yz, the command failed but might work if it's tried again later
+    TransientNegative,
+
+    /// 5yz, the command failed and shouldn't be tried again as it is
+    PermanentNegative,
+}
+
+#[derive(Clone, Copy, Debug, Hash, PartialEq, Eq)]
+pub enum Category {
+    Syntax,
+    Information,
+    Connections,
+    Unspecified,
+    MailSystem,
+}
+
+/// An enhanced status code like `5.1.1` (RFC 3463)
+#[derive(Clone, Copy, Debug, Hash, PartialEq, Eq)]
+pub struct EnhancedCode {
+    pub class: u8,
+    pub subject: u16,
+    pub detail: u16,
+}
+
+impl fmt::Display for EnhancedCode {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}.{}.{}", self.class, self.subject, self.detail)
+    }
+}
+
+/// What the server said about itself in its reply to EHLO
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Ehlo {
+    /// The name the server gave for itself
+    pub domain: String,
+
+    pub extensions: Vec<Extension>,
+}
+
+impl Ehlo {
+    pub fn has_starttls(&self) -> bool {
+        self.extensions.contains(&Extension::StartTls)
+    }
+
+    /// The mechanisms that can be used with AUTH, which is empty if the server doesn't support it
+    pub fn auth_mechanisms(&self) -> Vec<AuthMechanism> {
+        let mut mechanisms = Vec::new();
+        for extension in self.extensions.iter() {
+            if let Extension::Auth(list) = extension {
+                for mechanism in list {
+                    if !mechanisms.contains(mechanism) {
+                        mechanisms.push(mechanism.clone());
+                    }
+                }
+            }
+        }
+        mechanisms
+    }
+
+    /// The largest message the server will take, or `None` if it doesn't say
+    pub fn max_size(&self) -> Option<usize> {
+        self.extensions
+            .iter()
+            .find_map(|extension| match extension {
+                Extension::Size(Some(size)) if *size > 0 => Some(*size),
+                _ => None,
+            })
+    }
+
+    pub fn has_extension(&self, extension: &Extension) -> bool {
+        self.extensions.contains(extension)
+    }
+}
+
+/// A single line from the reply to EHLO
+#[derive(Clone, Debug, Hash, PartialEq, Eq)]
+pub enum Extension {
+    StartTls,                 // RFC 3207
+    Auth(Vec<AuthMechanism>), // RFC 4954
+    Size(Option<usize>),      // RFC 1870
+    EightBitMime,             // RFC 6152
+    SmtpUtf8,                 // RFC 6531
+    Pipelining,               // RFC 2920
+    EnhancedStatusCodes,      // RFC 2034
+    Other(String, Vec<String>),
+}
+
+#[derive(Clone, Debug, Hash, PartialEq, Eq)]
+pub enum AuthMechanism {
+    Plain,
+    Login,
+    XOAuth2,
+    Other(String),
+}
+
+impl fmt::Display for AuthMechanism {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            AuthMechanism::Plain => write!(f, "PLAIN"),
+            AuthMechanism::Login => write!(f, "LOGIN"),
+            AuthMechanism::XOAuth2 => write!(f, "XOAUTH2"),
+            AuthMechanism::Other(name) => write!(f, "{}", name),
+        }
+    }
+}
+
+/// The error returned when the server refuses a command, so the reply can be looked at to tell
+/// whether it's worth trying again later
+#[derive(Debug)]
+pub struct ReplyError {
+    /// The name of the command that was refused
+    pub command: &'static str,
+
+    pub reply: Reply,
+}
+
+impl ReplyError {
+    /// Whether the same command might work if it's tried again later
+    pub fn is_transient(&self) -> bool {
+        self.reply.code.severity() == Severity::TransientNegative
+    }
+}
+
+impl fmt::Display for ReplyError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "server refused {}: {}", self.command, self.reply)
+    }
+}
+
+impl Error for ReplyError {}
@@ -10,6 +10,7 @@ use std::path::{Path, PathBuf};
 use anyhow::{Context, Result};
 use futures::{future::TryFutureExt, stream::StreamExt};
 use inotify::{Inotify, WatchMask};
+use sha2::{Digest, Sha256};
 use tokio::{sync::watch, task::JoinHandle};
 use xdg::BaseDirectories;
 
@@ -31,17 +32,109 @@ pub struct Config {
     /// Mail accounts
     #[serde(rename = "mail")]
     pub mail_accounts: HashMap<String, MailAccountConfig>,
+
+    /// Encrypting the mail that's stored in the data directory, which is off if this is missing
+    #[serde(default)]
+    pub encryption: Option<EncryptionConfig>,
+}
+
+/// Where the passphrase for encrypting the mail store comes from. Once the store is encrypted,
+/// the same passphrase has to be given every time it's opened.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct EncryptionConfig {
+    /// Command whose first line of output is the passphrase (ex. `pass show panorama`), which is
+    /// run every time the config is loaded. This is used over `passphrase` if both are set.
+    #[serde(default)]
+    pub passphrase_command: Option<String>,
+
+    /// The passphrase itself, for when it's fine to keep it in the config file
+    #[serde(default)]
+    pub passphrase: Option<String>,
 }
 
 /// Configuration for a single mail account
-#[derive(Serialize, Deserialize, Clone, Debug)]
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
 pub struct MailAccountConfig {
-    /// Imap
-    pub imap: ImapConfig,
+    /// Where the account's mail comes from
+    #[serde(flatten)]
+    pub source: MailSource,
+
+    /// How much of the account's mail is kept around for reading offline
+    #[serde(default)]
+    pub cache: CachePolicy,
+}
+
+impl MailAccountConfig {
+    /// Hash of the details that decide which mailbox this account points to. Changing anything
+    /// else (ex. the password) leaves it alone, but if this changes, the cached mail is for a
+    /// different mailbox and has to be thrown out.
+    pub fn checksum(&self) -> String {
+        let mut hasher = Sha256::new();
+        match &self.source {
+            MailSource::Imap(imap) => {
+                let username = match &imap.auth {
+                    ImapAuth::Plain { username, .. } => username,
+                };
+                hasher.update(imap.server.as_bytes());
+                hasher.update(b"\0");
+                hasher.update(imap.port.to_string().as_bytes());
+                hasher.update(b"\0");
+                hasher.update(username.as_bytes());
+            }
+            MailSource::Maildir(path) => {
+                hasher.update(b"maildir\0");
+                hasher.update(path.to_string_lossy().as_bytes());
+            }
+            MailSource::Mbox(path) => {
+                hasher.update(b"mbox\0");
+                hasher.update(path.to_string_lossy().as_bytes());
+            }
+        }
+        hex::encode(hasher.finalize())
+    }
+}
+
+/// Where a mail account's mail comes from
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub enum MailSource {
+    /// An IMAP server
+    #[serde(rename = "imap")]
+    Imap(ImapConfig),
+
+    /// A Maildir on this machine, like the ones fetchmail, procmail or mbsync deliver to. Folders
+    /// are laid out the way Maildir++ does it.
+    #[serde(rename = "maildir")]
+    Maildir(PathBuf),
+
+    /// An mbox file on this machine, like the spool in /var/mail that the system's MTA delivers
+    /// to. This is only ever read, so flags changed in panorama are only kept locally, and
+    /// messages can't be moved or deleted.
+    #[serde(rename = "mbox")]
+    Mbox(PathBuf),
+}
+
+/// Limits on how many message bodies are kept in the cache. Bodies that are left out are
+/// downloaded again when the message is opened, and the headers of every message are always kept
+/// so folders can still be listed. This only applies to IMAP accounts, since local accounts
+/// already have all of their mail on disk.
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq, Eq)]
+pub struct CachePolicy {
+    /// Bodies of messages that arrived more than this many days ago are removed
+    #[serde(default)]
+    pub keep_days: Option<u32>,
+
+    /// The most space the bodies of the account's messages can take up, in megabytes. The oldest
+    /// messages are removed first when it goes over.
+    #[serde(default)]
+    pub max_size_mb: Option<u64>,
+
+    /// Folders where only the headers of messages are downloaded (ex. Trash or Spam)
+    #[serde(default)]
+    pub headers_only: Vec<String>,
 }
 
 /// Configuring an IMAP server
-#[derive(Serialize, Deserialize, Clone, Debug)]
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
 pub struct ImapConfig {
     /// Host of the IMAP server (needs to be hostname for TLS)
     pub server: String,
@@ -52,13 +145,22 @@ pub struct ImapConfig {
     /// TLS
     pub tls: TlsMethod,
 
+    /// Whether or not to compress the connection if the server supports COMPRESS=DEFLATE
+    #[serde(default = "default_true")]
+    pub compress: bool,
+
+    /// The most connections to keep open to the server at once, including the one that's used for
+    /// IDLE
+    #[serde(default = "default_max_connections")]
+    pub max_connections: usize,
+
     /// Auth
     #[serde(flatten)]
     pub auth: ImapAuth,
 }
 
 /// Method of authentication for the IMAP server
-#[derive(Serialize, Deserialize, Clone, Debug)]
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
 #[serde(tag = "auth")]
 pub enum ImapAuth {
     /// Use plain username/password authentication
@@ -68,7 +170,7 @@ pub enum ImapAuth {
 }
 
 /// Describes when to perform the TLS handshake
-#[derive(Serialize, Deserialize, Clone, Debug)]
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
 pub enum TlsMethod {
     /// Perform TLS handshake immediately upon connection
     #[serde(rename = "on")]
@@ -83,6 +185,14 @@ pub enum TlsMethod {
     Off,
 }
 
+fn default_true() -> bool {
+    true
+}
+
+fn default_max_connections() -> usize {
+    3
+}
+
 async fn read_config(path: impl AsRef<Path>) -> Result<Config> {
     let mut file = File::open(path.as_ref())?;
     let mut contents = Vec::new();
@@ -1,205 +1,609 @@
+use std::collections::HashSet;
+use std::time::Duration;
+
 use anyhow::{Context, Result};
 use futures::{
-    future::{FutureExt, TryFutureExt},
+    future::{self, FutureExt, TryFutureExt},
     stream::{self, Stream, StreamExt, TryStreamExt},
 };
 use notify_rust::{Notification, Timeout};
 use panorama_imap::{
     client::{
         auth::{self, Auth},
-        ClientBuilder, ClientConfig,
+        ClientAuthenticated, ClientBuilder, ClientConfig,
     },
-    command::{Command as ImapCommand, FetchItems},
-    response::{AttributeValue, Envelope, MailboxData, Response},
+    command::{Command as ImapCommand, FetchItems, SearchCriteria, StoreAction},
+    response::{AttributeValue, Envelope, MailboxData, MailboxFlag, Namespaces, Response},
 };
 use tokio::{
     sync::mpsc::{UnboundedReceiver, UnboundedSender},
     task::JoinHandle,
 };
 
-use crate::config::{Config, ConfigWatcher, ImapAuth, MailAccountConfig, TlsMethod};
+use crate::config::{Config, ConfigWatcher, ImapAuth, ImapConfig, TlsMethod};
+
+use super::session::SyncRequest;
+use super::{AccountState, FolderMetadata, MailCommand, MailEvent, MailOperation, MailStore};
+
+/// The maximum number of messages to download in a single UID FETCH command
+const FETCH_CHUNK_SIZE: usize = 500;
+
+/// How long to stay in IDLE before restarting it, comfortably under the 30 minutes servers are
+/// allowed to wait before dropping the connection
+const IDLE_TIMEOUT: Duration = Duration::from_secs(25 * 60);
 
-use super::{MailCommand, MailEvent, MailStore};
+/// How often to check the INBOX when the server doesn't support IDLE
+const POLL_INTERVAL: Duration = Duration::from_secs(60);
 
-/// The main function for the IMAP syncing thread
-pub async fn sync_main(
-    config: Config,
-    acct_name: impl AsRef<str>,
-    acct: MailAccountConfig,
-    mail2ui_tx: UnboundedSender<MailEvent>,
-    mail_store: MailStore,
+/// Applies the operations in the journal on the server, in the order they were made
+///
+/// An operation that fails is left in the journal to be retried on the next connection, unless it
+/// has failed too many times already, in which case it's skipped so it doesn't hold up the rest.
+pub async fn replay_operations(
+    authed: &mut ClientAuthenticated,
+    mail_store: &MailStore,
+    acct_name: &str,
 ) -> Result<()> {
-    let acct_name = acct_name.as_ref().to_owned();
+    let ops = mail_store.get_pending_operations(acct_name).await?;
+    if !ops.is_empty() {
+        debug!("replaying {} operations for {}", ops.len(), acct_name);
+    }
 
-    // loop ensures that the connection is retried after it dies
-    loop {
-        let builder: ClientConfig = ClientBuilder::default()
-            .hostname(acct.imap.server.clone())
-            .port(acct.imap.port)
-            .tls(matches!(acct.imap.tls, TlsMethod::On))
-            .build()
-            .map_err(|err| anyhow!("err: {}", err))?;
-
-        debug!("connecting to {}:{}", &acct.imap.server, acct.imap.port);
-        let unauth = builder.open().await?;
-
-        let unauth = if matches!(acct.imap.tls, TlsMethod::Starttls) {
-            debug!("attempting to upgrade");
-            let client = unauth.upgrade().await?;
-            debug!("upgrade successful");
-            client
-        } else {
-            unauth
-        };
-
-        debug!("preparing to auth");
-        // check if the authentication method is supported
-        let mut authed = match &acct.imap.auth {
-            ImapAuth::Plain { username, password } => {
-                let auth = auth::Plain {
-                    username: username.clone(),
-                    password: password.clone(),
-                };
-                auth.perform_auth(unauth).await?
+    for (id, op) in ops {
+        match apply_operation(authed, mail_store, &op).await {
+            Ok(()) => mail_store.complete_operation(id).await?,
+            Err(err) => {
+                if mail_store.fail_operation(id, &err).await? {
+                    error!("giving up on operation {:?}: {:#}", op, err);
+                    continue;
+                }
+                return Err(err.context(format!("error applying operation {:?}", op)));
             }
-        };
-
-        debug!("authentication successful!");
-
-        let folder_list = authed.list().await?;
-        let _ = mail2ui_tx.send(MailEvent::FolderList(
-            acct_name.clone(),
-            folder_list.clone(),
-        ));
-        debug!("mailbox list: {:?}", folder_list);
-
-        for folder in folder_list.iter() {
-            debug!("folder: {}", folder);
-            let select = authed.select(folder).await?;
-            debug!("select response: {:?}", select);
-
-            if let (Some(exists), Some(uidvalidity)) = (select.exists, select.uid_validity) {
-                // figure out which uids don't exist locally yet
-                let new_uids = stream::iter(1..exists).map(Ok).try_filter_map(|uid| {
-                        mail_store.try_identify_email(&acct_name, &folder, uid, uidvalidity, None)
-                            // invert the option to only select uids that haven't been downloaded
-                            .map_ok(move |o| o.map_or_else(move || Some(uid), |v| None))
-                            .map_err(|err| err.context("error checking if the email is already downloaded [try_identify_email]"))
-                    }).try_collect::<Vec<_>>().await?;
-
-                if !new_uids.is_empty() {
-                    debug!("fetching uids {:?}", new_uids);
-                    let fetched = authed
-                        .uid_fetch(&new_uids, FetchItems::PanoramaAll)
-                        .await
-                        .context("error fetching uids")?;
+        }
+    }
 
-                    fetched
-                        .map(Ok)
-                        .try_for_each_concurrent(None, |(uid, attrs)| {
-                            mail_store.store_email(&acct_name, &folder, uid, uidvalidity, attrs)
-                        })
-                        .await
-                        .context("error during fetch-store")?;
+    Ok(())
+}
+
+async fn apply_operation(
+    authed: &mut ClientAuthenticated,
+    mail_store: &MailStore,
+    op: &MailOperation,
+) -> Result<()> {
+    // if the folder's UIDVALIDITY changed since the operation was made, the UID could point to a
+    // completely different message now, so it's not safe to do anything
+    if let Some((folder, uidvalidity, _)) = op.message() {
+        let select = authed.select(folder).await?;
+        if select.uid_validity != Some(uidvalidity) {
+            debug!("uidvalidity of {} changed, dropping {:?}", folder, op);
+            return Ok(());
+        }
+    }
+
+    match op {
+        MailOperation::SetFlag {
+            uid, flag, added, ..
+        } => {
+            let action = if *added {
+                StoreAction::Add
+            } else {
+                StoreAction::Remove
+            };
+            let flag = MailboxFlag::from(flag.as_str());
+            authed.uid_store(&[*uid], action, vec![flag]).await?;
+        }
+
+        MailOperation::Move { uid, to, .. } => {
+            if authed.has_capability("MOVE").await? {
+                authed.uid_move(&[*uid], to).await?;
+            } else {
+                authed.uid_copy(&[*uid], to).await?;
+                delete_uid(authed, *uid).await?;
+            }
+        }
+
+        MailOperation::Delete { uid, .. } => delete_uid(authed, *uid).await?,
+
+        MailOperation::Expunge { folder } => {
+            authed.select(folder).await?;
+            authed.expunge().await?;
+        }
+
+        MailOperation::Append {
+            folder,
+            filename,
+            message_id,
+            flags,
+        } => {
+            // the connection might have dropped after the last attempt went through
+            if let Some(message_id) = message_id {
+                authed.select(folder).await?;
+                let existing = authed
+                    .uid_search(SearchCriteria::Header {
+                        name: "Message-ID".to_owned(),
+                        value: message_id.clone(),
+                    })
+                    .await?;
+                if !existing.is_empty() {
+                    return Ok(());
                 }
             }
+
+            let message = mail_store.read_mail_file(filename).await?;
+            let flags = flags
+                .iter()
+                .map(|flag| MailboxFlag::from(flag.as_str()))
+                .collect();
+            authed.append(folder, flags, &message).await?;
         }
 
-        tokio::time::sleep(std::time::Duration::from_secs(50)).await;
+        MailOperation::CreateFolder { name } => {
+            if !folder_exists(authed, name).await? {
+                authed.create(name).await?;
+            }
+        }
 
-        // TODO: remove this later
-        continue;
+        MailOperation::DeleteFolder { name } => {
+            if folder_exists(authed, name).await? {
+                authed.delete(name).await?;
+            }
+        }
+
+        MailOperation::RenameFolder { from, to } => {
+            if folder_exists(authed, from).await? {
+                authed.rename(from, to).await?;
+            }
+        }
+    }
+
+    Ok(())
+}
 
-        // let's just select INBOX for now, maybe have a config for default mailbox later?
-        debug!("selecting the INBOX mailbox");
-        let select = authed.select("INBOX").await?;
-        debug!("select result: {:?}", select);
+/// Permanently deletes a message from the selected folder. Without UIDPLUS the message is only
+/// marked `\Deleted`, since a plain EXPUNGE would also remove anything else that's marked, so it
+/// stays on the server until the folder is expunged on purpose.
+async fn delete_uid(authed: &mut ClientAuthenticated, uid: u32) -> Result<()> {
+    authed
+        .uid_store(&[uid], StoreAction::Add, vec![MailboxFlag::Deleted])
+        .await?;
 
-        loop {
-            let message_uids = authed.uid_search().await?;
-            let message_uids = message_uids.into_iter().take(30).collect::<Vec<_>>();
-            let _ = mail2ui_tx.send(MailEvent::MessageUids(
-                acct_name.clone(),
-                message_uids.clone(),
-            ));
-
-            // TODO: make this happen concurrently with the main loop?
-            let mut message_list = authed
-                .uid_fetch(&message_uids, FetchItems::All)
+    if authed.has_capability("UIDPLUS").await? {
+        authed.uid_expunge(&[uid]).await?;
+    } else {
+        debug!(
+            "server has no UIDPLUS, leaving uid {} marked \\Deleted",
+            uid
+        );
+    }
+    Ok(())
+}
+
+async fn folder_exists(authed: &mut ClientAuthenticated, name: &str) -> Result<bool> {
+    let folders = authed.list().await?;
+    Ok(folders.iter().any(|folder| folder.name == name))
+}
+
+/// Opens a new connection to the account's IMAP server and logs in
+pub async fn connect(imap: &ImapConfig) -> Result<ClientAuthenticated> {
+    let builder: ClientConfig = ClientBuilder::default()
+        .hostname(imap.server.clone())
+        .port(imap.port)
+        .tls(matches!(imap.tls, TlsMethod::On))
+        .build()
+        .map_err(|err| anyhow!("err: {}", err))?;
+
+    debug!("connecting to {}:{}", &imap.server, imap.port);
+    let unauth = builder.open().await?;
+
+    let unauth = if matches!(imap.tls, TlsMethod::Starttls) {
+        debug!("attempting to upgrade");
+        let client = unauth.upgrade().await?;
+        debug!("upgrade successful");
+        client
+    } else {
+        unauth
+    };
+
+    debug!("preparing to auth");
+    // check if the authentication method is supported
+    let mut authed = match &imap.auth {
+        ImapAuth::Plain { username, password } => {
+            let auth = auth::Plain {
+                username: username.clone(),
+                password: password.clone(),
+            };
+            auth.perform_auth(unauth).await?
+        }
+    };
+
+    debug!("authentication successful!");
+
+    // compress the rest of the session if the server supports it, this makes a big difference
+    // for the initial sync of large mailboxes
+    if imap.compress && authed.has_capability("COMPRESS=DEFLATE").await? {
+        debug!("attempting to compress");
+        authed = authed.compress().await?;
+        debug!("compress successful");
+    }
+
+    // identify ourselves, some servers (ex. 163.com) refuse to SELECT before this happens
+    if authed.has_capability("ID").await? {
+        let server_id = authed
+            .id(Some(vec![
+                ("name".to_owned(), "panorama".to_owned()),
+                ("version".to_owned(), env!("CARGO_PKG_VERSION").to_owned()),
+            ]))
+            .await?;
+        debug!("server id: {:?}", server_id);
+    }
+
+    Ok(authed)
+}
+
+/// Syncs every folder in the account, after sending the server any operations that were made
+/// while offline
+pub async fn sync_all(
+    authed: &mut ClientAuthenticated,
+    acct_name: &str,
+    mail2ui_tx: &UnboundedSender<MailEvent>,
+    mail_store: &MailStore,
+) -> Result<()> {
+    let namespaces = if authed.has_capability("NAMESPACE").await? {
+        authed.namespace().await?
+    } else {
+        Namespaces::default()
+    };
+    debug!("namespaces: {:?}", namespaces);
+
+    // catch the server up on everything that was done while we were offline, before syncing
+    // anything back down
+    replay_operations(authed, mail_store, acct_name).await?;
+
+    let folder_list = authed
+        .list()
+        .await?
+        .iter()
+        .map(|entry| FolderMetadata::from_list_entry(entry, &namespaces))
+        .collect::<Vec<_>>();
+    let _ = mail2ui_tx.send(MailEvent::FolderList(
+        acct_name.to_owned(),
+        folder_list.clone(),
+    ));
+    debug!("mailbox list: {:?}", folder_list);
+
+    if authed.has_capability("QUOTA").await? {
+        let quota_root = authed.get_quota_root("INBOX").await?;
+        debug!("quota root: {:?}", quota_root);
+        let _ = mail2ui_tx.send(MailEvent::Quota(acct_name.to_owned(), quota_root.quotas));
+    }
+
+    let folders = folder_list
+        .iter()
+        .filter(|folder| folder.selectable)
+        .collect::<Vec<_>>();
+    for (i, folder) in folders.iter().enumerate() {
+        let state = AccountState::Syncing(i, folders.len());
+        let _ = mail2ui_tx.send(MailEvent::AccountState(acct_name.to_owned(), state));
+        sync_folder(authed, acct_name, mail_store, &folder.name).await?;
+    }
+
+    Ok(())
+}
+
+/// Brings the local copy of a single folder up to date with the server
+pub async fn sync_folder(
+    authed: &mut ClientAuthenticated,
+    acct_name: &str,
+    mail_store: &MailStore,
+    folder: &str,
+) -> Result<()> {
+    debug!("folder: {}", folder);
+    let select = if authed.has_capability("CONDSTORE").await? {
+        authed.select_condstore(folder).await?
+    } else {
+        authed.select(folder).await?
+    };
+    debug!("select response: {:?}", select);
+
+    if let (Some(exists), Some(uidvalidity)) = (select.exists, select.uid_validity) {
+        // if the server reset the UIDVALIDITY, all of the UIDs we have stored for this
+        // folder are meaningless now, so try to match them back up by Message-ID
+        if exists > 0
+            && mail_store
+                .has_stale_uidvalidity(&acct_name, folder, uidvalidity)
+                .await?
+        {
+            debug!("uidvalidity of {} changed to {}", folder, uidvalidity);
+            let seqs = (1..=exists).collect::<Vec<_>>();
+            let message_ids = authed
+                .fetch(&seqs, FetchItems::PanoramaEnvelope)
+                .await
+                .context("error fetching envelopes")?
+                .filter_map(|(_, attrs)| {
+                    let mut uid = None;
+                    let mut message_id = None;
+                    for attr in attrs {
+                        match attr {
+                            AttributeValue::Uid(n) => uid = Some(n),
+                            AttributeValue::Envelope(Envelope {
+                                message_id: Some(id),
+                                ..
+                            }) => message_id = Some(id),
+                            _ => {}
+                        }
+                    }
+                    future::ready(uid.zip(message_id))
+                })
+                .collect::<Vec<_>>()
+                .await;
+            let reconciled = mail_store
+                .reconcile_uidvalidity(&acct_name, folder, uidvalidity, message_ids)
+                .await?;
+            debug!("reconciled {} emails in {}", reconciled, folder);
+        }
+
+        let synced_uidnext = mail_store
+            .get_synced_uidnext(&acct_name, folder, uidvalidity)
+            .await?;
+        let (synced_count, synced_modseq) = mail_store
+            .get_flags_sync_state(&acct_name, folder, uidvalidity)
+            .await?;
+        // nothing arrived since the last sync, and since the number of messages is the same,
+        // nothing was expunged either
+        let unchanged = select.uid_next == Some(synced_uidnext) && synced_count == Some(exists);
+
+        // pick up flag changes made by other clients. without CONDSTORE there's no cheap way to
+        // tell whether any flags changed, so an unchanged folder is trusted to have the same flags
+        // and they're left for the next time messages come or go
+        if exists > 0 {
+            let seqs = (1..=exists).collect::<Vec<_>>();
+            let server_flags = match (select.highest_modseq, synced_modseq) {
+                (Some(modseq), Some(synced)) if modseq == synced => None,
+                (Some(_), Some(synced)) => Some(
+                    authed
+                        .fetch_changed_since(&seqs, FetchItems::PanoramaFlags, synced)
+                        .await
+                        .context("error fetching changed flags")?
+                        .filter_map(|(_, attrs)| future::ready(uid_and_flags(attrs)))
+                        .collect::<Vec<_>>()
+                        .await,
+                ),
+                (None, _) if unchanged => None,
+                _ => Some(
+                    authed
+                        .fetch(&seqs, FetchItems::PanoramaFlags)
+                        .await
+                        .context("error fetching flags")?
+                        .filter_map(|(_, attrs)| future::ready(uid_and_flags(attrs)))
+                        .collect::<Vec<_>>()
+                        .await,
+                ),
+            };
+            if let Some(server_flags) = server_flags {
+                mail_store
+                    .sync_flags(&acct_name, folder, uidvalidity, server_flags)
+                    .await?;
+            }
+        }
+
+        // anything we have that isn't on the server anymore was deleted by another client
+        if !unchanged {
+            let server_uids = authed
+                .uid_search(SearchCriteria::All)
+                .await
+                .context("error searching for expunged uids")?;
+            mail_store
+                .expunge_missing(&acct_name, folder, uidvalidity, server_uids)
+                .await?;
+        }
+
+        // only look at the messages that arrived since the last time this folder was synced
+        if exists > 0 && select.uid_next.map_or(true, |n| n > synced_uidnext) {
+            let uids = authed
+                .uid_search(SearchCriteria::Uid {
+                    start: synced_uidnext,
+                    end: None,
+                })
                 .await
-                .unwrap();
-            while let Some((uid, attrs)) = message_list.next().await {
-                let evt = MailEvent::UpdateUid(acct_name.clone(), uid, attrs);
-                // TODO: probably odn't care about this?
-                let _ = mail2ui_tx.send(evt);
+                .context("error searching for new uids")?;
+
+            // n:* always matches the last message, even if its UID is lower than n
+            let uids = uids
+                .into_iter()
+                .filter(|uid| *uid >= synced_uidnext)
+                .collect::<Vec<_>>();
+            let mut new_uids = mail_store
+                .filter_new_uids(&acct_name, folder, uidvalidity, uids)
+                .await?;
+            new_uids.sort_unstable();
+
+            // folders that only keep headers don't get bodies until a message is opened
+            let headers_only = mail_store.is_headers_only(&acct_name, folder).await;
+            if !headers_only && mail_store.has_recovered_bodies().await? {
+                new_uids =
+                    recover_bodies(authed, acct_name, mail_store, folder, uidvalidity, new_uids)
+                        .await?;
             }
+            let items = if headers_only {
+                FetchItems::PanoramaHeaders
+            } else {
+                FetchItems::PanoramaAll
+            };
+            for chunk in new_uids.chunks(FETCH_CHUNK_SIZE) {
+                debug!("fetching {} uids in {}", chunk.len(), folder);
+                let fetched = authed
+                    .uid_fetch(chunk, items.clone())
+                    .await
+                    .context("error fetching uids")?;
 
-            // check if IDLE is supported
-            let supports_idle = authed.has_capability("IDLE").await?;
-            if supports_idle {
-                let mut idle_stream = authed.idle().await?;
-
-                loop {
-                    let evt = match idle_stream.next().await {
-                        Some(v) => v,
-                        None => break,
-                    };
-                    debug!("got an event: {:?}", evt);
-
-                    match evt {
-                        Response::MailboxData(MailboxData::Exists(uid)) => {
-                            debug!("NEW MESSAGE WITH UID {:?}, droping everything", uid);
-                            // send DONE to stop the idle
-                            std::mem::drop(idle_stream);
-
-                            let handle = Notification::new()
-                                .summary("New Email")
-                                .body("holy Shit,")
-                                .icon("firefox")
-                                .timeout(Timeout::Milliseconds(6000))
-                                .show()?;
-
-                            let message_uids = authed.uid_search().await?;
-                            let message_uids =
-                                message_uids.into_iter().take(20).collect::<Vec<_>>();
-                            let _ = mail2ui_tx.send(MailEvent::MessageUids(
-                                acct_name.clone(),
-                                message_uids.clone(),
-                            ));
-
-                            // TODO: make this happen concurrently with the main loop?
-                            let mut message_list = authed
-                                .uid_fetch(&message_uids, FetchItems::All)
-                                .await
-                                .unwrap();
-                            while let Some((uid, attrs)) = message_list.next().await {
-                                let evt = MailEvent::UpdateUid(acct_name.clone(), uid, attrs);
-                                // debug!("sent {:?}", evt);
-                                mail2ui_tx.send(evt);
+                let (acct_name, mail_store) = (&acct_name, &mail_store);
+                fetched
+                    .map(Ok)
+                    .try_for_each_concurrent(None, |(_, attrs)| async move {
+                        // the number in the fetch response is a sequence number, not a UID
+                        let uid = attrs.iter().find_map(|attr| match attr {
+                            AttributeValue::Uid(uid) => Some(*uid),
+                            _ => None,
+                        });
+                        match uid {
+                            Some(uid) => {
+                                mail_store
+                                    .store_email(acct_name, folder, uid, uidvalidity, attrs)
+                                    .await
                             }
-
-                            idle_stream = authed.idle().await?;
+                            None => Ok(()),
                         }
-                        _ => {}
-                    }
+                    })
+                    .await
+                    .context("error during fetch-store")?;
+
+                // save progress so a dropped connection doesn't mean starting over
+                if let Some(last) = chunk.last() {
+                    mail_store
+                        .set_synced_uidnext(acct_name, folder, uidvalidity, last + 1)
+                        .await?;
                 }
-            } else {
-                loop {
-                    tokio::time::sleep(std::time::Duration::from_secs(20)).await;
-                    debug!("heartbeat");
+            }
+            mail_store.publish_counts(&acct_name, folder).await?;
+        }
+
+        if let Some(uid_next) = select.uid_next {
+            if uid_next > synced_uidnext {
+                mail_store
+                    .set_synced_uidnext(&acct_name, folder, uidvalidity, uid_next)
+                    .await?;
+            }
+        }
+
+        // anything that couldn't be matched up by now doesn't exist on the server anymore
+        mail_store
+            .nuke_old_uidvalidity(&acct_name, folder, uidvalidity)
+            .await?;
+        mail_store
+            .set_flags_sync_state(
+                &acct_name,
+                folder,
+                uidvalidity,
+                exists,
+                select.highest_modseq,
+            )
+            .await?;
+    }
+
+    Ok(())
+}
+
+/// Picks the UID and flags out of a message's fetched attributes
+fn uid_and_flags(attrs: Vec<AttributeValue>) -> Option<(u32, Vec<MailboxFlag>)> {
+    let mut uid = None;
+    let mut flags = None;
+    for attr in attrs {
+        match attr {
+            AttributeValue::Uid(n) => uid = Some(n),
+            AttributeValue::Flags(f) => flags = Some(f),
+            _ => {}
+        }
+    }
+    uid.zip(flags)
+}
+
+/// Stores the messages whose bodies are still in the mail directory from before the database was
+/// lost, so they don't have to be downloaded again. Returns the UIDs that weren't found.
+async fn recover_bodies(
+    authed: &mut ClientAuthenticated,
+    acct_name: &str,
+    mail_store: &MailStore,
+    folder: &str,
+    uidvalidity: u32,
+    uids: Vec<u32>,
+) -> Result<Vec<u32>> {
+    let mut remaining = Vec::new();
+    for chunk in uids.chunks(FETCH_CHUNK_SIZE) {
+        let fetched = authed
+            .uid_fetch(chunk, FetchItems::PanoramaSummary)
+            .await
+            .context("error fetching envelopes")?
+            .collect::<Vec<_>>()
+            .await;
+
+        let mut recovered = HashSet::new();
+        for (_, attrs) in fetched {
+            let uid = attrs.iter().find_map(|attr| match attr {
+                AttributeValue::Uid(uid) => Some(*uid),
+                _ => None,
+            });
+            if let Some(uid) = uid {
+                if mail_store
+                    .store_recovered(acct_name, folder, uid, uidvalidity, attrs)
+                    .await?
+                {
+                    recovered.insert(uid);
                 }
             }
+        }
+        remaining.extend(chunk.iter().filter(|uid| !recovered.contains(uid)));
+    }
 
-            if false {
-                break;
+    debug!(
+        "recovered {} bodies in {}",
+        uids.len() - remaining.len(),
+        folder
+    );
+    Ok(remaining)
+}
+
+/// Sits in IDLE on the INBOX, asking for it to be synced whenever new mail arrives
+///
+/// This is meant to get a connection to itself, since nothing else can be done on a connection
+/// while it's idling.
+pub async fn idle_main(
+    mut authed: ClientAuthenticated,
+    sync_tx: &UnboundedSender<SyncRequest>,
+) -> Result<()> {
+    let select = authed.select("INBOX").await?;
+    debug!("select result: {:?}", select);
+
+    if !authed.has_capability("IDLE").await? {
+        loop {
+            tokio::time::sleep(POLL_INTERVAL).await;
+            let _ = sync_tx.send(SyncRequest::Folder("INBOX".to_owned()));
+        }
+    }
+
+    loop {
+        let mut idle_stream = authed.idle().await?;
+
+        // servers are allowed to drop connections that have been idle for 30 minutes, so IDLE has
+        // to be restarted every so often (RFC 2177)
+        let restart = tokio::time::sleep(IDLE_TIMEOUT);
+        tokio::pin!(restart);
+
+        loop {
+            let evt = tokio::select! {
+                evt = idle_stream.next() => match evt {
+                    Some(v) => v,
+                    None => bail!("connection closed during IDLE"),
+                },
+                _ = &mut restart => break,
+            };
+            debug!("got an event: {:?}", evt);
+
+            match evt {
+                Response::MailboxData(MailboxData::Exists(n)) => {
+                    debug!("INBOX now has {} messages", n);
+                    let _ = sync_tx.send(SyncRequest::Folder("INBOX".to_owned()));
+                }
+                // EXPUNGE only has a sequence number, so the UID SEARCH diff during a sync works
+                // out which message it was
+                Response::Expunge(n) => {
+                    debug!("message {} was expunged from INBOX", n);
+                    let _ = sync_tx.send(SyncRequest::Folder("INBOX".to_owned()));
+                }
+                _ => {}
             }
         }
 
-        // wait a bit so we're not hitting the server really fast if the fail happens
-        // early on
-        //
-        // TODO: some kind of smart exponential backoff that considers some time
-        // threshold to be a failing case?
-        tokio::time::sleep(std::time::Duration::from_secs(5)).await;
+        // dropping the stream sends DONE
+        std::mem::drop(idle_stream);
     }
 }
@@ -0,0 +1,323 @@
+//! Encryption of the mail store at rest
+//!
+//! Everything is encrypted with ChaCha20-Poly1305 under keys derived from a single passphrase, so
+//! anything that was tampered with fails to decrypt instead of showing up wrong. Message bodies
+//! are named by a keyed hash of their contents instead of a plain SHA-256, so the names of the
+//! files in the mail directory can't be used to tell whether a known message is in there.
+
+use std::fmt;
+use std::num::NonZeroU32;
+
+use anyhow::{Context, Result};
+use ring::{
+    aead::{self, Aad, LessSafeKey, Nonce, UnboundKey, CHACHA20_POLY1305, NONCE_LEN},
+    hmac, pbkdf2,
+    rand::{SecureRandom, SystemRandom},
+};
+use tokio::process::Command;
+
+use crate::config::EncryptionConfig;
+
+/// How many rounds of PBKDF2 go into deriving the key from the passphrase
+const PBKDF2_ITERATIONS: u32 = 100_000;
+
+/// Length of the salt that's stored next to the encrypted data
+const SALT_LEN: usize = 16;
+
+/// What every encrypted body file starts with, so files from before encryption was turned on can
+/// still be told apart and read
+const BODY_MAGIC: &[u8] = b"panorama-encrypted-v1\n";
+
+/// What every encrypted column value starts with
+const FIELD_PREFIX: &str = "enc1:";
+
+/// What gets encrypted to make the check value
+const CHECK_PLAINTEXT: &[u8] = b"panorama";
+
+/// The keys for encrypting everything in the mail store
+pub struct Cipher {
+    /// For the files in the mail directory
+    body_key: LessSafeKey,
+
+    /// For values in the database
+    field_key: LessSafeKey,
+
+    /// For hashes that need to stay the same for the same input (ex. file names)
+    hash_key: hmac::Key,
+
+    rng: SystemRandom,
+}
+
+impl fmt::Debug for Cipher {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Cipher").finish()
+    }
+}
+
+impl Cipher {
+    /// Derives the keys from a passphrase and the salt that was saved with the store
+    pub fn derive(passphrase: &str, salt: &[u8]) -> Self {
+        let mut master = [0; 32];
+        pbkdf2::derive(
+            pbkdf2::PBKDF2_HMAC_SHA256,
+            NonZeroU32::new(PBKDF2_ITERATIONS).unwrap(),
+            salt,
+            passphrase.as_bytes(),
+            &mut master,
+        );
+
+        // every key gets its own subkey, so none of them are ever used for two things
+        let master = hmac::Key::new(hmac::HMAC_SHA256, &master);
+        let subkey = |label: &[u8]| hmac::sign(&master, label);
+        let aead_key = |label: &[u8]| {
+            let key = UnboundKey::new(&CHACHA20_POLY1305, subkey(label).as_ref()).unwrap();
+            LessSafeKey::new(key)
+        };
+        Cipher {
+            body_key: aead_key(b"body"),
+            field_key: aead_key(b"field"),
+            hash_key: hmac::Key::new(hmac::HMAC_SHA256, subkey(b"hash").as_ref()),
+            rng: SystemRandom::new(),
+        }
+    }
+
+    /// Makes a new random salt for a store that's being encrypted for the first time
+    pub fn new_salt() -> Result<Vec<u8>> {
+        let mut salt = vec![0; SALT_LEN];
+        SystemRandom::new()
+            .fill(&mut salt)
+            .map_err(|_| anyhow!("couldn't get random bytes for the salt"))?;
+        Ok(salt)
+    }
+
+    /// Makes the value that's saved with the store to check the passphrase against later
+    pub fn check_value(&self) -> Result<String> {
+        Ok(hex::encode(self.seal(
+            &self.field_key,
+            CHECK_PLAINTEXT,
+            b"check",
+        )?))
+    }
+
+    /// Whether these keys were derived from the same passphrase as the check value was
+    pub fn verify(&self, check_value: &str) -> bool {
+        let sealed = match hex::decode(check_value) {
+            Ok(v) => v,
+            Err(_) => return false,
+        };
+        matches!(self.open(&self.field_key, &sealed, b"check"), Ok(v) if v == CHECK_PLAINTEXT)
+    }
+
+    /// Hashes a message body to get the name of its file
+    pub fn hash_body(&self, body: &[u8]) -> String {
+        hex::encode(hmac::sign(&self.hash_key, body))
+    }
+
+    /// Hashes a value that has to be looked up by in the database (ex. the subject of a thread)
+    pub fn hash_field(&self, value: &str) -> String {
+        let mut data = b"field\0".to_vec();
+        data.extend_from_slice(value.as_bytes());
+        hex::encode(hmac::sign(&self.hash_key, &data))
+    }
+
+    /// Encrypts a message body to be written to the file with the given name. The name goes into
+    /// the authentication tag, so one file can't be swapped for another.
+    pub fn seal_body(&self, filename: &str, body: &[u8]) -> Result<Vec<u8>> {
+        let mut data = BODY_MAGIC.to_vec();
+        data.extend(self.seal(&self.body_key, body, filename.as_bytes())?);
+        Ok(data)
+    }
+
+    /// Decrypts the contents of the file with the given name
+    pub fn open_body(&self, filename: &str, data: &[u8]) -> Result<Vec<u8>> {
+        if !is_sealed_body(data) {
+            bail!("{} isn't encrypted", filename);
+        }
+        let sealed = &data[BODY_MAGIC.len()..];
+        self.open(&self.body_key, sealed, filename.as_bytes())
+            .with_context(|| format!("couldn't decrypt {}", filename))
+    }
+
+    /// Encrypts a value to be stored in the given column of a row in the database. Where it's
+    /// stored goes into the authentication tag, so a value can't be moved to another row or column.
+    pub fn seal_field(&self, table: &str, column: &str, rowid: i64, value: &str) -> Result<String> {
+        let aad = field_aad(table, column, rowid);
+        let sealed = self.seal(&self.field_key, value.as_bytes(), &aad)?;
+        Ok(format!("{}{}", FIELD_PREFIX, hex::encode(sealed)))
+    }
+
+    /// Decrypts a value from the given column of a row in the database
+    pub fn open_field(&self, table: &str, column: &str, rowid: i64, value: &str) -> Result<String> {
+        let sealed = value
+            .strip_prefix(FIELD_PREFIX)
+            .context("this value isn't encrypted")?;
+        let sealed = hex::decode(sealed).context("encrypted value isn't hex")?;
+        let plain = self
+            .open(&self.field_key, &sealed, &field_aad(table, column, rowid))
+            .with_context(|| format!("couldn't decrypt {}.{} of row {}", table, column, rowid))?;
+        String::from_utf8(plain).context("decrypted value isn't UTF-8")
+    }
+
+    /// Encrypts data with a random nonce, which goes in front of it
+    fn seal(&self, key: &LessSafeKey, plain: &[u8], aad: &[u8]) -> Result<Vec<u8>> {
+        let mut nonce = [0; NONCE_LEN];
+        self.rng
+            .fill(&mut nonce)
+            .map_err(|_| anyhow!("couldn't get random bytes for a nonce"))?;
+
+        let mut data = plain.to_vec();
+        key.seal_in_place_append_tag(
+            Nonce::assume_unique_for_key(nonce),
+            Aad::from(aad),
+            &mut data,
+        )
+        .map_err(|_| anyhow!("couldn't encrypt data"))?;
+
+        let mut sealed = nonce.to_vec();
+        sealed.extend(data);
+        Ok(sealed)
+    }
+
+    /// Decrypts data from [`Cipher::seal`]
+    fn open(&self, key: &LessSafeKey, sealed: &[u8], aad: &[u8]) -> Result<Vec<u8>> {
+        if sealed.len() < NONCE_LEN + aead::MAX_TAG_LEN {
+            bail!("encrypted data is too short");
+        }
+        let (nonce, data) = sealed.split_at(NONCE_LEN);
+        let nonce = Nonce::try_assume_unique_for_key(nonce).unwrap();
+        let mut data = data.to_vec();
+        let plain = key
+            .open_in_place(nonce, Aad::from(aad), &mut data)
+            .map_err(|_| anyhow!("encrypted data was changed, or the key is wrong"))?;
+        Ok(plain.to_vec())
+    }
+}
+
+/// The associated data for a value in the database, which says exactly where it's stored
+fn field_aad(table: &str, column: &str, rowid: i64) -> Vec<u8> {
+    format!("field\0{}\0{}\0{}", table, column, rowid).into_bytes()
+}
+
+/// Whether the contents of a body file are encrypted
+pub fn is_sealed_body(data: &[u8]) -> bool {
+    data.starts_with(BODY_MAGIC)
+}
+
+/// Whether a value from the database is encrypted
+pub fn is_sealed_field(value: &str) -> bool {
+    value.starts_with(FIELD_PREFIX)
+}
+
+/// Gets the passphrase from the config, running its command if it has one
+pub async fn get_passphrase(config: &EncryptionConfig) -> Result<String> {
+    let command = match (&config.passphrase_command, &config.passphrase) {
+        (Some(command), _) => command,
+        (None, Some(passphrase)) => return Ok(passphrase.clone()),
+        (None, None) => bail!("encryption needs either a passphrase or a passphrase_command"),
+    };
+
+    let output = Command::new("sh")
+        .arg("-c")
+        .arg(command)
+        .output()
+        .await
+        .with_context(|| format!("error running passphrase command {:?}", command))?;
+    if !output.status.success() {
+        bail!(
+            "passphrase command {:?} failed ({}): {}",
+            command,
+            output.status,
+            String::from_utf8_lossy(&output.stderr).trim()
+        );
+    }
+
+    // only the first line counts, the same as with pass(1)
+    let stdout = String::from_utf8(output.stdout).context("passphrase isn't UTF-8")?;
+    let passphrase = stdout.lines().next().unwrap_or("");
+    if passphrase.is_empty() {
+        bail!("passphrase command {:?} didn't print anything", command);
+    }
+    Ok(passphrase.to_owned())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn cipher(passphrase: &str) -> Cipher {
+        Cipher::derive(passphrase, b"0123456789abcdef")
+    }
+
+    #[test]
+    fn test_field_roundtrip() -> Result<()> {
+        let cipher = cipher("hunter2");
+        let sealed = cipher.seal_field("mail", "subject", 7, "Grüße")?;
+        assert!(is_sealed_field(&sealed));
+        assert!(!sealed.contains("Grüße"));
+        assert_eq!(cipher.open_field("mail", "subject", 7, &sealed)?, "Grüße");
+
+        // the same value is sealed differently every time
+        assert_ne!(sealed, cipher.seal_field("mail", "subject", 7, "Grüße")?);
+        Ok(())
+    }
+
+    #[test]
+    fn test_body_roundtrip() -> Result<()> {
+        let cipher = cipher("hunter2");
+        let body = b"Subject: hi\r\n\r\n\xff\x00binary";
+        let name = cipher.hash_body(body);
+        let sealed = cipher.seal_body(&name, body)?;
+        assert!(is_sealed_body(&sealed));
+        assert_eq!(cipher.open_body(&name, &sealed)?, body.to_vec());
+        assert!(cipher.open_body("other", &sealed).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_wrong_passphrase() -> Result<()> {
+        let right = cipher("hunter2");
+        let wrong = cipher("hunter3");
+        assert!(right.verify(&right.check_value()?));
+        assert!(!wrong.verify(&right.check_value()?));
+
+        let field = right.seal_field("mail", "subject", 1, "hello")?;
+        assert!(wrong.open_field("mail", "subject", 1, &field).is_err());
+        let body = right.seal_body("name", b"hello")?;
+        assert!(wrong.open_body("name", &body).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_tampered() -> Result<()> {
+        let cipher = cipher("hunter2");
+        let sealed = cipher.seal_field("mail", "subject", 1, "hello")?;
+
+        // flip one bit of the ciphertext
+        let mut bytes = hex::decode(&sealed[FIELD_PREFIX.len()..]).unwrap();
+        let last = bytes.len() - 1;
+        bytes[last] ^= 1;
+        let tampered = format!("{}{}", FIELD_PREFIX, hex::encode(bytes));
+        assert!(cipher.open_field("mail", "subject", 1, &tampered).is_err());
+
+        // cut off the end
+        let truncated = &sealed[..sealed.len() - 2];
+        assert!(cipher.open_field("mail", "subject", 1, truncated).is_err());
+        assert!(cipher.open_field("mail", "subject", 1, "hello").is_err());
+
+        let mut body = cipher.seal_body("name", b"hello")?;
+        let last = body.len() - 1;
+        body[last] ^= 1;
+        assert!(cipher.open_body("name", &body).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_moved_field() -> Result<()> {
+        let cipher = cipher("hunter2");
+        let sealed = cipher.seal_field("mail", "subject", 1, "hello")?;
+        assert!(cipher.open_field("mail", "subject", 2, &sealed).is_err());
+        assert!(cipher.open_field("mail", "snippet", 1, &sealed).is_err());
+        assert!(cipher.open_field("threads", "subject", 1, &sealed).is_err());
+        Ok(())
+    }
+}
@@ -1,11 +1,15 @@
-use panorama_imap::response::{AttributeValue, Envelope};
+use std::fmt;
+
+use panorama_imap::response::{AttributeValue, Envelope, Quota};
+
+use super::FolderMetadata;
 
 /// Possible events returned from the server that should be sent to the UI
 #[derive(Debug)]
 #[non_exhaustive]
 pub enum MailEvent {
     /// Got the list of folders
-    FolderList(String, Vec<String>),
+    FolderList(String, Vec<FolderMetadata>),
 
     /// A list of the UIDs in the current mail view
     MessageUids(String, Vec<u32>),
@@ -13,8 +17,11 @@ pub enum MailEvent {
     /// Update the given UID with the given attribute list
     UpdateUid(String, u32, Vec<AttributeValue>),
 
-    /// New message came in with given UID
-    NewUid(String, u32),
+    /// Got the usage and limits of the quota roots on the account
+    Quota(String, Vec<Quota>),
+
+    /// The account's connection changed state
+    AccountState(String, AccountState),
 }
 
 impl MailEvent {
@@ -25,7 +32,40 @@ impl MailEvent {
             FolderList(name, _)
             | MessageUids(name, _)
             | UpdateUid(name, _, _)
-            | NewUid(name, _) => name,
+            | Quota(name, _)
+            | AccountState(name, _) => name,
+        }
+    }
+}
+
+/// What an account's connection is currently doing
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum AccountState {
+    /// Opening a connection and logging in
+    Connecting,
+
+    /// Syncing folders, with how many are done so far out of the total
+    Syncing(usize, usize),
+
+    /// Connected, and waiting for something to change
+    Idle,
+
+    /// Disconnected, and waiting to try again
+    Offline,
+
+    /// The server rejected the credentials. Nothing else is tried until the account's config
+    /// changes.
+    AuthFailed,
+}
+
+impl fmt::Display for AccountState {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            AccountState::Connecting => write!(f, "connecting"),
+            AccountState::Syncing(done, total) => write!(f, "syncing {}/{}", done, total),
+            AccountState::Idle => write!(f, "idle"),
+            AccountState::Offline => write!(f, "offline"),
+            AccountState::AuthFailed => write!(f, "login failed, check config"),
         }
     }
 }
@@ -0,0 +1,280 @@
+use panorama_imap::{
+    client::ListEntry,
+    response::{Namespace, Namespaces, SpecialUse},
+};
+
+/// The role that a folder plays in an account, used for ordering and decorating folders in the UI
+///
+/// Variants are declared in the order they should be displayed in.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum FolderRole {
+    /// The INBOX
+    Inbox,
+
+    /// Drafts of messages that haven't been sent yet
+    Drafts,
+
+    /// Copies of messages that have been sent
+    Sent,
+
+    /// Messages that have been archived
+    Archive,
+
+    /// A virtual folder containing every message (ex. Gmail's "All Mail")
+    All,
+
+    /// A virtual folder containing every flagged message
+    Flagged,
+
+    /// Spam
+    Junk,
+
+    /// Messages that have been deleted
+    Trash,
+}
+
+impl FolderRole {
+    /// Detects the role of a folder, preferring the SPECIAL-USE attributes (RFC 6154) sent by the
+    /// server and falling back to guessing based on the name of the folder. The namespaces (RFC
+    /// 2342) are used to tell which folders are the user's own, and where in them the roles are.
+    pub fn detect(entry: &ListEntry, namespaces: &Namespaces) -> Option<FolderRole> {
+        if entry.name.eq_ignore_ascii_case("INBOX") {
+            return Some(FolderRole::Inbox);
+        }
+
+        if let Some(special_use) = entry.special_use() {
+            return Some(FolderRole::from(special_use));
+        }
+
+        // another user's Sent folder isn't this account's
+        let foreign = namespaces
+            .other_users
+            .iter()
+            .chain(namespaces.shared.iter())
+            .any(|namespace| !namespace.prefix.is_empty() && in_namespace(&entry.name, namespace));
+        if foreign {
+            return None;
+        }
+
+        // with a personal namespace, only the folders right at the top of it are guessed, so
+        // INBOX.Sent is the Sent folder on servers that keep everything under INBOX but
+        // INBOX.Projects.Sent isn't
+        let personal = namespaces
+            .personal
+            .iter()
+            .filter(|namespace| in_namespace(&entry.name, namespace))
+            .max_by_key(|namespace| namespace.prefix.len());
+        match personal {
+            Some(namespace) => {
+                let name = &entry.name[namespace.prefix.len()..];
+                let delimiter = entry
+                    .delimiter
+                    .as_deref()
+                    .or(namespace.delimiter.as_deref());
+                if let Some(delimiter) = delimiter {
+                    if name.contains(delimiter) {
+                        return None;
+                    }
+                }
+                FolderRole::guess(name, None)
+            }
+            None => FolderRole::guess(&entry.name, entry.delimiter.as_deref()),
+        }
+    }
+
+    /// Guesses the role of a folder from its name alone, for when there's nothing else to go on
+    pub fn guess(name: &str, delimiter: Option<&str>) -> Option<FolderRole> {
+        if name.eq_ignore_ascii_case("INBOX") {
+            return Some(FolderRole::Inbox);
+        }
+
+        // only look at the last part of the path, so things like INBOX.Sent get detected
+        let leaf = match delimiter {
+            Some(delimiter) => name.rsplit(delimiter).next(),
+            None => Some(name),
+        };
+        let leaf = leaf.unwrap_or_default().to_lowercase();
+        Some(match leaf.as_str() {
+            "drafts" | "draft" => FolderRole::Drafts,
+            "sent" | "sent items" | "sent messages" | "sent mail" => FolderRole::Sent,
+            "archive" | "archives" => FolderRole::Archive,
+            "junk" | "spam" | "junk e-mail" | "junk email" | "bulk mail" => FolderRole::Junk,
+            "trash" | "deleted items" | "deleted messages" | "bin" => FolderRole::Trash,
+            _ => return None,
+        })
+    }
+
+    /// A short icon that can be displayed next to the folder name
+    pub fn icon(&self) -> &'static str {
+        match self {
+            FolderRole::Inbox => "\u{1f4e5}",
+            FolderRole::Drafts => "\u{1f4dd}",
+            FolderRole::Sent => "\u{1f4e4}",
+            FolderRole::Archive => "\u{1f5c3}",
+            FolderRole::All => "\u{1f4da}",
+            FolderRole::Flagged => "\u{2691}",
+            FolderRole::Junk => "\u{26a0}",
+            FolderRole::Trash => "\u{1f5d1}",
+        }
+    }
+}
+
+impl From<SpecialUse> for FolderRole {
+    fn from(special_use: SpecialUse) -> Self {
+        match special_use {
+            SpecialUse::All => FolderRole::All,
+            SpecialUse::Archive => FolderRole::Archive,
+            SpecialUse::Drafts => FolderRole::Drafts,
+            SpecialUse::Flagged => FolderRole::Flagged,
+            SpecialUse::Junk => FolderRole::Junk,
+            SpecialUse::Sent => FolderRole::Sent,
+            SpecialUse::Trash => FolderRole::Trash,
+        }
+    }
+}
+
+/// Whether a folder is inside of the given namespace
+fn in_namespace(name: &str, namespace: &Namespace) -> bool {
+    name.starts_with(&namespace.prefix)
+}
+
+/// A record that describes a folder as it appears in the UI list
+#[derive(Clone, Debug)]
+pub struct FolderMetadata {
+    /// Full name of the folder on the server
+    pub name: String,
+
+    /// Hierarchy delimiter used by the server for this folder
+    pub delimiter: Option<String>,
+
+    /// Detected role of this folder
+    pub role: Option<FolderRole>,
+
+    /// Whether or not the folder can be selected (folders marked \Noselect only hold other folders)
+    pub selectable: bool,
+}
+
+impl FolderMetadata {
+    /// Construct a FolderMetadata from an entry in the server's LIST response, given the
+    /// namespaces the server has (which are empty if it doesn't support NAMESPACE)
+    pub fn from_list_entry(entry: &ListEntry, namespaces: &Namespaces) -> Self {
+        FolderMetadata {
+            name: entry.name.clone(),
+            delimiter: entry.delimiter.clone(),
+            role: FolderRole::detect(entry, namespaces),
+            selectable: entry.is_selectable(),
+        }
+    }
+
+    /// Construct a FolderMetadata for a folder in a local Maildir or mbox account
+    pub fn local(name: impl Into<String>, delimiter: Option<&str>) -> Self {
+        let name = name.into();
+        FolderMetadata {
+            role: FolderRole::guess(&name, delimiter),
+            delimiter: delimiter.map(|delimiter| delimiter.to_owned()),
+            name,
+            selectable: true,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use panorama_imap::response::MailboxListFlag;
+
+    use super::*;
+
+    fn entry(name: &str, flags: Vec<MailboxListFlag>) -> ListEntry {
+        ListEntry {
+            name: name.to_owned(),
+            delimiter: Some(".".to_owned()),
+            flags,
+        }
+    }
+
+    fn namespace(prefix: &str) -> Namespace {
+        Namespace {
+            prefix: prefix.to_owned(),
+            delimiter: Some(".".to_owned()),
+        }
+    }
+
+    #[test]
+    fn test_special_use() {
+        let namespaces = Namespaces::default();
+        let sent = entry(
+            "Gesendet",
+            vec![
+                MailboxListFlag::HasNoChildren,
+                MailboxListFlag::SpecialUse(SpecialUse::Sent),
+            ],
+        );
+        assert_eq!(
+            FolderRole::detect(&sent, &namespaces),
+            Some(FolderRole::Sent)
+        );
+
+        // the attribute wins over the name
+        let all = entry("Trash", vec![MailboxListFlag::SpecialUse(SpecialUse::All)]);
+        assert_eq!(FolderRole::detect(&all, &namespaces), Some(FolderRole::All));
+
+        assert_eq!(
+            FolderRole::detect(&entry("inbox", vec![]), &namespaces),
+            Some(FolderRole::Inbox)
+        );
+        assert_eq!(
+            FolderRole::detect(&entry("Receipts", vec![]), &namespaces),
+            None
+        );
+    }
+
+    #[test]
+    fn test_guess() {
+        assert_eq!(FolderRole::guess("INBOX", None), Some(FolderRole::Inbox));
+        assert_eq!(FolderRole::guess("Drafts", None), Some(FolderRole::Drafts));
+        assert_eq!(
+            FolderRole::guess("Sent Items", None),
+            Some(FolderRole::Sent)
+        );
+        assert_eq!(FolderRole::guess("SPAM", None), Some(FolderRole::Junk));
+        assert_eq!(
+            FolderRole::guess("Deleted Messages", None),
+            Some(FolderRole::Trash)
+        );
+        assert_eq!(
+            FolderRole::guess("INBOX.Sent", Some(".")),
+            Some(FolderRole::Sent)
+        );
+        assert_eq!(
+            FolderRole::guess("[Gmail]/Spam", Some("/")),
+            Some(FolderRole::Junk)
+        );
+        assert_eq!(FolderRole::guess("INBOX.Sent", Some("/")), None);
+        assert_eq!(FolderRole::guess("Sentimental", None), None);
+    }
+
+    #[test]
+    fn test_namespaces() {
+        let namespaces = Namespaces {
+            personal: vec![namespace("INBOX.")],
+            other_users: vec![namespace("user.")],
+            shared: vec![namespace("shared.")],
+        };
+        let detect = |name| FolderRole::detect(&entry(name, vec![]), &namespaces);
+        assert_eq!(detect("INBOX.Sent"), Some(FolderRole::Sent));
+        assert_eq!(detect("INBOX.Trash"), Some(FolderRole::Trash));
+        assert_eq!(detect("INBOX.Projects.Sent"), None);
+        assert_eq!(detect("user.alice.Sent"), None);
+        assert_eq!(detect("shared.Junk"), None);
+
+        // the personal namespace can be the whole server
+        let namespaces = Namespaces {
+            personal: vec![namespace("")],
+            ..Namespaces::default()
+        };
+        let detect = |name| FolderRole::detect(&entry(name, vec![]), &namespaces);
+        assert_eq!(detect("Sent"), Some(FolderRole::Sent));
+        assert_eq!(detect("Archive"), Some(FolderRole::Archive));
+        assert_eq!(detect("Clients.Archive"), None);
+    }
+}
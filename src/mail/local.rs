@@ -0,0 +1,695 @@
+//! Accounts whose mail is delivered to this machine (ex. by fetchmail, procmail or the system's
+//! MTA) instead of being kept on an IMAP server
+//!
+//! Local messages don't come with UIDs, so the store gives them out the first time it sees each
+//! message. Maildir messages are known by the unique part of their file name, and messages in an
+//! mbox file by the hash of their contents. Changes made in panorama go through the same journal
+//! as they do for IMAP accounts, and get written straight back to a Maildir. An mbox file is only
+//! ever read, since the MTA could be delivering to it at the same time.
+
+use std::collections::{HashMap, HashSet};
+use std::ffi::OsString;
+use std::io::ErrorKind;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use anyhow::{Context, Result};
+use chrono::{Local, Utc};
+use futures::{future, stream::StreamExt};
+use inotify::{Event, Inotify, WatchMask};
+use panorama_imap::{client::SelectResponse, response::MailboxFlag};
+use sha2::{Digest, Sha256};
+use tokio::{
+    fs,
+    io::BufReader,
+    sync::mpsc::{UnboundedReceiver, UnboundedSender},
+    time::{self, Instant},
+};
+
+use crate::config::{MailAccountConfig, MailSource};
+
+use super::maildir::{self, MaildirMessage, MaildirWriter};
+use super::mbox::MboxReader;
+use super::{AccountState, FolderMetadata, MailCommand, MailEvent, MailOperation, MailStore};
+
+/// UIDVALIDITY of every folder in a local account. UIDs are never handed out again once they've
+/// been used, so this never has to change.
+pub const LOCAL_UIDVALIDITY: u32 = 1;
+
+/// How long to wait after something changes before rescanning, so that a burst of deliveries (ex.
+/// fetchmail pulling down a whole mailbox) only causes one rescan
+const RESCAN_DELAY: Duration = Duration::from_millis(500);
+
+/// Maildir++ separates the levels of the folder hierarchy with dots
+const MAILDIR_DELIMITER: &str = ".";
+
+/// Where a local account's mail is, with any `~` expanded
+#[derive(Debug)]
+enum LocalSource {
+    Maildir(PathBuf),
+    Mbox(PathBuf),
+}
+
+impl LocalSource {
+    fn from_config(source: &MailSource) -> Option<Self> {
+        let expand =
+            |path: &Path| PathBuf::from(shellexpand::tilde(&path.to_string_lossy()).as_ref());
+        match source {
+            MailSource::Imap(_) => None,
+            MailSource::Maildir(path) => Some(LocalSource::Maildir(expand(path))),
+            MailSource::Mbox(path) => Some(LocalSource::Mbox(expand(path))),
+        }
+    }
+
+    /// Lists the folders in the account, along with where each of them is
+    async fn folders(&self) -> Result<Vec<(String, PathBuf)>> {
+        match self {
+            LocalSource::Maildir(root) => maildir::list_folders(root).await,
+            LocalSource::Mbox(path) => Ok(vec![(String::from("INBOX"), path.clone())]),
+        }
+    }
+
+    /// Starts watching everything that a delivery could change. This is safe to call again after
+    /// folders have been added, since watching the same place twice doesn't do anything.
+    async fn watch(&self, inotify: &mut Inotify) -> Result<()> {
+        match self {
+            LocalSource::Maildir(root) => {
+                let mask = WatchMask::CREATE
+                    | WatchMask::DELETE
+                    | WatchMask::MOVED_FROM
+                    | WatchMask::MOVED_TO;
+                inotify
+                    .add_watch(root, mask)
+                    .with_context(|| format!("error watching {:?}", root))?;
+
+                // messages are delivered to tmp and only show up once they're moved to new, so
+                // tmp doesn't need to be watched
+                for (_, dir) in self.folders().await? {
+                    for subdir in &["new", "cur"] {
+                        let path = dir.join(subdir);
+                        if !path.is_dir() {
+                            continue;
+                        }
+                        inotify
+                            .add_watch(&path, mask)
+                            .with_context(|| format!("error watching {:?}", path))?;
+                    }
+                }
+            }
+            LocalSource::Mbox(path) => {
+                // the file is watched through its directory, since some programs replace the
+                // whole file instead of changing it
+                let dir = path.parent().unwrap_or_else(|| Path::new("/"));
+                let mask = WatchMask::MODIFY
+                    | WatchMask::CLOSE_WRITE
+                    | WatchMask::CREATE
+                    | WatchMask::DELETE
+                    | WatchMask::MOVED_TO;
+                inotify
+                    .add_watch(dir, mask)
+                    .with_context(|| format!("error watching {:?}", dir))?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Whether the event means the account has to be rescanned. Other users' mail spools are
+    /// usually in the same directory as an mbox file, and changes to them are ignored.
+    fn is_relevant(&self, event: &Event<OsString>) -> bool {
+        match self {
+            LocalSource::Maildir(_) => true,
+            LocalSource::Mbox(path) => event.name.as_deref() == path.file_name(),
+        }
+    }
+}
+
+/// Keeps a local account in sync with the files it reads from, rescanning whenever they change
+pub async fn run_local_session(
+    acct_name: &str,
+    acct: &MailAccountConfig,
+    mail2ui_tx: &UnboundedSender<MailEvent>,
+    mail_store: &MailStore,
+    commands: &mut UnboundedReceiver<MailCommand>,
+) -> Result<()> {
+    let source = LocalSource::from_config(&acct.source).context("not a local account")?;
+    mail_store
+        .check_account_checksum(acct_name, acct.checksum())
+        .await?;
+
+    let mut inotify = Inotify::init()?;
+    let mut events = inotify.event_stream(vec![0; 4096])?;
+    source.watch(&mut inotify).await?;
+    sync_all(acct_name, &source, mail2ui_tx, mail_store).await?;
+
+    let mut rescan_at = None;
+    loop {
+        let rescan = async move {
+            match rescan_at {
+                Some(at) => time::sleep_until(at).await,
+                None => future::pending().await,
+            }
+        };
+
+        tokio::select! {
+            event = events.next() => {
+                let event = event.context("stopped getting file system events")??;
+                if rescan_at.is_none() && source.is_relevant(&event) {
+                    debug!("change in {}: {:?}", acct_name, event);
+                    rescan_at = Some(Instant::now() + RESCAN_DELAY);
+                }
+            }
+            _ = rescan => {
+                rescan_at = None;
+                source.watch(&mut inotify).await?;
+                sync_all(acct_name, &source, mail2ui_tx, mail_store).await?;
+            }
+            cmd = commands.recv() => match cmd {
+                Some(cmd) => {
+                    run_command(acct_name, &source, mail2ui_tx, mail_store, cmd).await?
+                }
+                None => return Ok(()),
+            }
+        }
+    }
+}
+
+/// Runs a command from the UI. Like on an IMAP account, a command that fails just sends back the
+/// error, but if applying the journal afterwards fails, the session is restarted.
+async fn run_command(
+    acct_name: &str,
+    source: &LocalSource,
+    mail2ui_tx: &UnboundedSender<MailEvent>,
+    mail_store: &MailStore,
+    cmd: MailCommand,
+) -> Result<()> {
+    debug!("command for {}: {:?}", acct_name, cmd);
+    let read_only = matches!(source, LocalSource::Mbox(_));
+    match cmd {
+        MailCommand::Refresh => sync_all(acct_name, source, mail2ui_tx, mail_store).await?,
+        MailCommand::Raw { reply, .. } => {
+            let _ = reply.send(Err(anyhow!("{} doesn't have an IMAP server", acct_name)));
+        }
+        MailCommand::SelectFolder { folder, reply, .. } => {
+            let dir = source
+                .folders()
+                .await?
+                .into_iter()
+                .find(|(name, _)| *name == folder)
+                .map(|(_, dir)| dir);
+            let result = match dir {
+                Some(dir) => sync_folder(acct_name, source, mail_store, &folder, &dir)
+                    .await
+                    .map(|exists| SelectResponse {
+                        exists: Some(exists as u32),
+                        uid_validity: Some(LOCAL_UIDVALIDITY),
+                        ..SelectResponse::default()
+                    }),
+                None => Err(anyhow!("no folder named {:?}", folder)),
+            };
+            let _ = reply.send(result);
+        }
+        MailCommand::FetchBody {
+            folder, uid, reply, ..
+        } => {
+            let result = match mail_store.get_filename(acct_name, &folder, uid).await {
+                Ok(Some(filename)) => mail_store.read_mail_file(filename).await,
+                Ok(None) => Err(anyhow!("no message with uid {} in {}", uid, folder)),
+                Err(err) => Err(err),
+            };
+            let _ = reply.send(result);
+        }
+
+        // these are saved locally and then applied to the files through the journal, the same
+        // way they're sent to a server
+        MailCommand::SetFlag {
+            folder,
+            uid,
+            flag,
+            added,
+            reply,
+            ..
+        } => {
+            let result = mail_store
+                .set_flag(acct_name, &folder, uid, flag, added)
+                .await;
+            let _ = reply.send(result);
+            apply_operations(mail_store, acct_name, source).await?;
+        }
+        MailCommand::Move {
+            folder,
+            uid,
+            to,
+            reply,
+            ..
+        } => {
+            if read_only {
+                let _ = reply.send(Err(anyhow!("messages can't be moved out of an mbox file")));
+                return Ok(());
+            }
+            let result = mail_store.move_message(acct_name, &folder, uid, &to).await;
+            let _ = reply.send(result);
+            apply_operations(mail_store, acct_name, source).await?;
+        }
+        MailCommand::Delete {
+            folder, uid, reply, ..
+        } => {
+            if read_only {
+                let _ = reply.send(Err(anyhow!("messages can't be deleted from an mbox file")));
+                return Ok(());
+            }
+            let result = mail_store.delete_message(acct_name, &folder, uid).await;
+            let _ = reply.send(result);
+            apply_operations(mail_store, acct_name, source).await?;
+        }
+    }
+    Ok(())
+}
+
+/// Rescans every folder in the account, after applying any changes that were made in panorama
+async fn sync_all(
+    acct_name: &str,
+    source: &LocalSource,
+    mail2ui_tx: &UnboundedSender<MailEvent>,
+    mail_store: &MailStore,
+) -> Result<()> {
+    apply_operations(mail_store, acct_name, source).await?;
+
+    let folders = source.folders().await?;
+    let delimiter = match source {
+        LocalSource::Maildir(_) => Some(MAILDIR_DELIMITER),
+        LocalSource::Mbox(_) => None,
+    };
+    let folder_list = folders
+        .iter()
+        .map(|(name, _)| FolderMetadata::local(name.as_str(), delimiter))
+        .collect();
+    let _ = mail2ui_tx.send(MailEvent::FolderList(acct_name.to_owned(), folder_list));
+
+    for (i, (folder, dir)) in folders.iter().enumerate() {
+        let state = AccountState::Syncing(i, folders.len());
+        let _ = mail2ui_tx.send(MailEvent::AccountState(acct_name.to_owned(), state));
+        sync_folder(acct_name, source, mail_store, folder, dir).await?;
+    }
+
+    let _ = mail2ui_tx.send(MailEvent::AccountState(
+        acct_name.to_owned(),
+        AccountState::Idle,
+    ));
+    Ok(())
+}
+
+/// Brings the local copy of a single folder up to date with its files
+///
+/// Returns the number of messages in the folder.
+async fn sync_folder(
+    acct_name: &str,
+    source: &LocalSource,
+    mail_store: &MailStore,
+    folder: &str,
+    path: &Path,
+) -> Result<usize> {
+    debug!("scanning {} ({:?})", folder, path);
+    match source {
+        LocalSource::Maildir(_) => sync_maildir_folder(acct_name, mail_store, folder, path).await,
+        LocalSource::Mbox(_) => sync_mbox(acct_name, mail_store, folder, path).await,
+    }
+}
+
+async fn sync_maildir_folder(
+    acct_name: &str,
+    mail_store: &MailStore,
+    folder: &str,
+    dir: &Path,
+) -> Result<usize> {
+    let messages = maildir::list_messages(dir).await?;
+    let keys = messages
+        .iter()
+        .map(|message| message.unique.clone())
+        .collect();
+    let uids = mail_store
+        .assign_local_uids(acct_name, folder, keys)
+        .await?;
+    let all_uids = uids.values().copied().collect::<Vec<_>>();
+    let new_uids = mail_store
+        .filter_new_uids(acct_name, folder, LOCAL_UIDVALIDITY, all_uids.clone())
+        .await?
+        .into_iter()
+        .collect::<HashSet<_>>();
+
+    let mut flags = Vec::new();
+    for message in messages {
+        let uid = match uids.get(&message.unique) {
+            Some(uid) => *uid,
+            None => continue,
+        };
+
+        if new_uids.contains(&uid) {
+            let body = match fs::read(&message.path).await {
+                Ok(body) => body,
+                // another client renamed it in the meantime, it'll be picked up on the next scan
+                Err(err) if err.kind() == ErrorKind::NotFound => continue,
+                Err(err) => {
+                    return Err(err).with_context(|| format!("error reading {:?}", message.path))
+                }
+            };
+            let date = message.date.unwrap_or_else(Local::now);
+            mail_store
+                .store_message(
+                    acct_name,
+                    folder,
+                    uid,
+                    LOCAL_UIDVALIDITY,
+                    body,
+                    date.into(),
+                    message.flags.clone(),
+                )
+                .await?;
+        }
+        flags.push((uid, message.flags));
+    }
+
+    if !new_uids.is_empty() {
+        mail_store.publish_counts(acct_name, folder).await?;
+    }
+
+    let exists = all_uids.len();
+    mail_store
+        .expunge_missing(acct_name, folder, LOCAL_UIDVALIDITY, all_uids)
+        .await?;
+    mail_store
+        .sync_flags(acct_name, folder, LOCAL_UIDVALIDITY, flags)
+        .await?;
+    Ok(exists)
+}
+
+/// Rescans an mbox file. Flags are only read from it the first time a message is seen, since
+/// they're never written back and would undo any changes made in panorama.
+async fn sync_mbox(
+    acct_name: &str,
+    mail_store: &MailStore,
+    folder: &str,
+    path: &Path,
+) -> Result<usize> {
+    // the file is read twice, once to find out which messages are new and again to store them,
+    // so that a big spool doesn't have to be held in memory
+    let mut keys = Vec::new();
+    let mut reader = match open_mbox(path).await? {
+        Some(reader) => reader,
+        None => {
+            // the file is gone, which some programs do when they've taken all the mail out of it
+            mail_store
+                .assign_local_uids(acct_name, folder, Vec::new())
+                .await?;
+            mail_store
+                .expunge_missing(acct_name, folder, LOCAL_UIDVALIDITY, Vec::new())
+                .await?;
+            return Ok(0);
+        }
+    };
+    while let Some(message) = reader.next_message().await? {
+        keys.push(mbox_key(&message.body));
+    }
+
+    let uids = mail_store
+        .assign_local_uids(acct_name, folder, keys.clone())
+        .await?;
+    let all_uids = uids.values().copied().collect::<Vec<_>>();
+    let new_uids = mail_store
+        .filter_new_uids(acct_name, folder, LOCAL_UIDVALIDITY, all_uids.clone())
+        .await?
+        .into_iter()
+        .collect::<HashSet<_>>();
+
+    if !new_uids.is_empty() {
+        if let Some(mut reader) = open_mbox(path).await? {
+            while let Some(message) = reader.next_message().await? {
+                let uid = match uids.get(&mbox_key(&message.body)) {
+                    Some(uid) if new_uids.contains(uid) => *uid,
+                    _ => continue,
+                };
+                let date = message.date.unwrap_or_else(Local::now);
+                mail_store
+                    .store_message(
+                        acct_name,
+                        folder,
+                        uid,
+                        LOCAL_UIDVALIDITY,
+                        message.body,
+                        date.into(),
+                        message.flags,
+                    )
+                    .await?;
+            }
+        }
+        mail_store.publish_counts(acct_name, folder).await?;
+    }
+
+    let exists = all_uids.len();
+    mail_store
+        .expunge_missing(acct_name, folder, LOCAL_UIDVALIDITY, all_uids)
+        .await?;
+    Ok(exists)
+}
+
+async fn open_mbox(path: &Path) -> Result<Option<MboxReader<BufReader<fs::File>>>> {
+    match fs::File::open(path).await {
+        Ok(file) => Ok(Some(MboxReader::new(BufReader::new(file)))),
+        Err(err) if err.kind() == ErrorKind::NotFound => Ok(None),
+        Err(err) => Err(err).with_context(|| format!("error opening mbox {:?}", path)),
+    }
+}
+
+/// Messages in an mbox file are known by the hash of their contents, since there's nothing else
+/// that stays the same when messages before them are taken out
+fn mbox_key(body: &[u8]) -> String {
+    let mut hasher = Sha256::new();
+    hasher.update(body);
+    hex::encode(hasher.finalize())
+}
+
+/// Applies the operations in the journal to the account's files, in the order they were made
+///
+/// Just like on a server, an operation that fails is left in the journal to be retried, unless
+/// it has failed too many times already.
+pub async fn replay_operations(
+    mail_store: &MailStore,
+    acct_name: &str,
+    source: &MailSource,
+) -> Result<()> {
+    let source = LocalSource::from_config(source).context("not a local account")?;
+    apply_operations(mail_store, acct_name, &source).await
+}
+
+async fn apply_operations(
+    mail_store: &MailStore,
+    acct_name: &str,
+    source: &LocalSource,
+) -> Result<()> {
+    let ops = mail_store.get_pending_operations(acct_name).await?;
+    if !ops.is_empty() {
+        debug!("applying {} operations to {}", ops.len(), acct_name);
+    }
+
+    let mut listings = MaildirListings::default();
+    for (id, op) in ops {
+        let result = match source {
+            LocalSource::Maildir(root) => {
+                apply_maildir_operation(mail_store, acct_name, root, &mut listings, &op).await
+            }
+            // flags are only kept in the store, and nothing else can be done to an mbox file
+            LocalSource::Mbox(_) => {
+                if !matches!(op, MailOperation::SetFlag { .. }) {
+                    warn!("can't apply {:?} to an mbox file, dropping it", op);
+                }
+                Ok(())
+            }
+        };
+
+        match result {
+            Ok(()) => mail_store.complete_operation(id).await?,
+            Err(err) => {
+                if mail_store.fail_operation(id, &err).await? {
+                    error!("giving up on operation {:?}: {:#}", op, err);
+                    continue;
+                }
+                return Err(err.context(format!("error applying operation {:?}", op)));
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// The messages in each Maildir folder, listed the first time an operation needs them and kept up
+/// to date as operations change them, so that replaying the journal reads each folder only once
+#[derive(Default)]
+struct MaildirListings(HashMap<PathBuf, Vec<MaildirMessage>>);
+
+impl MaildirListings {
+    async fn get(&mut self, dir: &Path) -> Result<&mut Vec<MaildirMessage>> {
+        if !self.0.contains_key(dir) {
+            let messages = maildir::list_messages(dir).await?;
+            self.0.insert(dir.to_path_buf(), messages);
+        }
+        Ok(self.0.get_mut(dir).unwrap())
+    }
+
+    /// Adds a message that was just written to a folder. A folder that hasn't been listed yet
+    /// will have it when it is.
+    fn add(&mut self, dir: &Path, message: MaildirMessage) {
+        if let Some(messages) = self.0.get_mut(dir) {
+            messages.push(message);
+        }
+    }
+}
+
+async fn apply_maildir_operation(
+    mail_store: &MailStore,
+    acct_name: &str,
+    root: &Path,
+    listings: &mut MaildirListings,
+    op: &MailOperation,
+) -> Result<()> {
+    // replaying an operation has to be harmless if it was already applied, so a message that
+    // can't be found anymore is taken to be already done
+    let message = match op.message() {
+        Some((folder, _, uid)) => {
+            let dir = maildir::folder_dir(root, folder);
+            match mail_store.get_local_key(acct_name, folder, uid).await? {
+                Some(key) => listings
+                    .get(&dir)
+                    .await?
+                    .iter()
+                    .position(|message| message.unique == key)
+                    .map(|index| (dir, index)),
+                None => None,
+            }
+        }
+        None => None,
+    };
+
+    match op {
+        MailOperation::SetFlag { flag, added, .. } => {
+            if let Some((dir, index)) = message {
+                let message = &mut listings.get(&dir).await?[index];
+                let flag = MailboxFlag::from(flag.as_str());
+                let mut flags = message.flags.clone();
+                if *added && !flags.contains(&flag) {
+                    flags.push(flag);
+                } else if !*added {
+                    flags.retain(|other| *other != flag);
+                }
+                message.path = maildir::move_message(message, &dir, &flags).await?;
+                message.flags = flags;
+            }
+        }
+
+        MailOperation::Move { to, .. } => {
+            if let Some((dir, index)) = message {
+                let to_dir = maildir::folder_dir(root, to);
+                MaildirWriter::open(&to_dir).await?;
+                let messages = listings.get(&dir).await?;
+                let path = maildir::move_message(&messages[index], &to_dir, &messages[index].flags)
+                    .await?;
+                let mut message = messages.remove(index);
+                message.path = path;
+                listings.add(&to_dir, message);
+            }
+        }
+
+        MailOperation::Delete { .. } => {
+            if let Some((dir, index)) = message {
+                let messages = listings.get(&dir).await?;
+                fs::remove_file(&messages[index].path)
+                    .await
+                    .with_context(|| format!("error deleting {:?}", messages[index].path))?;
+                messages.remove(index);
+            }
+        }
+
+        MailOperation::Expunge { folder } => {
+            let messages = listings.get(&maildir::folder_dir(root, folder)).await?;
+            let deleted = messages
+                .iter()
+                .filter(|message| message.flags.contains(&MailboxFlag::Deleted))
+                .map(|message| message.path.clone())
+                .collect::<Vec<_>>();
+            for path in deleted {
+                fs::remove_file(&path)
+                    .await
+                    .with_context(|| format!("error deleting {:?}", path))?;
+                messages.retain(|message| message.path != path);
+            }
+        }
+
+        MailOperation::Append {
+            folder,
+            filename,
+            flags,
+            ..
+        } => {
+            // messages appended here are named after the hash of their body, which is how one
+            // that was already written before the operation could be completed is found
+            let dir = maildir::folder_dir(root, folder);
+            let suffix = format!(".{}.panorama", filename.trim_end_matches(".mail"));
+            let already_there = listings
+                .get(&dir)
+                .await?
+                .iter()
+                .any(|message| message.unique.ends_with(&suffix));
+            if !already_there {
+                let body = mail_store.read_mail_file(filename).await?;
+                let flags = flags
+                    .iter()
+                    .map(|flag| MailboxFlag::from(flag.as_str()))
+                    .collect::<Vec<_>>();
+                let unique = format!("{}{}", Utc::now().timestamp(), suffix);
+                let path = MaildirWriter::open(&dir)
+                    .await?
+                    .write(&unique, &body, &flags)
+                    .await?;
+                let message = MaildirMessage {
+                    path,
+                    unique,
+                    flags,
+                    date: None,
+                };
+                listings.add(&dir, message);
+            }
+        }
+
+        MailOperation::CreateFolder { name } => {
+            MaildirWriter::open(maildir::folder_dir(root, name)).await?;
+        }
+
+        MailOperation::DeleteFolder { name } => {
+            let dir = maildir::folder_dir(root, name);
+            if dir == root {
+                bail!("the INBOX can't be deleted");
+            }
+            listings.0.remove(&dir);
+            if dir.is_dir() {
+                fs::remove_dir_all(&dir)
+                    .await
+                    .with_context(|| format!("error deleting {:?}", dir))?;
+            }
+        }
+
+        MailOperation::RenameFolder { from, to } => {
+            let from_dir = maildir::folder_dir(root, from);
+            let to_dir = maildir::folder_dir(root, to);
+            if from_dir == root || to_dir == root {
+                bail!("the INBOX can't be renamed");
+            }
+            listings.0.remove(&from_dir);
+            listings.0.remove(&to_dir);
+            if from_dir.is_dir() {
+                fs::rename(&from_dir, &to_dir)
+                    .await
+                    .with_context(|| format!("error renaming {:?} to {:?}", from_dir, to_dir))?;
+            }
+        }
+    }
+
+    Ok(())
+}
@@ -0,0 +1,304 @@
+//! Reading and writing the Maildir format (<https://cr.yp.to/proto/maildir.html>), for moving mail
+//! between panorama and other tools like mbsync and offlineimap
+//!
+//! Folders are laid out the way Maildir++ does it: INBOX is the top level directory, and every other
+//! folder is a subdirectory whose name starts with a dot (ex. `.Sent`). Maildir++ separates levels
+//! of the hierarchy with dots, so a folder exported as `Work/Project` comes back as `Work.Project`.
+
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+
+use anyhow::{Context, Result};
+use chrono::{DateTime, Local};
+use panorama_imap::response::MailboxFlag;
+use tokio::fs;
+
+/// The flags that Maildir can store, by the letter used for them in file names. These have to stay
+/// in alphabetical order, since that's the order they're written in.
+const FLAG_LETTERS: &[(char, &str)] = &[
+    ('D', "\\Draft"),
+    ('F', "\\Flagged"),
+    ('P', "$Forwarded"),
+    ('R', "\\Answered"),
+    ('S', "\\Seen"),
+    ('T', "\\Deleted"),
+];
+
+/// A message found in a Maildir
+#[derive(Debug)]
+pub struct MaildirMessage {
+    /// Where the message is
+    pub path: PathBuf,
+
+    /// The part of the file name that identifies the message, which stays the same when its
+    /// flags change or it's moved to another folder
+    pub unique: String,
+
+    /// Flags on the message. Messages that are still in `new` haven't been seen by any client
+    /// yet, so they don't have any.
+    pub flags: Vec<MailboxFlag>,
+
+    /// When the message was delivered, going by the modification time of the file
+    pub date: Option<DateTime<Local>>,
+}
+
+/// Turns a list of flags into the info part of a Maildir file name (ex. `:2,FS`)
+pub fn flags_to_info(flags: &[MailboxFlag]) -> String {
+    let flags = flags
+        .iter()
+        .map(|flag| flag.to_string())
+        .collect::<Vec<_>>();
+    let letters = FLAG_LETTERS
+        .iter()
+        .filter(|(_, flag)| flags.iter().any(|other| other == flag))
+        .map(|(letter, _)| *letter)
+        .collect::<String>();
+    format!(":2,{}", letters)
+}
+
+/// Reads the flags out of the info part of a Maildir file name. Letters that don't mean anything
+/// to IMAP are dropped.
+pub fn flags_from_file_name(name: &str) -> Vec<MailboxFlag> {
+    let letters = match name.rfind(":2,") {
+        Some(idx) => &name[idx + 3..],
+        None => return Vec::new(),
+    };
+    FLAG_LETTERS
+        .iter()
+        .filter(|(letter, _)| letters.contains(*letter))
+        .map(|(_, flag)| MailboxFlag::from(*flag))
+        .collect()
+}
+
+/// The directory a folder goes in, under the top level of a Maildir++ tree
+pub fn folder_dir(root: &Path, folder: &str) -> PathBuf {
+    if folder.eq_ignore_ascii_case("INBOX") {
+        root.to_path_buf()
+    } else {
+        root.join(format!(".{}", folder.replace('/', ".")))
+    }
+}
+
+/// Lists the folders in a Maildir++ tree as (folder name, directory)
+pub async fn list_folders(root: &Path) -> Result<Vec<(String, PathBuf)>> {
+    let mut folders = Vec::new();
+    if is_maildir(root) {
+        folders.push((String::from("INBOX"), root.to_path_buf()));
+    }
+
+    let mut entries = fs::read_dir(root)
+        .await
+        .with_context(|| format!("error reading maildir {:?}", root))?;
+    while let Some(entry) = entries.next_entry().await? {
+        let name = entry.file_name().to_string_lossy().into_owned();
+        let path = entry.path();
+        if let Some(folder) = name.strip_prefix('.') {
+            if !folder.is_empty() && folder != "." && is_maildir(&path) {
+                folders.push((folder.to_owned(), path));
+            }
+        }
+    }
+    folders.sort();
+    Ok(folders)
+}
+
+/// Whether the directory is a Maildir, which is anything with a `cur` directory in it
+fn is_maildir(dir: &Path) -> bool {
+    dir.join("cur").is_dir()
+}
+
+/// Lists the messages in a single Maildir folder. Anything still being delivered (in `tmp`) is
+/// skipped.
+pub async fn list_messages(dir: &Path) -> Result<Vec<MaildirMessage>> {
+    let mut messages = Vec::new();
+    for subdir in &["new", "cur"] {
+        let subdir_path = dir.join(subdir);
+        if !subdir_path.is_dir() {
+            continue;
+        }
+
+        let mut entries = fs::read_dir(&subdir_path)
+            .await
+            .with_context(|| format!("error reading maildir {:?}", subdir_path))?;
+        while let Some(entry) = entries.next_entry().await? {
+            let name = entry.file_name().to_string_lossy().into_owned();
+            if name.starts_with('.') {
+                continue;
+            }
+            let metadata = entry.metadata().await?;
+            if !metadata.is_file() {
+                continue;
+            }
+
+            let flags = match *subdir {
+                "cur" => flags_from_file_name(&name),
+                _ => Vec::new(),
+            };
+            messages.push(MaildirMessage {
+                path: entry.path(),
+                unique: unique_name(&name).to_owned(),
+                flags,
+                date: metadata.modified().ok().map(DateTime::from),
+            });
+        }
+    }
+    Ok(messages)
+}
+
+/// The part of a Maildir file name before the flags
+fn unique_name(file_name: &str) -> &str {
+    file_name.split(':').next().unwrap_or_default()
+}
+
+/// Moves a message into the `cur` directory of a folder with the given flags. The folder can be
+/// the one the message is already in, to just change its flags.
+pub async fn move_message(
+    message: &MaildirMessage,
+    dir: &Path,
+    flags: &[MailboxFlag],
+) -> Result<PathBuf> {
+    let target = dir
+        .join("cur")
+        .join(format!("{}{}", message.unique, flags_to_info(flags)));
+    if target != message.path {
+        fs::rename(&message.path, &target)
+            .await
+            .with_context(|| format!("error moving {:?} to {:?}", message.path, target))?;
+    }
+    Ok(target)
+}
+
+/// A Maildir folder that messages are being written into
+#[derive(Debug)]
+pub struct MaildirWriter {
+    dir: PathBuf,
+
+    /// Files that are already in the folder, by their unique name
+    existing: HashMap<String, PathBuf>,
+}
+
+impl MaildirWriter {
+    /// Opens a Maildir folder for writing, creating it if it doesn't exist yet
+    pub async fn open(dir: impl Into<PathBuf>) -> Result<Self> {
+        let dir = dir.into();
+        for subdir in &["tmp", "new", "cur"] {
+            fs::create_dir_all(dir.join(subdir))
+                .await
+                .with_context(|| format!("error creating maildir {:?}", dir))?;
+        }
+
+        let existing = list_messages(&dir)
+            .await?
+            .into_iter()
+            .map(|message| (message.unique, message.path))
+            .collect::<HashMap<_, _>>();
+
+        Ok(MaildirWriter { dir, existing })
+    }
+
+    /// Writes a message into the folder. `unique` is the part of the file name that identifies
+    /// the message, and if the folder already has a message by that name, only its flags are
+    /// updated.
+    pub async fn write(
+        &mut self,
+        unique: &str,
+        body: &[u8],
+        flags: &[MailboxFlag],
+    ) -> Result<PathBuf> {
+        let target = self
+            .dir
+            .join("cur")
+            .join(format!("{}{}", unique, flags_to_info(flags)));
+        if let Some(existing) = self.existing.get(unique) {
+            if *existing != target {
+                fs::rename(existing, &target).await?;
+            }
+        } else {
+            // the message has to be written out completely before it shows up in cur, so other
+            // clients never see half of it
+            let tmp = self.dir.join("tmp").join(unique);
+            fs::write(&tmp, body)
+                .await
+                .with_context(|| format!("error writing message to {:?}", tmp))?;
+            fs::rename(&tmp, &target).await?;
+        }
+
+        self.existing.insert(unique.to_owned(), target.clone());
+        Ok(target)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_flags_to_info() {
+        assert_eq!(flags_to_info(&[]), ":2,");
+        assert_eq!(
+            flags_to_info(&[
+                MailboxFlag::Seen,
+                MailboxFlag::Answered,
+                MailboxFlag::Flagged
+            ]),
+            ":2,FRS"
+        );
+        assert_eq!(
+            flags_to_info(&[
+                MailboxFlag::Deleted,
+                MailboxFlag::from("$Forwarded"),
+                MailboxFlag::Draft,
+            ]),
+            ":2,DPT"
+        );
+
+        // flags that Maildir has no letter for are left out
+        assert_eq!(
+            flags_to_info(&[MailboxFlag::from("$Junk"), MailboxFlag::Seen]),
+            ":2,S"
+        );
+    }
+
+    #[test]
+    fn test_flags_from_file_name() {
+        assert_eq!(
+            flags_from_file_name("1614608400.M1P2.host:2,FRS"),
+            vec![
+                MailboxFlag::Flagged,
+                MailboxFlag::Answered,
+                MailboxFlag::Seen
+            ]
+        );
+        assert_eq!(
+            flags_from_file_name("1614608400.M1P2.host:2,DPT"),
+            vec![
+                MailboxFlag::Draft,
+                MailboxFlag::from("$Forwarded"),
+                MailboxFlag::Deleted,
+            ]
+        );
+
+        // lowercase letters are keywords that only mean something to the client that set them,
+        // and other letters aren't defined at all
+        assert_eq!(
+            flags_from_file_name("1614608400.M1P2.host:2,abSXZ"),
+            vec![MailboxFlag::Seen]
+        );
+        assert_eq!(flags_from_file_name("1614608400.M1P2.host:2,"), vec![]);
+        assert_eq!(flags_from_file_name("1614608400.M1P2.host"), vec![]);
+        assert_eq!(flags_from_file_name("1614608400.M1P2.host:1,S"), vec![]);
+    }
+
+    #[test]
+    fn test_flags_roundtrip() {
+        let flags = vec![
+            MailboxFlag::Draft,
+            MailboxFlag::Flagged,
+            MailboxFlag::Answered,
+            MailboxFlag::Seen,
+        ];
+        let name = format!("unique{}", flags_to_info(&flags));
+        assert_eq!(flags_from_file_name(&name), flags);
+        assert_eq!(unique_name(&name), "unique");
+    }
+}
@@ -0,0 +1,387 @@
+//! Reading and writing mbox files, in the mboxrd flavor
+//! (<https://www.loc.gov/preservation/digital/formats/fdd/fdd000385.shtml>)
+//!
+//! Every message starts with a `From ` line, so any line in a message that would look like one
+//! (including ones that were already quoted, like `>From `) gets another `>` in front of it when
+//! it's written, and one taken off when it's read. Flags are kept in `Status` and `X-Status`
+//! headers the way mutt and most other clients do it.
+//!
+//! Messages are read and written as bytes, since mbox files often have messages in 8-bit
+//! charsets that aren't UTF-8.
+
+use anyhow::{bail, Result};
+use chrono::{DateTime, Local, NaiveDateTime, TimeZone, Utc};
+use panorama_imap::response::MailboxFlag;
+use tokio::io::{AsyncBufRead, AsyncBufReadExt};
+
+/// Format of the date on a `From ` line, which is what C's asctime puts out
+const FROM_LINE_DATE: &str = "%a %b %e %H:%M:%S %Y";
+
+/// The flags that go in the X-Status header, by the letter used for them. \Seen goes in Status
+/// instead.
+const X_STATUS_LETTERS: &[(char, &str)] = &[
+    ('A', "\\Answered"),
+    ('F', "\\Flagged"),
+    ('T', "\\Draft"),
+    ('D', "\\Deleted"),
+];
+
+/// A message read out of an mbox file
+#[derive(Debug)]
+pub struct MboxMessage {
+    /// The message with CRLF line endings, the same way it would come from an IMAP server
+    pub body: Vec<u8>,
+
+    /// Flags from the message's Status and X-Status headers, which are taken out of the body
+    pub flags: Vec<MailboxFlag>,
+
+    /// When the message was delivered, going by its `From ` line
+    pub date: Option<DateTime<Local>>,
+}
+
+/// Turns a message into its mbox form, ready to be appended to a file. Any Status or X-Status
+/// headers the message already has are replaced with ones for the given flags.
+pub fn format_message(body: &[u8], flags: &[MailboxFlag], date: DateTime<Utc>) -> Vec<u8> {
+    let flags = flags
+        .iter()
+        .map(|flag| flag.to_string())
+        .collect::<Vec<_>>();
+    let has_flag = |name: &str| flags.iter().any(|flag| flag == name);
+    let status_headers = || {
+        let mut out = Vec::new();
+        let status = if has_flag("\\Seen") { "RO" } else { "O" };
+        out.extend_from_slice(format!("Status: {}\n", status).as_bytes());
+        let x_status = X_STATUS_LETTERS
+            .iter()
+            .filter(|(_, flag)| has_flag(flag))
+            .map(|(letter, _)| *letter)
+            .collect::<String>();
+        if !x_status.is_empty() {
+            out.extend_from_slice(format!("X-Status: {}\n", x_status).as_bytes());
+        }
+        out
+    };
+
+    let mut out = format!(
+        "From {} {}\n",
+        envelope_sender(body),
+        date.format(FROM_LINE_DATE)
+    )
+    .into_bytes();
+    let mut in_headers = true;
+    let mut skipping_header = false;
+    for line in lines(body) {
+        if in_headers {
+            if line.is_empty() {
+                in_headers = false;
+                out.extend(status_headers());
+            } else if line.starts_with(b" ") || line.starts_with(b"\t") {
+                // a folded header goes along with the line before it
+                if skipping_header {
+                    continue;
+                }
+            } else {
+                skipping_header = is_status_header(line);
+                if skipping_header {
+                    continue;
+                }
+            }
+        }
+
+        if is_from_line(trim_quoting(line)) {
+            out.push(b'>');
+        }
+        out.extend_from_slice(line);
+        out.push(b'\n');
+    }
+    if in_headers {
+        out.extend(status_headers());
+    }
+
+    // messages are separated by an empty line
+    out.push(b'\n');
+    out
+}
+
+/// Splits a message into lines, without their line endings
+fn lines(body: &[u8]) -> impl Iterator<Item = &[u8]> {
+    let body = if body.ends_with(b"\n") {
+        &body[..body.len() - 1]
+    } else {
+        body
+    };
+    body.split(|b| *b == b'\n').map(|line| {
+        if line.ends_with(b"\r") {
+            &line[..line.len() - 1]
+        } else {
+            line
+        }
+    })
+}
+
+/// Takes any `>` that a line was quoted with off of the front of it
+fn trim_quoting(line: &[u8]) -> &[u8] {
+    let quoted = line.iter().take_while(|b| **b == b'>').count();
+    &line[quoted..]
+}
+
+/// Whether the line starts a new message, once any quoting is taken off
+fn is_from_line(line: &[u8]) -> bool {
+    line.starts_with(b"From ")
+}
+
+/// Whether the header line is one that flags are kept in
+fn is_status_header(line: &[u8]) -> bool {
+    let lower = line.to_ascii_lowercase();
+    lower.starts_with(b"status:") || lower.starts_with(b"x-status:")
+}
+
+/// The address that goes on the `From ` line, which comes from the Return-Path or From header.
+/// It can't have any spaces in it, since the date comes right after it.
+fn envelope_sender(body: &[u8]) -> String {
+    let headers = lines(body)
+        .take_while(|line| !line.is_empty())
+        .map(String::from_utf8_lossy)
+        .collect::<Vec<_>>();
+    for name in &["return-path:", "from:"] {
+        let value = match headers
+            .iter()
+            .find(|line| line.to_ascii_lowercase().starts_with(name))
+        {
+            Some(line) => &line[name.len()..],
+            None => continue,
+        };
+
+        let address = match (value.find('<'), value.rfind('>')) {
+            (Some(start), Some(end)) if start < end => &value[start + 1..end],
+            _ => value.trim(),
+        };
+        if !address.is_empty() && !address.contains(char::is_whitespace) {
+            return address.to_owned();
+        }
+    }
+    String::from("MAILER-DAEMON")
+}
+
+/// Reads the date off of a `From ` line. Some programs put other things after the date (ex. a
+/// time zone), in which case there's no date.
+fn parse_from_line(line: &[u8]) -> Option<DateTime<Local>> {
+    let line = String::from_utf8_lossy(line);
+    let rest = line.strip_prefix("From ")?.trim_start();
+    let date = rest[rest.find(' ')?..].trim();
+    let date = NaiveDateTime::parse_from_str(date, FROM_LINE_DATE).ok()?;
+    Some(Utc.from_utc_datetime(&date).with_timezone(&Local))
+}
+
+/// Reads messages out of an mbox file one at a time, so the whole file doesn't have to fit in
+/// memory
+#[derive(Debug)]
+pub struct MboxReader<R> {
+    reader: R,
+
+    /// The `From ` line of the next message, which was read while looking for the end of the
+    /// previous one
+    next_from_line: Option<Vec<u8>>,
+}
+
+impl<R: AsyncBufRead + Unpin> MboxReader<R> {
+    /// Creates a reader that starts at the beginning of an mbox file
+    pub fn new(reader: R) -> Self {
+        MboxReader {
+            reader,
+            next_from_line: None,
+        }
+    }
+
+    /// Reads the next message, or returns None at the end of the file
+    pub async fn next_message(&mut self) -> Result<Option<MboxMessage>> {
+        let from_line = match self.next_from_line.take() {
+            Some(line) => line,
+            None => loop {
+                match self.read_line().await? {
+                    Some(line) if line.iter().all(u8::is_ascii_whitespace) => continue,
+                    Some(line) if is_from_line(&line) => break line,
+                    Some(_) => bail!("not an mbox file, it doesn't start with a From line"),
+                    None => return Ok(None),
+                }
+            },
+        };
+
+        // files written by older programs (mboxo) don't quote every From line in the body, but
+        // those always come after an empty line when they're the start of a message
+        let mut lines = Vec::new();
+        while let Some(line) = self.read_line().await? {
+            if is_from_line(&line) && lines.last().map(Vec::is_empty).unwrap_or(true) {
+                self.next_from_line = Some(line);
+                break;
+            }
+            lines.push(line);
+        }
+        if lines.last().map(Vec::is_empty).unwrap_or(false) {
+            lines.pop();
+        }
+
+        let mut flags = Vec::new();
+        let mut body = Vec::new();
+        let mut in_headers = true;
+        for line in lines {
+            if line.is_empty() {
+                in_headers = false;
+            } else if in_headers && is_status_header(&line) {
+                let lower = String::from_utf8_lossy(&line).to_ascii_lowercase();
+                if let Some(status) = lower.strip_prefix("status:") {
+                    if status.contains('r') {
+                        flags.push(MailboxFlag::Seen);
+                    }
+                } else if let Some(x_status) = lower.strip_prefix("x-status:") {
+                    let x_status = x_status.to_ascii_uppercase();
+                    flags.extend(
+                        X_STATUS_LETTERS
+                            .iter()
+                            .filter(|(letter, _)| x_status.contains(*letter))
+                            .map(|(_, flag)| MailboxFlag::from(*flag)),
+                    );
+                }
+                continue;
+            }
+
+            let line = if line.starts_with(b">") && is_from_line(trim_quoting(&line)) {
+                &line[1..]
+            } else {
+                &line[..]
+            };
+            body.extend_from_slice(line);
+            body.extend_from_slice(b"\r\n");
+        }
+
+        Ok(Some(MboxMessage {
+            body,
+            flags,
+            date: parse_from_line(&from_line),
+        }))
+    }
+
+    /// Reads a line without its line ending
+    async fn read_line(&mut self) -> Result<Option<Vec<u8>>> {
+        let mut buf = Vec::new();
+        if self.reader.read_until(b'\n', &mut buf).await? == 0 {
+            return Ok(None);
+        }
+        if buf.ends_with(b"\n") {
+            buf.pop();
+        }
+        if buf.ends_with(b"\r") {
+            buf.pop();
+        }
+        Ok(Some(buf))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn date() -> DateTime<Utc> {
+        DateTime::parse_from_rfc3339("2021-03-01T14:20:00Z")
+            .unwrap()
+            .with_timezone(&Utc)
+    }
+
+    async fn read_all(data: &[u8]) -> Result<Vec<MboxMessage>> {
+        let mut reader = MboxReader::new(data);
+        let mut messages = Vec::new();
+        while let Some(message) = reader.next_message().await? {
+            messages.push(message);
+        }
+        Ok(messages)
+    }
+
+    #[tokio::test]
+    async fn test_from_quoting() -> Result<()> {
+        let body = b"From: Me <me@example.com>\r\nSubject: hi\r\n\r\n\
+                     From here on\r\n\
+                     >From there\r\n\
+                     >>From everywhere\r\n\
+                     > From nowhere\r\n";
+        let formatted = format_message(body, &[], date());
+        let text = String::from_utf8(formatted.clone()).unwrap();
+        assert!(text.starts_with("From me@example.com Mon Mar  1 14:20:00 2021\n"));
+        assert!(text.contains("\n>From here on\n>>From there\n>>>From everywhere\n"));
+        assert!(text.contains("\n> From nowhere\n"));
+
+        let messages = read_all(&formatted).await?;
+        assert_eq!(messages.len(), 1);
+        assert_eq!(messages[0].body, body.to_vec());
+        assert_eq!(
+            messages[0].date.map(|d| d.with_timezone(&Utc)),
+            Some(date())
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_roundtrip() -> Result<()> {
+        let first = b"From: a@example.com\r\nSubject: one\r\n\r\nGr\xfc\xdfe\r\n".to_vec();
+        let second = b"From: b@example.com\r\nSubject: two\r\n\r\nFrom me\r\n".to_vec();
+        let mut file = format_message(&first, &[MailboxFlag::Seen], date());
+        file.extend(format_message(
+            &second,
+            &[MailboxFlag::Flagged, MailboxFlag::Answered],
+            date(),
+        ));
+
+        let messages = read_all(&file).await?;
+        assert_eq!(messages.len(), 2);
+        assert_eq!(messages[0].body, first);
+        assert_eq!(messages[0].flags, vec![MailboxFlag::Seen]);
+        assert_eq!(messages[1].body, second);
+        assert_eq!(
+            messages[1].flags,
+            vec![MailboxFlag::Answered, MailboxFlag::Flagged]
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_mboxo() -> Result<()> {
+        // an unquoted From line only starts a message after an empty line
+        let file = b"From a@example.com Mon Mar  1 14:20:00 2021\n\
+                     Subject: one\n\
+                     \n\
+                     hello\n\
+                     From the start\n\
+                     \n\
+                     From b@example.com Mon Mar  1 14:20:00 2021\n\
+                     Subject: two\n\
+                     \n\
+                     bye\n";
+        let messages = read_all(file).await?;
+        assert_eq!(messages.len(), 2);
+        assert_eq!(
+            messages[0].body,
+            b"Subject: one\r\n\r\nhello\r\nFrom the start\r\n".to_vec()
+        );
+        assert_eq!(messages[1].body, b"Subject: two\r\n\r\nbye\r\n".to_vec());
+        assert!(read_all(b"Subject: nope\n\nhi\n").await.is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_status_replaced() {
+        let body = b"From: a@example.com\r\nStatus: O\r\nX-Status: AF\r\n \
+                     folded\r\nSubject: hi\r\n\r\nStatus: body\r\n";
+        let formatted = format_message(body, &[MailboxFlag::Seen, MailboxFlag::Draft], date());
+        let text = String::from_utf8(formatted).unwrap();
+        assert_eq!(
+            text,
+            "From a@example.com Mon Mar  1 14:20:00 2021\n\
+             From: a@example.com\n\
+             Subject: hi\n\
+             Status: RO\n\
+             X-Status: T\n\
+             \n\
+             Status: body\n\
+             \n"
+        );
+    }
+}
@@ -0,0 +1,540 @@
+//! Messages parsed for reading
+//!
+//! This sits on top of `mailparse`, which takes care of transfer encodings, RFC 2047
+//! encoded-words in headers (ex. `=?ISO-8859-1?Q?caf=E9?=`), and decoding text from whatever
+//! charset it's in (ISO-8859-*, Windows-125x, Shift_JIS, GB2312, and everything else the WHATWG
+//! encoding standard knows about). What's here turns that into a tree of parts that can be shown
+//! without having to know anything about MIME.
+
+use anyhow::Result;
+use mailparse::{DispositionType, MailHeader, ParsedContentDisposition, ParsedMail};
+
+/// How many messages forwarded as attachments are parsed inside of each other before the rest are
+/// left as attachments, so a message can't nest them deep enough to overflow the stack
+const MAX_NESTED_MESSAGES: usize = 8;
+
+/// A message, with its headers and body decoded
+#[derive(Clone, Debug)]
+pub struct Message {
+    /// Every header in the order they appear, with encoded-words decoded
+    pub headers: Vec<(String, String)>,
+
+    /// The body of the message, which is a tree of parts if it's multipart
+    pub body: Part,
+}
+
+/// A single MIME part of a message
+#[derive(Clone, Debug)]
+pub struct Part {
+    /// The MIME type of the part, in lowercase (ex. `text/plain`)
+    pub mimetype: String,
+
+    /// The name of the file this part came from, if it has one
+    pub filename: Option<String>,
+
+    /// Whether the part is meant to be saved instead of shown along with the message
+    /// (`Content-Disposition: attachment`)
+    pub attached: bool,
+
+    /// What's in the part
+    pub content: PartContent,
+}
+
+/// The decoded contents of a part
+#[derive(Clone, Debug)]
+pub enum PartContent {
+    /// Text to be shown inline
+    Text(String),
+
+    /// HTML to be shown inline
+    Html(String),
+
+    /// A file that's attached to the message, or anything else that isn't text
+    Attachment(Vec<u8>),
+
+    /// A message that's forwarded as an attachment (`message/rfc822`)
+    Message(Box<Message>),
+
+    /// Parts that are put together in the way their MIME type says (ex. `multipart/alternative`
+    /// has the same content in different formats)
+    Multipart(Vec<Part>),
+
+    /// A part that couldn't be decoded, along with why
+    Broken(String),
+}
+
+impl Message {
+    /// Parses a message in RFC 5322 format
+    pub fn parse(raw: &[u8]) -> Result<Self> {
+        Message::parse_nested(raw, 0, true)
+    }
+
+    /// Parses a message without decoding its attachments, which are left empty. That's still
+    /// enough to tell whether it has any and to get its text, which is all that's needed to list
+    /// it.
+    pub fn parse_without_attachments(raw: &[u8]) -> Result<Self> {
+        Message::parse_nested(raw, 0, false)
+    }
+
+    fn parse_nested(raw: &[u8], depth: usize, attachments: bool) -> Result<Self> {
+        let mail = mailparse::parse_mail(raw)?;
+        Ok(Message {
+            headers: mail
+                .headers
+                .iter()
+                .map(|header| (header.get_key(), header.get_value()))
+                .collect(),
+            body: Part::from_mail(&mail, depth, attachments),
+        })
+    }
+
+    /// Gets the first value of a header, ignoring case
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(key, _)| key.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+    }
+
+    /// Every part that's attached to the message as a file, not counting the ones inside of
+    /// forwarded messages. Parts without a file name that are only there to be shown inline (ex.
+    /// images in an HTML message) aren't included.
+    pub fn attachments(&self) -> Vec<&Part> {
+        let mut attachments = Vec::new();
+        self.body.collect_attachments(&mut attachments);
+        attachments
+    }
+
+    /// The text of the message for reading, with HTML turned into plain text. Forwarded messages
+    /// are included after a few of their headers.
+    pub fn text(&self) -> String {
+        let mut sections = Vec::new();
+        for part in self.body.readable_parts() {
+            match &part.content {
+                PartContent::Text(text) => sections.push(text.trim_end().to_owned()),
+                PartContent::Html(html) => sections.push(html_to_text(html)),
+                PartContent::Message(message) => {
+                    let mut section = "---------- Forwarded message ----------\n".to_owned();
+                    for name in &["From", "Date", "Subject", "To"] {
+                        if let Some(value) = message.header(name) {
+                            section.push_str(&format!("{}: {}\n", name, value));
+                        }
+                    }
+                    section.push('\n');
+                    section.push_str(&message.text());
+                    sections.push(section);
+                }
+                _ => {}
+            }
+        }
+        sections.join("\n\n")
+    }
+}
+
+impl Part {
+    fn from_mail(part: &ParsedMail, depth: usize, attachments: bool) -> Self {
+        let mimetype = part.ctype.mimetype.to_ascii_lowercase();
+        let disposition = part.get_content_disposition();
+        let filename = get_filename(part, &disposition);
+        let attached = disposition.disposition == DispositionType::Attachment;
+
+        let content = if mimetype.starts_with("multipart/") || !part.subparts.is_empty() {
+            PartContent::Multipart(
+                part.subparts
+                    .iter()
+                    .map(|subpart| Part::from_mail(subpart, depth, attachments))
+                    .collect(),
+            )
+        } else {
+            let decoded = if mimetype == "message/rfc822" && depth < MAX_NESTED_MESSAGES {
+                part.get_body_raw()
+                    .map_err(Into::into)
+                    .and_then(|raw| Message::parse_nested(&raw, depth + 1, attachments))
+                    .map(|message| PartContent::Message(Box::new(message)))
+            } else if !attachments && (attached || !mimetype.starts_with("text/")) {
+                Ok(PartContent::Attachment(Vec::new()))
+            } else if attached || !mimetype.starts_with("text/") {
+                part.get_body_raw()
+                    .map(PartContent::Attachment)
+                    .map_err(Into::into)
+            } else if mimetype == "text/html" {
+                decode_text(part).map(PartContent::Html)
+            } else {
+                decode_text(part).map(PartContent::Text)
+            };
+            // one bad part (ex. base64 that got cut off) shouldn't keep the rest from being read
+            decoded.unwrap_or_else(|err| PartContent::Broken(err.to_string()))
+        };
+
+        Part {
+            mimetype,
+            filename,
+            attached,
+            content,
+        }
+    }
+
+    /// The parts that are shown when reading this one, in order. Only one version is picked out
+    /// of a `multipart/alternative`, which is the plain text one if there is one.
+    pub fn readable_parts(&self) -> Vec<&Part> {
+        let mut parts = Vec::new();
+        self.collect_readable(&mut parts);
+        parts
+    }
+
+    fn collect_readable<'a>(&'a self, parts: &mut Vec<&'a Part>) {
+        match &self.content {
+            PartContent::Text(_) | PartContent::Html(_) | PartContent::Message(_) => {
+                parts.push(self)
+            }
+            PartContent::Multipart(subparts) if self.mimetype == "multipart/alternative" => {
+                let picked = subparts
+                    .iter()
+                    .find(|part| matches!(part.content, PartContent::Text(_)))
+                    .or_else(|| {
+                        subparts
+                            .iter()
+                            .find(|part| matches!(part.content, PartContent::Html(_)))
+                    })
+                    // the last alternative is the one that's closest to what the sender wrote
+                    .or_else(|| subparts.last());
+                if let Some(part) = picked {
+                    part.collect_readable(parts);
+                }
+            }
+            PartContent::Multipart(subparts) => {
+                for part in subparts {
+                    part.collect_readable(parts);
+                }
+            }
+            PartContent::Attachment(_) | PartContent::Broken(_) => {}
+        }
+    }
+
+    fn collect_attachments<'a>(&'a self, attachments: &mut Vec<&'a Part>) {
+        match &self.content {
+            PartContent::Multipart(subparts) => {
+                for part in subparts {
+                    part.collect_attachments(attachments);
+                }
+            }
+            PartContent::Attachment(_) if self.filename.is_some() => attachments.push(self),
+            _ if self.attached => attachments.push(self),
+            _ => {}
+        }
+    }
+}
+
+/// Decodes a header value that might have RFC 2047 encoded-words in it, such as the ones that
+/// come back from the server in an ENVELOPE
+pub fn decode_header_value(value: &str) -> String {
+    let raw = format!("X: {}", value);
+    match mailparse::parse_header(raw.as_bytes()) {
+        Ok((header, _)) => header.get_value(),
+        Err(_) => value.to_owned(),
+    }
+}
+
+/// Decodes the text in a part from its transfer encoding and charset
+fn decode_text(part: &ParsedMail) -> Result<String> {
+    // parts that don't say what charset they're in are supposed to be ASCII, but a lot of mail
+    // that leaves it out is UTF-8, which would come out garbled if it were decoded as ASCII
+    if !part_has_charset(&part.headers) {
+        if let Ok(text) = String::from_utf8(part.get_body_raw()?) {
+            return Ok(text);
+        }
+    }
+    Ok(part.get_body()?)
+}
+
+/// Whether the Content-Type of a part says what charset it's in
+fn part_has_charset(headers: &[MailHeader]) -> bool {
+    headers.iter().any(|header| {
+        header.get_key_ref().eq_ignore_ascii_case("content-type")
+            && header.get_value().to_ascii_lowercase().contains("charset")
+    })
+}
+
+/// Gets the file name of a part from its Content-Disposition, or from its Content-Type for mail
+/// that only puts it there
+fn get_filename(part: &ParsedMail, disposition: &ParsedContentDisposition) -> Option<String> {
+    let params = [&disposition.params, &part.ctype.params];
+    for (params, name) in params.iter().zip(&["filename", "name"]) {
+        if let Some(filename) = params.get(*name) {
+            return Some(filename.clone());
+        }
+        if let Some(filename) = params.get(&format!("{}*", name)) {
+            return Some(decode_extended_param(filename));
+        }
+    }
+    None
+}
+
+/// Decodes a parameter value in the RFC 2231 format (ex. `UTF-8''%E2%82%AC%20rates.pdf`)
+fn decode_extended_param(value: &str) -> String {
+    let mut pieces = value.splitn(3, '\'');
+    let (charset, encoded) = match (pieces.next(), pieces.next(), pieces.next()) {
+        (Some(charset), Some(_language), Some(encoded)) => (charset, encoded),
+        _ => return value.to_owned(),
+    };
+
+    // this is the same percent-encoding as a Q encoded-word, except for what it uses as the escape
+    // character, so it's easiest to decode it as one
+    let mut word = String::new();
+    for c in encoded.chars() {
+        match c {
+            '%' => word.push('='),
+            '=' | '?' | '_' => word.push_str(&format!("={:02X}", c as u32)),
+            c if c.is_ascii() && !c.is_ascii_whitespace() => word.push(c),
+            _ => return value.to_owned(),
+        }
+    }
+    decode_header_value(&format!("=?{}?Q?{}?=", charset, word))
+}
+
+/// Turns HTML into plain text for reading in the terminal. This only goes as far as line breaks,
+/// list items, and entities, which is enough for most mail that doesn't come with a text version.
+pub fn html_to_text(html: &str) -> String {
+    let mut text = String::new();
+    let mut rest = html;
+    while let Some(start) = rest.find('<') {
+        push_html_text(&mut text, &rest[..start]);
+        let end = match rest[start..].find('>') {
+            Some(i) => start + i,
+            None => {
+                rest = "";
+                break;
+            }
+        };
+        let tag = rest[start + 1..end].trim().to_ascii_lowercase();
+        rest = &rest[end + 1..];
+
+        let closing = tag.starts_with('/');
+        let name = tag
+            .trim_start_matches('/')
+            .split(|c: char| c.is_ascii_whitespace() || c == '/')
+            .next()
+            .unwrap_or("");
+        match name {
+            // nothing in these is meant to be read
+            "head" | "script" | "style" | "title" if !closing => {
+                let close = format!("</{}", name);
+                rest = match rest.to_ascii_lowercase().find(&close) {
+                    Some(i) => match rest[i..].find('>') {
+                        Some(j) => &rest[i + j + 1..],
+                        None => "",
+                    },
+                    None => "",
+                };
+            }
+            "li" if !closing => text.push_str("\n- "),
+            "br" | "p" | "div" | "tr" | "ul" | "ol" | "table" | "blockquote" | "h1" | "h2"
+            | "h3" | "h4" | "h5" | "h6" | "hr" => text.push('\n'),
+            _ => {}
+        }
+    }
+    push_html_text(&mut text, rest);
+
+    // whitespace around the line breaks doesn't mean anything, and neither do runs of empty lines
+    let mut lines = Vec::new();
+    for line in text.lines().map(str::trim) {
+        if line.is_empty() && matches!(lines.last(), None | Some(&"")) {
+            continue;
+        }
+        lines.push(line);
+    }
+    while lines.last() == Some(&"") {
+        lines.pop();
+    }
+    lines.join("\n")
+}
+
+/// Adds text from between HTML tags, which only has whitespace where a space goes
+fn push_html_text(text: &mut String, html: &str) {
+    let mut words = html.split_ascii_whitespace().peekable();
+    if words.peek().is_some() && html.starts_with(|c: char| c.is_ascii_whitespace()) {
+        text.push(' ');
+    }
+    while let Some(word) = words.next() {
+        text.push_str(&decode_entities(word));
+        if words.peek().is_some() || html.ends_with(|c: char| c.is_ascii_whitespace()) {
+            text.push(' ');
+        }
+    }
+}
+
+/// Decodes the character references in HTML text (ex. `&amp;` or `&#8364;`)
+fn decode_entities(html: &str) -> String {
+    let mut text = String::with_capacity(html.len());
+    let mut rest = html;
+    while let Some(start) = rest.find('&') {
+        text.push_str(&rest[..start]);
+        rest = &rest[start..];
+
+        // anything that doesn't look like a reference is left as it is
+        let decoded = rest
+            .find(';')
+            .and_then(|end| decode_entity(&rest[1..end]).map(|c| (c, end)));
+        match decoded {
+            Some((c, end)) => {
+                text.push(c);
+                rest = &rest[end + 1..];
+            }
+            None => {
+                text.push('&');
+                rest = &rest[1..];
+            }
+        }
+    }
+    text.push_str(rest);
+    text
+}
+
+fn decode_entity(name: &str) -> Option<char> {
+    let c = match name {
+        "amp" => '&',
+        "lt" => '<',
+        "gt" => '>',
+        "quot" => '"',
+        "apos" => '\'',
+        "nbsp" => ' ',
+        "copy" => '©',
+        "reg" => '®',
+        "hellip" => '…',
+        "mdash" => '—',
+        "ndash" => '–',
+        "lsquo" => '‘',
+        "rsquo" => '’',
+        "ldquo" => '“',
+        "rdquo" => '”',
+        "euro" => '€',
+        _ => {
+            let code = if name.starts_with("#x") || name.starts_with("#X") {
+                u32::from_str_radix(&name[2..], 16).ok()?
+            } else if let Some(code) = name.strip_prefix('#') {
+                code.parse().ok()?
+            } else {
+                return None;
+            };
+            std::char::from_u32(code)?
+        }
+    };
+    Some(c)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_encoded_words() {
+        assert_eq!(decode_header_value("=?ISO-8859-1?Q?caf=E9?="), "café");
+        assert_eq!(
+            decode_header_value("=?UTF-8?B?w6l0w6k=?= plans"),
+            "été plans"
+        );
+        assert_eq!(
+            decode_header_value("=?utf-8?q?Gr=C3=BC=C3=9Fe?= =?utf-8?q?_aus_Berlin?="),
+            "Grüße aus Berlin"
+        );
+        assert_eq!(decode_header_value("plain text"), "plain text");
+    }
+
+    #[test]
+    fn test_charsets() {
+        let message = Message::parse(
+            b"Subject: =?windows-1252?Q?=93quoted=94?=\r\n\
+              Content-Type: text/plain; charset=iso-8859-1\r\n\
+              Content-Transfer-Encoding: quoted-printable\r\n\
+              \r\n\
+              caf=E9 cr=E8me\r\n",
+        )
+        .unwrap();
+        assert_eq!(message.header("subject"), Some("“quoted”"));
+        assert_eq!(message.text(), "café crème");
+
+        // no charset at all, but the body is UTF-8
+        let message = Message::parse("Subject: hi\r\n\r\nnaïve\r\n".as_bytes()).unwrap();
+        assert_eq!(message.text(), "naïve");
+
+        let message = Message::parse(
+            b"Content-Type: text/plain; charset=Shift_JIS\r\n\r\n\x82\xb1\x82\xf1\x82\xc9\x82\xbf\x82\xcd\r\n",
+        )
+        .unwrap();
+        assert_eq!(message.text(), "こんにちは");
+    }
+
+    #[test]
+    fn test_decode_extended_param() {
+        assert_eq!(
+            decode_extended_param("UTF-8''%E2%82%AC%20rates.pdf"),
+            "€ rates.pdf"
+        );
+        assert_eq!(
+            decode_extended_param("iso-8859-1'fr'%E9t%E9.txt"),
+            "été.txt"
+        );
+        assert_eq!(decode_extended_param("us-ascii''a_b=c?.txt"), "a_b=c?.txt");
+        // not in the RFC 2231 format at all
+        assert_eq!(decode_extended_param("report.pdf"), "report.pdf");
+    }
+
+    #[test]
+    fn test_attachment_filenames() {
+        let raw = b"Content-Type: multipart/mixed; boundary=b\r\n\
+                    \r\n\
+                    --b\r\n\
+                    Content-Type: text/plain\r\n\
+                    \r\n\
+                    see attached\r\n\
+                    --b\r\n\
+                    Content-Type: application/pdf\r\n\
+                    Content-Disposition: attachment; filename*=UTF-8''%E2%82%AC%20rates.pdf\r\n\
+                    Content-Transfer-Encoding: base64\r\n\
+                    \r\n\
+                    JVBERi0=\r\n\
+                    --b--\r\n";
+        let message = Message::parse(raw).unwrap();
+        let attachments = message.attachments();
+        assert_eq!(attachments.len(), 1);
+        assert_eq!(attachments[0].filename.as_deref(), Some("€ rates.pdf"));
+        assert!(
+            matches!(&attachments[0].content, PartContent::Attachment(data) if data == b"%PDF-")
+        );
+        assert_eq!(message.text(), "see attached");
+
+        // the same, but without the attachment being decoded
+        let message = Message::parse_without_attachments(raw).unwrap();
+        let attachments = message.attachments();
+        assert_eq!(attachments.len(), 1);
+        assert!(
+            matches!(&attachments[0].content, PartContent::Attachment(data) if data.is_empty())
+        );
+        assert_eq!(message.text(), "see attached");
+    }
+
+    #[test]
+    fn test_html_to_text() {
+        assert_eq!(
+            html_to_text(
+                "<html><head><title>x</title><style>p { color: red }</style></head>\
+                 <body><p>Hello   <b>there</b>,</p><ul><li>one</li><li>two</li></ul>\
+                 <script>alert(1)</script>bye<br>now</body></html>"
+            ),
+            "Hello there,\n\n- one\n- two\nbye\nnow"
+        );
+        assert_eq!(html_to_text("a &lt;b&gt; &amp; c"), "a <b> & c");
+        assert_eq!(html_to_text("unclosed <b"), "unclosed");
+    }
+
+    #[test]
+    fn test_decode_entities() {
+        assert_eq!(decode_entities("&amp;&lt;&gt;&quot;&apos;"), "&<>\"'");
+        assert_eq!(decode_entities("&#8364; &#x20AC; &#X20ac;"), "€ € €");
+        assert_eq!(
+            decode_entities("caf&eacute; &bogus; AT&T"),
+            "caf&eacute; &bogus; AT&T"
+        );
+        assert_eq!(decode_entities("&#xZZ; &#99999999;"), "&#xZZ; &#99999999;");
+    }
+}
@@ -3,12 +3,20 @@ use std::collections::HashSet;
 use chrono::{DateTime, Local};
 use panorama_imap::response::*;
 
+use super::message::decode_header_value;
+
 /// A record that describes the metadata of an email as it appears in the UI list
 #[derive(Clone, Debug, Default)]
 pub struct EmailMetadata {
     /// UID if the message has one
     pub uid: Option<u32>,
 
+    /// Folder the message is in
+    pub folder: String,
+
+    /// Every folder that has a copy of this message, including this one (ex. INBOX and Archive)
+    pub folders: Vec<String>,
+
     /// Whether or not this message is unread
     pub unread: bool,
 
@@ -20,6 +28,12 @@ pub struct EmailMetadata {
 
     /// Subject
     pub subject: String,
+
+    /// Whether the message has any attachments
+    pub has_attachment: bool,
+
+    /// The start of the message's text
+    pub snippet: String,
 }
 
 impl EmailMetadata {
@@ -47,21 +61,13 @@ impl EmailMetadata {
                     if let Some(new_from) = new_from {
                         meta.from = new_from
                             .iter()
-                            .filter_map(|addr| addr.name.to_owned())
+                            .filter_map(|addr| addr.name.as_deref().map(decode_header_value))
                             .collect::<Vec<_>>()
                             .join(", ");
                     }
                     if let Some(new_subject) = new_subject {
-                        // TODO: probably shouldn't insert quoted-printable here
-                        // but this is just the most convenient for it to look right at the moment
-                        // there's probably some header that indicates it's quoted-printable
-                        // MIME?
-                        use quoted_printable::ParseMode;
-                        let new_subject =
-                            quoted_printable::decode(new_subject.as_bytes(), ParseMode::Robust)
-                                .unwrap();
-                        let new_subject = String::from_utf8(new_subject).unwrap();
-                        meta.subject = new_subject;
+                        // the envelope has the subject exactly as it is in the header
+                        meta.subject = decode_header_value(&new_subject);
                     }
                 }
                 _ => {}
@@ -1,11 +1,22 @@
 //! Mail
 
 mod client;
+mod crypto;
 mod event;
+mod folder;
+mod local;
+mod maildir;
+mod mbox;
+mod message;
 mod metadata;
+mod operation;
+mod session;
 pub mod store;
+mod thread;
 
-use anyhow::Result;
+use std::collections::HashMap;
+
+use anyhow::{Error, Result};
 use futures::{
     future::FutureExt,
     stream::{Stream, StreamExt},
@@ -14,100 +25,270 @@ use notify_rust::{Notification, Timeout};
 use panorama_imap::{
     client::{
         auth::{self, Auth},
-        ClientBuilder, ClientConfig,
+        ClientBuilder, ClientConfig, SelectResponse,
     },
     command::Command as ImapCommand,
-    response::{AttributeValue, Envelope, MailboxData, Response},
+    response::{AttributeValue, Envelope, MailboxData, MailboxFlag, Response, ResponseDone},
 };
 use tokio::{
-    sync::mpsc::{UnboundedReceiver, UnboundedSender},
+    sync::{
+        mpsc::{UnboundedReceiver, UnboundedSender},
+        oneshot,
+    },
     task::JoinHandle,
 };
 use tokio_stream::wrappers::WatchStream;
 
-use crate::config::{Config, ConfigWatcher, ImapAuth, MailAccountConfig, TlsMethod};
+use crate::config::{Config, ConfigWatcher, ImapAuth, MailAccountConfig, MailSource, TlsMethod};
 
-pub use self::event::MailEvent;
+pub use self::event::{AccountState, MailEvent};
+pub use self::folder::{FolderMetadata, FolderRole};
+pub use self::message::{html_to_text, Message, Part, PartContent};
 pub use self::metadata::EmailMetadata;
+pub use self::operation::MailOperation;
+pub use self::session::{AccountHandle, ConnectionPool, PooledConnection};
 pub use self::store::MailStore;
+pub use self::thread::{ThreadNode, ThreadSummary};
+
+/// The other end of a command, where its result gets sent once it's done
+pub type Reply<T> = oneshot::Sender<Result<T>>;
 
 /// Command sent to the mail thread by something else (i.e. UI)
 #[derive(Debug)]
 #[non_exhaustive]
 pub enum MailCommand {
-    /// Refresh the list
+    /// Refresh every account
     Refresh,
 
     /// Send a raw command
-    Raw(ImapCommand),
+    Raw {
+        /// Account to send the command to
+        acct: String,
+
+        /// The command
+        command: ImapCommand,
+
+        /// Gets the final response from the server, along with any untagged data
+        reply: Reply<(Option<ResponseDone>, Vec<Response>)>,
+    },
+
+    /// Open a folder, syncing it right away instead of waiting for the next sweep
+    SelectFolder {
+        /// Account the folder is in
+        acct: String,
+
+        /// Name of the folder
+        folder: String,
+
+        /// Gets the message counts the server reported for the folder
+        reply: Reply<SelectResponse>,
+    },
+
+    /// Get the full contents of a message, downloading it if it isn't cached
+    FetchBody {
+        /// Account the message is in
+        acct: String,
+
+        /// Folder the message is in
+        folder: String,
+
+        /// UID of the message
+        uid: u32,
+
+        /// Gets the raw message
+        reply: Reply<Vec<u8>>,
+    },
+
+    /// Add or remove a flag on a message
+    SetFlag {
+        /// Account the message is in
+        acct: String,
+
+        /// Folder the message is in
+        folder: String,
+
+        /// UID of the message
+        uid: u32,
+
+        /// The flag
+        flag: MailboxFlag,
+
+        /// Whether the flag should be added or removed
+        added: bool,
+
+        /// Gets whether the change was saved locally. It's sent to the server in the background.
+        reply: Reply<()>,
+    },
+
+    /// Move a message to another folder
+    Move {
+        /// Account the message is in
+        acct: String,
+
+        /// Folder the message is in
+        folder: String,
+
+        /// UID of the message
+        uid: u32,
+
+        /// Folder to move the message to
+        to: String,
+
+        /// Gets whether the move was saved locally. It's sent to the server in the background.
+        reply: Reply<()>,
+    },
+
+    /// Permanently delete a message
+    Delete {
+        /// Account the message is in
+        acct: String,
+
+        /// Folder the message is in
+        folder: String,
+
+        /// UID of the message
+        uid: u32,
+
+        /// Gets whether the deletion was saved locally. It's sent to the server in the background.
+        reply: Reply<()>,
+    },
+}
+
+impl MailCommand {
+    /// The account this command should be sent to, or None if it's meant for all of them
+    pub fn acct_name(&self) -> Option<&str> {
+        use MailCommand::*;
+        match self {
+            Refresh => None,
+            Raw { acct, .. }
+            | SelectFolder { acct, .. }
+            | FetchBody { acct, .. }
+            | SetFlag { acct, .. }
+            | Move { acct, .. }
+            | Delete { acct, .. } => Some(acct),
+        }
+    }
+
+    /// Answers the command with an error without running it
+    pub fn reject(self, err: Error) {
+        use MailCommand::*;
+        match self {
+            Refresh => {}
+            Raw { reply, .. } => {
+                let _ = reply.send(Err(err));
+            }
+            SelectFolder { reply, .. } => {
+                let _ = reply.send(Err(err));
+            }
+            FetchBody { reply, .. } => {
+                let _ = reply.send(Err(err));
+            }
+            SetFlag { reply, .. } | Move { reply, .. } | Delete { reply, .. } => {
+                let _ = reply.send(Err(err));
+            }
+        }
+    }
 }
 
 /// Main entrypoint for the mail listener.
 pub async fn run_mail(
     mail_store: MailStore,
     mut config_watcher: ConfigWatcher,
-    ui2mail_rx: UnboundedReceiver<MailCommand>,
+    mut ui2mail_rx: UnboundedReceiver<MailCommand>,
     mail2ui_tx: UnboundedSender<MailEvent>,
 ) -> Result<()> {
-    let mut curr_conn: Vec<JoinHandle<_>> = Vec::new();
+    let mut curr_conn: HashMap<String, (MailAccountConfig, AccountHandle)> = HashMap::new();
 
     // let mut config_watcher = WatchStream::new(config_watcher);
     loop {
         debug!("listening for configs");
-        let config: Config = match config_watcher.changed().await {
-            Ok(_) => config_watcher.borrow().clone(),
-            _ => break,
+        let config: Config = tokio::select! {
+            changed = config_watcher.changed() => match changed {
+                Ok(_) => config_watcher.borrow().clone(),
+                _ => break,
+            },
+            cmd = ui2mail_rx.recv() => {
+                match cmd {
+                    Some(cmd) => dispatch_command(&curr_conn, cmd),
+                    None => break,
+                }
+                continue;
+            }
         };
         debug!("got");
 
+        // shut down the accounts that were removed or changed, leaving everything else connected
         // TODO: gracefully shut down connection
-        // just gonna drop the connection for now
-        // FUTURE TODO: possible to hash the connections and only reconn the ones that changed
-        debug!("dropping all connections...");
-        for conn in curr_conn.drain(0..) {
-            conn.abort();
-        }
+        curr_conn.retain(|acct_name, (acct, handle)| {
+            let keep = config.mail_accounts.get(acct_name) == Some(acct);
+            if !keep {
+                debug!("dropping connection to {}", acct_name);
+                handle.abort();
+            }
+            keep
+        });
 
         for (acct_name, acct) in config.mail_accounts.clone().into_iter() {
-            let mail2ui_tx = mail2ui_tx.clone();
-            let mail_store = mail_store.clone();
-            let config2 = config.clone();
-            let handle = tokio::spawn(async move {
-                // debug!("opening imap connection for {:?}", acct);
-
-                // this loop is to make sure accounts are restarted on error
-                loop {
-                    match client::sync_main(
-                        config2.clone(),
-                        &acct_name,
-                        acct.clone(),
-                        mail2ui_tx.clone(),
-                        mail_store.clone(),
-                    )
-                    .await
-                    {
-                        Ok(_) => {}
-                        Err(err) => {
-                            error!("error from sync_main: {}", err);
-                            for err in err.chain() {
-                                error!("cause: {}", err);
-                            }
-                        }
-                    }
-
-                    warn!("connection dropped, retrying");
-
-                    // wait a bit so we're not hitting the server really fast if the fail happens
-                    // early on
-                    //
-                    // TODO: some kind of smart exponential backoff that considers some time
-                    // threshold to be a failing case?
-                    tokio::time::sleep(std::time::Duration::from_secs(5)).await;
-                }
-            });
-            curr_conn.push(handle);
+            if curr_conn.contains_key(&acct_name) {
+                continue;
+            }
+
+            debug!("connecting to {}", acct_name);
+            let handle = AccountHandle::spawn(
+                acct_name.clone(),
+                acct.clone(),
+                mail2ui_tx.clone(),
+                mail_store.clone(),
+            );
+            curr_conn.insert(acct_name, (acct, handle));
         }
     }
 
     Ok(())
 }
+
+/// Connects to an account just long enough to apply the operations waiting in its journal (ex.
+/// messages that were imported with uploading turned on), for when the mail thread isn't running
+pub async fn upload_operations(
+    mail_store: &MailStore,
+    acct_name: &str,
+    acct: &MailAccountConfig,
+) -> Result<()> {
+    match &acct.source {
+        MailSource::Imap(imap) => {
+            let mut authed = client::connect(imap).await?;
+            client::replay_operations(&mut authed, mail_store, acct_name).await?;
+        }
+        MailSource::Maildir(_) | MailSource::Mbox(_) => {
+            local::replay_operations(mail_store, acct_name, &acct.source).await?;
+        }
+    }
+    Ok(())
+}
+
+/// Sends a command to the account it's meant for
+fn dispatch_command(
+    accounts: &HashMap<String, (MailAccountConfig, AccountHandle)>,
+    cmd: MailCommand,
+) {
+    debug!("dispatching command: {:?}", cmd);
+    let acct_name = match cmd.acct_name() {
+        Some(v) => v.to_owned(),
+        // only refreshing is meant for every account
+        None => {
+            for (_, handle) in accounts.values() {
+                let _ = handle.send_command(MailCommand::Refresh);
+            }
+            return;
+        }
+    };
+
+    match accounts.get(&acct_name) {
+        Some((_, handle)) => {
+            if let Err(cmd) = handle.send_command(cmd) {
+                (*cmd).reject(anyhow!("{} isn't connected", acct_name));
+            }
+        }
+        None => cmd.reject(anyhow!("no account named {:?}", acct_name)),
+    }
+}
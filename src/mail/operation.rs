@@ -0,0 +1,136 @@
+/// A change made locally that still needs to be applied on the server
+///
+/// Operations are saved to the journal in the database as soon as they're made, and then replayed
+/// in order by the mail thread whenever it has a connection. Replaying an operation more than
+/// once must be harmless, since the connection might drop after the server has applied it but
+/// before it's been removed from the journal.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub enum MailOperation {
+    /// Add or remove a flag on a message
+    SetFlag {
+        /// Folder the message is in
+        folder: String,
+
+        /// UIDVALIDITY of the folder when the change was made
+        uidvalidity: u32,
+
+        /// UID of the message
+        uid: u32,
+
+        /// The flag, as it's written in IMAP (ex. `\Seen`)
+        flag: String,
+
+        /// Whether the flag was added or removed
+        added: bool,
+    },
+
+    /// Move a message to another folder
+    Move {
+        /// Folder the message is in
+        folder: String,
+
+        /// UIDVALIDITY of the folder when the change was made
+        uidvalidity: u32,
+
+        /// UID of the message
+        uid: u32,
+
+        /// Folder to move the message to
+        to: String,
+    },
+
+    /// Permanently delete a message
+    Delete {
+        /// Folder the message is in
+        folder: String,
+
+        /// UIDVALIDITY of the folder when the change was made
+        uidvalidity: u32,
+
+        /// UID of the message
+        uid: u32,
+    },
+
+    /// Permanently remove every message in a folder that's marked `\Deleted`, for servers that
+    /// can't remove a single message on its own
+    Expunge {
+        /// Name of the folder
+        folder: String,
+    },
+
+    /// Upload a message (ex. a draft) to a folder
+    Append {
+        /// Folder to upload the message to
+        folder: String,
+
+        /// Name of the file in the mail directory that holds the message
+        filename: String,
+
+        /// Message-ID of the message, used to check whether it has already been uploaded
+        message_id: Option<String>,
+
+        /// Flags to set on the uploaded message
+        flags: Vec<String>,
+    },
+
+    /// Create a folder
+    CreateFolder {
+        /// Name of the folder
+        name: String,
+    },
+
+    /// Delete a folder
+    DeleteFolder {
+        /// Name of the folder
+        name: String,
+    },
+
+    /// Rename a folder
+    RenameFolder {
+        /// Current name of the folder
+        from: String,
+
+        /// New name of the folder
+        to: String,
+    },
+}
+
+impl MailOperation {
+    /// A short name for the kind of operation, stored alongside it in the journal
+    pub fn kind(&self) -> &'static str {
+        match self {
+            MailOperation::SetFlag { .. } => "set_flag",
+            MailOperation::Move { .. } => "move",
+            MailOperation::Delete { .. } => "delete",
+            MailOperation::Expunge { .. } => "expunge",
+            MailOperation::Append { .. } => "append",
+            MailOperation::CreateFolder { .. } => "create_folder",
+            MailOperation::DeleteFolder { .. } => "delete_folder",
+            MailOperation::RenameFolder { .. } => "rename_folder",
+        }
+    }
+
+    /// The message that this operation applies to, if any, as (folder, uidvalidity, uid)
+    pub fn message(&self) -> Option<(&str, u32, u32)> {
+        match self {
+            MailOperation::SetFlag {
+                folder,
+                uidvalidity,
+                uid,
+                ..
+            }
+            | MailOperation::Move {
+                folder,
+                uidvalidity,
+                uid,
+                ..
+            }
+            | MailOperation::Delete {
+                folder,
+                uidvalidity,
+                uid,
+            } => Some((folder, *uidvalidity, *uid)),
+            _ => None,
+        }
+    }
+}
@@ -0,0 +1,467 @@
+//! Managing the connections to a single account's server
+
+use std::cmp;
+use std::ops::{Deref, DerefMut};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use anyhow::{Context, Result};
+use futures::{future, stream::StreamExt};
+use panorama_imap::{
+    client::{auth::AuthError, ClientAuthenticated, SelectResponse},
+    command::{Command as ImapCommand, FetchItems},
+    response::{Response, ResponseDone},
+};
+use tokio::{
+    sync::{
+        mpsc::{self, UnboundedReceiver, UnboundedSender},
+        OwnedSemaphorePermit, Semaphore,
+    },
+    task::JoinHandle,
+};
+
+use crate::config::{ImapConfig, MailAccountConfig, MailSource};
+
+use super::{client, local, AccountState, MailCommand, MailEvent, MailStore};
+
+/// How often every folder in the account gets synced. The INBOX is also synced whenever IDLE
+/// reports that something changed.
+const SYNC_INTERVAL: Duration = Duration::from_secs(5 * 60);
+
+/// How long to wait before the first reconnect attempt. This doubles after every failure in a row,
+/// up to [MAX_BACKOFF][self::MAX_BACKOFF].
+const MIN_BACKOFF: Duration = Duration::from_secs(1);
+
+/// The longest to ever wait before trying to reconnect
+const MAX_BACKOFF: Duration = Duration::from_secs(5 * 60);
+
+/// A session that stayed up at least this long is counted as having worked, so the backoff starts
+/// over the next time it fails
+const HEALTHY_SESSION: Duration = Duration::from_secs(60);
+
+/// Something that the sync loop needs to bring up to date
+#[derive(Debug)]
+pub enum SyncRequest {
+    /// Every folder in the account
+    All,
+
+    /// A single folder
+    Folder(String),
+
+    /// Just the operations in the journal, which need to be sent to the server
+    Operations,
+}
+
+/// A pool of connections to a single account's server
+///
+/// This struct is clone-safe: cloning it will just return a reference to the same pool
+#[derive(Clone)]
+pub struct ConnectionPool {
+    inner: Arc<PoolInner>,
+}
+
+struct PoolInner {
+    imap: ImapConfig,
+    idle: Mutex<Vec<ClientAuthenticated>>,
+    permits: Arc<Semaphore>,
+}
+
+impl ConnectionPool {
+    /// Creates a pool that keeps at most `size` connections open at once. Connections are only
+    /// opened once they're needed.
+    pub fn new(imap: ImapConfig, size: usize) -> Self {
+        ConnectionPool {
+            inner: Arc::new(PoolInner {
+                imap,
+                idle: Mutex::new(Vec::new()),
+                permits: Arc::new(Semaphore::new(size)),
+            }),
+        }
+    }
+
+    /// Takes a connection out of the pool, opening a new one if none are free. If the pool is
+    /// already at its limit, this waits for another connection to be released.
+    pub async fn get(&self) -> Result<PooledConnection> {
+        let permit = self.inner.permits.clone().acquire_owned().await?;
+        let client = self.inner.idle.lock().unwrap().pop();
+        let client = match client {
+            Some(v) => v,
+            None => client::connect(&self.inner.imap).await?,
+        };
+
+        Ok(PooledConnection {
+            client,
+            pool: self.inner.clone(),
+            _permit: permit,
+        })
+    }
+}
+
+/// A connection that was taken out of a [ConnectionPool][self::ConnectionPool]
+///
+/// Once done with it, [release][Self::release] puts it back in the pool. Dropping it instead closes
+/// the connection, which is what should happen if a command failed partway through and it isn't
+/// clear what state the connection is in anymore.
+pub struct PooledConnection {
+    client: ClientAuthenticated,
+    pool: Arc<PoolInner>,
+    _permit: OwnedSemaphorePermit,
+}
+
+impl PooledConnection {
+    /// Puts the connection back in the pool so it can be reused
+    pub fn release(self) {
+        let PooledConnection { client, pool, .. } = self;
+        pool.idle.lock().unwrap().push(client);
+    }
+}
+
+impl Deref for PooledConnection {
+    type Target = ClientAuthenticated;
+    fn deref(&self) -> &Self::Target {
+        &self.client
+    }
+}
+
+impl DerefMut for PooledConnection {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        &mut self.client
+    }
+}
+
+/// Handle to an account session running in the background
+pub struct AccountHandle {
+    commands: UnboundedSender<MailCommand>,
+    handle: JoinHandle<()>,
+}
+
+impl AccountHandle {
+    /// Starts a session for the given account, which keeps running (and reconnecting if it has to)
+    /// until it's aborted
+    pub fn spawn(
+        acct_name: String,
+        acct: MailAccountConfig,
+        mail2ui_tx: UnboundedSender<MailEvent>,
+        mail_store: MailStore,
+    ) -> Self {
+        let (commands, mut commands_rx) = mpsc::unbounded_channel();
+        let handle = tokio::spawn(async move {
+            let send_state = |state| {
+                let _ = mail2ui_tx.send(MailEvent::AccountState(acct_name.clone(), state));
+            };
+            let mut backoff = Backoff::default();
+
+            // this loop is to make sure accounts are restarted on error
+            loop {
+                send_state(AccountState::Connecting);
+                let started = Instant::now();
+                let result = match &acct.source {
+                    MailSource::Imap(imap) => {
+                        run_session(
+                            &acct_name,
+                            &acct,
+                            imap,
+                            &mail2ui_tx,
+                            &mail_store,
+                            &mut commands_rx,
+                        )
+                        .await
+                    }
+                    MailSource::Maildir(_) | MailSource::Mbox(_) => {
+                        local::run_local_session(
+                            &acct_name,
+                            &acct,
+                            &mail2ui_tx,
+                            &mail_store,
+                            &mut commands_rx,
+                        )
+                        .await
+                    }
+                };
+                match result {
+                    Ok(_) => {}
+                    Err(err) => {
+                        // trying the same credentials again isn't going to help, so wait for the
+                        // user to fix them
+                        if err.chain().any(|err| err.is::<AuthError>()) {
+                            error!("login to {} failed, not retrying: {}", acct_name, err);
+                            send_state(AccountState::AuthFailed);
+                            return;
+                        }
+
+                        error!("error from account {}: {}", acct_name, err);
+                        for err in err.chain() {
+                            error!("cause: {}", err);
+                        }
+                    }
+                }
+
+                if started.elapsed() >= HEALTHY_SESSION {
+                    backoff.reset();
+                }
+                let delay = backoff.next_delay();
+                warn!(
+                    "connection to {} dropped, retrying in {:?}",
+                    acct_name, delay
+                );
+                send_state(AccountState::Offline);
+                tokio::time::sleep(delay).await;
+            }
+        });
+
+        AccountHandle { commands, handle }
+    }
+
+    /// Sends a command to be run on one of the account's connections. If the session has stopped
+    /// (ex. because logging in failed), the command is given back.
+    pub fn send_command(&self, cmd: MailCommand) -> Result<(), Box<MailCommand>> {
+        self.commands.send(cmd).map_err(|err| Box::new(err.0))
+    }
+
+    /// Stops the session, dropping all of its connections
+    pub fn abort(&self) {
+        self.handle.abort();
+    }
+}
+
+/// Keeps a single account in sync with its server
+///
+/// If the account is allowed more than one connection, one of them is set aside to IDLE on the
+/// INBOX so new mail is picked up right away. The rest are pooled, and shared between folder syncs
+/// and commands from the UI.
+async fn run_session(
+    acct_name: &str,
+    acct: &MailAccountConfig,
+    imap: &ImapConfig,
+    mail2ui_tx: &UnboundedSender<MailEvent>,
+    mail_store: &MailStore,
+    commands: &mut UnboundedReceiver<MailCommand>,
+) -> Result<()> {
+    let use_idle = imap.max_connections > 1;
+    let pool_size = if use_idle {
+        imap.max_connections - 1
+    } else {
+        1
+    };
+    // if the account was pointed at a different server, nothing that's cached is any good anymore
+    mail_store
+        .check_account_checksum(acct_name, acct.checksum())
+        .await?;
+
+    let pool = ConnectionPool::new(imap.clone(), pool_size);
+    let (sync_tx, sync_rx) = mpsc::unbounded_channel();
+
+    let idle = async {
+        if use_idle {
+            let authed = client::connect(imap).await?;
+            client::idle_main(authed, &sync_tx).await
+        } else {
+            // without a connection to spare, the INBOX only gets checked along with everything
+            // else
+            future::pending::<Result<()>>().await
+        }
+    };
+    let sync = sync_loop(&pool, acct_name, mail2ui_tx, mail_store, sync_rx);
+    let commands = command_loop(&pool, acct_name, mail_store, &sync_tx, commands);
+
+    tokio::try_join!(idle, sync, commands)?;
+    Ok(())
+}
+
+async fn sync_loop(
+    pool: &ConnectionPool,
+    acct_name: &str,
+    mail2ui_tx: &UnboundedSender<MailEvent>,
+    mail_store: &MailStore,
+    mut sync_rx: UnboundedReceiver<SyncRequest>,
+) -> Result<()> {
+    // the first tick happens right away, which takes care of the initial sync
+    let mut interval = tokio::time::interval(SYNC_INTERVAL);
+
+    loop {
+        let req = tokio::select! {
+            _ = interval.tick() => SyncRequest::All,
+            req = sync_rx.recv() => req.unwrap_or(SyncRequest::All),
+        };
+
+        let mut conn = pool.get().await?;
+        match &req {
+            SyncRequest::All => {
+                client::sync_all(&mut conn, acct_name, mail2ui_tx, mail_store).await?
+            }
+            SyncRequest::Folder(folder) => {
+                let _ = mail2ui_tx.send(MailEvent::AccountState(
+                    acct_name.to_owned(),
+                    AccountState::Syncing(0, 1),
+                ));
+                client::sync_folder(&mut conn, acct_name, mail_store, folder).await?
+            }
+            SyncRequest::Operations => {
+                client::replay_operations(&mut conn, mail_store, acct_name).await?
+            }
+        }
+        conn.release();
+
+        let _ = mail2ui_tx.send(MailEvent::AccountState(
+            acct_name.to_owned(),
+            AccountState::Idle,
+        ));
+    }
+}
+
+/// Runs commands from the UI as they come in. A command that fails just sends back the error, it
+/// doesn't bring down the whole session.
+async fn command_loop(
+    pool: &ConnectionPool,
+    acct_name: &str,
+    mail_store: &MailStore,
+    sync_tx: &UnboundedSender<SyncRequest>,
+    commands: &mut UnboundedReceiver<MailCommand>,
+) -> Result<()> {
+    while let Some(cmd) = commands.recv().await {
+        debug!("command for {}: {:?}", acct_name, cmd);
+        match cmd {
+            MailCommand::Refresh => {
+                let _ = sync_tx.send(SyncRequest::All);
+            }
+            MailCommand::Raw { command, reply, .. } => {
+                let _ = reply.send(run_raw(pool, command).await);
+            }
+            MailCommand::SelectFolder { folder, reply, .. } => {
+                let result = select_folder(pool, &folder).await;
+                if result.is_ok() {
+                    let _ = sync_tx.send(SyncRequest::Folder(folder));
+                }
+                let _ = reply.send(result);
+            }
+            MailCommand::FetchBody {
+                folder, uid, reply, ..
+            } => {
+                let result = fetch_body(pool, acct_name, mail_store, &folder, uid).await;
+                let _ = reply.send(result);
+            }
+
+            // these are applied locally right away, and then sent to the server through the journal
+            MailCommand::SetFlag {
+                folder,
+                uid,
+                flag,
+                added,
+                reply,
+                ..
+            } => {
+                let result = mail_store
+                    .set_flag(acct_name, &folder, uid, flag, added)
+                    .await;
+                let _ = sync_tx.send(SyncRequest::Operations);
+                let _ = reply.send(result);
+            }
+            MailCommand::Move {
+                folder,
+                uid,
+                to,
+                reply,
+                ..
+            } => {
+                let result = mail_store.move_message(acct_name, &folder, uid, &to).await;
+                let _ = sync_tx.send(SyncRequest::Operations);
+                let _ = reply.send(result);
+            }
+            MailCommand::Delete {
+                folder, uid, reply, ..
+            } => {
+                let result = mail_store.delete_message(acct_name, &folder, uid).await;
+                let _ = sync_tx.send(SyncRequest::Operations);
+                let _ = reply.send(result);
+            }
+        }
+    }
+
+    Ok(())
+}
+
+async fn run_raw(
+    pool: &ConnectionPool,
+    command: ImapCommand,
+) -> Result<(Option<ResponseDone>, Vec<Response>)> {
+    let mut conn = pool.get().await?;
+    let result = conn.run(command).await?;
+    conn.release();
+    Ok(result)
+}
+
+async fn select_folder(pool: &ConnectionPool, folder: &str) -> Result<SelectResponse> {
+    let mut conn = pool.get().await?;
+    let select = conn.select(folder).await?;
+    conn.release();
+    Ok(select)
+}
+
+/// Reads a message from the cache, downloading it first if it isn't there yet
+async fn fetch_body(
+    pool: &ConnectionPool,
+    acct_name: &str,
+    mail_store: &MailStore,
+    folder: &str,
+    uid: u32,
+) -> Result<Vec<u8>> {
+    if let Some(filename) = mail_store.get_filename(acct_name, folder, uid).await? {
+        return mail_store.read_mail_file(filename).await;
+    }
+
+    let mut conn = pool.get().await?;
+    let select = conn.select(folder).await?;
+    let uidvalidity = select
+        .uid_validity
+        .context("server didn't send a UIDVALIDITY")?;
+    let fetched = conn
+        .uid_fetch(&[uid], FetchItems::PanoramaAll)
+        .await?
+        .collect::<Vec<_>>()
+        .await;
+    conn.release();
+
+    for (_, attrs) in fetched {
+        mail_store
+            .store_email(acct_name, folder, uid, uidvalidity, attrs)
+            .await?;
+    }
+    mail_store.publish_counts(acct_name, folder).await?;
+
+    match mail_store.get_filename(acct_name, folder, uid).await? {
+        Some(filename) => mail_store.read_mail_file(filename).await,
+        None => bail!("no message with uid {} in {}", uid, folder),
+    }
+}
+
+/// Capped exponential backoff with jitter, for spacing out reconnect attempts
+#[derive(Debug)]
+struct Backoff {
+    current: Duration,
+}
+
+impl Default for Backoff {
+    fn default() -> Self {
+        Backoff {
+            current: MIN_BACKOFF,
+        }
+    }
+}
+
+impl Backoff {
+    /// Gets how long to wait before the next attempt, and doubles the delay for the one after
+    fn next_delay(&mut self) -> Duration {
+        let delay = self.current;
+        self.current = cmp::min(self.current * 2, MAX_BACKOFF);
+
+        // somewhere between half and all of the delay, so that accounts on the same server that
+        // dropped at the same time don't all come back at once either
+        let half = delay.as_millis() as u64 / 2;
+        Duration::from_millis(half + fastrand::u64(0..=half))
+    }
+
+    /// Starts the delay over from the beginning
+    fn reset(&mut self) {
+        self.current = MIN_BACKOFF;
+    }
+}
@@ -1,33 +1,52 @@
 //! Module for managing the offline storage of emails
 
-use std::collections::HashMap;
+use std::cmp;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::io;
 use std::mem;
 use std::path::{Path, PathBuf};
 use std::sync::Arc;
+use std::time::Duration;
 
 use anyhow::{Context, Error, Result};
-use chrono::{DateTime, Local};
+use chrono::{DateTime, FixedOffset, Local, TimeZone, Utc};
 use futures::{
     future::{self, FutureExt, TryFutureExt},
     stream::{StreamExt, TryStreamExt},
 };
 use indexmap::IndexMap;
-use panorama_imap::response::AttributeValue;
+use mailparse::MailAddr;
+use panorama_imap::response::{
+    AttributeValue, MailboxFlag, MessageSection, Quota, QuotaResourceName, SectionPath,
+};
 use sha2::{Digest, Sha256};
 use sqlx::{
-    migrate::{MigrateDatabase, Migrator},
-    sqlite::{Sqlite, SqlitePool},
-    Error as SqlxError, Row,
+    migrate::Migrator,
+    sqlite::{
+        Sqlite, SqliteConnectOptions, SqliteJournalMode, SqlitePool, SqlitePoolOptions,
+        SqliteSynchronous,
+    },
+    Error as SqlxError, Executor, Row, Transaction,
 };
 use tokio::{
     fs,
-    sync::{broadcast, watch, RwLock},
-    task::JoinHandle,
+    io::{AsyncWriteExt, BufReader, BufWriter},
+    sync::{
+        broadcast::{self, error::RecvError},
+        Mutex, RwLock,
+    },
+    task::{self, JoinHandle},
 };
 
-use crate::config::{Config, ConfigWatcher};
+use crate::config::{CachePolicy, Config, ConfigWatcher, EncryptionConfig, MailSource};
 
-use super::{EmailMetadata, MailEvent};
+use super::crypto::{self, Cipher};
+use super::local::LOCAL_UIDVALIDITY;
+use super::maildir::{self, MaildirWriter};
+use super::mbox::{self, MboxReader};
+use super::message::Message;
+use super::thread::{self, ThreadMessage, ThreadNode, ThreadSummary};
+use super::{AccountState, EmailMetadata, FolderMetadata, FolderRole, MailEvent, MailOperation};
 
 static MIGRATOR: Migrator = sqlx::migrate!();
 
@@ -39,26 +58,133 @@ pub struct MailStore {
     config: Arc<RwLock<Option<Config>>>,
     inner: Arc<RwLock<Option<MailStoreInner>>>,
     handle: Arc<JoinHandle<()>>,
-    store_out_tx: Arc<watch::Sender<Option<MailStoreUpdate>>>,
 
-    /// A receiver for listening to updates to the mail store
-    pub store_out_rx: watch::Receiver<Option<MailStoreUpdate>>,
+    /// Why the store couldn't be set up from the last config, if it couldn't
+    setup_error: Arc<RwLock<Option<String>>>,
+
+    store_out_tx: broadcast::Sender<MailStoreUpdate>,
 }
 
 #[derive(Debug)]
 /// This is associated with a particular config. When the config is updated, this gets replaced
 struct MailStoreInner {
     pool: SqlitePool,
+    db_path: PathBuf,
     mail_dir: PathBuf,
     accounts: IndexMap<String, Arc<AccountRef>>,
+
+    /// The keys for the mail directory and the database, if the store is encrypted
+    cipher: Option<Arc<Cipher>>,
+
+    /// Held while files in the mail directory are being removed, so that a message that's being
+    /// stored at the same time can't lose its file
+    files_lock: Mutex<()>,
 }
 
+/// How many updates can pile up for a subscriber before it starts missing them
+const STORE_UPDATE_CAPACITY: usize = 1024;
+
+/// How often message bodies are evicted from the cache to keep accounts within their limits
+const COMPACT_INTERVAL: Duration = Duration::from_secs(60 * 60);
+
+/// How long to wait for another connection (ex. panorama running a command at the same time) to
+/// finish writing to the database before giving up
+const DB_BUSY_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// How many of the copies of the database made before migrating it are kept around
+const DB_BACKUPS_KEPT: usize = 3;
+
 #[derive(Clone, Debug)]
 #[non_exhaustive]
-/// Probably an event about new emails? i forgot
+/// A change to the contents of the mail store, for anything showing them to keep up with
 pub enum MailStoreUpdate {
     /// The list of accounts has been updated (probably as a result of a config update)
     AccountListUpdate(()),
+
+    /// A message was added to a folder. Messages that were moved or appended locally don't have
+    /// a UID yet, and show up with a UID of 0.
+    MessageAdded {
+        /// Account the message is in
+        acct: String,
+
+        /// Folder the message is in
+        folder: String,
+
+        /// UID of the message
+        uid: u32,
+    },
+
+    /// The flags on a message changed
+    MessageUpdated {
+        /// Account the message is in
+        acct: String,
+
+        /// Folder the message is in
+        folder: String,
+
+        /// UID of the message
+        uid: u32,
+    },
+
+    /// A message was removed from a folder
+    MessageRemoved {
+        /// Account the message was in
+        acct: String,
+
+        /// Folder the message was in
+        folder: String,
+
+        /// UID of the message
+        uid: u32,
+    },
+
+    /// Too much changed in a folder to go message by message (ex. its UIDVALIDITY was reset), so
+    /// anything showing it should load it again
+    FolderChanged {
+        /// Account the folder is in
+        acct: String,
+
+        /// Name of the folder
+        folder: String,
+    },
+
+    /// The number of messages in a folder changed
+    FolderCounts {
+        /// Account the folder is in
+        acct: String,
+
+        /// Name of the folder
+        folder: String,
+
+        /// Number of messages in the folder
+        total: u32,
+
+        /// Number of messages that don't have the \Seen flag
+        unread: u32,
+    },
+
+    /// The store couldn't be set up from the config (ex. the database couldn't be opened), and
+    /// stays the way it was until the config changes again
+    SetupFailed(String),
+}
+
+/// What [`MailStore::check_database`] found, and fixed where it could
+#[derive(Debug, Default)]
+pub struct DatabaseCheck {
+    /// Problems that SQLite found in the database file itself, which can only be fixed by
+    /// rebuilding it. Nothing else is checked if there are any.
+    pub errors: Vec<String>,
+
+    /// Message bodies whose reference counts were wrong, and were counted again
+    pub recounted: u64,
+
+    /// Message bodies whose files were missing from the mail directory, which were marked as
+    /// evicted so that they get downloaded again when they're opened
+    pub missing: usize,
+
+    /// Files in the mail directory that nothing refers to. These are left alone, since they might
+    /// still be needed after a rebuild.
+    pub unreferenced: usize,
 }
 
 impl MailStore {
@@ -68,13 +194,21 @@ impl MailStore {
         let config2 = config.clone();
         let inner = Arc::new(RwLock::new(None));
         let inner2 = inner.clone();
+        let setup_error = Arc::new(RwLock::new(None));
+        let setup_error2 = setup_error.clone();
 
-        let (store_out_tx, store_out_rx) = watch::channel(None);
-        let store_out_tx = Arc::new(store_out_tx);
+        let (store_out_tx, _) = broadcast::channel(STORE_UPDATE_CAPACITY);
         let store_out_tx2 = store_out_tx.clone();
 
         let handle = tokio::spawn(async move {
-            match mail_store_config_listener(config_watcher, config2, inner2, store_out_tx2).await {
+            let listener = mail_store_config_listener(
+                config_watcher,
+                config2,
+                inner2,
+                setup_error2,
+                store_out_tx2,
+            );
+            match listener.await {
                 Ok(_) => {}
                 Err(e) => {
                     error!("mail store listener error: {}", e);
@@ -86,331 +220,3916 @@ impl MailStore {
             config,
             inner,
             handle: Arc::new(handle),
+            setup_error,
             store_out_tx,
-            store_out_rx,
         }
     }
 
-    /// Nuke all messages with an invalid UIDVALIDITY
-    pub async fn nuke_old_uidvalidity(&self, current: usize) {}
+    /// Starts evicting message bodies every so often to keep accounts within their
+    /// [`CachePolicy`], for as long as the store is around. This is only meant for when panorama
+    /// keeps running, not for one-off commands.
+    pub fn start_compactor(&self) {
+        tokio::spawn(run_compactor(self.clone()));
+    }
 
-    /// Given a UID and optional message-id try to identify a particular message
-    pub async fn try_identify_email(
+    /// Starts listening for updates to the mail store. Only updates that happen after this is
+    /// called are received.
+    pub fn subscribe(&self) -> broadcast::Receiver<MailStoreUpdate> {
+        self.store_out_tx.subscribe()
+    }
+
+    /// Waits until the store has been set up from the config, for anything that needs to use it
+    /// right after starting up. This fails if the store couldn't be set up.
+    pub async fn wait_until_ready(&self) -> Result<()> {
+        let mut updates = self.subscribe();
+        loop {
+            if self.inner.read().await.is_some() {
+                return Ok(());
+            }
+            if let Some(err) = &*self.setup_error.read().await {
+                bail!("couldn't set up the mail store: {}", err);
+            }
+            match updates.recv().await {
+                Ok(_) | Err(RecvError::Lagged(_)) => {}
+                Err(RecvError::Closed) => bail!("mail store was shut down"),
+            }
+        }
+    }
+
+    /// Sends an update to every subscriber, if there are any
+    fn publish(&self, update: MailStoreUpdate) {
+        let _ = self.store_out_tx.send(update);
+    }
+
+    /// Sends out the current message counts of a folder
+    async fn publish_folder_counts(
+        &self,
+        pool: &SqlitePool,
+        acct: &str,
+        folder: &str,
+    ) -> Result<()> {
+        let (total, unread): (u32, u32) = sqlx::query_as(
+            r#"
+            SELECT COUNT(*), COALESCE(SUM(instr(' ' || flags || ' ', ' \Seen ') = 0), 0)
+            FROM "mail"
+            WHERE account = ? AND folder = ?
+            "#,
+        )
+        .bind(acct)
+        .bind(folder)
+        .fetch_one(pool)
+        .await?;
+
+        self.publish(MailStoreUpdate::FolderCounts {
+            acct: acct.to_owned(),
+            folder: folder.to_owned(),
+            total,
+            unread,
+        });
+        Ok(())
+    }
+
+    /// Sends out the current message counts of a folder once a batch of messages has been stored
+    /// in it. [`MailStore::store_message`] and [`MailStore::import_message`] leave this to their
+    /// callers, since counting the whole folder after every message adds up.
+    pub async fn publish_counts(
         &self,
         acct: impl AsRef<str>,
         folder: impl AsRef<str>,
-        uid: u32,
-        uidvalidity: u32,
-        message_id: Option<&str>,
-    ) -> Result<Option<u32>> {
+    ) -> Result<()> {
         let read = self.inner.read().await;
         let inner = match &*read {
             Some(v) => v,
-            None => return Ok(None),
+            None => return Ok(()),
         };
-        let existing: Option<(u32,)> = into_opt(
+        self.publish_folder_counts(&inner.pool, acct.as_ref(), folder.as_ref())
+            .await
+    }
+
+    /// Checks whether there are messages in the given folder that were stored under a different
+    /// UIDVALIDITY, meaning that the server has invalidated the UIDs in that folder
+    pub async fn has_stale_uidvalidity(
+        &self,
+        acct: impl AsRef<str>,
+        folder: impl AsRef<str>,
+        current: u32,
+    ) -> Result<bool> {
+        let read = self.inner.read().await;
+        let inner = match &*read {
+            Some(v) => v,
+            None => return Ok(false),
+        };
+        let stale: Option<(u32,)> = into_opt(
             sqlx::query_as(
                 r#"
             SELECT rowid FROM "mail"
-            WHERE account = ? AND folder = ?
-                AND uid = ? AND uidvalidity = ?
+            WHERE account = ? AND folder = ? AND uidvalidity != ?
+            LIMIT 1
             "#,
             )
             .bind(acct.as_ref())
             .bind(folder.as_ref())
-            .bind(uid)
-            .bind(uidvalidity)
+            .bind(current)
             .fetch_one(&inner.pool)
             .await,
         )?;
-        mem::drop(read);
+        Ok(stale.is_some())
+    }
 
-        if let Some(existing) = existing {
-            let rowid = existing.0;
-            return Ok(Some(rowid));
+    /// After the UIDVALIDITY of a folder has changed, moves messages that are already downloaded
+    /// over to their new UIDs by matching up their Message-IDs, so their bodies don't need to be
+    /// downloaded again
+    ///
+    /// Returns the number of messages that were matched up.
+    pub async fn reconcile_uidvalidity(
+        &self,
+        acct: impl AsRef<str>,
+        folder: impl AsRef<str>,
+        current: u32,
+        message_ids: Vec<(u32, String)>,
+    ) -> Result<u64> {
+        let read = self.inner.read().await;
+        let inner = match &*read {
+            Some(v) => v,
+            None => return Ok(0),
+        };
+
+        let mut reconciled = 0;
+        let mut tx = inner.pool.begin().await?;
+        for (uid, message_id) in message_ids {
+            reconciled += sqlx::query(
+                r#"
+                UPDATE "mail" SET uid = ?, uidvalidity = ?
+                WHERE rowid = (
+                    SELECT rowid FROM "mail"
+                    WHERE account = ? AND folder = ? AND uidvalidity != ?
+                        AND message_id = ?
+                    LIMIT 1
+                )
+                "#,
+            )
+            .bind(uid)
+            .bind(current)
+            .bind(acct.as_ref())
+            .bind(folder.as_ref())
+            .bind(current)
+            .bind(message_id.trim())
+            .execute(&mut tx)
+            .await
+            .context("error reconciling email with new uidvalidity")?
+            .rows_affected();
         }
+        tx.commit().await?;
 
-        Ok(None)
+        if reconciled > 0 {
+            self.publish(MailStoreUpdate::FolderChanged {
+                acct: acct.as_ref().to_owned(),
+                folder: folder.as_ref().to_owned(),
+            });
+        }
+        Ok(reconciled)
     }
 
-    /// Stores the given email
-    pub async fn store_email(
+    /// Nuke all messages with an invalid UIDVALIDITY, along with any files on disk that are no
+    /// longer referenced by any message
+    ///
+    /// Messages that were moved or appended locally are kept while there are still operations in
+    /// the journal, since they won't show up on the server until those have been replayed.
+    pub async fn nuke_old_uidvalidity(
         &self,
         acct: impl AsRef<str>,
         folder: impl AsRef<str>,
-        uid: u32,
-        uidvalidity: u32,
-        attrs: Vec<AttributeValue>,
+        current: u32,
     ) -> Result<()> {
-        let mut body = None;
-        let mut internaldate = None;
-        for attr in attrs {
-            match attr {
-                AttributeValue::BodySection(body_attr) => body = body_attr.data,
-                AttributeValue::InternalDate(date) => internaldate = Some(date),
-                _ => {}
-            }
-        }
-
-        let body = match body {
-            Some(v) => v,
-            None => return Ok(()),
-        };
-        let internaldate = match internaldate {
+        let read = self.inner.read().await;
+        let inner = match &*read {
             Some(v) => v,
             None => return Ok(()),
         };
 
-        let mut hasher = Sha256::new();
-        hasher.update(body.as_bytes());
-        let hash = hasher.finalize();
-        let filename = format!("{}.mail", hex::encode(hash));
-        let path = {
-            match &*self.inner.read().await {
-                Some(inner) => inner.mail_dir.join(&filename),
-                None => return Ok(()),
-            }
-        };
-        fs::write(path, &body)
-            .await
-            .context("error writing email to file")?;
+        let mut tx = inner.pool.begin().await?;
+        let nuked = sqlx::query(
+            r#"
+            DELETE FROM "mail"
+            WHERE account = ? AND folder = ? AND uidvalidity != ?
+                AND NOT (uidvalidity = 0 AND EXISTS (
+                    SELECT 1 FROM "operations" WHERE account = ? AND failed = 0
+                ))
+            "#,
+        )
+        .bind(acct.as_ref())
+        .bind(folder.as_ref())
+        .bind(current)
+        .bind(acct.as_ref())
+        .execute(&mut tx)
+        .await
+        .context("error deleting emails with old uidvalidity")?
+        .rows_affected();
 
-        // parse email
-        let mut message_id = None;
-        let mut subject = None;
-        let mail = mailparse::parse_mail(body.as_bytes())
-            .with_context(|| format!("error parsing email with uid {}", uid))?;
-        for header in mail.headers.iter() {
-            let key = header.get_key_ref();
-            let value = header.get_value();
-            match key.to_ascii_lowercase().as_str() {
-                "message-id" => message_id = Some(value),
-                "subject" => subject = Some(value),
-                _ => {}
-            }
+        let orphaned = take_orphaned_contents(&mut tx).await?;
+        tx.commit().await?;
+
+        if nuked > 0 {
+            debug!(
+                "nuked {} emails ({} files) with old uidvalidity in {}/{}",
+                nuked,
+                orphaned.len(),
+                acct.as_ref(),
+                folder.as_ref()
+            );
+            self.publish(MailStoreUpdate::FolderChanged {
+                acct: acct.as_ref().to_owned(),
+                folder: folder.as_ref().to_owned(),
+            });
+            self.publish_folder_counts(&inner.pool, acct.as_ref(), folder.as_ref())
+                .await?;
         }
+        inner.remove_orphaned_files(orphaned).await;
+
+        Ok(())
+    }
 
-        debug!("message-id: {:?}", message_id);
+    /// Deletes the given messages from a folder after they were expunged on the server, along with
+    /// any files on disk that are no longer referenced by any message
+    ///
+    /// Returns the number of messages that were deleted.
+    pub async fn expunge_uids(
+        &self,
+        acct: impl AsRef<str>,
+        folder: impl AsRef<str>,
+        uidvalidity: u32,
+        uids: &[u32],
+    ) -> Result<u64> {
+        if uids.is_empty() {
+            return Ok(0);
+        }
 
         let read = self.inner.read().await;
         let inner = match &*read {
             Some(v) => v,
-            None => return Ok(()),
+            None => return Ok(0),
         };
-        let existing = into_opt(
-            sqlx::query(
+
+        let mut tx = inner.pool.begin().await?;
+        let mut expunged = 0;
+        let mut expunged_uids = Vec::new();
+        for uid in uids {
+            let deleted = sqlx::query(
                 r#"
-            SELECT * FROM "mail"
-            WHERE account = ? AND folder = ?
-                AND uid = ? AND uidvalidity = ?
-            "#,
+                DELETE FROM "mail"
+                WHERE account = ? AND folder = ? AND uidvalidity = ? AND uid = ?
+                "#,
             )
             .bind(acct.as_ref())
             .bind(folder.as_ref())
-            .bind(uid)
             .bind(uidvalidity)
-            .fetch_one(&inner.pool)
-            .await,
-        )?;
+            .bind(uid)
+            .execute(&mut tx)
+            .await
+            .context("error deleting expunged email")?
+            .rows_affected();
+            if deleted == 0 {
+                continue;
+            }
+            expunged += deleted;
+            expunged_uids.push(*uid);
 
-        if existing.is_none() {
-            let id = sqlx::query(
+            // there's nothing left to change the flags on
+            sqlx::query(
                 r#"
-                INSERT INTO "mail" (
-                    account, subject, message_id, folder, uid, uidvalidity,
-                    filename, internaldate
-                ) VALUES (?, ?, ?, ?, ?, ?, ?, ?)
+                DELETE FROM "operations"
+                WHERE account = ? AND folder = ? AND uidvalidity = ? AND uid = ?
+                    AND kind = 'set_flag'
                 "#,
             )
             .bind(acct.as_ref())
-            .bind(subject)
-            .bind(message_id)
             .bind(folder.as_ref())
-            .bind(uid)
             .bind(uidvalidity)
-            .bind(filename)
-            .bind(internaldate.to_rfc3339())
-            .execute(&inner.pool)
-            .await
-            .context("error inserting email into db")?
-            .last_insert_rowid();
+            .bind(uid)
+            .execute(&mut tx)
+            .await?;
         }
-        mem::drop(read);
-
-        // self.email_events
-        //     .send(EmailUpdateInfo {})
-        //     .context("error sending email update info to the broadcast channel")?;
 
-        Ok(())
-    }
+        let orphaned = take_orphaned_contents(&mut tx).await?;
+        tx.commit().await?;
 
-    /// Event handerl
-    pub async fn handle_mail_event(&self, evt: MailEvent) -> Result<()> {
-        debug!("TODO: handle {:?}", evt);
-        match evt {
-            MailEvent::FolderList(acct, folders) => {
-                let inner = self.inner.write().await;
-                let acct_ref = match inner.as_ref().and_then(|inner| inner.accounts.get(&acct)) {
-                    Some(inner) => inner.clone(),
-                    None => return Ok(()),
-                };
-                mem::drop(inner);
-                acct_ref.set_folders(folders).await;
+        if expunged > 0 {
+            debug!(
+                "expunged {} emails ({} files) in {}/{}",
+                expunged,
+                orphaned.len(),
+                acct.as_ref(),
+                folder.as_ref()
+            );
+            for uid in expunged_uids {
+                self.publish(MailStoreUpdate::MessageRemoved {
+                    acct: acct.as_ref().to_owned(),
+                    folder: folder.as_ref().to_owned(),
+                    uid,
+                });
             }
-            _ => {}
+            self.publish_folder_counts(&inner.pool, acct.as_ref(), folder.as_ref())
+                .await?;
         }
-        Ok(())
+        inner.remove_orphaned_files(orphaned).await;
+
+        Ok(expunged)
     }
 
-    /// Return a map of the accounts that are currently being tracked as well as a reference to the
-    /// account handles themselves
-    pub async fn list_accounts(&self) -> IndexMap<String, Arc<AccountRef>> {
-        let read = self.inner.read().await;
-        let inner = match read.as_ref() {
-            Some(v) => v,
-            None => return IndexMap::new(),
+    /// Deletes every message in a folder that isn't in the list of UIDs that are currently on the
+    /// server
+    ///
+    /// Returns the number of messages that were deleted.
+    pub async fn expunge_missing(
+        &self,
+        acct: impl AsRef<str>,
+        folder: impl AsRef<str>,
+        uidvalidity: u32,
+        server_uids: Vec<u32>,
+    ) -> Result<u64> {
+        let local_uids: Vec<(u32,)> = {
+            let read = self.inner.read().await;
+            let inner = match &*read {
+                Some(v) => v,
+                None => return Ok(0),
+            };
+            sqlx::query_as(
+                r#"
+                SELECT uid FROM "mail"
+                WHERE account = ? AND folder = ? AND uidvalidity = ?
+                "#,
+            )
+            .bind(acct.as_ref())
+            .bind(folder.as_ref())
+            .bind(uidvalidity)
+            .fetch_all(&inner.pool)
+            .await?
         };
 
-        inner.accounts.clone()
+        let server_uids = server_uids.into_iter().collect::<HashSet<_>>();
+        let missing = local_uids
+            .into_iter()
+            .map(|(uid,)| uid)
+            .filter(|uid| !server_uids.contains(uid))
+            .collect::<Vec<_>>();
+        self.expunge_uids(acct, folder, uidvalidity, &missing).await
     }
-}
-
-async fn mail_store_config_listener(
-    mut config_watcher: ConfigWatcher,
-    config: Arc<RwLock<Option<Config>>>,
-    inner: Arc<RwLock<Option<MailStoreInner>>>,
-    store_out_tx: Arc<watch::Sender<Option<MailStoreUpdate>>>,
-) -> Result<()> {
-    while let Ok(()) = config_watcher.changed().await {
-        let new_config = config_watcher.borrow().clone();
-
-        let fut = future::try_join(
-            async {
-                let mut write = config.write().await;
-                write.replace(new_config.clone());
-                Ok::<_, Error>(())
-            },
-            async {
-                let new_inner = MailStoreInner::init_with_config(new_config.clone()).await?;
-                let mut write = inner.write().await;
-                write.replace(new_inner);
-                Ok(())
-            },
-        );
 
-        match fut.await {
-            Ok(_) => store_out_tx.send(Some(MailStoreUpdate::AccountListUpdate(())))?,
-            Err(e) => {
-                error!("during mail loop: {}", e);
-                panic!();
-            }
+    /// Gets the UIDNEXT of the folder as of the last sync, every message with a lower UID has
+    /// already been downloaded
+    ///
+    /// If the folder hasn't been synced with the given UIDVALIDITY yet, this returns 1.
+    pub async fn get_synced_uidnext(
+        &self,
+        acct: impl AsRef<str>,
+        folder: impl AsRef<str>,
+        uidvalidity: u32,
+    ) -> Result<u32> {
+        let read = self.inner.read().await;
+        let inner = match &*read {
+            Some(v) => v,
+            None => return Ok(1),
         };
+        let synced: Option<(u32,)> = into_opt(
+            sqlx::query_as(
+                r#"
+            SELECT uidnext FROM "folders"
+            WHERE account = ? AND folder = ? AND uidvalidity = ?
+            "#,
+            )
+            .bind(acct.as_ref())
+            .bind(folder.as_ref())
+            .bind(uidvalidity)
+            .fetch_one(&inner.pool)
+            .await,
+        )?;
+        Ok(synced.map_or(1, |(uidnext,)| uidnext))
     }
 
-    Ok(())
-}
-
-impl MailStoreInner {
-    async fn init_with_config(config: Config) -> Result<Self> {
-        let data_dir = config.data_dir.to_string_lossy();
-        let data_dir = PathBuf::from(shellexpand::tilde(data_dir.as_ref()).as_ref());
-
-        let mail_dir = data_dir.join("mail");
-        if !mail_dir.exists() {
-            fs::create_dir_all(&mail_dir).await?;
-        }
-        info!("using mail dir: {:?}", mail_dir);
-
+    /// Records that every message in the folder with a UID lower than `uidnext` has been
+    /// downloaded
+    pub async fn set_synced_uidnext(
+        &self,
+        acct: impl AsRef<str>,
+        folder: impl AsRef<str>,
+        uidvalidity: u32,
+        uidnext: u32,
+    ) -> Result<()> {
+        let read = self.inner.read().await;
+        let inner = match &*read {
+            Some(v) => v,
+            None => return Ok(()),
+        };
+        sqlx::query(
+            r#"
+            INSERT OR REPLACE INTO "folders" (account, folder, uidvalidity, uidnext)
+            VALUES (?, ?, ?, ?)
+            "#,
+        )
+        .bind(acct.as_ref())
+        .bind(folder.as_ref())
+        .bind(uidvalidity)
+        .bind(uidnext)
+        .execute(&inner.pool)
+        .await
+        .context("error updating folder sync state")?;
+        Ok(())
+    }
+
+    /// Gets the number of messages and the highest mod-sequence that the folder had the last time
+    /// its flags were synced
+    pub async fn get_flags_sync_state(
+        &self,
+        acct: impl AsRef<str>,
+        folder: impl AsRef<str>,
+        uidvalidity: u32,
+    ) -> Result<(Option<u32>, Option<u64>)> {
+        let read = self.inner.read().await;
+        let inner = match &*read {
+            Some(v) => v,
+            None => return Ok((None, None)),
+        };
+        let state: Option<(Option<u32>, Option<i64>)> = sqlx::query_as(
+            r#"
+            SELECT message_count, highestmodseq FROM "folders"
+            WHERE account = ? AND folder = ? AND uidvalidity = ?
+            "#,
+        )
+        .bind(acct.as_ref())
+        .bind(folder.as_ref())
+        .bind(uidvalidity)
+        .fetch_optional(&inner.pool)
+        .await?;
+        Ok(match state {
+            Some((message_count, highestmodseq)) => {
+                (message_count, highestmodseq.map(|modseq| modseq as u64))
+            }
+            None => (None, None),
+        })
+    }
+
+    /// Records what the folder looked like once its flags were synced. This has to come after
+    /// [`set_synced_uidnext`][Self::set_synced_uidnext], which clears it.
+    pub async fn set_flags_sync_state(
+        &self,
+        acct: impl AsRef<str>,
+        folder: impl AsRef<str>,
+        uidvalidity: u32,
+        message_count: u32,
+        highestmodseq: Option<u64>,
+    ) -> Result<()> {
+        let read = self.inner.read().await;
+        let inner = match &*read {
+            Some(v) => v,
+            None => return Ok(()),
+        };
+        sqlx::query(
+            r#"
+            UPDATE "folders" SET message_count = ?, highestmodseq = ?
+            WHERE account = ? AND folder = ? AND uidvalidity = ?
+            "#,
+        )
+        .bind(message_count)
+        .bind(highestmodseq.map(|modseq| modseq as i64))
+        .bind(acct.as_ref())
+        .bind(folder.as_ref())
+        .bind(uidvalidity)
+        .execute(&inner.pool)
+        .await
+        .context("error updating folder sync state")?;
+        Ok(())
+    }
+
+    /// Filters the given UIDs down to the ones that haven't been downloaded yet, using a single
+    /// query
+    pub async fn filter_new_uids(
+        &self,
+        acct: impl AsRef<str>,
+        folder: impl AsRef<str>,
+        uidvalidity: u32,
+        uids: Vec<u32>,
+    ) -> Result<Vec<u32>> {
+        let (min, max) = match (uids.iter().min(), uids.iter().max()) {
+            (Some(min), Some(max)) => (*min, *max),
+            _ => return Ok(uids),
+        };
+
+        let read = self.inner.read().await;
+        let inner = match &*read {
+            Some(v) => v,
+            None => return Ok(uids),
+        };
+        let existing: HashSet<u32> = sqlx::query_as(
+            r#"
+            SELECT uid FROM "mail"
+            WHERE account = ? AND folder = ? AND uidvalidity = ?
+                AND uid BETWEEN ? AND ?
+            "#,
+        )
+        .bind(acct.as_ref())
+        .bind(folder.as_ref())
+        .bind(uidvalidity)
+        .bind(min)
+        .bind(max)
+        .fetch_all(&inner.pool)
+        .await
+        .context("error checking which emails are already downloaded")?
+        .into_iter()
+        .map(|(uid,): (u32,)| uid)
+        .collect();
+
+        Ok(uids
+            .into_iter()
+            .filter(|uid| !existing.contains(uid))
+            .collect())
+    }
+
+    /// Gives out UIDs for the messages in a folder of a local account, which are identified by
+    /// `keys` instead. Messages that were seen before keep the UID they had, and anything that's
+    /// not in the folder anymore is forgotten.
+    pub async fn assign_local_uids(
+        &self,
+        acct: impl AsRef<str>,
+        folder: impl AsRef<str>,
+        keys: Vec<String>,
+    ) -> Result<HashMap<String, u32>> {
+        let read = self.inner.read().await;
+        let inner = match &*read {
+            Some(v) => v,
+            None => return Ok(HashMap::new()),
+        };
+
+        let mut tx = inner.pool.begin().await?;
+        let mut uids: HashMap<String, u32> =
+            sqlx::query_as(r#"SELECT key, uid FROM "local_uids" WHERE account = ? AND folder = ?"#)
+                .bind(acct.as_ref())
+                .bind(folder.as_ref())
+                .fetch_all(&mut tx)
+                .await?
+                .into_iter()
+                .collect();
+
+        let keys = keys.into_iter().collect::<HashSet<_>>();
+        let gone = uids
+            .keys()
+            .filter(|key| !keys.contains(*key))
+            .cloned()
+            .collect::<Vec<_>>();
+        for key in gone {
+            sqlx::query(r#"DELETE FROM "local_uids" WHERE account = ? AND folder = ? AND key = ?"#)
+                .bind(acct.as_ref())
+                .bind(folder.as_ref())
+                .bind(&key)
+                .execute(&mut tx)
+                .await?;
+            uids.remove(&key);
+        }
+
+        // UIDs are never reused, even for messages that are gone now, so the next one is kept
+        // track of the same way it is for folders on a server
+        let synced: Option<(u32,)> =
+            sqlx::query_as(r#"SELECT uidnext FROM "folders" WHERE account = ? AND folder = ?"#)
+                .bind(acct.as_ref())
+                .bind(folder.as_ref())
+                .fetch_optional(&mut tx)
+                .await?;
+        let mut next_uid = cmp::max(
+            synced.map_or(1, |(uidnext,)| uidnext),
+            uids.values().max().map_or(1, |uid| uid + 1),
+        );
+        for key in keys {
+            if uids.contains_key(&key) {
+                continue;
+            }
+            sqlx::query(
+                r#"INSERT INTO "local_uids" (account, folder, key, uid) VALUES (?, ?, ?, ?)"#,
+            )
+            .bind(acct.as_ref())
+            .bind(folder.as_ref())
+            .bind(&key)
+            .bind(next_uid)
+            .execute(&mut tx)
+            .await?;
+            uids.insert(key, next_uid);
+            next_uid += 1;
+        }
+
+        sqlx::query(
+            r#"
+            INSERT OR REPLACE INTO "folders" (account, folder, uidvalidity, uidnext)
+            VALUES (?, ?, ?, ?)
+            "#,
+        )
+        .bind(acct.as_ref())
+        .bind(folder.as_ref())
+        .bind(LOCAL_UIDVALIDITY)
+        .bind(next_uid)
+        .execute(&mut tx)
+        .await?;
+        tx.commit().await?;
+        Ok(uids)
+    }
+
+    /// Looks up what identifies a message in a local account, given the UID it was given by
+    /// [`MailStore::assign_local_uids`]
+    pub async fn get_local_key(
+        &self,
+        acct: impl AsRef<str>,
+        folder: impl AsRef<str>,
+        uid: u32,
+    ) -> Result<Option<String>> {
+        let read = self.inner.read().await;
+        let inner = match &*read {
+            Some(v) => v,
+            None => return Ok(None),
+        };
+        let key: Option<(String,)> = sqlx::query_as(
+            r#"SELECT key FROM "local_uids" WHERE account = ? AND folder = ? AND uid = ?"#,
+        )
+        .bind(acct.as_ref())
+        .bind(folder.as_ref())
+        .bind(uid)
+        .fetch_optional(&inner.pool)
+        .await?;
+        Ok(key.map(|(key,)| key))
+    }
+
+    /// Given a UID and optional message-id try to identify a particular message
+    pub async fn try_identify_email(
+        &self,
+        acct: impl AsRef<str>,
+        folder: impl AsRef<str>,
+        uid: u32,
+        uidvalidity: u32,
+        message_id: Option<&str>,
+    ) -> Result<Option<u32>> {
+        let read = self.inner.read().await;
+        let inner = match &*read {
+            Some(v) => v,
+            None => return Ok(None),
+        };
+        let existing: Option<(u32,)> = into_opt(
+            sqlx::query_as(
+                r#"
+            SELECT rowid FROM "mail"
+            WHERE account = ? AND folder = ?
+                AND uid = ? AND uidvalidity = ?
+            "#,
+            )
+            .bind(acct.as_ref())
+            .bind(folder.as_ref())
+            .bind(uid)
+            .bind(uidvalidity)
+            .fetch_one(&inner.pool)
+            .await,
+        )?;
+        mem::drop(read);
+
+        if let Some(existing) = existing {
+            let rowid = existing.0;
+            return Ok(Some(rowid));
+        }
+
+        Ok(None)
+    }
+
+    /// Stores the given email
+    pub async fn store_email(
+        &self,
+        acct: impl AsRef<str>,
+        folder: impl AsRef<str>,
+        uid: u32,
+        uidvalidity: u32,
+        attrs: Vec<AttributeValue>,
+    ) -> Result<()> {
+        let mut body = None;
+        let mut headers_only = false;
+        let mut internaldate = None;
+        let mut flags = Vec::new();
+        for attr in attrs {
+            match attr {
+                AttributeValue::BodySection(body_attr) => {
+                    headers_only =
+                        body_attr.section == Some(SectionPath::Full(MessageSection::Header));
+                    body = body_attr.data;
+                }
+                AttributeValue::InternalDate(date) => internaldate = Some(date),
+                AttributeValue::Flags(new_flags) => flags = new_flags,
+                _ => {}
+            }
+        }
+
+        let body = match body {
+            Some(v) => v,
+            None => return Ok(()),
+        };
+        let internaldate = match internaldate {
+            Some(v) => v,
+            None => return Ok(()),
+        };
+
+        let body = body.into_bytes();
+        self.store_message(&acct, &folder, uid, uidvalidity, body, internaldate, flags)
+            .await?;
+        if headers_only {
+            // the header is enough to list the message, and the rest gets downloaded when it's
+            // opened
+            self.mark_headers_only(&acct, &folder, uid).await?;
+        }
+        Ok(())
+    }
+
+    /// Marks the body that was just stored for a message as only being its header
+    async fn mark_headers_only(
+        &self,
+        acct: impl AsRef<str>,
+        folder: impl AsRef<str>,
+        uid: u32,
+    ) -> Result<()> {
+        let read = self.inner.read().await;
+        let inner = match &*read {
+            Some(v) => v,
+            None => return Ok(()),
+        };
+        let hashes: Vec<(String,)> = sqlx::query_as(
+            r#"
+            SELECT content_hash FROM "mail"
+            WHERE account = ? AND folder = ? AND uid = ? AND content_hash IS NOT NULL
+            "#,
+        )
+        .bind(acct.as_ref())
+        .bind(folder.as_ref())
+        .bind(uid)
+        .fetch_all(&inner.pool)
+        .await?;
+        for (hash,) in hashes {
+            sqlx::query(r#"UPDATE "contents" SET headers_only = 1 WHERE hash = ?"#)
+                .bind(&hash)
+                .execute(&inner.pool)
+                .await?;
+        }
+        Ok(())
+    }
+
+    /// Whether only the headers of the messages in a folder are downloaded when it's synced
+    pub async fn is_headers_only(&self, acct: impl AsRef<str>, folder: impl AsRef<str>) -> bool {
+        match &*self.config.read().await {
+            Some(config) => config
+                .mail_accounts
+                .get(acct.as_ref())
+                .map(|acct| acct.cache.headers_only.iter().any(|f| f == folder.as_ref()))
+                .unwrap_or(false),
+            None => false,
+        }
+    }
+
+    /// Removes message bodies from the cache to keep every IMAP account within its
+    /// [`CachePolicy`]
+    pub async fn compact(&self) -> Result<()> {
+        let config = match &*self.config.read().await {
+            Some(v) => v.clone(),
+            None => return Ok(()),
+        };
+        let read = self.inner.read().await;
+        let inner = match &*read {
+            Some(v) => v,
+            None => return Ok(()),
+        };
+
+        for (acct, acct_config) in config.mail_accounts.iter() {
+            // local accounts can always read their mail from disk, so there's nothing to save
+            if !matches!(acct_config.source, MailSource::Imap(_))
+                || acct_config.cache == CachePolicy::default()
+            {
+                continue;
+            }
+            let evicted = inner.compact_account(acct, &acct_config.cache).await?;
+            if evicted > 0 {
+                info!("evicted {} message bodies from {}", evicted, acct);
+            }
+        }
+        Ok(())
+    }
+
+    /// Stores a message under the given UID, unless it's already there. The folder's counts are
+    /// left for the caller to send out with [`MailStore::publish_counts`].
+    #[allow(clippy::too_many_arguments)]
+    pub async fn store_message(
+        &self,
+        acct: impl AsRef<str>,
+        folder: impl AsRef<str>,
+        uid: u32,
+        uidvalidity: u32,
+        body: Vec<u8>,
+        internaldate: DateTime<FixedOffset>,
+        flags: Vec<MailboxFlag>,
+    ) -> Result<()> {
+        let hash = match self.write_mail_file(&body).await? {
+            Some(v) => v,
+            None => return Ok(()),
+        };
+        let filename = mail_filename(&hash);
+        let message = parse_message(&body)
+            .with_context(|| format!("error parsing email with uid {}", uid))?;
+
+        debug!("message-id: {:?}", message.message_id);
+
+        let read = self.inner.read().await;
+        let inner = match &*read {
+            Some(v) => v,
+            None => return Ok(()),
+        };
+        let existing: Option<(i64, Option<String>, bool)> = sqlx::query_as(
+            r#"
+            SELECT mail.id, mail.content_hash,
+                COALESCE(contents.evicted OR contents.headers_only, 0)
+            FROM "mail"
+            LEFT JOIN "contents" ON contents.hash = mail.content_hash
+            WHERE account = ? AND folder = ?
+                AND uid = ? AND uidvalidity = ?
+            "#,
+        )
+        .bind(acct.as_ref())
+        .bind(folder.as_ref())
+        .bind(uid)
+        .bind(uidvalidity)
+        .fetch_optional(&inner.pool)
+        .await?;
+
+        // if this message was already downloaded before the folder's UIDVALIDITY changed, just move
+        // the old row over to the new UID
+        let mut reconciled = false;
+        if existing.is_none() {
+            reconciled = sqlx::query(
+                r#"
+                UPDATE "mail" SET uid = ?, uidvalidity = ?
+                WHERE rowid = (
+                    SELECT rowid FROM "mail"
+                    WHERE account = ? AND folder = ? AND uidvalidity != ?
+                        AND filename = ?
+                    LIMIT 1
+                )
+                "#,
+            )
+            .bind(uid)
+            .bind(uidvalidity)
+            .bind(acct.as_ref())
+            .bind(folder.as_ref())
+            .bind(uidvalidity)
+            .bind(&filename)
+            .execute(&inner.pool)
+            .await
+            .context("error reconciling email with new uidvalidity")?
+            .rows_affected()
+                > 0;
+        }
+
+        if reconciled {
+            // the message is already being shown, just under its old UID
+            self.publish(MailStoreUpdate::FolderChanged {
+                acct: acct.as_ref().to_owned(),
+                folder: folder.as_ref().to_owned(),
+            });
+        } else if let Some((mail_id, Some(old_hash), true)) = &existing {
+            // the body was evicted from the cache, or only the header was kept, and this is it
+            // being downloaded again. if only the header was there, the hash is different and
+            // there's more to parse now
+            let mut tx = inner.pool.begin().await?;
+            if *old_hash != hash {
+                sqlx::query(r#"UPDATE "mail" SET content_hash = ?, filename = ? WHERE id = ?"#)
+                    .bind(&hash)
+                    .bind(&filename)
+                    .bind(mail_id)
+                    .execute(&mut tx)
+                    .await?;
+                save_message_metadata(&mut tx, inner.cipher.as_deref(), *mail_id, &message).await?;
+            }
+            mark_cached(&mut tx, &hash).await?;
+            let orphaned = take_orphaned_contents(&mut tx).await?;
+            tx.commit().await?;
+            inner.restore_mail_file(&hash, &body).await?;
+            inner.remove_orphaned_files(orphaned).await;
+
+            self.publish(MailStoreUpdate::FolderChanged {
+                acct: acct.as_ref().to_owned(),
+                folder: folder.as_ref().to_owned(),
+            });
+        } else if existing.is_none() {
+            let cipher = inner.cipher.as_deref();
+            let mut tx = inner.pool.begin().await?;
+            let mail_id = sqlx::query(
+                r#"
+                INSERT INTO "mail" (
+                    account, subject, message_id, folder, uid, uidvalidity,
+                    filename, content_hash, internaldate, flags, flags_synced_at
+                ) VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?)
+                "#,
+            )
+            .bind(acct.as_ref())
+            .bind(insert_column(cipher, message.subject.as_deref()))
+            .bind(&message.message_id)
+            .bind(folder.as_ref())
+            .bind(uid)
+            .bind(uidvalidity)
+            .bind(&filename)
+            .bind(&hash)
+            .bind(internaldate.to_rfc3339())
+            .bind(flags_to_string(&flags))
+            .bind(Utc::now().timestamp_millis())
+            .execute(&mut tx)
+            .await
+            .context("error inserting email into db")?
+            .last_insert_rowid();
+            let subject = message.subject.as_deref();
+            seal_new_row(&mut tx, cipher, "mail", mail_id, &[("subject", subject)]).await?;
+            save_message_metadata(&mut tx, cipher, mail_id, &message).await?;
+            let date = internaldate.to_rfc3339();
+            assign_thread(&mut tx, cipher, acct.as_ref(), mail_id, &date, &message).await?;
+            mark_cached(&mut tx, &hash).await?;
+            tx.commit().await?;
+            inner.restore_mail_file(&hash, &body).await?;
+
+            self.publish(MailStoreUpdate::MessageAdded {
+                acct: acct.as_ref().to_owned(),
+                folder: folder.as_ref().to_owned(),
+                uid,
+            });
+        }
+        mem::drop(read);
+
+        Ok(())
+    }
+
+    /// Adds or removes a flag on a message locally, and queues the change to be made on the server
+    pub async fn set_flag(
+        &self,
+        acct: impl AsRef<str>,
+        folder: impl AsRef<str>,
+        uid: u32,
+        flag: MailboxFlag,
+        added: bool,
+    ) -> Result<()> {
+        let read = self.inner.read().await;
+        let inner = match &*read {
+            Some(v) => v,
+            None => return Ok(()),
+        };
+
+        let mut tx = inner.pool.begin().await?;
+        let (rowid, uidvalidity, flags): (i64, u32, String) = sqlx::query_as(
+            r#"
+            SELECT rowid, uidvalidity, flags FROM "mail"
+            WHERE account = ? AND folder = ? AND uid = ?
+            ORDER BY rowid DESC
+            LIMIT 1
+            "#,
+        )
+        .bind(acct.as_ref())
+        .bind(folder.as_ref())
+        .bind(uid)
+        .fetch_one(&mut tx)
+        .await
+        .with_context(|| format!("could not find email with uid {}", uid))?;
+
+        let mut flags = flags_from_string(&flags);
+        apply_flag_change(&mut flags, &flag, added);
+        sqlx::query(r#"UPDATE "mail" SET flags = ? WHERE rowid = ?"#)
+            .bind(flags_to_string(&flags))
+            .bind(rowid)
+            .execute(&mut tx)
+            .await?;
+
+        let op = MailOperation::SetFlag {
+            folder: folder.as_ref().to_owned(),
+            uidvalidity,
+            uid,
+            flag: flag.to_string(),
+            added,
+        };
+        push_operation(&mut tx, acct.as_ref(), &op).await?;
+        tx.commit().await?;
+
+        self.publish(MailStoreUpdate::MessageUpdated {
+            acct: acct.as_ref().to_owned(),
+            folder: folder.as_ref().to_owned(),
+            uid,
+        });
+        self.publish_folder_counts(&inner.pool, acct.as_ref(), folder.as_ref())
+            .await?;
+        Ok(())
+    }
+
+    /// Merges the flags that were just fetched from the server with the local flags
+    ///
+    /// Flag changes that haven't made it to the server yet are kept on top of the server's flags,
+    /// unless the message's flags were synced after the change was made, in which case the server
+    /// wins and the change is dropped.
+    pub async fn sync_flags(
+        &self,
+        acct: impl AsRef<str>,
+        folder: impl AsRef<str>,
+        uidvalidity: u32,
+        server_flags: Vec<(u32, Vec<MailboxFlag>)>,
+    ) -> Result<()> {
+        let read = self.inner.read().await;
+        let inner = match &*read {
+            Some(v) => v,
+            None => return Ok(()),
+        };
+
+        let now = Utc::now().timestamp_millis();
+        let mut tx = inner.pool.begin().await?;
+
+        // changes made under an old UIDVALIDITY can't be sent anymore
+        sqlx::query(
+            r#"
+            DELETE FROM "operations"
+            WHERE account = ? AND folder = ? AND kind = 'set_flag' AND uidvalidity != ?
+            "#,
+        )
+        .bind(acct.as_ref())
+        .bind(folder.as_ref())
+        .bind(uidvalidity)
+        .execute(&mut tx)
+        .await?;
+
+        let pending: Vec<(i64, u32, String, i64, Option<i64>)> = sqlx::query_as(
+            r#"
+            SELECT o.id, o.uid, o.payload, o.created_at, m.flags_synced_at
+            FROM "operations" o
+            LEFT JOIN "mail" m
+                ON m.account = o.account AND m.folder = o.folder
+                AND m.uidvalidity = o.uidvalidity AND m.uid = o.uid
+            WHERE o.account = ? AND o.folder = ? AND o.kind = 'set_flag' AND o.failed = 0
+            ORDER BY o.id
+            "#,
+        )
+        .bind(acct.as_ref())
+        .bind(folder.as_ref())
+        .fetch_all(&mut tx)
+        .await?;
+
+        // used to tell which messages actually changed
+        let local_flags: HashMap<u32, String> = sqlx::query_as(
+            r#"
+            SELECT uid, flags FROM "mail"
+            WHERE account = ? AND folder = ? AND uidvalidity = ?
+            "#,
+        )
+        .bind(acct.as_ref())
+        .bind(folder.as_ref())
+        .bind(uidvalidity)
+        .fetch_all(&mut tx)
+        .await?
+        .into_iter()
+        .collect();
+        let mut changed = Vec::new();
+
+        let mut changes_by_uid = HashMap::<u32, Vec<(MailboxFlag, bool)>>::new();
+        for (id, uid, payload, created_at, synced_at) in pending {
+            if synced_at.map_or(true, |synced_at| created_at > synced_at) {
+                if let MailOperation::SetFlag { flag, added, .. } = serde_json::from_str(&payload)?
+                {
+                    let flag = MailboxFlag::from(flag.as_str());
+                    changes_by_uid.entry(uid).or_default().push((flag, added));
+                }
+            } else {
+                // the server's flags are newer than this change
+                sqlx::query(r#"DELETE FROM "operations" WHERE id = ?"#)
+                    .bind(id)
+                    .execute(&mut tx)
+                    .await?;
+            }
+        }
+
+        for (uid, mut flags) in server_flags {
+            // \Recent only applies to the current session
+            flags.retain(|flag| *flag != MailboxFlag::Recent);
+            if let Some(changes) = changes_by_uid.get(&uid) {
+                for (flag, added) in changes {
+                    apply_flag_change(&mut flags, flag, *added);
+                }
+            }
+            let flags = flags_to_string(&flags);
+            if matches!(local_flags.get(&uid), Some(local) if *local != flags) {
+                changed.push(uid);
+            }
+
+            if changes_by_uid.contains_key(&uid) {
+                // these haven't made it to the server yet, so the flags aren't synced
+                sqlx::query(
+                    r#"
+                        UPDATE "mail" SET flags = ?
+                        WHERE account = ? AND folder = ? AND uidvalidity = ? AND uid = ?
+                        "#,
+                )
+                .bind(&flags)
+                .bind(acct.as_ref())
+                .bind(folder.as_ref())
+                .bind(uidvalidity)
+                .bind(uid)
+                .execute(&mut tx)
+                .await?;
+            } else {
+                sqlx::query(
+                    r#"
+                        UPDATE "mail" SET flags = ?, flags_synced_at = ?
+                        WHERE account = ? AND folder = ? AND uidvalidity = ? AND uid = ?
+                        "#,
+                )
+                .bind(&flags)
+                .bind(now)
+                .bind(acct.as_ref())
+                .bind(folder.as_ref())
+                .bind(uidvalidity)
+                .bind(uid)
+                .execute(&mut tx)
+                .await?;
+            }
+        }
+        tx.commit().await.context("error syncing flags")?;
+
+        if !changed.is_empty() {
+            for uid in changed {
+                self.publish(MailStoreUpdate::MessageUpdated {
+                    acct: acct.as_ref().to_owned(),
+                    folder: folder.as_ref().to_owned(),
+                    uid,
+                });
+            }
+            self.publish_folder_counts(&inner.pool, acct.as_ref(), folder.as_ref())
+                .await?;
+        }
+        Ok(())
+    }
+
+    /// Moves a message to another folder locally, and queues the move to be made on the server
+    pub async fn move_message(
+        &self,
+        acct: impl AsRef<str>,
+        folder: impl AsRef<str>,
+        uid: u32,
+        to: impl AsRef<str>,
+    ) -> Result<()> {
+        let read = self.inner.read().await;
+        let inner = match &*read {
+            Some(v) => v,
+            None => return Ok(()),
+        };
+
+        let mut tx = inner.pool.begin().await?;
+        let (rowid, uidvalidity) =
+            find_message(&mut tx, acct.as_ref(), folder.as_ref(), uid).await?;
+
+        // the message gets a new UID in the other folder, which isn't known until that folder is
+        // synced again. a UIDVALIDITY of 0 gets it matched back up during that sync
+        sqlx::query(r#"UPDATE "mail" SET folder = ?, uid = 0, uidvalidity = 0 WHERE rowid = ?"#)
+            .bind(to.as_ref())
+            .bind(rowid)
+            .execute(&mut tx)
+            .await?;
+
+        let op = MailOperation::Move {
+            folder: folder.as_ref().to_owned(),
+            uidvalidity,
+            uid,
+            to: to.as_ref().to_owned(),
+        };
+        push_operation(&mut tx, acct.as_ref(), &op).await?;
+        tx.commit().await?;
+
+        self.publish(MailStoreUpdate::MessageRemoved {
+            acct: acct.as_ref().to_owned(),
+            folder: folder.as_ref().to_owned(),
+            uid,
+        });
+        self.publish(MailStoreUpdate::MessageAdded {
+            acct: acct.as_ref().to_owned(),
+            folder: to.as_ref().to_owned(),
+            uid: 0,
+        });
+        self.publish_folder_counts(&inner.pool, acct.as_ref(), folder.as_ref())
+            .await?;
+        self.publish_folder_counts(&inner.pool, acct.as_ref(), to.as_ref())
+            .await?;
+        Ok(())
+    }
+
+    /// Deletes a message locally, and queues the deletion to be made on the server
+    pub async fn delete_message(
+        &self,
+        acct: impl AsRef<str>,
+        folder: impl AsRef<str>,
+        uid: u32,
+    ) -> Result<()> {
+        let uidvalidity = {
+            let read = self.inner.read().await;
+            let inner = match &*read {
+                Some(v) => v,
+                None => return Ok(()),
+            };
+
+            let mut tx = inner.pool.begin().await?;
+            let (_, uidvalidity) =
+                find_message(&mut tx, acct.as_ref(), folder.as_ref(), uid).await?;
+            let op = MailOperation::Delete {
+                folder: folder.as_ref().to_owned(),
+                uidvalidity,
+                uid,
+            };
+            push_operation(&mut tx, acct.as_ref(), &op).await?;
+            tx.commit().await?;
+            uidvalidity
+        };
+
+        self.expunge_uids(acct, folder, uidvalidity, &[uid]).await?;
+        Ok(())
+    }
+
+    /// Saves a message locally (ex. a draft), and queues it to be uploaded to the given folder
+    pub async fn append_message(
+        &self,
+        acct: impl AsRef<str>,
+        folder: impl AsRef<str>,
+        body: Vec<u8>,
+        flags: Vec<MailboxFlag>,
+    ) -> Result<()> {
+        self.import_message(&acct, &folder, body, flags, None, true)
+            .await?;
+        self.publish_counts(acct, folder).await
+    }
+
+    /// Adds a message that came from somewhere other than the server (ex. a Maildir) to a folder.
+    /// If `upload` is set, it's also queued to be uploaded to the server. Otherwise it's only kept
+    /// until the folder is next synced, since it isn't on the server, so anything that's meant to
+    /// stay in an IMAP account has to be uploaded.
+    ///
+    /// Returns false if the folder already had the same message. Like with
+    /// [`MailStore::store_message`], the folder's counts are left to the caller.
+    pub async fn import_message(
+        &self,
+        acct: impl AsRef<str>,
+        folder: impl AsRef<str>,
+        body: Vec<u8>,
+        flags: Vec<MailboxFlag>,
+        date: Option<DateTime<Local>>,
+        upload: bool,
+    ) -> Result<bool> {
+        let hash = match self.write_mail_file(&body).await? {
+            Some(v) => v,
+            None => return Ok(false),
+        };
+        let filename = mail_filename(&hash);
+        let message = parse_message(&body).context("error parsing email to import")?;
+        let date = date
+            .or_else(|| message.date.map(|date| date.with_timezone(&Local)))
+            .unwrap_or_else(Local::now);
+
+        let read = self.inner.read().await;
+        let inner = match &*read {
+            Some(v) => v,
+            None => return Ok(false),
+        };
+
+        let mut tx = inner.pool.begin().await?;
+        let existing: Option<(i64,)> = sqlx::query_as(
+            r#"SELECT id FROM "mail" WHERE account = ? AND folder = ? AND content_hash = ?"#,
+        )
+        .bind(acct.as_ref())
+        .bind(folder.as_ref())
+        .bind(&hash)
+        .fetch_optional(&mut tx)
+        .await?;
+        if existing.is_some() {
+            return Ok(false);
+        }
+
+        // like moved messages, this gets matched up with its real UID once the folder is synced
+        let cipher = inner.cipher.as_deref();
+        let mail_id = sqlx::query(
+            r#"
+            INSERT INTO "mail" (
+                account, subject, message_id, folder, uid, uidvalidity,
+                filename, content_hash, internaldate, flags
+            ) VALUES (?, ?, ?, ?, 0, 0, ?, ?, ?, ?)
+            "#,
+        )
+        .bind(acct.as_ref())
+        .bind(insert_column(cipher, message.subject.as_deref()))
+        .bind(&message.message_id)
+        .bind(folder.as_ref())
+        .bind(&filename)
+        .bind(&hash)
+        .bind(date.to_rfc3339())
+        .bind(flags_to_string(&flags))
+        .execute(&mut tx)
+        .await
+        .context("error inserting email into db")?
+        .last_insert_rowid();
+        let subject = message.subject.as_deref();
+        seal_new_row(&mut tx, cipher, "mail", mail_id, &[("subject", subject)]).await?;
+        save_message_metadata(&mut tx, cipher, mail_id, &message).await?;
+        let date = date.to_rfc3339();
+        assign_thread(&mut tx, cipher, acct.as_ref(), mail_id, &date, &message).await?;
+        mark_cached(&mut tx, &hash).await?;
+
+        if upload {
+            let op = MailOperation::Append {
+                folder: folder.as_ref().to_owned(),
+                filename,
+                message_id: message.message_id,
+                flags: flags.iter().map(|flag| flag.to_string()).collect(),
+            };
+            push_operation(&mut tx, acct.as_ref(), &op).await?;
+        }
+        tx.commit().await?;
+        inner.restore_mail_file(&hash, &body).await?;
+
+        self.publish(MailStoreUpdate::MessageAdded {
+            acct: acct.as_ref().to_owned(),
+            folder: folder.as_ref().to_owned(),
+            uid: 0,
+        });
+        Ok(true)
+    }
+
+    /// Imports every message in a Maildir++ tree (ex. one kept by mbsync or offlineimap) into an
+    /// account, with each Maildir folder going into the folder of the same name. Messages that are
+    /// already in the folder are skipped. See [`MailStore::import_message`] for what `upload`
+    /// does.
+    ///
+    /// Returns the number of messages that were imported.
+    pub async fn import_maildir(
+        &self,
+        acct: impl AsRef<str>,
+        root: impl AsRef<Path>,
+        upload: bool,
+    ) -> Result<usize> {
+        let mut imported = 0;
+        for (folder, dir) in maildir::list_folders(root.as_ref()).await? {
+            for message in maildir::list_messages(&dir).await? {
+                let body = fs::read(&message.path)
+                    .await
+                    .with_context(|| format!("error reading {:?}", message.path))?;
+                if self
+                    .import_message(
+                        acct.as_ref(),
+                        &folder,
+                        body,
+                        message.flags,
+                        message.date,
+                        upload,
+                    )
+                    .await?
+                {
+                    imported += 1;
+                }
+            }
+            self.publish_counts(acct.as_ref(), &folder).await?;
+            debug!("imported maildir folder {:?} into {}", dir, folder);
+        }
+        Ok(imported)
+    }
+
+    /// Writes every message in an account out to a Maildir++ tree, with flags stored in the file
+    /// names. Exporting into the same tree again only adds what's new and updates flags, and
+    /// nothing is ever removed from it. Messages whose bodies were evicted from the cache are left
+    /// out.
+    ///
+    /// Returns the number of messages that were exported.
+    pub async fn export_maildir(
+        &self,
+        acct: impl AsRef<str>,
+        root: impl AsRef<Path>,
+    ) -> Result<usize> {
+        let rows: Vec<(String, String, String, String, String)> = {
+            let read = self.inner.read().await;
+            let inner = match &*read {
+                Some(v) => v,
+                None => bail!("mail store isn't ready yet"),
+            };
+            sqlx::query_as(
+                r#"
+                SELECT folder, content_hash, filename, internaldate, flags FROM "mail"
+                JOIN "contents" ON contents.hash = mail.content_hash
+                WHERE account = ? AND NOT contents.evicted AND NOT contents.headers_only
+                "#,
+            )
+            .bind(acct.as_ref())
+            .fetch_all(&inner.pool)
+            .await?
+        };
+
+        let exported = rows.len();
+        let mut by_folder = BTreeMap::<String, Vec<_>>::new();
+        for (folder, hash, filename, internaldate, flags) in rows {
+            by_folder
+                .entry(folder)
+                .or_default()
+                .push((hash, filename, internaldate, flags));
+        }
+
+        for (folder, messages) in by_folder {
+            let mut writer =
+                MaildirWriter::open(maildir::folder_dir(root.as_ref(), &folder)).await?;
+            for (hash, filename, internaldate, flags) in messages {
+                let timestamp = DateTime::parse_from_rfc3339(&internaldate)
+                    .map(|date| date.timestamp())
+                    .unwrap_or_default();
+                let unique = format!("{}.{}.panorama", timestamp, hash);
+                let body = self.read_mail_file(&filename).await?;
+                writer
+                    .write(&unique, &body, &flags_from_string(&flags))
+                    .await?;
+            }
+        }
+        Ok(exported)
+    }
+
+    /// Imports every message in an mbox file into a folder. Messages that are already in the
+    /// folder are skipped. See [`MailStore::import_message`] for what `upload` does.
+    ///
+    /// Returns the number of messages that were imported.
+    pub async fn import_mbox(
+        &self,
+        acct: impl AsRef<str>,
+        folder: impl AsRef<str>,
+        path: impl AsRef<Path>,
+        upload: bool,
+    ) -> Result<usize> {
+        let file = fs::File::open(path.as_ref())
+            .await
+            .with_context(|| format!("error opening mbox {:?}", path.as_ref()))?;
+        let mut reader = MboxReader::new(BufReader::new(file));
+
+        let mut imported = 0;
+        while let Some(message) = reader.next_message().await? {
+            if self
+                .import_message(
+                    acct.as_ref(),
+                    folder.as_ref(),
+                    message.body,
+                    message.flags,
+                    message.date,
+                    upload,
+                )
+                .await?
+            {
+                imported += 1;
+            }
+        }
+        self.publish_counts(acct.as_ref(), folder.as_ref()).await?;
+        debug!("imported mbox {:?} into {}", path.as_ref(), folder.as_ref());
+        Ok(imported)
+    }
+
+    /// Writes every message in a folder out to an mbox file, oldest first. The file is replaced
+    /// if it already exists. Messages whose bodies were evicted from the cache are left out.
+    ///
+    /// Returns the number of messages that were exported.
+    pub async fn export_mbox(
+        &self,
+        acct: impl AsRef<str>,
+        folder: impl AsRef<str>,
+        path: impl AsRef<Path>,
+    ) -> Result<usize> {
+        let rows: Vec<(String, String, String)> = {
+            let read = self.inner.read().await;
+            let inner = match &*read {
+                Some(v) => v,
+                None => bail!("mail store isn't ready yet"),
+            };
+            sqlx::query_as(
+                r#"
+                SELECT filename, internaldate, flags FROM "mail"
+                JOIN "contents" ON contents.hash = mail.content_hash
+                WHERE account = ? AND folder = ? AND NOT contents.evicted
+                    AND NOT contents.headers_only
+                ORDER BY internaldate
+                "#,
+            )
+            .bind(acct.as_ref())
+            .bind(folder.as_ref())
+            .fetch_all(&inner.pool)
+            .await?
+        };
+
+        let file = fs::File::create(path.as_ref())
+            .await
+            .with_context(|| format!("error creating mbox {:?}", path.as_ref()))?;
+        let mut writer = BufWriter::new(file);
+        for (filename, internaldate, flags) in rows.iter() {
+            let date = DateTime::parse_from_rfc3339(internaldate)
+                .map(|date| date.with_timezone(&Utc))
+                .unwrap_or_else(|_| Utc::now());
+            let body = self.read_mail_file(filename).await?;
+            let message = mbox::format_message(&body, &flags_from_string(flags), date);
+            writer.write_all(&message).await?;
+        }
+        writer.flush().await?;
+        Ok(rows.len())
+    }
+
+    /// Queues a folder to be created on the server
+    pub async fn create_folder(&self, acct: impl AsRef<str>, name: impl AsRef<str>) -> Result<()> {
+        let op = MailOperation::CreateFolder {
+            name: name.as_ref().to_owned(),
+        };
+        self.push_operation(acct, op).await
+    }
+
+    /// Queues the messages marked `\Deleted` in a folder to be removed from the server for good,
+    /// which is how deleted messages go away on servers without UIDPLUS
+    pub async fn expunge_folder(
+        &self,
+        acct: impl AsRef<str>,
+        folder: impl AsRef<str>,
+    ) -> Result<()> {
+        let op = MailOperation::Expunge {
+            folder: folder.as_ref().to_owned(),
+        };
+        self.push_operation(acct, op).await
+    }
+
+    /// Deletes a folder and all of its messages locally, and queues the deletion to be made on the
+    /// server
+    pub async fn delete_folder(&self, acct: impl AsRef<str>, name: impl AsRef<str>) -> Result<()> {
+        let read = self.inner.read().await;
+        let inner = match &*read {
+            Some(v) => v,
+            None => return Ok(()),
+        };
+
+        let mut tx = inner.pool.begin().await?;
+        sqlx::query(r#"DELETE FROM "mail" WHERE account = ? AND folder = ?"#)
+            .bind(acct.as_ref())
+            .bind(name.as_ref())
+            .execute(&mut tx)
+            .await?;
+        sqlx::query(r#"DELETE FROM "folders" WHERE account = ? AND folder = ?"#)
+            .bind(acct.as_ref())
+            .bind(name.as_ref())
+            .execute(&mut tx)
+            .await?;
+
+        let op = MailOperation::DeleteFolder {
+            name: name.as_ref().to_owned(),
+        };
+        push_operation(&mut tx, acct.as_ref(), &op).await?;
+        let orphaned = take_orphaned_contents(&mut tx).await?;
+        tx.commit().await?;
+
+        self.publish(MailStoreUpdate::FolderChanged {
+            acct: acct.as_ref().to_owned(),
+            folder: name.as_ref().to_owned(),
+        });
+        inner.remove_orphaned_files(orphaned).await;
+        Ok(())
+    }
+
+    /// Renames a folder locally, and queues the rename to be made on the server
+    pub async fn rename_folder(
+        &self,
+        acct: impl AsRef<str>,
+        from: impl AsRef<str>,
+        to: impl AsRef<str>,
+    ) -> Result<()> {
+        let read = self.inner.read().await;
+        let inner = match &*read {
+            Some(v) => v,
+            None => return Ok(()),
+        };
+
+        // operations that are already queued still refer to the old name, which is fine since
+        // they'll be replayed before the rename is
+        let mut tx = inner.pool.begin().await?;
+        for table in &["mail", "folders"] {
+            sqlx::query(&format!(
+                r#"UPDATE "{}" SET folder = ? WHERE account = ? AND folder = ?"#,
+                table
+            ))
+            .bind(to.as_ref())
+            .bind(acct.as_ref())
+            .bind(from.as_ref())
+            .execute(&mut tx)
+            .await?;
+        }
+
+        let op = MailOperation::RenameFolder {
+            from: from.as_ref().to_owned(),
+            to: to.as_ref().to_owned(),
+        };
+        push_operation(&mut tx, acct.as_ref(), &op).await?;
+        tx.commit().await?;
+
+        for folder in &[from.as_ref(), to.as_ref()] {
+            self.publish(MailStoreUpdate::FolderChanged {
+                acct: acct.as_ref().to_owned(),
+                folder: folder.to_string(),
+            });
+        }
+        Ok(())
+    }
+
+    /// Adds an operation to the end of the journal, to be applied on the server by the mail thread
+    pub async fn push_operation(&self, acct: impl AsRef<str>, op: MailOperation) -> Result<()> {
+        let read = self.inner.read().await;
+        let inner = match &*read {
+            Some(v) => v,
+            None => return Ok(()),
+        };
+
+        let mut tx = inner.pool.begin().await?;
+        push_operation(&mut tx, acct.as_ref(), &op).await?;
+        tx.commit().await?;
+        Ok(())
+    }
+
+    /// Gets the operations for the given account that still need to be applied on the server, in
+    /// the order they were made
+    pub async fn get_pending_operations(
+        &self,
+        acct: impl AsRef<str>,
+    ) -> Result<Vec<(i64, MailOperation)>> {
+        let read = self.inner.read().await;
+        let inner = match &*read {
+            Some(v) => v,
+            None => return Ok(Vec::new()),
+        };
+
+        let rows: Vec<(i64, String)> = sqlx::query_as(
+            r#"
+            SELECT id, payload FROM "operations"
+            WHERE account = ? AND failed = 0
+            ORDER BY id
+            "#,
+        )
+        .bind(acct.as_ref())
+        .fetch_all(&inner.pool)
+        .await?;
+
+        rows.into_iter()
+            .map(|(id, payload)| {
+                let op = serde_json::from_str(&payload)
+                    .with_context(|| format!("error reading operation {}", id))?;
+                Ok((id, op))
+            })
+            .collect()
+    }
+
+    /// Removes an operation from the journal once it's been applied on the server
+    pub async fn complete_operation(&self, id: i64) -> Result<()> {
+        let read = self.inner.read().await;
+        let inner = match &*read {
+            Some(v) => v,
+            None => return Ok(()),
+        };
+        sqlx::query(r#"DELETE FROM "operations" WHERE id = ?"#)
+            .bind(id)
+            .execute(&inner.pool)
+            .await?;
+        Ok(())
+    }
+
+    /// Records that applying an operation on the server failed
+    ///
+    /// Returns true if the operation has failed too many times and won't be retried anymore.
+    pub async fn fail_operation(&self, id: i64, err: &Error) -> Result<bool> {
+        let read = self.inner.read().await;
+        let inner = match &*read {
+            Some(v) => v,
+            None => return Ok(false),
+        };
+        let mut tx = inner.pool.begin().await?;
+        sqlx::query(
+            r#"
+            UPDATE "operations" SET attempts = attempts + 1, last_error = ?
+            WHERE id = ?
+            "#,
+        )
+        .bind(format!("{:#}", err))
+        .bind(id)
+        .execute(&mut tx)
+        .await?;
+        let (attempts,): (u32,) =
+            sqlx::query_as(r#"SELECT attempts FROM "operations" WHERE id = ?"#)
+                .bind(id)
+                .fetch_one(&mut tx)
+                .await?;
+
+        let gave_up = attempts >= MAX_OPERATION_ATTEMPTS;
+        if gave_up {
+            sqlx::query(r#"UPDATE "operations" SET failed = 1 WHERE id = ?"#)
+                .bind(id)
+                .execute(&mut tx)
+                .await?;
+        }
+        tx.commit().await?;
+        Ok(gave_up)
+    }
+
+    /// Gets the name of the file in the mail directory that holds the given message, if it's been
+    /// downloaded and its body hasn't been evicted from the cache since
+    pub async fn get_filename(
+        &self,
+        acct: impl AsRef<str>,
+        folder: impl AsRef<str>,
+        uid: u32,
+    ) -> Result<Option<String>> {
+        let read = self.inner.read().await;
+        let inner = match &*read {
+            Some(v) => v,
+            None => return Ok(None),
+        };
+
+        let filename: Option<(String,)> = sqlx::query_as(
+            r#"
+            SELECT filename FROM "mail"
+            JOIN "contents" ON contents.hash = mail.content_hash
+            WHERE account = ? AND folder = ? AND uid = ? AND NOT contents.evicted
+                AND NOT contents.headers_only
+            ORDER BY mail.rowid DESC
+            LIMIT 1
+            "#,
+        )
+        .bind(acct.as_ref())
+        .bind(folder.as_ref())
+        .bind(uid)
+        .fetch_optional(&inner.pool)
+        .await?;
+        Ok(filename.map(|(filename,)| filename))
+    }
+
+    /// Reads a message from the mail directory
+    pub async fn read_mail_file(&self, filename: impl AsRef<str>) -> Result<Vec<u8>> {
+        let (mail_dir, cipher) = match &*self.inner.read().await {
+            Some(inner) => (inner.mail_dir.clone(), inner.cipher.clone()),
+            None => bail!("mail store isn't ready yet"),
+        };
+        read_body_file(&mail_dir, filename.as_ref(), cipher.as_deref()).await
+    }
+
+    /// Writes a message to the mail directory, returning the hash of its contents
+    async fn write_mail_file(&self, body: &[u8]) -> Result<Option<String>> {
+        let (hash, path, contents) = match &*self.inner.read().await {
+            Some(inner) => {
+                let hash = hash_body(inner.cipher.as_deref(), body);
+                let contents = seal_body(inner.cipher.as_deref(), &hash, body)?;
+                (
+                    hash.clone(),
+                    inner.mail_dir.join(mail_filename(&hash)),
+                    contents,
+                )
+            }
+            None => return Ok(None),
+        };
+        fs::write(path, contents)
+            .await
+            .context("error writing email to file")?;
+        Ok(Some(hash))
+    }
+
+    /// Compares the checksum of an account's config with the one from the last time it was synced,
+    /// and if the account points to a different mailbox now, throws out everything that was cached
+    /// for it. Returns true if the cache was reset.
+    pub async fn check_account_checksum(
+        &self,
+        acct: impl AsRef<str>,
+        checksum: impl AsRef<str>,
+    ) -> Result<bool> {
+        let read = self.inner.read().await;
+        let inner = match &*read {
+            Some(v) => v,
+            None => return Ok(false),
+        };
+
+        let mut tx = inner.pool.begin().await?;
+        let old: Option<(Option<String>,)> =
+            sqlx::query_as(r#"SELECT checksum FROM "accounts" WHERE name = ?"#)
+                .bind(acct.as_ref())
+                .fetch_optional(&mut tx)
+                .await?;
+        let changed = match &old {
+            Some((Some(old),)) => old != checksum.as_ref(),
+            _ => false,
+        };
+
+        let mut orphaned = Vec::new();
+        if changed {
+            for table in &["mail", "folders", "operations", "threads", "local_uids"] {
+                sqlx::query(&format!(r#"DELETE FROM "{}" WHERE account = ?"#, table))
+                    .bind(acct.as_ref())
+                    .execute(&mut tx)
+                    .await?;
+            }
+            orphaned = take_orphaned_contents(&mut tx).await?;
+        }
+
+        sqlx::query(r#"INSERT OR REPLACE INTO "accounts" (name, checksum) VALUES (?, ?)"#)
+            .bind(acct.as_ref())
+            .bind(checksum.as_ref())
+            .execute(&mut tx)
+            .await?;
+        tx.commit().await?;
+
+        if changed {
+            info!(
+                "{} points to a different mailbox now, cleared {} cached files",
+                acct.as_ref(),
+                orphaned.len()
+            );
+            inner.remove_orphaned_files(orphaned).await;
+            self.publish(MailStoreUpdate::AccountListUpdate(()));
+        }
+        Ok(changed)
+    }
+
+    /// Event handerl
+    pub async fn handle_mail_event(&self, evt: MailEvent) -> Result<()> {
+        match evt {
+            MailEvent::FolderList(acct, folders) => {
+                if let Some(acct_ref) = self.get_account(&acct).await {
+                    acct_ref.set_folders(folders).await;
+                }
+            }
+            MailEvent::Quota(acct, quotas) => {
+                if let Some(acct_ref) = self.get_account(&acct).await {
+                    *acct_ref.quotas.write().await = quotas;
+                }
+            }
+            MailEvent::AccountState(acct, state) => {
+                if let Some(acct_ref) = self.get_account(&acct).await {
+                    *acct_ref.state.write().await = Some(state);
+                }
+            }
+            _ => {}
+        }
+        Ok(())
+    }
+
+    /// Gets the handle of an account that's being tracked, without holding onto the lock
+    async fn get_account(&self, acct: &str) -> Option<Arc<AccountRef>> {
+        let read = self.inner.read().await;
+        read.as_ref()?.accounts.get(acct).cloned()
+    }
+
+    /// Whether there are bodies from the mail directory that still have to be matched up with
+    /// messages, after the database was lost or rebuilt
+    pub async fn has_recovered_bodies(&self) -> Result<bool> {
+        let read = self.inner.read().await;
+        let inner = match &*read {
+            Some(v) => v,
+            None => return Ok(false),
+        };
+        let (exists,): (bool,) = sqlx::query_as(
+            r#"
+            SELECT EXISTS (
+                SELECT 1 FROM "recovered_bodies" WHERE hash NOT IN (SELECT hash FROM "contents")
+            )
+            "#,
+        )
+        .fetch_one(&inner.pool)
+        .await?;
+        Ok(exists)
+    }
+
+    /// Stores a message using a body from the mail directory that has the same Message-ID, given
+    /// its flags, INTERNALDATE, and envelope. Returns whether there was a body for it, since it
+    /// has to be downloaded otherwise.
+    pub async fn store_recovered(
+        &self,
+        acct: impl AsRef<str>,
+        folder: impl AsRef<str>,
+        uid: u32,
+        uidvalidity: u32,
+        attrs: Vec<AttributeValue>,
+    ) -> Result<bool> {
+        let mut message_id = None;
+        let mut internaldate = None;
+        let mut flags = Vec::new();
+        for attr in attrs {
+            match attr {
+                AttributeValue::Envelope(envelope) => message_id = envelope.message_id,
+                AttributeValue::InternalDate(date) => internaldate = Some(date),
+                AttributeValue::Flags(new_flags) => flags = new_flags,
+                _ => {}
+            }
+        }
+        let (message_id, internaldate) = match (message_id, internaldate) {
+            (Some(message_id), Some(internaldate)) => (message_id, internaldate),
+            _ => return Ok(false),
+        };
+
+        let read = self.inner.read().await;
+        let inner = match &*read {
+            Some(v) => v,
+            None => return Ok(false),
+        };
+        let hashes: Vec<(String,)> =
+            sqlx::query_as(r#"SELECT hash FROM "recovered_bodies" WHERE message_id = ?"#)
+                .bind(&message_id)
+                .fetch_all(&inner.pool)
+                .await?;
+        let mut body = None;
+        for (hash,) in hashes {
+            let filename = mail_filename(&hash);
+            match read_body_file(&inner.mail_dir, &filename, inner.cipher.as_deref()).await {
+                Ok(v) => {
+                    body = Some(v);
+                    break;
+                }
+                // the file was removed since (ex. every message that had this body was deleted)
+                Err(err) => {
+                    debug!("recovered body {} is gone: {:?}", hash, err);
+                    sqlx::query(r#"DELETE FROM "recovered_bodies" WHERE hash = ?"#)
+                        .bind(&hash)
+                        .execute(&inner.pool)
+                        .await?;
+                }
+            }
+        }
+        mem::drop(read);
+
+        match body {
+            Some(body) => {
+                self.store_message(acct, folder, uid, uidvalidity, body, internaldate, flags)
+                    .await?;
+                Ok(true)
+            }
+            None => Ok(false),
+        }
+    }
+
+    /// Checks the database for damage, and fixes anything in it that doesn't agree with what's in
+    /// the mail directory
+    pub async fn check_database(&self) -> Result<DatabaseCheck> {
+        let read = self.inner.read().await;
+        let inner = match &*read {
+            Some(v) => v,
+            None => bail!("mail store isn't ready yet"),
+        };
+        let mut check = DatabaseCheck::default();
+
+        let rows: Vec<(String,)> = sqlx::query_as("PRAGMA integrity_check")
+            .fetch_all(&inner.pool)
+            .await?;
+        check.errors = rows
+            .into_iter()
+            .map(|(row,)| row)
+            .filter(|row| row != "ok")
+            .collect();
+        if !check.errors.is_empty() {
+            return Ok(check);
+        }
+
+        // the triggers should keep these right, but if they're off, bodies could be removed while
+        // they're still needed (or never be removed at all)
+        let mut tx = inner.pool.begin().await?;
+        sqlx::query(
+            r#"
+            INSERT OR IGNORE INTO "contents" (hash)
+            SELECT DISTINCT content_hash FROM "mail" WHERE content_hash IS NOT NULL
+            "#,
+        )
+        .execute(&mut tx)
+        .await?;
+        check.recounted = sqlx::query(
+            r#"
+            UPDATE "contents" SET refcount = (
+                SELECT COUNT(*) FROM "mail" WHERE content_hash = contents.hash
+            )
+            WHERE refcount != (SELECT COUNT(*) FROM "mail" WHERE content_hash = contents.hash)
+            "#,
+        )
+        .execute(&mut tx)
+        .await?
+        .rows_affected();
+        let orphaned = take_orphaned_contents(&mut tx).await?;
+        tx.commit().await?;
+        inner.remove_orphaned_files(orphaned).await;
+
+        // bodies whose files are gone can still be downloaded again, the same as evicted ones
+        let cached: Vec<(String,)> =
+            sqlx::query_as(r#"SELECT hash FROM "contents" WHERE NOT evicted"#)
+                .fetch_all(&inner.pool)
+                .await?;
+        let missing = cached
+            .into_iter()
+            .map(|(hash,)| hash)
+            .filter(|hash| !inner.mail_dir.join(mail_filename(hash)).exists())
+            .collect::<Vec<_>>();
+        check.missing = missing.len();
+        inner.evict_bodies(missing).await?;
+
+        let known: Vec<(String,)> = sqlx::query_as(
+            r#"SELECT hash FROM "contents" UNION SELECT hash FROM "recovered_bodies""#,
+        )
+        .fetch_all(&inner.pool)
+        .await?;
+        let known = known
+            .into_iter()
+            .map(|(hash,)| hash)
+            .collect::<HashSet<_>>();
+        let mut entries = fs::read_dir(&inner.mail_dir).await?;
+        while let Some(entry) = entries.next_entry().await? {
+            let filename = entry.file_name().to_string_lossy().into_owned();
+            if matches!(filename.strip_suffix(".mail"), Some(hash) if !known.contains(hash)) {
+                check.unreferenced += 1;
+            }
+        }
+
+        Ok(check)
+    }
+
+    /// Compacts the database file, returning how big it was before and after, in bytes
+    pub async fn vacuum_database(&self) -> Result<(u64, u64)> {
+        let read = self.inner.read().await;
+        let inner = match &*read {
+            Some(v) => v,
+            None => bail!("mail store isn't ready yet"),
+        };
+        let before = database_size(&inner.db_path).await;
+
+        // recovered bodies that were matched up with a message are known about the usual way now
+        sqlx::query(
+            r#"DELETE FROM "recovered_bodies" WHERE hash IN (SELECT hash FROM "contents")"#,
+        )
+        .execute(&inner.pool)
+        .await?;
+        sqlx::query("VACUUM").execute(&inner.pool).await?;
+        // VACUUM goes through the write-ahead log, which only shrinks after a checkpoint
+        sqlx::query("PRAGMA wal_checkpoint(TRUNCATE)")
+            .execute(&inner.pool)
+            .await?;
+        sqlx::query("PRAGMA optimize").execute(&inner.pool).await?;
+
+        let after = database_size(&inner.db_path).await;
+        Ok((before, after))
+    }
+
+    /// Starts the database over from what's in the mail directory, keeping the old one next to it
+    /// with the time in its name (ex. `panorama.db.20210301-142000.old`). Everything that's only in
+    /// the database (ex. changes that haven't been made on the server yet) is lost, and the bodies
+    /// in the mail directory are matched back up with messages as accounts are synced. Returns how
+    /// many bodies were found.
+    pub async fn rebuild_database(&self) -> Result<usize> {
+        let config = match &*self.config.read().await {
+            Some(v) => v.clone(),
+            None => bail!("mail store isn't ready yet"),
+        };
+
+        let mut write = self.inner.write().await;
+        if let Some(old) = write.take() {
+            old.pool.close().await;
+        }
+        let new_inner = MailStoreInner::init_with_config(config, true).await?;
+        let (recovered,): (i64,) = sqlx::query_as(r#"SELECT COUNT(*) FROM "recovered_bodies""#)
+            .fetch_one(&new_inner.pool)
+            .await?;
+        write.replace(new_inner);
+        mem::drop(write);
+
+        self.publish(MailStoreUpdate::AccountListUpdate(()));
+        Ok(recovered as usize)
+    }
+
+    /// Return a map of the accounts that are currently being tracked as well as a reference to the
+    /// account handles themselves
+    pub async fn list_accounts(&self) -> IndexMap<String, Arc<AccountRef>> {
+        let read = self.inner.read().await;
+        let inner = match read.as_ref() {
+            Some(v) => v,
+            None => return IndexMap::new(),
+        };
+
+        inner.accounts.clone()
+    }
+}
+
+/// Keeps the cache within each account's limits for as long as the store is around
+async fn run_compactor(mail_store: MailStore) {
+    let mut interval = tokio::time::interval(COMPACT_INTERVAL);
+    loop {
+        interval.tick().await;
+        if let Err(err) = mail_store.compact().await {
+            error!("error evicting bodies from the cache: {:?}", err);
+        }
+    }
+}
+
+async fn mail_store_config_listener(
+    mut config_watcher: ConfigWatcher,
+    config: Arc<RwLock<Option<Config>>>,
+    inner: Arc<RwLock<Option<MailStoreInner>>>,
+    setup_error: Arc<RwLock<Option<String>>>,
+    store_out_tx: broadcast::Sender<MailStoreUpdate>,
+) -> Result<()> {
+    while let Ok(()) = config_watcher.changed().await {
+        let new_config = config_watcher.borrow().clone();
+
+        let fut = future::try_join(
+            async {
+                let mut write = config.write().await;
+                write.replace(new_config.clone());
+                Ok::<_, Error>(())
+            },
+            async {
+                let new_inner = MailStoreInner::init_with_config(new_config.clone(), false).await?;
+                let mut write = inner.write().await;
+                write.replace(new_inner);
+                Ok(())
+            },
+        );
+
+        match fut.await {
+            Ok(_) => {
+                setup_error.write().await.take();
+                let _ = store_out_tx.send(MailStoreUpdate::AccountListUpdate(()));
+            }
+            Err(e) => {
+                // whatever was set up from the last config keeps working, and this is tried again
+                // the next time the config changes
+                error!("couldn't set up the mail store: {:?}", e);
+                setup_error.write().await.replace(format!("{:#}", e));
+                let _ = store_out_tx.send(MailStoreUpdate::SetupFailed(format!("{:#}", e)));
+            }
+        };
+    }
+
+    Ok(())
+}
+
+impl MailStoreInner {
+    /// Removes the files of bodies that were orphaned, unless something started referring to them
+    /// again in the meantime (ex. the same message was downloaded into another folder)
+    async fn remove_orphaned_files(&self, hashes: Vec<String>) {
+        let _lock = self.files_lock.lock().await;
+        for hash in hashes {
+            let refcount: Option<(i64,)> =
+                match sqlx::query_as(r#"SELECT refcount FROM "contents" WHERE hash = ?"#)
+                    .bind(&hash)
+                    .fetch_optional(&self.pool)
+                    .await
+                {
+                    Ok(v) => v,
+                    Err(err) => {
+                        warn!("error checking references to {}: {}", hash, err);
+                        continue;
+                    }
+                };
+            if matches!(refcount, Some((refcount,)) if refcount > 0) {
+                continue;
+            }
+
+            // evicted bodies don't have a file anymore
+            let path = self.mail_dir.join(mail_filename(&hash));
+            match fs::remove_file(&path).await {
+                Err(err) if err.kind() != io::ErrorKind::NotFound => {
+                    warn!("error removing orphaned email file {:?}: {}", path, err);
+                }
+                _ => {}
+            }
+        }
+    }
+
+    /// Marks bodies as evicted from the cache and removes their files, unless they were
+    /// downloaded again in the meantime
+    async fn evict_bodies(&self, hashes: Vec<String>) -> Result<()> {
+        if hashes.is_empty() {
+            return Ok(());
+        }
+        let mut tx = self.pool.begin().await?;
+        for hash in hashes.iter() {
+            sqlx::query(r#"UPDATE "contents" SET evicted = 1 WHERE hash = ?"#)
+                .bind(hash)
+                .execute(&mut tx)
+                .await?;
+        }
+        tx.commit().await?;
+
+        let _lock = self.files_lock.lock().await;
+        for hash in hashes {
+            let evicted: Option<(bool,)> =
+                sqlx::query_as(r#"SELECT evicted FROM "contents" WHERE hash = ?"#)
+                    .bind(&hash)
+                    .fetch_optional(&self.pool)
+                    .await?;
+            if !matches!(evicted, Some((true,))) {
+                continue;
+            }
+
+            let path = self.mail_dir.join(mail_filename(&hash));
+            match fs::remove_file(&path).await {
+                Err(err) if err.kind() != io::ErrorKind::NotFound => {
+                    warn!("error removing evicted email file {:?}: {}", path, err);
+                }
+                _ => {}
+            }
+        }
+        Ok(())
+    }
+
+    /// Evicts the bodies of an account's messages that its policy says shouldn't be kept anymore,
+    /// returning how many were evicted. A body that's shared by more than one message (ex. copies
+    /// in Gmail's "All Mail") is only evicted if it can go for all of them, and bodies that can't
+    /// be downloaded again (ex. messages that haven't been uploaded yet) are always kept.
+    async fn compact_account(&self, acct: &str, policy: &CachePolicy) -> Result<usize> {
+        let rows: Vec<(String, String, String, u32, String, Option<i64>)> = sqlx::query_as(
+            r#"
+            SELECT mail.content_hash, mail.account, mail.folder, mail.uid, mail.internaldate,
+                mail.size
+            FROM "mail" JOIN "contents" ON contents.hash = mail.content_hash
+            WHERE NOT contents.evicted AND NOT contents.headers_only AND contents.hash IN (
+                SELECT content_hash FROM "mail" WHERE account = ?
+            )
+            "#,
+        )
+        .bind(acct)
+        .fetch_all(&self.pool)
+        .await?;
+
+        let cutoff = policy
+            .keep_days
+            .map(|days| Utc::now() - chrono::Duration::days(days as i64));
+        let mut bodies = HashMap::<String, CachedBody>::new();
+        for (hash, account, folder, uid, internaldate, size) in rows {
+            let date = DateTime::parse_from_rfc3339(&internaldate)
+                .map(|date| date.with_timezone(&Utc))
+                .ok();
+            let expired = policy.headers_only.contains(&folder)
+                || matches!((cutoff, date), (Some(cutoff), Some(date)) if date < cutoff);
+
+            let body = bodies.entry(hash).or_insert_with(|| CachedBody {
+                removable: true,
+                expired: true,
+                date,
+                size: 0,
+            });
+            body.removable &= account == acct && uid > 0;
+            body.expired &= expired;
+            body.date = cmp::max(body.date, date);
+            body.size = cmp::max(body.size, size.unwrap_or(0) as u64);
+        }
+
+        let mut evict = Vec::new();
+        let mut kept = Vec::new();
+        for (hash, body) in bodies {
+            if body.removable && body.expired {
+                evict.push(hash);
+            } else {
+                kept.push((hash, body));
+            }
+        }
+
+        // after that, the oldest messages go until everything fits
+        if let Some(max_size_mb) = policy.max_size_mb {
+            let max_size = max_size_mb * 1024 * 1024;
+            let mut total = kept.iter().map(|(_, body)| body.size).sum::<u64>();
+            kept.sort_by_key(|(_, body)| body.date);
+            for (hash, body) in kept {
+                if total <= max_size {
+                    break;
+                }
+                if body.removable {
+                    total -= body.size;
+                    evict.push(hash);
+                }
+            }
+        }
+
+        let evicted = evict.len();
+        self.evict_bodies(evict).await?;
+        Ok(evicted)
+    }
+
+    /// Writes a body back to the mail directory if its file was removed while the message was
+    /// being stored
+    async fn restore_mail_file(&self, hash: &str, body: &[u8]) -> Result<()> {
+        let _lock = self.files_lock.lock().await;
+        let path = self.mail_dir.join(mail_filename(hash));
+        if !path.exists() {
+            fs::write(&path, seal_body(self.cipher.as_deref(), hash, body)?)
+                .await
+                .context("error writing email to file")?;
+        }
+        Ok(())
+    }
+
+    /// Opens the database and the mail directory for the given config. If `rebuild` is set, the
+    /// database is started over from what's in the mail directory.
+    async fn init_with_config(config: Config, rebuild: bool) -> Result<Self> {
+        let data_dir = config.data_dir.to_string_lossy();
+        let data_dir = PathBuf::from(shellexpand::tilde(data_dir.as_ref()).as_ref());
+
+        let mail_dir = data_dir.join("mail");
+        if !mail_dir.exists() {
+            fs::create_dir_all(&mail_dir).await?;
+        }
+        info!("using mail dir: {:?}", mail_dir);
+
         // create database parent
         let db_path = data_dir.join("panorama.db");
         let db_parent = db_path.parent();
         if let Some(path) = db_parent {
             fs::create_dir_all(path).await?;
         }
+        info!("using database path: {:?}", db_path);
+
+        let encrypted = config.encryption.is_some();
+        let (pool, is_new) = open_database(&db_path, rebuild, encrypted).await?;
+        debug!("run migrations : {:?}", MIGRATOR);
+        let salt_path = data_dir.join("encryption");
+        let cipher = load_cipher(&pool, config.encryption.as_ref(), &salt_path)
+            .await?
+            .map(Arc::new);
+        backfill_metadata(&pool, &mail_dir, cipher.as_deref()).await?;
+        if let Some(cipher) = &cipher {
+            if encrypt_existing(&pool, &mail_dir, cipher).await? {
+                // the backups from before this are full copies of the mail that was just encrypted
+                prune_database_backups(&db_path, 0).await?;
+            }
+        }
+        backfill_threads(&pool, cipher.as_deref()).await?;
+
+        // if the database was lost, the bodies in the mail directory are all that's left of it
+        if is_new {
+            let recovered = index_mail_dir(&pool, &mail_dir, cipher.as_deref()).await?;
+            if recovered > 0 {
+                info!(
+                    "found {} message bodies in the mail directory, which will be matched up with \
+                     messages as they're synced",
+                    recovered
+                );
+            }
+        }
+
+        let accounts = config
+            .mail_accounts
+            .keys()
+            .map(|acct| {
+                let folders = RwLock::new(Vec::new());
+                let quotas = RwLock::new(Vec::new());
+                let state = RwLock::new(None);
+                (
+                    acct.to_owned(),
+                    Arc::new(AccountRef {
+                        name: acct.to_owned(),
+                        folders,
+                        quotas,
+                        state,
+                        pool: pool.clone(),
+                        cipher: cipher.clone(),
+                    }),
+                )
+            })
+            .collect();
+
+        Ok(MailStoreInner {
+            files_lock: Mutex::new(()),
+            db_path,
+            mail_dir,
+            pool,
+            accounts,
+            cipher,
+        })
+    }
+}
+
+#[derive(Debug)]
+/// Holds a reference to an account
+pub struct AccountRef {
+    name: String,
+    folders: RwLock<Vec<FolderMetadata>>,
+    quotas: RwLock<Vec<Quota>>,
+    state: RwLock<Option<AccountState>>,
+    pool: SqlitePool,
+    cipher: Option<Arc<Cipher>>,
+}
+
+impl AccountRef {
+    /// Gets the folders on this account, ordered by their role and then by name
+    pub async fn get_folders(&self) -> Vec<FolderMetadata> {
+        self.folders.read().await.clone()
+    }
+
+    /// Sets the folders on this account
+    pub async fn set_folders(&self, mut folders: Vec<FolderMetadata>) {
+        // folders without a role go after all the ones that have one
+        folders.sort_by(|a, b| {
+            (a.role.is_none(), a.role, &a.name).cmp(&(b.role.is_none(), b.role, &b.name))
+        });
+        *self.folders.write().await = folders;
+    }
+
+    /// Gets the role that was detected for the given folder
+    pub async fn get_folder_role(&self, folder: impl AsRef<str>) -> Option<FolderRole> {
+        let folder = folder.as_ref();
+        self.folders
+            .read()
+            .await
+            .iter()
+            .find(|meta| meta.name == folder)
+            .and_then(|meta| meta.role)
+    }
+
+    /// Gets the name of the folder that plays the given role on this account, if there is one
+    pub async fn get_folder_by_role(&self, role: FolderRole) -> Option<String> {
+        self.folders
+            .read()
+            .await
+            .iter()
+            .find(|meta| meta.role == Some(role))
+            .map(|meta| meta.name.clone())
+    }
+
+    /// Gets the last known state of the account's connection
+    pub async fn get_state(&self) -> Option<AccountState> {
+        *self.state.read().await
+    }
+
+    /// Gets the storage usage and limit of the account in KiB, if the server reported a storage
+    /// quota for it
+    pub async fn get_storage_usage(&self) -> Option<(u64, u64)> {
+        self.quotas
+            .read()
+            .await
+            .iter()
+            .filter_map(|quota| quota.get(&QuotaResourceName::Storage))
+            .map(|resource| (resource.usage, resource.limit))
+            .min_by_key(|(usage, limit)| limit.saturating_sub(*usage))
+    }
+
+    /// Gets a page of the messages in the given folder, newest first
+    pub async fn get_messages(
+        &self,
+        folder: impl AsRef<str>,
+        offset: usize,
+        limit: usize,
+    ) -> Result<Vec<EmailMetadata>> {
+        let folder = folder.as_ref();
+        let messages: Vec<EmailMetadata> = sqlx::query_as(&format!(
+            r#"
+            {}
+            WHERE account = ? AND folder = ?
+            ORDER BY internaldate DESC, id DESC
+            LIMIT ? OFFSET ?
+            "#,
+            EMAIL_METADATA_SELECT
+        ))
+        .bind(&self.name)
+        .bind(folder)
+        .bind(limit as i64)
+        .bind(offset as i64)
+        .fetch(&self.pool)
+        .map_ok(|row| email_metadata_from_row(row, self.cipher.as_deref()))
+        .try_collect()
+        .await?;
+        debug!("found {} messages", messages.len());
+        Ok(messages)
+    }
+
+    /// Gets a page of every message in the account, newest first. Copies of the same message in
+    /// more than one folder (ex. Gmail's "All Mail") only show up once, and list every folder
+    /// they're in.
+    pub async fn get_all_messages(
+        &self,
+        offset: usize,
+        limit: usize,
+    ) -> Result<Vec<EmailMetadata>> {
+        let messages: Vec<EmailMetadata> = sqlx::query_as(&format!(
+            r#"
+            {}
+            WHERE id IN (
+                SELECT MIN(id) FROM "mail" WHERE account = ?
+                GROUP BY COALESCE(message_id, content_hash, id)
+            )
+            ORDER BY internaldate DESC, id DESC
+            LIMIT ? OFFSET ?
+            "#,
+            EMAIL_METADATA_SELECT
+        ))
+        .bind(&self.name)
+        .bind(limit as i64)
+        .bind(offset as i64)
+        .fetch(&self.pool)
+        .map_ok(|row| email_metadata_from_row(row, self.cipher.as_deref()))
+        .try_collect()
+        .await?;
+        Ok(messages)
+    }
+
+    /// Gets a single message by its UID, or the newest one if there's more than one (ex. messages
+    /// that were moved or appended locally all have a UID of 0 until they're synced)
+    pub async fn get_message(
+        &self,
+        folder: impl AsRef<str>,
+        uid: u32,
+    ) -> Result<Option<EmailMetadata>> {
+        let row = sqlx::query_as(&format!(
+            r#"
+            {}
+            WHERE account = ? AND folder = ? AND uid = ?
+            ORDER BY id DESC
+            LIMIT 1
+            "#,
+            EMAIL_METADATA_SELECT
+        ))
+        .bind(&self.name)
+        .bind(folder.as_ref())
+        .bind(uid)
+        .fetch_optional(&self.pool)
+        .await?;
+        Ok(row.map(|row| email_metadata_from_row(row, self.cipher.as_deref())))
+    }
+
+    /// Gets a page of the conversations that have messages in the given folder, most recently
+    /// active first. Counts include the messages in other folders (ex. replies in Sent), and
+    /// copies of the same message in more than one folder are only counted once.
+    pub async fn get_threads(
+        &self,
+        folder: impl AsRef<str>,
+        offset: usize,
+        limit: usize,
+    ) -> Result<Vec<ThreadSummary>> {
+        let rows: Vec<(i64, String, u32, u32, i64)> = sqlx::query_as(
+            r#"
+            SELECT
+                threads.id, threads.subject,
+                COUNT(DISTINCT COALESCE(mail.message_id, mail.id)),
+                COUNT(DISTINCT CASE
+                    WHEN instr(' ' || mail.flags || ' ', ' \Seen ') = 0
+                    THEN COALESCE(mail.message_id, mail.id)
+                END),
+                (
+                    SELECT latest.id FROM "mail" AS latest
+                    WHERE latest.account = threads.account AND latest.thread_id = threads.id
+                    ORDER BY latest.internaldate DESC, latest.id DESC
+                    LIMIT 1
+                )
+            FROM "threads" JOIN "mail" ON mail.thread_id = threads.id
+            WHERE threads.account = ? AND threads.id IN (
+                SELECT thread_id FROM "mail" WHERE account = ? AND folder = ?
+            )
+            GROUP BY threads.id
+            ORDER BY MAX(mail.internaldate) DESC, threads.id DESC
+            LIMIT ? OFFSET ?
+            "#,
+        )
+        .bind(&self.name)
+        .bind(&self.name)
+        .bind(folder.as_ref())
+        .bind(limit as i64)
+        .bind(offset as i64)
+        .fetch_all(&self.pool)
+        .await?;
+
+        if rows.is_empty() {
+            return Ok(Vec::new());
+        }
+
+        // the newest message of every thread on the page is looked up all at once
+        let placeholders = vec!["?"; rows.len()].join(", ");
+        let query = format!("{} WHERE id IN ({})", EMAIL_METADATA_SELECT, placeholders);
+        let mut latest_query = sqlx::query_as(&query);
+        for (_, _, _, _, latest_id) in rows.iter() {
+            latest_query = latest_query.bind(latest_id);
+        }
+        let latest_rows: Vec<EmailMetadataRow> = latest_query.fetch_all(&self.pool).await?;
+        let mut latest = latest_rows
+            .into_iter()
+            .map(|row| (row.0, row))
+            .collect::<HashMap<_, _>>();
+
+        let cipher = self.cipher.as_deref();
+        let mut threads = Vec::with_capacity(rows.len());
+        for (id, subject, messages, unread, latest_id) in rows {
+            let latest = latest
+                .remove(&latest_id)
+                .with_context(|| format!("thread {} has no messages", id))?;
+            threads.push(ThreadSummary {
+                id,
+                subject: open_column(cipher, "threads", "subject", id, Some(subject))
+                    .unwrap_or_default(),
+                messages,
+                unread,
+                latest: email_metadata_from_row(latest, cipher),
+            });
+        }
+        Ok(threads)
+    }
+
+    /// Gets the messages in a conversation, arranged by who replied to who
+    pub async fn get_thread(&self, thread_id: i64) -> Result<Vec<ThreadNode<EmailMetadata>>> {
+        let headers: Vec<ThreadHeadersRow> = sqlx::query_as(
+            r#"
+            SELECT id, message_id, in_reply_to, "references", subject FROM "mail"
+            WHERE account = ? AND thread_id = ?
+            ORDER BY internaldate, id
+            "#,
+        )
+        .bind(&self.name)
+        .bind(thread_id)
+        .fetch_all(&self.pool)
+        .await?;
+
+        let meta_rows: Vec<EmailMetadataRow> = sqlx::query_as(&format!(
+            "{} WHERE account = ? AND thread_id = ?",
+            EMAIL_METADATA_SELECT
+        ))
+        .bind(&self.name)
+        .bind(thread_id)
+        .fetch_all(&self.pool)
+        .await?;
+        let mut metas = meta_rows
+            .into_iter()
+            .map(|row| (row.0, row))
+            .collect::<HashMap<_, _>>();
+
+        let mut messages = Vec::with_capacity(headers.len());
+        for (id, message_id, in_reply_to, references, subject) in headers {
+            // a message that was deleted in between the two queries
+            let meta = match metas.remove(&id) {
+                Some(v) => v,
+                None => continue,
+            };
+            let subject = open_column(self.cipher.as_deref(), "mail", "subject", id, subject);
+            messages.push(ThreadMessage::new(
+                message_id.as_deref(),
+                in_reply_to.as_deref(),
+                references.as_deref(),
+                subject.as_deref(),
+                email_metadata_from_row(meta, self.cipher.as_deref()),
+            ));
+        }
+        Ok(thread::thread(messages))
+    }
+}
+
+/// The columns that make up an [`EmailMetadata`], in the order that [`email_metadata_from_row`]
+/// expects them
+const EMAIL_METADATA_SELECT: &str = r#"
+    SELECT
+        id, uid, folder, internaldate, subject, flags, has_attachment, snippet,
+        (
+            SELECT group_concat(
+                id || ' ' || CASE WHEN name IS NULL THEN 'address' ELSE 'name' END || ' '
+                    || COALESCE(name, address),
+                char(10)
+            ) FROM "addresses"
+            WHERE mail_id = mail.id AND kind = 'from'
+        ),
+        (
+            SELECT group_concat(copy.folder, char(10)) FROM "mail" AS copy
+            WHERE copy.account = mail.account AND (
+                copy.message_id = mail.message_id
+                OR (mail.message_id IS NULL AND copy.content_hash = mail.content_hash)
+            )
+        )
+    FROM "mail"
+"#;
+
+type EmailMetadataRow = (
+    i64,
+    u32,
+    String,
+    String,
+    Option<String>,
+    String,
+    bool,
+    Option<String>,
+    Option<String>,
+    Option<String>,
+);
+
+fn email_metadata_from_row(
+    (id, uid, folder, date, subject, flags, has_attachment, snippet, from, folders): EmailMetadataRow,
+    cipher: Option<&Cipher>,
+) -> EmailMetadata {
+    // each sender comes with the rowid and column it's from, since that's what it's sealed with
+    let from = from
+        .as_deref()
+        .unwrap_or_default()
+        .split('\n')
+        .filter_map(|sender| {
+            let mut parts = sender.splitn(3, ' ');
+            let rowid = parts.next()?.parse().ok()?;
+            let column = parts.next()?;
+            let value = parts.next()?.to_owned();
+            open_column(cipher, "addresses", column, rowid, Some(value))
+        })
+        .filter(|sender| !sender.is_empty())
+        .collect::<Vec<_>>();
+    let mut folders = folders
+        .as_deref()
+        .unwrap_or_default()
+        .split('\n')
+        .filter(|folder| !folder.is_empty())
+        .map(|folder| folder.to_owned())
+        .collect::<Vec<_>>();
+    folders.sort();
+    folders.dedup();
+    EmailMetadata {
+        uid: Some(uid),
+        folder,
+        folders,
+        unread: !flags_from_string(&flags).contains(&MailboxFlag::Seen),
+        date: Some(
+            DateTime::parse_from_rfc3339(&date)
+                .unwrap()
+                .with_timezone(&Local),
+        ),
+        from: from.join(", "),
+        subject: open_column(cipher, "mail", "subject", id, subject).unwrap_or_default(),
+        has_attachment,
+        snippet: open_column(cipher, "mail", "snippet", id, snippet).unwrap_or_default(),
+    }
+}
+
+/// The number of times applying an operation on the server is tried before giving up on it
+const MAX_OPERATION_ATTEMPTS: u32 = 5;
+
+async fn push_operation(
+    tx: &mut Transaction<'_, Sqlite>,
+    acct: &str,
+    op: &MailOperation,
+) -> Result<()> {
+    let (folder, uidvalidity, uid) = match op.message() {
+        Some((folder, uidvalidity, uid)) => (Some(folder), Some(uidvalidity), Some(uid)),
+        None => (None, None, None),
+    };
+    sqlx::query(
+        r#"
+        INSERT INTO "operations" (
+            account, kind, folder, uidvalidity, uid, payload, created_at
+        ) VALUES (?, ?, ?, ?, ?, ?, ?)
+        "#,
+    )
+    .bind(acct)
+    .bind(op.kind())
+    .bind(folder)
+    .bind(uidvalidity)
+    .bind(uid)
+    .bind(serde_json::to_string(op)?)
+    .bind(Utc::now().timestamp_millis())
+    .execute(&mut *tx)
+    .await
+    .context("error saving operation to the journal")?;
+    Ok(())
+}
+
+/// Finds the row and UIDVALIDITY of the newest message in a folder with the given UID
+async fn find_message(
+    tx: &mut Transaction<'_, Sqlite>,
+    acct: &str,
+    folder: &str,
+    uid: u32,
+) -> Result<(i64, u32)> {
+    sqlx::query_as(
+        r#"
+        SELECT rowid, uidvalidity FROM "mail"
+        WHERE account = ? AND folder = ? AND uid = ?
+        ORDER BY rowid DESC
+        LIMIT 1
+        "#,
+    )
+    .bind(acct)
+    .bind(folder)
+    .bind(uid)
+    .fetch_one(&mut *tx)
+    .await
+    .with_context(|| format!("could not find email with uid {}", uid))
+}
+
+/// How many characters of the body are kept as a preview
+const SNIPPET_LENGTH: usize = 200;
+
+/// The headers that addresses are saved from
+const ADDRESS_HEADERS: &[&str] = &["from", "sender", "reply-to", "to", "cc", "bcc"];
+
+/// Metadata read out of a message when it's stored
+#[derive(Debug, Default)]
+struct ParsedMessage {
+    message_id: Option<String>,
+    subject: Option<String>,
+    date: Option<DateTime<Utc>>,
+    size: usize,
+    in_reply_to: Option<String>,
+    references: Option<String>,
+    /// (header, display name, address)
+    addresses: Vec<(&'static str, Option<String>, String)>,
+    has_attachment: bool,
+    snippet: Option<String>,
+}
+
+/// Parses the headers and structure of a message
+fn parse_message(body: &[u8]) -> Result<ParsedMessage> {
+    // attachments can be big, and all that matters here is whether there are any
+    let mail = Message::parse_without_attachments(body)?;
+    let mut message = ParsedMessage {
+        size: body.len(),
+        has_attachment: !mail.attachments().is_empty(),
+        ..ParsedMessage::default()
+    };
+
+    for (key, value) in mail.headers.iter() {
+        let key = key.to_ascii_lowercase();
+        match key.as_str() {
+            "message-id" => message.message_id = Some(value.trim().to_owned()),
+            "subject" => message.subject = Some(value.clone()),
+            "date" => {
+                message.date = mailparse::dateparse(value)
+                    .ok()
+                    .and_then(|timestamp| Utc.timestamp_opt(timestamp, 0).single());
+            }
+            "in-reply-to" => message.in_reply_to = Some(value.trim().to_owned()),
+            "references" => {
+                message.references = Some(value.split_whitespace().collect::<Vec<_>>().join(" "))
+            }
+            _ => {
+                if let Some(kind) = ADDRESS_HEADERS.iter().find(|kind| **kind == key) {
+                    parse_addresses(kind, value, &mut message.addresses);
+                }
+            }
+        }
+    }
+
+    // skip over quoted replies, since they're not what's new in the message
+    let snippet = mail
+        .text()
+        .lines()
+        .filter(|line| !line.trim_start().starts_with('>'))
+        .flat_map(|line| line.split_whitespace())
+        .collect::<Vec<_>>()
+        .join(" ")
+        .chars()
+        .take(SNIPPET_LENGTH)
+        .collect::<String>();
+    if !snippet.is_empty() {
+        message.snippet = Some(snippet);
+    }
+    Ok(message)
+}
+
+/// Parses the addresses out of an address header like From or To
+fn parse_addresses(
+    kind: &'static str,
+    value: &str,
+    addresses: &mut Vec<(&'static str, Option<String>, String)>,
+) {
+    // a malformed address header shouldn't keep the message from being stored
+    let addrs = match mailparse::addrparse(value) {
+        Ok(v) => v,
+        Err(err) => {
+            warn!("error parsing {} header {:?}: {}", kind, value, err);
+            return;
+        }
+    };
+    for addr in addrs.iter() {
+        match addr {
+            MailAddr::Single(info) => {
+                addresses.push((kind, info.display_name.clone(), info.addr.clone()));
+            }
+            MailAddr::Group(group) => {
+                for info in group.addrs.iter() {
+                    addresses.push((kind, info.display_name.clone(), info.addr.clone()));
+                }
+            }
+        }
+    }
+}
+
+/// Saves the parsed metadata of a message onto its row, replacing anything that was there
+async fn save_message_metadata(
+    tx: &mut Transaction<'_, Sqlite>,
+    cipher: Option<&Cipher>,
+    mail_id: i64,
+    message: &ParsedMessage,
+) -> Result<()> {
+    let snippet = message.snippet.as_deref();
+    sqlx::query(
+        r#"
+        UPDATE "mail" SET
+            date = ?, size = ?, in_reply_to = ?, "references" = ?, has_attachment = ?, snippet = ?
+        WHERE id = ?
+        "#,
+    )
+    .bind(message.date.map(|date| date.to_rfc3339()))
+    .bind(message.size as i64)
+    .bind(&message.in_reply_to)
+    .bind(&message.references)
+    .bind(message.has_attachment)
+    .bind(seal_column(cipher, "mail", "snippet", mail_id, snippet)?)
+    .bind(mail_id)
+    .execute(&mut *tx)
+    .await?;
+
+    sqlx::query(r#"DELETE FROM "addresses" WHERE mail_id = ?"#)
+        .bind(mail_id)
+        .execute(&mut *tx)
+        .await?;
+    let mut positions = HashMap::new();
+    for (kind, name, address) in message.addresses.iter() {
+        let position = positions.entry(kind).or_insert(0u32);
+        let id = sqlx::query(
+            r#"
+            INSERT INTO "addresses" (mail_id, kind, position, name, address)
+            VALUES (?, ?, ?, ?, ?)
+            "#,
+        )
+        .bind(mail_id)
+        .bind(*kind)
+        .bind(*position)
+        .bind(insert_column(cipher, name.as_deref()))
+        .bind(insert_column(cipher, Some(address)).unwrap_or(""))
+        .execute(&mut *tx)
+        .await?
+        .last_insert_rowid();
+        let values = [
+            ("name", name.as_deref()),
+            ("address", Some(address.as_str())),
+        ];
+        seal_new_row(tx, cipher, "addresses", id, &values).await?;
+        *position += 1;
+    }
+    Ok(())
+}
+
+/// Fills in the metadata of messages that were stored before it was being saved
+async fn backfill_metadata(
+    pool: &SqlitePool,
+    mail_dir: &Path,
+    cipher: Option<&Cipher>,
+) -> Result<()> {
+    let missing: Vec<(i64, String)> =
+        sqlx::query_as(r#"SELECT id, filename FROM "mail" WHERE size IS NULL"#)
+            .fetch_all(pool)
+            .await?;
+    if missing.is_empty() {
+        return Ok(());
+    }
+
+    info!("filling in metadata for {} messages", missing.len());
+    let mut tx = pool.begin().await?;
+    for (mail_id, filename) in missing {
+        let message = match read_body_file(mail_dir, &filename, cipher)
+            .await
+            .and_then(|body| parse_message(&body))
+        {
+            Ok(v) => v,
+            Err(err) => {
+                warn!("error reading metadata from {:?}: {}", filename, err);
+                continue;
+            }
+        };
+        save_message_metadata(&mut tx, cipher, mail_id, &message).await?;
+    }
+    tx.commit().await?;
+    Ok(())
+}
+
+/// (id, Message-ID, In-Reply-To, References, subject) of a message
+type ThreadHeadersRow = (
+    i64,
+    Option<String>,
+    Option<String>,
+    Option<String>,
+    Option<String>,
+);
+
+/// Puts a newly stored message into a conversation. This links it up with messages it replies to,
+/// replies to it that came in first, and other copies of it in other folders, merging their
+/// conversations together if they were separate. Replies that lost their References header are
+/// matched up by subject instead, as long as the conversation had a message around the same
+/// `date` (the message's INTERNALDATE, as RFC 3339).
+async fn assign_thread(
+    tx: &mut Transaction<'_, Sqlite>,
+    cipher: Option<&Cipher>,
+    acct: &str,
+    mail_id: i64,
+    date: &str,
+    message: &ParsedMessage,
+) -> Result<i64> {
+    let headers = ThreadMessage::new(
+        message.message_id.as_deref(),
+        message.in_reply_to.as_deref(),
+        message.references.as_deref(),
+        message.subject.as_deref(),
+        (),
+    );
+    save_references(tx, mail_id, &headers.references).await?;
+
+    let mut related = headers.references.clone();
+    related.extend(headers.message_id.clone());
+
+    let mut thread_ids = Vec::new();
+    for id in related.iter() {
+        let rows: Vec<(i64,)> = sqlx::query_as(
+            r#"
+            SELECT DISTINCT thread_id FROM "mail"
+            WHERE account = ? AND message_id = ? AND thread_id IS NOT NULL
+            "#,
+        )
+        .bind(acct)
+        .bind(id)
+        .fetch_all(&mut *tx)
+        .await?;
+        thread_ids.extend(rows.into_iter().map(|(thread_id,)| thread_id));
+    }
+
+    if let Some(id) = &headers.message_id {
+        let rows: Vec<(i64,)> = sqlx::query_as(
+            r#"
+            SELECT DISTINCT mail.thread_id FROM "message_references"
+            JOIN "mail" ON mail.id = message_references.mail_id
+            WHERE message_references.message_id = ? AND mail.account = ?
+                AND mail.thread_id IS NOT NULL
+            "#,
+        )
+        .bind(id)
+        .bind(acct)
+        .fetch_all(&mut *tx)
+        .await?;
+        thread_ids.extend(rows.into_iter().map(|(thread_id,)| thread_id));
+    }
+
+    let subject = message.subject.as_deref().unwrap_or("");
+    if thread_ids.is_empty() && thread::is_reply(subject) {
+        let row: Option<(i64,)> = sqlx::query_as(
+            r#"
+            SELECT threads.id FROM "threads"
+            JOIN "mail" ON mail.thread_id = threads.id
+            WHERE threads.account = ? AND threads.subject_key = ?
+                AND abs(julianday(mail.internaldate) - julianday(?)) <= ?
+            ORDER BY threads.id DESC
+            LIMIT 1
+            "#,
+        )
+        .bind(acct)
+        .bind(subject_key(cipher, thread::base_subject(subject)))
+        .bind(date)
+        .bind(SUBJECT_THREAD_WINDOW_DAYS)
+        .fetch_optional(&mut *tx)
+        .await?;
+        thread_ids.extend(row.map(|(thread_id,)| thread_id));
+    }
+
+    thread_ids.sort_unstable();
+    thread_ids.dedup();
+    let thread_id = match thread_ids.split_first() {
+        Some((thread_id, others)) => {
+            for other in others {
+                sqlx::query(r#"UPDATE "mail" SET thread_id = ? WHERE thread_id = ?"#)
+                    .bind(thread_id)
+                    .bind(other)
+                    .execute(&mut *tx)
+                    .await?;
+                sqlx::query(r#"DELETE FROM "threads" WHERE id = ?"#)
+                    .bind(other)
+                    .execute(&mut *tx)
+                    .await?;
+            }
+            *thread_id
+        }
+        None => insert_thread(tx, cipher, acct, thread::base_subject(subject)).await?,
+    };
+
+    sqlx::query(r#"UPDATE "mail" SET thread_id = ? WHERE id = ?"#)
+        .bind(thread_id)
+        .bind(mail_id)
+        .execute(&mut *tx)
+        .await?;
+    Ok(thread_id)
+}
+
+/// How many days apart a reply without References and the conversation it's matched to by subject
+/// can be. Subjects like "Re: Meeting notes" come up again and again over the years.
+const SUBJECT_THREAD_WINDOW_DAYS: f64 = 30.0;
+
+/// Saves the Message-IDs that a message refers to, replacing any that were saved before
+async fn save_references(
+    tx: &mut Transaction<'_, Sqlite>,
+    mail_id: i64,
+    references: &[String],
+) -> Result<()> {
+    sqlx::query(r#"DELETE FROM "message_references" WHERE mail_id = ?"#)
+        .bind(mail_id)
+        .execute(&mut *tx)
+        .await?;
+    for reference in references {
+        sqlx::query(r#"INSERT INTO "message_references" (mail_id, message_id) VALUES (?, ?)"#)
+            .bind(mail_id)
+            .bind(reference)
+            .execute(&mut *tx)
+            .await?;
+    }
+    Ok(())
+}
+
+/// Threads the messages of any account that has messages that aren't in a conversation yet
+async fn backfill_threads(pool: &SqlitePool, cipher: Option<&Cipher>) -> Result<()> {
+    let accounts: Vec<(String,)> =
+        sqlx::query_as(r#"SELECT DISTINCT account FROM "mail" WHERE thread_id IS NULL"#)
+            .fetch_all(pool)
+            .await?;
+    for (acct,) in accounts {
+        info!("threading messages in account {}", acct);
+        let mut tx = pool.begin().await?;
+        rebuild_threads(&mut tx, cipher, &acct).await?;
+        tx.commit().await?;
+    }
+    Ok(())
+}
+
+/// Throws out the conversations of an account and threads all of its messages from scratch
+async fn rebuild_threads(
+    tx: &mut Transaction<'_, Sqlite>,
+    cipher: Option<&Cipher>,
+    acct: &str,
+) -> Result<()> {
+    let rows: Vec<ThreadHeadersRow> = sqlx::query_as(
+        r#"
+        SELECT id, message_id, in_reply_to, "references", subject FROM "mail"
+        WHERE account = ?
+        ORDER BY internaldate, id
+        "#,
+    )
+    .bind(acct)
+    .fetch_all(&mut *tx)
+    .await?;
+
+    // copies of the same message in other folders go wherever the first one ends up
+    let mut copies = HashMap::<String, Vec<i64>>::new();
+    let mut messages = Vec::new();
+    for (id, message_id, in_reply_to, references, subject) in rows {
+        let subject = open_column(cipher, "mail", "subject", id, subject);
+        let message = ThreadMessage::new(
+            message_id.as_deref(),
+            in_reply_to.as_deref(),
+            references.as_deref(),
+            subject.as_deref(),
+            (id, subject.clone().unwrap_or_default()),
+        );
+        save_references(tx, id, &message.references).await?;
+        if let Some(message_id) = &message.message_id {
+            if let Some(copies) = copies.get_mut(message_id) {
+                copies.push(id);
+                continue;
+            }
+            copies.insert(message_id.clone(), Vec::new());
+        }
+        messages.push(message);
+    }
+    let message_ids = messages
+        .iter()
+        .filter_map(|message| Some((message.data.0, message.message_id.clone()?)))
+        .collect::<HashMap<_, _>>();
+
+    sqlx::query(r#"DELETE FROM "threads" WHERE account = ?"#)
+        .bind(acct)
+        .execute(&mut *tx)
+        .await?;
+    for root in thread::thread(messages) {
+        let subject = root
+            .first_message()
+            .map(|(_, subject)| thread::base_subject(subject))
+            .unwrap_or("");
+        let thread_id = insert_thread(tx, cipher, acct, subject).await?;
+
+        for (id, _) in root.messages() {
+            let copies = message_ids
+                .get(id)
+                .and_then(|message_id| copies.get(message_id))
+                .map(|copies| copies.as_slice())
+                .unwrap_or(&[]);
+            for id in std::iter::once(id).chain(copies) {
+                sqlx::query(r#"UPDATE "mail" SET thread_id = ? WHERE id = ?"#)
+                    .bind(thread_id)
+                    .bind(id)
+                    .execute(&mut *tx)
+                    .await?;
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Name of the file in the mail directory that holds the body with the given hash
+fn mail_filename(hash: &str) -> String {
+    format!("{}.mail", hash)
+}
+
+/// Whether a body ends right after its header, the way `BODY[HEADER]` does
+fn is_header_only(body: &[u8]) -> bool {
+    let header_end = (0..body.len()).find_map(|i| {
+        if body[i..].starts_with(b"\n\r\n") {
+            Some(i + 3)
+        } else if body[i..].starts_with(b"\n\n") {
+            Some(i + 2)
+        } else {
+            None
+        }
+    });
+    match header_end {
+        Some(end) => end == body.len(),
+        None => true,
+    }
+}
+
+/// Forgets about the bodies that aren't referenced by any message anymore, returning their hashes
+/// so their files can be removed once the transaction is committed
+async fn take_orphaned_contents(tx: &mut Transaction<'_, Sqlite>) -> Result<Vec<String>> {
+    let orphaned: Vec<(String,)> =
+        sqlx::query_as(r#"SELECT hash FROM "contents" WHERE refcount <= 0"#)
+            .fetch_all(&mut *tx)
+            .await?;
+    sqlx::query(r#"DELETE FROM "contents" WHERE refcount <= 0"#)
+        .execute(&mut *tx)
+        .await?;
+    Ok(orphaned.into_iter().map(|(hash,)| hash).collect())
+}
+
+/// A body in the cache, as far as deciding whether to evict it goes
+#[derive(Debug)]
+struct CachedBody {
+    /// Whether every message that has this body can download it again
+    removable: bool,
+
+    /// Whether the policy says that every message that has this body shouldn't keep it anymore
+    expired: bool,
 
-        let db_path_str = db_path.to_string_lossy();
-        let db_path = format!("sqlite:{}", db_path_str);
-        info!("using database path: {}", db_path_str);
+    /// When the newest message that has this body arrived
+    date: Option<DateTime<Utc>>,
+
+    size: u64,
+}
+
+/// Marks a body as being in the cache again, in case it was evicted before
+async fn mark_cached(tx: &mut Transaction<'_, Sqlite>, hash: &str) -> Result<()> {
+    sqlx::query(r#"UPDATE "contents" SET evicted = 0 WHERE hash = ?"#)
+        .bind(hash)
+        .execute(&mut *tx)
+        .await?;
+    Ok(())
+}
 
-        // create the database file if it doesn't already exist -_ -
-        if !Sqlite::database_exists(&db_path_str).await? {
-            Sqlite::create_database(&db_path_str).await?;
+/// Hashes a body to get the name of its file, which is keyed if the store is encrypted
+fn hash_body(cipher: Option<&Cipher>, body: &[u8]) -> String {
+    match cipher {
+        Some(cipher) => cipher.hash_body(body),
+        None => {
+            let mut hasher = Sha256::new();
+            hasher.update(body);
+            hex::encode(hasher.finalize())
         }
+    }
+}
 
-        let pool = SqlitePool::connect(&db_path_str).await?;
-        MIGRATOR.run(&pool).await?;
-        debug!("run migrations : {:?}", MIGRATOR);
+/// What gets written to the file for the body with the given hash
+fn seal_body(cipher: Option<&Cipher>, hash: &str, body: &[u8]) -> Result<Vec<u8>> {
+    match cipher {
+        Some(cipher) => cipher.seal_body(&mail_filename(hash), body),
+        None => Ok(body.to_vec()),
+    }
+}
 
-        let accounts = config
-            .mail_accounts
-            .keys()
-            .map(|acct| {
-                let folders = RwLock::new(Vec::new());
-                (
-                    acct.to_owned(),
-                    Arc::new(AccountRef {
-                        folders,
-                        pool: pool.clone(),
-                    }),
-                )
-            })
-            .collect();
+/// Reads a body from the mail directory, decrypting it if it's encrypted. Files from before
+/// encryption was turned on are read as they are.
+async fn read_body_file(
+    mail_dir: &Path,
+    filename: &str,
+    cipher: Option<&Cipher>,
+) -> Result<Vec<u8>> {
+    let path = mail_dir.join(filename);
+    let data = fs::read(&path)
+        .await
+        .with_context(|| format!("error reading email from {:?}", path))?;
+    match cipher {
+        Some(cipher) if crypto::is_sealed_body(&data) => cipher.open_body(filename, &data),
+        None if crypto::is_sealed_body(&data) => {
+            bail!("{:?} is encrypted, but encryption isn't set up", path)
+        }
+        _ => Ok(data),
+    }
+}
 
-        Ok(MailStoreInner {
-            mail_dir,
-            pool,
-            accounts,
-        })
+/// Encrypts a value for the given column of a row if the store is encrypted
+fn seal_column(
+    cipher: Option<&Cipher>,
+    table: &str,
+    column: &str,
+    rowid: i64,
+    value: Option<&str>,
+) -> Result<Option<String>> {
+    match (cipher, value) {
+        (Some(cipher), Some(value)) => cipher.seal_field(table, column, rowid, value).map(Some),
+        (_, value) => Ok(value.map(|value| value.to_owned())),
     }
 }
 
-#[derive(Debug)]
-/// Holds a reference to an account
-pub struct AccountRef {
-    folders: RwLock<Vec<String>>,
-    pool: SqlitePool,
+/// What goes into a column that gets encrypted when its row is inserted. An encrypted value is
+/// tied to its rowid, which isn't known until after the INSERT, so the column is left empty and
+/// filled in by [`seal_new_row`] in the same transaction. That way the plaintext never gets
+/// written to the database.
+fn insert_column<'a>(cipher: Option<&Cipher>, value: Option<&'a str>) -> Option<&'a str> {
+    match cipher {
+        Some(_) => None,
+        None => value,
+    }
 }
 
-impl AccountRef {
-    /// Gets the folders on this account
-    pub async fn get_folders(&self) -> Vec<String> {
-        self.folders.read().await.clone()
+/// Fills in the encrypted columns of a row that was just inserted with [`insert_column`]
+async fn seal_new_row(
+    tx: &mut Transaction<'_, Sqlite>,
+    cipher: Option<&Cipher>,
+    table: &str,
+    rowid: i64,
+    values: &[(&str, Option<&str>)],
+) -> Result<()> {
+    let cipher = match cipher {
+        Some(cipher) => cipher,
+        None => return Ok(()),
+    };
+    for (column, value) in values {
+        let sealed = seal_column(Some(cipher), table, column, rowid, *value)?;
+        sqlx::query(&format!(
+            r#"UPDATE "{}" SET "{}" = ? WHERE rowid = ?"#,
+            table, column
+        ))
+        .bind(sealed)
+        .bind(rowid)
+        .execute(&mut *tx)
+        .await?;
     }
+    Ok(())
+}
 
-    /// Sets the folders on this account
-    pub async fn set_folders(&self, folders: Vec<String>) {
-        *self.folders.write().await = folders;
+/// Decrypts a value from the given column of a row if it's encrypted. Values that can't be
+/// decrypted are treated as missing, since they're only ever shown.
+fn open_column(
+    cipher: Option<&Cipher>,
+    table: &str,
+    column: &str,
+    rowid: i64,
+    value: Option<String>,
+) -> Option<String> {
+    let value = value?;
+    if !crypto::is_sealed_field(&value) {
+        return Some(value);
+    }
+    match cipher.map(|cipher| cipher.open_field(table, column, rowid, &value)) {
+        Some(Ok(value)) => Some(value),
+        Some(Err(err)) => {
+            warn!("{:?}", err);
+            None
+        }
+        None => None,
     }
+}
 
-    /// Gets the n latest messages in the given folder
-    pub async fn get_newest_n_messages(
-        &self,
-        folder: impl AsRef<str>,
-        n: usize,
-    ) -> Result<Vec<EmailMetadata>> {
-        let folder = folder.as_ref();
-        let messages: Vec<EmailMetadata> = sqlx::query_as(
+/// What a thread with the given subject is looked up by. Case is ignored the same way SQLite's
+/// NOCASE does it, which only folds ASCII.
+fn subject_key(cipher: Option<&Cipher>, subject: &str) -> String {
+    let subject = subject.to_ascii_lowercase();
+    match cipher {
+        Some(cipher) => cipher.hash_field(&subject),
+        None => subject,
+    }
+}
+
+/// Starts a new conversation, returning its id
+async fn insert_thread(
+    tx: &mut Transaction<'_, Sqlite>,
+    cipher: Option<&Cipher>,
+    acct: &str,
+    subject: &str,
+) -> Result<i64> {
+    let id =
+        sqlx::query(r#"INSERT INTO "threads" (account, subject, subject_key) VALUES (?, ?, ?)"#)
+            .bind(acct)
+            .bind(insert_column(cipher, Some(subject)).unwrap_or(""))
+            .bind(subject_key(cipher, subject))
+            .execute(&mut *tx)
+            .await?
+            .last_insert_rowid();
+    seal_new_row(tx, cipher, "threads", id, &[("subject", Some(subject))]).await?;
+    Ok(id)
+}
+
+/// Gets the keys for the store if it's encrypted, setting encryption up if it's being turned on
+/// for the first time. The salt and check value are also kept in a file next to the database, so
+/// that the mail directory can still be decrypted if the database is lost.
+async fn load_cipher(
+    pool: &SqlitePool,
+    config: Option<&EncryptionConfig>,
+    salt_path: &Path,
+) -> Result<Option<Cipher>> {
+    let in_db: Option<(String, String)> =
+        sqlx::query_as(r#"SELECT salt, check_value FROM "encryption""#)
+            .fetch_optional(pool)
+            .await?;
+    let saved = match &in_db {
+        Some(v) => Some(v.clone()),
+        None => read_salt_file(salt_path).await?,
+    };
+    let config = match (config, &saved) {
+        (Some(config), _) => config,
+        (None, Some(_)) => {
+            bail!("the mail store is encrypted, but there's no [encryption] section in the config")
+        }
+        (None, None) => return Ok(None),
+    };
+    let passphrase = crypto::get_passphrase(config).await?;
+
+    let salt = match &saved {
+        Some((salt, _)) => hex::decode(salt).context("salt of the mail store isn't hex")?,
+        None => Cipher::new_salt()?,
+    };
+    // deriving the key takes long enough on purpose that it shouldn't hold up other tasks
+    let cipher = {
+        let salt = salt.clone();
+        task::spawn_blocking(move || Cipher::derive(&passphrase, &salt)).await?
+    };
+
+    let check_value = match &saved {
+        Some((_, check_value)) => {
+            if !cipher.verify(check_value) {
+                bail!("wrong passphrase for the mail store");
+            }
+            check_value.clone()
+        }
+        None => {
+            info!("turning on encryption for the mail store");
+            cipher.check_value()?
+        }
+    };
+    if in_db.is_none() {
+        sqlx::query(r#"INSERT INTO "encryption" (id, salt, check_value) VALUES (1, ?, ?)"#)
+            .bind(hex::encode(&salt))
+            .bind(&check_value)
+            .execute(pool)
+            .await?;
+    }
+    if !salt_path.exists() {
+        fs::write(
+            salt_path,
+            format!("{}\n{}\n", hex::encode(&salt), check_value),
+        )
+        .await
+        .with_context(|| format!("error writing {:?}", salt_path))?;
+    }
+    Ok(Some(cipher))
+}
+
+/// Reads the salt and check value from the file that's kept next to the database, if there is one
+async fn read_salt_file(salt_path: &Path) -> Result<Option<(String, String)>> {
+    let contents = match fs::read_to_string(salt_path).await {
+        Ok(v) => v,
+        Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(None),
+        Err(err) => return Err(err).with_context(|| format!("error reading {:?}", salt_path)),
+    };
+    let mut lines = contents.lines();
+    match (lines.next(), lines.next()) {
+        (Some(salt), Some(check_value)) => Ok(Some((salt.to_owned(), check_value.to_owned()))),
+        _ => bail!("{:?} isn't a salt and a check value", salt_path),
+    }
+}
+
+/// Encrypts everything that was stored before encryption was turned on. This only has to be done
+/// once, and picks up where it left off if it's interrupted.
+async fn encrypt_existing(pool: &SqlitePool, mail_dir: &Path, cipher: &Cipher) -> Result<bool> {
+    let (converted,): (bool,) = sqlx::query_as(r#"SELECT converted FROM "encryption""#)
+        .fetch_one(pool)
+        .await?;
+    if converted {
+        return Ok(false);
+    }
+    info!("encrypting mail that was stored before encryption was turned on");
+
+    // bodies are renamed to the keyed hash of their contents as they're encrypted, one at a time
+    // so the files and the database never disagree for long
+    let hashes: Vec<(String,)> = sqlx::query_as(r#"SELECT hash FROM "contents" WHERE NOT evicted"#)
+        .fetch_all(pool)
+        .await?;
+    for (hash,) in hashes {
+        let path = mail_dir.join(mail_filename(&hash));
+        let body = match fs::read(&path).await {
+            Ok(v) => v,
+            Err(err) => {
+                warn!("error reading {:?} to encrypt it: {}", path, err);
+                continue;
+            }
+        };
+        if crypto::is_sealed_body(&body) {
+            continue;
+        }
+
+        let new_hash = cipher.hash_body(&body);
+        let new_filename = mail_filename(&new_hash);
+        fs::write(
+            mail_dir.join(&new_filename),
+            cipher.seal_body(&new_filename, &body)?,
+        )
+        .await
+        .context("error writing encrypted email to file")?;
+
+        let mut tx = pool.begin().await?;
+        sqlx::query(r#"UPDATE "mail" SET content_hash = ?, filename = ? WHERE content_hash = ?"#)
+            .bind(&new_hash)
+            .bind(&new_filename)
+            .bind(&hash)
+            .execute(&mut tx)
+            .await?;
+        // the trigger adds the new hash as a complete body, so whether it's only a header has to
+        // be carried over before the old row goes away
+        sqlx::query(
             r#"
-            SELECT internaldate, subject FROM mail
-            WHERE folder = ?
-            ORDER BY internaldate DESC
-        "#,
+            UPDATE "contents" SET
+                headers_only = (SELECT headers_only FROM "contents" WHERE hash = ?),
+                evicted = (SELECT evicted FROM "contents" WHERE hash = ?)
+            WHERE hash = ?
+            "#,
         )
-        .bind(folder)
-        .fetch(&self.pool)
-        .map_ok(|(date, subject): (String, String)| EmailMetadata {
-            date: Some(
-                DateTime::parse_from_rfc3339(&date)
-                    .unwrap()
-                    .with_timezone(&Local),
-            ),
-            subject,
-            ..EmailMetadata::default()
+        .bind(&hash)
+        .bind(&hash)
+        .bind(&new_hash)
+        .execute(&mut tx)
+        .await?;
+        let orphaned = take_orphaned_contents(&mut tx).await?;
+        tx.commit().await?;
+        for hash in orphaned {
+            let path = mail_dir.join(mail_filename(&hash));
+            if let Err(err) = fs::remove_file(&path).await {
+                warn!("error removing unencrypted email file {:?}: {}", path, err);
+            }
+        }
+    }
+
+    let seal = |table: &str, column: &str, rowid: i64, value: Option<String>| match value {
+        Some(value) if !crypto::is_sealed_field(&value) => {
+            cipher.seal_field(table, column, rowid, &value).map(Some)
+        }
+        value => Ok(value),
+    };
+    let mut tx = pool.begin().await?;
+    let rows: Vec<(i64, Option<String>, Option<String>)> =
+        sqlx::query_as(r#"SELECT id, subject, snippet FROM "mail""#)
+            .fetch_all(&mut tx)
+            .await?;
+    for (id, subject, snippet) in rows {
+        sqlx::query(r#"UPDATE "mail" SET subject = ?, snippet = ? WHERE id = ?"#)
+            .bind(seal("mail", "subject", id, subject)?)
+            .bind(seal("mail", "snippet", id, snippet)?)
+            .bind(id)
+            .execute(&mut tx)
+            .await?;
+    }
+
+    let rows: Vec<(i64, Option<String>, Option<String>)> =
+        sqlx::query_as(r#"SELECT rowid, name, address FROM "addresses""#)
+            .fetch_all(&mut tx)
+            .await?;
+    for (rowid, name, address) in rows {
+        sqlx::query(r#"UPDATE "addresses" SET name = ?, address = ? WHERE rowid = ?"#)
+            .bind(seal("addresses", "name", rowid, name)?)
+            .bind(seal("addresses", "address", rowid, address)?)
+            .bind(rowid)
+            .execute(&mut tx)
+            .await?;
+    }
+
+    let rows: Vec<(i64, String)> = sqlx::query_as(r#"SELECT id, subject FROM "threads""#)
+        .fetch_all(&mut tx)
+        .await?;
+    for (id, subject) in rows {
+        if crypto::is_sealed_field(&subject) {
+            continue;
+        }
+        sqlx::query(r#"UPDATE "threads" SET subject = ?, subject_key = ? WHERE id = ?"#)
+            .bind(cipher.seal_field("threads", "subject", id, &subject)?)
+            .bind(subject_key(Some(cipher), &subject))
+            .bind(id)
+            .execute(&mut tx)
+            .await?;
+    }
+
+    sqlx::query(r#"UPDATE "encryption" SET converted = 1"#)
+        .execute(&mut tx)
+        .await?;
+    tx.commit().await?;
+
+    // the UPDATEs above leave the old plaintext behind in the write-ahead log and in any pages
+    // that were freed before secure_delete was on, so the whole file gets rewritten and the log
+    // emptied out
+    sqlx::query("VACUUM").execute(pool).await?;
+    let (busy, _, _): (i64, i64, i64) = sqlx::query_as("PRAGMA wal_checkpoint(TRUNCATE)")
+        .fetch_one(pool)
+        .await?;
+    if busy != 0 {
+        warn!("couldn't empty the write-ahead log, it may still have unencrypted mail in it");
+    }
+    Ok(true)
+}
+
+/// Opens the database, setting it up or migrating it if needed. If `rebuild` is set, the old one is
+/// moved aside and started over. A database that's damaged is left alone, since rebuilding it
+/// loses anything that's only in there, so that has to be asked for. Returns the pool, and whether
+/// the database is new, meaning that nothing is known about what's in the mail directory.
+///
+/// When the store is encrypted, SQLite is told to zero out anything that gets deleted or
+/// overwritten, so that plaintext from before encryption was turned on doesn't linger in free
+/// pages.
+async fn open_database(
+    db_path: &Path,
+    rebuild: bool,
+    secure_delete: bool,
+) -> Result<(SqlitePool, bool)> {
+    let options = SqliteConnectOptions::new()
+        .filename(db_path)
+        .create_if_missing(true)
+        // the UI can keep reading while mail is being synced, instead of waiting for every write
+        .journal_mode(SqliteJournalMode::Wal)
+        // with WAL, this can only lose the last few transactions on a power loss, not corrupt the
+        // database, and it's much faster than waiting for every commit to reach the disk
+        .synchronous(SqliteSynchronous::Normal)
+        .busy_timeout(DB_BUSY_TIMEOUT);
+
+    if db_path.exists() {
+        if rebuild {
+            info!("rebuilding the database from the mail directory");
+            move_database_aside(db_path).await?;
+        } else if let Some(problem) = check_database_file(&options).await? {
+            bail!(
+                "the database at {:?} is damaged ({}). run `panorama db rebuild` to start it over \
+                 from the mail directory, which loses any changes that haven't been made on the \
+                 server yet",
+                db_path,
+                problem
+            );
+        }
+    }
+
+    let is_new = !db_path.exists();
+    let pool = SqlitePoolOptions::new()
+        .after_connect(move |conn| {
+            Box::pin(async move {
+                if secure_delete {
+                    conn.execute("PRAGMA secure_delete = ON").await?;
+                }
+                Ok(())
+            })
         })
-        .try_collect()
+        .connect_with(options)
+        .await
+        .context("error opening the database")?;
+    if !is_new {
+        backup_before_migrate(&pool, db_path).await?;
+    }
+    MIGRATOR.run(&pool).await?;
+    Ok((pool, is_new))
+}
+
+/// How much space the database takes up on disk, including its write-ahead log
+async fn database_size(db_path: &Path) -> u64 {
+    let mut size = 0;
+    for suffix in &["", "-wal"] {
+        if let Ok(metadata) = fs::metadata(database_sibling(db_path, suffix)).await {
+            size += metadata.len();
+        }
+    }
+    size
+}
+
+/// The path of a file that's kept next to the database (ex. a backup of it)
+fn database_sibling(db_path: &Path, suffix: &str) -> PathBuf {
+    let mut path = db_path.as_os_str().to_owned();
+    path.push(suffix);
+    PathBuf::from(path)
+}
+
+/// Runs SQLite's quick check on an existing database, returning what's wrong with it if it's
+/// damaged
+async fn check_database_file(options: &SqliteConnectOptions) -> Result<Option<String>> {
+    let pool = match SqlitePool::connect_with(options.clone()).await {
+        Ok(v) => v,
+        Err(err) if is_corruption(&err) => return Ok(Some(err.to_string())),
+        Err(err) => return Err(err).context("error opening the database"),
+    };
+    let result: Result<Vec<(String,)>, _> =
+        sqlx::query_as("PRAGMA quick_check").fetch_all(&pool).await;
+    pool.close().await;
+
+    match result {
+        Ok(rows) if rows.iter().all(|(row,)| row == "ok") => Ok(None),
+        Ok(rows) => {
+            let rows = rows.into_iter().map(|(row,)| row).collect::<Vec<_>>();
+            Ok(Some(rows.join("; ")))
+        }
+        Err(err) if is_corruption(&err) => Ok(Some(err.to_string())),
+        Err(err) => Err(err).context("error checking the database"),
+    }
+}
+
+/// Whether an error from SQLite means that the database file is damaged (SQLITE_CORRUPT or
+/// SQLITE_NOTADB), as opposed to something like it not being readable
+fn is_corruption(err: &SqlxError) -> bool {
+    let code = match err {
+        SqlxError::Database(err) => err.code().and_then(|code| code.parse::<i32>().ok()),
+        _ => None,
+    };
+    // extended result codes keep the primary code in the lowest byte
+    matches!(code, Some(code) if code & 0xff == 11 || code & 0xff == 26)
+}
+
+/// Moves the database aside to a name with the time in it (ex. `panorama.db.20210301-142000.old`),
+/// so that rebuilding more than once doesn't replace an older copy
+async fn move_database_aside(db_path: &Path) -> Result<()> {
+    let stamp = Local::now().format("%Y%m%d-%H%M%S").to_string();
+    let mut name = format!(".{}.old", stamp);
+    let mut n = 1;
+    while ["", "-wal", "-shm"]
+        .iter()
+        .any(|suffix| database_sibling(db_path, &format!("{}{}", name, suffix)).exists())
+    {
+        n += 1;
+        name = format!(".{}-{}.old", stamp, n);
+    }
+
+    // the write-ahead log has to go along with it, or it'd be applied to the wrong database
+    for suffix in &["", "-wal", "-shm"] {
+        let from = database_sibling(db_path, suffix);
+        let to = database_sibling(db_path, &format!("{}{}", name, suffix));
+        if from.exists() {
+            fs::rename(&from, &to)
+                .await
+                .with_context(|| format!("error moving {:?} to {:?}", from, to))?;
+        }
+    }
+    warn!(
+        "the old database was moved to {:?}",
+        database_sibling(db_path, &name)
+    );
+    Ok(())
+}
+
+/// Makes a copy of the database before migrating it, since migrations can't be undone. The copy is
+/// named after the last migration that was applied to it (ex. `panorama.db.v11.bak`).
+async fn backup_before_migrate(pool: &SqlitePool, db_path: &Path) -> Result<()> {
+    // a database without this table hasn't been set up yet, so there's nothing to lose
+    let applied: Vec<(i64,)> = match sqlx::query_as(r#"SELECT version FROM "_sqlx_migrations""#)
+        .fetch_all(pool)
+        .await
+    {
+        Ok(v) => v,
+        Err(_) => return Ok(()),
+    };
+    let applied = applied
+        .into_iter()
+        .map(|(version,)| version)
+        .collect::<HashSet<_>>();
+    let latest = match applied.iter().max() {
+        Some(v) => *v,
+        None => return Ok(()),
+    };
+    if MIGRATOR.iter().all(|m| applied.contains(&m.version)) {
+        return Ok(());
+    }
+
+    let backup = database_sibling(db_path, &format!(".v{}.bak", latest));
+    info!(
+        "backing up the database to {:?} before migrating it",
+        backup
+    );
+    if backup.exists() {
+        fs::remove_file(&backup).await?;
+    }
+    sqlx::query("VACUUM INTO ?")
+        .bind(backup.to_string_lossy().as_ref())
+        .execute(pool)
+        .await
+        .context("error backing up the database")?;
+    prune_database_backups(db_path, DB_BACKUPS_KEPT).await
+}
+
+/// Removes all but the `keep` newest backups made by [`backup_before_migrate`]
+async fn prune_database_backups(db_path: &Path, keep: usize) -> Result<()> {
+    let (dir, db_name) = match (db_path.parent(), db_path.file_name()) {
+        (Some(dir), Some(name)) => (dir, name.to_string_lossy().into_owned()),
+        _ => return Ok(()),
+    };
+
+    let mut backups = Vec::new();
+    let mut entries = fs::read_dir(dir).await?;
+    while let Some(entry) = entries.next_entry().await? {
+        let name = entry.file_name().to_string_lossy().into_owned();
+        let version = name
+            .strip_prefix(&db_name)
+            .and_then(|rest| rest.strip_prefix(".v"))
+            .and_then(|rest| rest.strip_suffix(".bak"))
+            .and_then(|version| version.parse::<i64>().ok());
+        if let Some(version) = version {
+            backups.push((version, entry.path()));
+        }
+    }
+
+    backups.sort();
+    let remove = backups.len().saturating_sub(keep);
+    for (_, path) in backups.into_iter().take(remove) {
+        info!("removing old database backup {:?}", path);
+        fs::remove_file(&path)
+            .await
+            .with_context(|| format!("error removing {:?}", path))?;
+    }
+    Ok(())
+}
+
+/// Indexes the bodies in the mail directory after the database was lost, so that they can be
+/// matched back up with messages by Message-ID instead of being downloaded again. Returns how many
+/// bodies were indexed.
+async fn index_mail_dir(
+    pool: &SqlitePool,
+    mail_dir: &Path,
+    cipher: Option<&Cipher>,
+) -> Result<usize> {
+    let mut indexed = 0;
+    let mut unreadable = 0;
+    let mut entries = fs::read_dir(mail_dir).await?;
+    while let Some(entry) = entries.next_entry().await? {
+        let filename = entry.file_name().to_string_lossy().into_owned();
+        let old_hash = match filename.strip_suffix(".mail") {
+            Some(v) => v.to_owned(),
+            None => continue,
+        };
+
+        // bodies that are encrypted with a key that was lost can't be used anymore, but they're
+        // left where they are in case the old database turns up
+        let body = match read_body_file(mail_dir, &filename, cipher).await {
+            Ok(v) => v,
+            Err(err) => {
+                debug!("can't recover {}: {:?}", filename, err);
+                unreadable += 1;
+                continue;
+            }
+        };
+        let message_id = match parse_message(&body).ok().and_then(|m| m.message_id) {
+            Some(v) => v,
+            None => continue,
+        };
+        // a header on its own (ex. from a folder that only keeps headers) would pass for a whole
+        // message with an empty body, so those are downloaded again instead
+        if is_header_only(&body) {
+            continue;
+        }
+
+        // bodies from before encryption was turned on are named by a plain hash
+        let hash = hash_body(cipher, &body);
+        if hash != old_hash {
+            fs::write(
+                mail_dir.join(mail_filename(&hash)),
+                seal_body(cipher, &hash, &body)?,
+            )
+            .await
+            .context("error writing email to file")?;
+            fs::remove_file(entry.path()).await?;
+        }
+
+        sqlx::query(
+            r#"INSERT OR REPLACE INTO "recovered_bodies" (hash, message_id) VALUES (?, ?)"#,
+        )
+        .bind(&hash)
+        .bind(&message_id)
+        .execute(pool)
         .await?;
-        debug!("found {} messages", messages.len());
-        Ok(messages)
+        indexed += 1;
+    }
+
+    if unreadable > 0 {
+        warn!(
+            "{} files in the mail directory couldn't be read, so they weren't recovered",
+            unreadable
+        );
+    }
+    Ok(indexed)
+}
+
+fn flags_to_string(flags: &[MailboxFlag]) -> String {
+    flags
+        .iter()
+        .map(|flag| flag.to_string())
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+fn flags_from_string(flags: &str) -> Vec<MailboxFlag> {
+    flags.split_whitespace().map(MailboxFlag::from).collect()
+}
+
+fn apply_flag_change(flags: &mut Vec<MailboxFlag>, flag: &MailboxFlag, added: bool) {
+    if added {
+        if !flags.contains(flag) {
+            flags.push(flag.clone());
+        }
+    } else {
+        flags.retain(|f| f != flag);
     }
 }
 
@@ -421,3 +4140,307 @@ fn into_opt<T>(res: Result<T, SqlxError>) -> Result<Option<T>> {
         Err(e) => Err(e.into()),
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    async fn memory_pool() -> Result<SqlitePool> {
+        // every connection to an in-memory database gets its own, so only keep one around
+        let pool = SqlitePoolOptions::new()
+            .max_connections(1)
+            .connect("sqlite::memory:")
+            .await?;
+        MIGRATOR.run(&pool).await?;
+        Ok(pool)
+    }
+
+    async fn insert_mail(pool: &SqlitePool, filename: &str) -> Result<i64> {
+        let id = sqlx::query(
+            r#"
+            INSERT INTO "mail" (account, folder, uidvalidity, uid, filename)
+            VALUES ('test', 'INBOX', 1, 1, ?)
+            "#,
+        )
+        .bind(filename)
+        .execute(pool)
+        .await?
+        .last_insert_rowid();
+        Ok(id)
+    }
+
+    async fn get_addresses(pool: &SqlitePool, mail_id: i64) -> Result<Vec<(String, i64, String)>> {
+        let addresses = sqlx::query_as(
+            r#"
+            SELECT kind, position, address FROM "addresses"
+            WHERE mail_id = ?
+            ORDER BY kind, position
+            "#,
+        )
+        .bind(mail_id)
+        .fetch_all(pool)
+        .await?;
+        Ok(addresses)
+    }
+
+    #[test]
+    fn test_parse_message() {
+        let body = b"Message-ID: <one@example.com>\r\n\
+                     Subject: =?UTF-8?Q?caf=C3=A9?=\r\n\
+                     Date: Mon, 1 Mar 2021 14:20:00 +0000\r\n\
+                     In-Reply-To: <zero@example.com>\r\n\
+                     References: <a@example.com>\r\n\
+                     \t<zero@example.com>\r\n\
+                     \r\n\
+                     Sounds good.\r\n\
+                     \r\n\
+                     > what about\r\n\
+                     >   lunch?\r\n";
+        let message = parse_message(body).unwrap();
+        assert_eq!(message.message_id.as_deref(), Some("<one@example.com>"));
+        assert_eq!(message.subject.as_deref(), Some("café"));
+        assert_eq!(message.date, Utc.timestamp_opt(1614608400, 0).single());
+        assert_eq!(message.size, body.len());
+        assert_eq!(message.in_reply_to.as_deref(), Some("<zero@example.com>"));
+        assert_eq!(
+            message.references.as_deref(),
+            Some("<a@example.com> <zero@example.com>")
+        );
+        assert!(!message.has_attachment);
+        assert_eq!(message.snippet.as_deref(), Some("Sounds good."));
+    }
+
+    #[test]
+    fn test_parse_message_missing_headers() {
+        let message = parse_message(b"X-Mailer: test\r\n\r\nhello\r\n").unwrap();
+        assert_eq!(message.message_id, None);
+        assert_eq!(message.subject, None);
+        assert_eq!(message.date, None);
+        assert_eq!(message.in_reply_to, None);
+        assert_eq!(message.references, None);
+        assert!(message.addresses.is_empty());
+        assert_eq!(message.snippet.as_deref(), Some("hello"));
+
+        // a date that can't be read is left out rather than failing the whole message
+        let message = parse_message(b"Date: sometime\r\nSubject: hi\r\n\r\n").unwrap();
+        assert_eq!(message.date, None);
+        assert_eq!(message.subject.as_deref(), Some("hi"));
+        assert_eq!(message.snippet, None);
+    }
+
+    #[test]
+    fn test_parse_message_multipart() {
+        let body = b"Subject: report\r\n\
+                     Content-Type: multipart/mixed; boundary=outer\r\n\
+                     \r\n\
+                     --outer\r\n\
+                     Content-Type: multipart/alternative; boundary=inner\r\n\
+                     \r\n\
+                     --inner\r\n\
+                     Content-Type: text/plain; charset=utf-8\r\n\
+                     \r\n\
+                     Here's the report.\r\n\
+                     --inner\r\n\
+                     Content-Type: text/html; charset=utf-8\r\n\
+                     \r\n\
+                     <p>Here's the <b>report</b>.</p>\r\n\
+                     --inner--\r\n\
+                     --outer\r\n\
+                     Content-Type: application/pdf; name=report.pdf\r\n\
+                     Content-Disposition: attachment; filename=report.pdf\r\n\
+                     Content-Transfer-Encoding: base64\r\n\
+                     \r\n\
+                     JVBERi0=\r\n\
+                     --outer--\r\n";
+        let message = parse_message(body).unwrap();
+        assert_eq!(message.subject.as_deref(), Some("report"));
+        assert!(message.has_attachment);
+        assert_eq!(message.snippet.as_deref(), Some("Here's the report."));
+
+        // inline parts without a file name aren't attachments
+        let body = b"Content-Type: multipart/related; boundary=b\r\n\
+                     \r\n\
+                     --b\r\n\
+                     Content-Type: text/html\r\n\
+                     \r\n\
+                     <p>Look:</p><img src=\"cid:logo\">\r\n\
+                     --b\r\n\
+                     Content-Type: image/png\r\n\
+                     Content-ID: <logo>\r\n\
+                     \r\n\
+                     PNG\r\n\
+                     --b--\r\n";
+        let message = parse_message(body).unwrap();
+        assert!(!message.has_attachment);
+        assert_eq!(message.snippet.as_deref(), Some("Look:"));
+    }
+
+    #[test]
+    fn test_snippet_length() {
+        // cutting the snippet off in the middle of a character would panic
+        let body = format!(
+            "Subject: long\r\n\r\n{}\r\n",
+            "é".repeat(SNIPPET_LENGTH + 50)
+        );
+        let message = parse_message(body.as_bytes()).unwrap();
+        assert_eq!(message.snippet, Some("é".repeat(SNIPPET_LENGTH)));
+
+        let body = format!(
+            "Subject: long\r\n\r\n{}\r\n",
+            "word   ".repeat(SNIPPET_LENGTH)
+        );
+        let snippet = parse_message(body.as_bytes()).unwrap().snippet.unwrap();
+        assert_eq!(snippet.chars().count(), SNIPPET_LENGTH);
+        assert!(snippet.starts_with("word word "));
+    }
+
+    #[test]
+    fn test_parse_addresses() {
+        let body = b"From: \"Doe, Jane\" <jane@example.com>\r\n\
+                     To: bob@example.com, Carol <carol@example.com>\r\n\
+                     Cc: friends: dave@example.com, Erin <erin@example.com>;\r\n\
+                     X-Other: frank@example.com\r\n\
+                     \r\n\
+                     hi\r\n";
+        let message = parse_message(body).unwrap();
+        assert_eq!(
+            message.addresses,
+            vec![
+                (
+                    "from",
+                    Some("Doe, Jane".to_owned()),
+                    "jane@example.com".to_owned()
+                ),
+                ("to", None, "bob@example.com".to_owned()),
+                (
+                    "to",
+                    Some("Carol".to_owned()),
+                    "carol@example.com".to_owned()
+                ),
+                ("cc", None, "dave@example.com".to_owned()),
+                ("cc", Some("Erin".to_owned()), "erin@example.com".to_owned()),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_is_header_only() {
+        assert!(is_header_only(b"Subject: hi\r\n\r\n"));
+        assert!(is_header_only(b"Subject: hi\n\n"));
+        assert!(is_header_only(b"Subject: hi\r\n"));
+        assert!(!is_header_only(b"Subject: hi\r\n\r\nhello\r\n"));
+        assert!(!is_header_only(b"Subject: hi\n\n\n"));
+    }
+
+    #[tokio::test]
+    async fn test_save_message_metadata() -> Result<()> {
+        let pool = memory_pool().await?;
+        let mail_id = insert_mail(&pool, "one.mail").await?;
+
+        let message = parse_message(
+            b"From: Jane <jane@example.com>\r\n\
+              To: bob@example.com, carol@example.com\r\n\
+              Date: Mon, 1 Mar 2021 14:20:00 +0000\r\n\
+              \r\n\
+              hello\r\n",
+        )?;
+        let mut tx = pool.begin().await?;
+        save_message_metadata(&mut tx, None, mail_id, &message).await?;
+        tx.commit().await?;
+
+        let (date, size, snippet): (Option<String>, Option<i64>, Option<String>) =
+            sqlx::query_as(r#"SELECT date, size, snippet FROM "mail" WHERE id = ?"#)
+                .bind(mail_id)
+                .fetch_one(&pool)
+                .await?;
+        assert_eq!(date.as_deref(), Some("2021-03-01T14:20:00+00:00"));
+        assert_eq!(size, Some(message.size as i64));
+        assert_eq!(snippet.as_deref(), Some("hello"));
+        assert_eq!(
+            get_addresses(&pool, mail_id).await?,
+            vec![
+                ("from".to_owned(), 0, "jane@example.com".to_owned()),
+                ("to".to_owned(), 0, "bob@example.com".to_owned()),
+                ("to".to_owned(), 1, "carol@example.com".to_owned()),
+            ]
+        );
+
+        // saving it again replaces the addresses instead of adding to them
+        let message = parse_message(b"From: dave@example.com\r\n\r\n")?;
+        let mut tx = pool.begin().await?;
+        save_message_metadata(&mut tx, None, mail_id, &message).await?;
+        tx.commit().await?;
+        assert_eq!(
+            get_addresses(&pool, mail_id).await?,
+            vec![("from".to_owned(), 0, "dave@example.com".to_owned())]
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_backfill_metadata() -> Result<()> {
+        let pool = memory_pool().await?;
+        let mail_dir =
+            std::env::temp_dir().join(format!("panorama-backfill-{}", std::process::id()));
+        fs::create_dir_all(&mail_dir).await?;
+        fs::write(
+            mail_dir.join("one.mail"),
+            b"From: jane@example.com\r\n\r\nhello\r\n",
+        )
+        .await?;
+        let stored = insert_mail(&pool, "one.mail").await?;
+        let missing = insert_mail(&pool, "missing.mail").await?;
+
+        // a body that can't be read is skipped without stopping the rest from being filled in
+        backfill_metadata(&pool, &mail_dir, None).await?;
+        fs::remove_dir_all(&mail_dir).await?;
+
+        let sizes: Vec<(i64, Option<i64>)> =
+            sqlx::query_as(r#"SELECT id, size FROM "mail" ORDER BY id"#)
+                .fetch_all(&pool)
+                .await?;
+        assert_eq!(sizes, vec![(stored, Some(33)), (missing, None)]);
+        assert_eq!(
+            get_addresses(&pool, stored).await?,
+            vec![("from".to_owned(), 0, "jane@example.com".to_owned())]
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_encrypt_headers_only() -> Result<()> {
+        let pool = memory_pool().await?;
+        let mail_dir =
+            std::env::temp_dir().join(format!("panorama-encrypt-{}", std::process::id()));
+        fs::create_dir_all(&mail_dir).await?;
+        let header = b"Subject: hi\r\n\r\n";
+        fs::write(mail_dir.join(mail_filename("plain")), header).await?;
+        sqlx::query(
+            r#"
+            INSERT INTO "mail" (account, folder, uidvalidity, uid, filename, content_hash)
+            VALUES ('test', 'INBOX', 1, 1, ?, 'plain')
+            "#,
+        )
+        .bind(mail_filename("plain"))
+        .execute(&pool)
+        .await?;
+        sqlx::query(r#"UPDATE "contents" SET headers_only = 1"#)
+            .execute(&pool)
+            .await?;
+        sqlx::query(r#"INSERT INTO "encryption" (salt, check_value) VALUES ('', '')"#)
+            .execute(&pool)
+            .await?;
+
+        let cipher = Cipher::derive("hunter2", b"0123456789abcdef");
+        assert!(encrypt_existing(&pool, &mail_dir, &cipher).await?);
+        fs::remove_dir_all(&mail_dir).await?;
+
+        // the body is still only a header after it's moved to its encrypted name
+        let contents: Vec<(String, bool)> =
+            sqlx::query_as(r#"SELECT hash, headers_only FROM "contents""#)
+                .fetch_all(&pool)
+                .await?;
+        assert_eq!(contents, vec![(cipher.hash_body(header), true)]);
+        Ok(())
+    }
+}
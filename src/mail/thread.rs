@@ -0,0 +1,540 @@
+//! Grouping messages into conversations
+//!
+//! This follows Jamie Zawinski's threading algorithm (<https://www.jwz.org/doc/threading.html>):
+//! messages are linked together by their References and In-Reply-To headers, and whatever is left
+//! over at the top is grouped together by subject.
+
+use std::collections::HashMap;
+use std::mem;
+
+use super::EmailMetadata;
+
+/// A conversation as it appears in the UI list
+#[derive(Clone, Debug)]
+pub struct ThreadSummary {
+    /// ID of the conversation in the database
+    pub id: i64,
+
+    /// Subject of the conversation, without any `Re:` in front
+    pub subject: String,
+
+    /// Number of messages in the conversation
+    pub messages: u32,
+
+    /// Number of unread messages in the conversation
+    pub unread: u32,
+
+    /// The newest message in the conversation
+    pub latest: EmailMetadata,
+}
+
+/// A message to be threaded, along with whatever the caller wants to get back out of the tree
+#[derive(Debug)]
+pub struct ThreadMessage<T> {
+    /// Message-ID of the message, if it has one
+    pub message_id: Option<String>,
+
+    /// Message-IDs of the message's ancestors, oldest first
+    pub references: Vec<String>,
+
+    /// Subject of the message
+    pub subject: String,
+
+    /// Data that's carried along to the resulting tree
+    pub data: T,
+}
+
+impl<T> ThreadMessage<T> {
+    /// Creates a message to be threaded out of its headers. In-Reply-To is only used if it isn't
+    /// already the last entry in References.
+    pub fn new(
+        message_id: Option<&str>,
+        in_reply_to: Option<&str>,
+        references: Option<&str>,
+        subject: Option<&str>,
+        data: T,
+    ) -> Self {
+        let mut refs = references.map(parse_message_ids).unwrap_or_default();
+        if let Some(parent) = in_reply_to.and_then(|v| parse_message_ids(v).into_iter().next()) {
+            if refs.last() != Some(&parent) {
+                refs.push(parent);
+            }
+        }
+        ThreadMessage {
+            message_id: message_id.and_then(|v| parse_message_ids(v).into_iter().next()),
+            references: refs,
+            subject: subject.unwrap_or_default().to_owned(),
+            data,
+        }
+    }
+}
+
+/// A node in a conversation tree
+#[derive(Debug)]
+pub struct ThreadNode<T> {
+    /// The message at this node. This is empty when none of the messages that were threaded are
+    /// at this spot, but more than one of them refer to it.
+    pub message: Option<T>,
+
+    /// Replies to this message
+    pub children: Vec<ThreadNode<T>>,
+}
+
+impl<T> ThreadNode<T> {
+    /// Goes through every message in this tree, parents before their replies
+    pub fn messages(&self) -> Vec<&T> {
+        let mut messages = Vec::new();
+        self.collect_messages(&mut messages);
+        messages
+    }
+
+    fn collect_messages<'a>(&'a self, messages: &mut Vec<&'a T>) {
+        messages.extend(self.message.iter());
+        for child in self.children.iter() {
+            child.collect_messages(messages);
+        }
+    }
+
+    /// The first message in this tree, used to name the conversation
+    pub fn first_message(&self) -> Option<&T> {
+        self.message
+            .as_ref()
+            .or_else(|| self.children.iter().find_map(|child| child.first_message()))
+    }
+}
+
+/// Pulls the Message-IDs (ex. `<1234@example.com>`) out of a header like References
+pub fn parse_message_ids(value: &str) -> Vec<String> {
+    let mut ids = Vec::new();
+    let mut rest = value;
+    while let Some(start) = rest.find('<') {
+        match rest[start..].find('>') {
+            Some(end) => {
+                ids.push(rest[start..start + end + 1].to_owned());
+                rest = &rest[start + end + 1..];
+            }
+            None => break,
+        }
+    }
+
+    // some clients leave off the brackets entirely
+    if ids.is_empty() {
+        ids.extend(value.split_whitespace().map(|id| id.to_owned()));
+    }
+    ids
+}
+
+/// Whether the subject is marked as a reply or forward (ex. `Re: hello`)
+pub fn is_reply(subject: &str) -> bool {
+    strip_prefix(subject.trim_start()).is_some()
+}
+
+/// The subject with any reply and forward markers taken off the front, which is what's compared
+/// when grouping messages by subject
+pub fn base_subject(subject: &str) -> &str {
+    let mut subject = subject.trim();
+    while let Some(rest) = strip_prefix(subject) {
+        subject = rest.trim_start();
+    }
+    subject
+}
+
+/// Takes a single `Re:`, `Fwd:` or `Re[2]:` off the front of a subject
+fn strip_prefix(subject: &str) -> Option<&str> {
+    let colon = subject.find(':')?;
+    let prefix = subject[..colon].trim_end();
+    let prefix = match prefix.find('[') {
+        Some(bracket) if prefix.ends_with(']') => &prefix[..bracket],
+        _ => prefix,
+    };
+    match prefix.to_ascii_lowercase().as_str() {
+        "re" | "fw" | "fwd" | "aw" | "sv" => Some(&subject[colon + 1..]),
+        _ => None,
+    }
+}
+
+#[derive(Debug)]
+struct Container<T> {
+    message: Option<T>,
+    subject: String,
+    parent: Option<usize>,
+    children: Vec<usize>,
+}
+
+/// Groups messages into conversations. Replies keep the order the messages were given in, so
+/// they should be sorted by date beforehand.
+pub fn thread<T>(messages: Vec<ThreadMessage<T>>) -> Vec<ThreadNode<T>> {
+    let mut containers: Vec<Container<T>> = Vec::new();
+    let mut id_table: HashMap<String, usize> = HashMap::new();
+
+    fn get_container<T>(
+        containers: &mut Vec<Container<T>>,
+        id_table: &mut HashMap<String, usize>,
+        id: &str,
+    ) -> usize {
+        *id_table.entry(id.to_owned()).or_insert_with(|| {
+            containers.push(Container {
+                message: None,
+                subject: String::new(),
+                parent: None,
+                children: Vec::new(),
+            });
+            containers.len() - 1
+        })
+    }
+
+    // link up every message with its ancestors
+    for message in messages {
+        let idx = match &message.message_id {
+            Some(id) => {
+                let idx = get_container(&mut containers, &mut id_table, id);
+                if containers[idx].message.is_some() {
+                    // a duplicate Message-ID, which gets treated like a separate message
+                    None
+                } else {
+                    Some(idx)
+                }
+            }
+            None => None,
+        };
+        let idx = idx.unwrap_or_else(|| {
+            containers.push(Container {
+                message: None,
+                subject: String::new(),
+                parent: None,
+                children: Vec::new(),
+            });
+            containers.len() - 1
+        });
+        containers[idx].message = Some(message.data);
+        containers[idx].subject = message.subject;
+
+        let mut prev = None;
+        for reference in message.references.iter() {
+            let current = get_container(&mut containers, &mut id_table, reference);
+            if let Some(prev) = prev {
+                if containers[current].parent.is_none() {
+                    set_parent(&mut containers, current, prev);
+                }
+            }
+            prev = Some(current);
+        }
+
+        // the message's own References are the most trustworthy, so they win over whatever
+        // other messages said its parent was
+        if let Some(parent) = containers[idx].parent.take() {
+            containers[parent].children.retain(|child| *child != idx);
+        }
+        if let Some(prev) = prev {
+            set_parent(&mut containers, idx, prev);
+        }
+    }
+
+    let roots = (0..containers.len())
+        .filter(|idx| containers[*idx].parent.is_none())
+        .collect::<Vec<_>>();
+
+    let mut roots = prune(&mut containers, roots, true);
+    group_by_subject(&mut containers, &mut roots);
+
+    roots
+        .into_iter()
+        .map(|root| into_node(&mut containers, root))
+        .collect()
+}
+
+/// Makes parent the parent of child, unless that would create a loop
+fn set_parent<T>(containers: &mut [Container<T>], child: usize, parent: usize) {
+    let mut ancestor = Some(parent);
+    while let Some(idx) = ancestor {
+        if idx == child {
+            return;
+        }
+        ancestor = containers[idx].parent;
+    }
+
+    if let Some(old_parent) = containers[child].parent {
+        containers[old_parent].children.retain(|idx| *idx != child);
+    }
+    containers[child].parent = Some(parent);
+    containers[parent].children.push(child);
+}
+
+/// Gets rid of containers without messages, moving their children up a level. Empty containers
+/// are kept at the top if they hold more than one message, since that's the only thing keeping
+/// those messages together.
+fn prune<T>(containers: &mut [Container<T>], siblings: Vec<usize>, is_root: bool) -> Vec<usize> {
+    let mut result = Vec::new();
+    for idx in siblings {
+        let children = mem::take(&mut containers[idx].children);
+        let children = prune(containers, children, false);
+        containers[idx].children = children;
+
+        if containers[idx].message.is_some() {
+            result.push(idx);
+        } else if containers[idx].children.is_empty() {
+            continue;
+        } else if !is_root || containers[idx].children.len() == 1 {
+            let children = mem::take(&mut containers[idx].children);
+            let parent = containers[idx].parent;
+            for child in children.iter() {
+                containers[*child].parent = parent;
+            }
+            result.extend(children);
+        } else {
+            result.push(idx);
+        }
+    }
+    result
+}
+
+/// Merges conversations at the top that have the same subject, in case some client along the way
+/// dropped the References header
+fn group_by_subject<T>(containers: &mut Vec<Container<T>>, roots: &mut Vec<usize>) {
+    let mut subject_table: HashMap<String, usize> = HashMap::new();
+    let mut merged = Vec::new();
+
+    for root in roots.iter().copied() {
+        let subject = base_subject(root_subject(containers, root)).to_lowercase();
+        if subject.is_empty() {
+            merged.push(root);
+            continue;
+        }
+
+        let other = match subject_table.get(&subject) {
+            Some(other) => *other,
+            None => {
+                subject_table.insert(subject, merged.len());
+                merged.push(root);
+                continue;
+            }
+        };
+
+        let other_root = merged[other];
+        let dummy = |containers: &Vec<Container<T>>, idx: usize| containers[idx].message.is_none();
+        let reply = |containers: &Vec<Container<T>>, idx: usize| {
+            containers[idx].message.is_some() && is_reply(&containers[idx].subject)
+        };
+
+        if dummy(containers, other_root) && dummy(containers, root) {
+            for child in mem::take(&mut containers[root].children) {
+                containers[child].parent = Some(other_root);
+                containers[other_root].children.push(child);
+            }
+        } else if dummy(containers, other_root) {
+            set_parent(containers, root, other_root);
+        } else if dummy(containers, root) {
+            set_parent(containers, other_root, root);
+            merged[other] = root;
+        } else if !reply(containers, other_root) && reply(containers, root) {
+            set_parent(containers, root, other_root);
+        } else if reply(containers, other_root) && !reply(containers, root) {
+            set_parent(containers, other_root, root);
+            merged[other] = root;
+        } else if !reply(containers, other_root) {
+            // two separate messages that just happen to have the same subject (ex. automated
+            // ones), which the original algorithm would merge
+            merged.push(root);
+        } else {
+            containers.push(Container {
+                message: None,
+                subject: String::new(),
+                parent: None,
+                children: Vec::new(),
+            });
+            let dummy = containers.len() - 1;
+            set_parent(containers, other_root, dummy);
+            set_parent(containers, root, dummy);
+            merged[other] = dummy;
+        }
+    }
+
+    *roots = merged;
+}
+
+/// The subject of a conversation, which comes from its first message
+fn root_subject<T>(containers: &[Container<T>], root: usize) -> &str {
+    if containers[root].message.is_some() {
+        return &containers[root].subject;
+    }
+    containers[root]
+        .children
+        .first()
+        .map(|child| containers[*child].subject.as_str())
+        .unwrap_or("")
+}
+
+fn into_node<T>(containers: &mut [Container<T>], idx: usize) -> ThreadNode<T> {
+    let children = mem::take(&mut containers[idx].children);
+    ThreadNode {
+        message: containers[idx].message.take(),
+        children: children
+            .into_iter()
+            .map(|child| into_node(containers, child))
+            .collect(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn message(id: &'static str, references: &str, subject: &str) -> ThreadMessage<&'static str> {
+        let message_id = format!("<{}@example.com>", id);
+        ThreadMessage::new(Some(&message_id), None, Some(references), Some(subject), id)
+    }
+
+    /// Writes a tree out like `a(b c(d))`, with `-` for a spot that has no message
+    fn shape(node: &ThreadNode<&str>) -> String {
+        let mut out = node.message.unwrap_or("-").to_owned();
+        if !node.children.is_empty() {
+            let children = node.children.iter().map(shape).collect::<Vec<_>>();
+            out.push_str(&format!("({})", children.join(" ")));
+        }
+        out
+    }
+
+    fn shapes(messages: Vec<ThreadMessage<&'static str>>) -> Vec<String> {
+        thread(messages).iter().map(shape).collect()
+    }
+
+    #[test]
+    fn test_parse_message_ids() {
+        assert_eq!(
+            parse_message_ids("<a@example.com>\r\n\t<b@example.com>"),
+            vec!["<a@example.com>", "<b@example.com>"]
+        );
+        assert_eq!(
+            parse_message_ids("Your message <a@example.com> of Monday"),
+            vec!["<a@example.com>"]
+        );
+        assert_eq!(
+            parse_message_ids("a@example.com b@example.com"),
+            vec!["a@example.com", "b@example.com"]
+        );
+        assert_eq!(
+            parse_message_ids("<a@example.com> <b@exam"),
+            vec!["<a@example.com>"]
+        );
+        assert!(parse_message_ids("").is_empty());
+    }
+
+    #[test]
+    fn test_base_subject() {
+        assert_eq!(base_subject("hello"), "hello");
+        assert_eq!(base_subject("  Re: hello "), "hello");
+        assert_eq!(base_subject("RE: Fwd: re[2]: hello"), "hello");
+        assert_eq!(base_subject("AW: SV: FW: hello"), "hello");
+        assert_eq!(base_subject("Re:"), "");
+        assert_eq!(base_subject("Regarding: hello"), "Regarding: hello");
+        assert_eq!(base_subject("hello: Re: world"), "hello: Re: world");
+
+        assert!(is_reply("Re: hello"));
+        assert!(is_reply("Fwd: hello"));
+        assert!(!is_reply("hello"));
+        assert!(!is_reply("Ref: hello"));
+    }
+
+    #[test]
+    fn test_in_reply_to() {
+        let message = ThreadMessage::new(
+            Some("<c@example.com>"),
+            Some("<b@example.com>"),
+            Some("<a@example.com>"),
+            None,
+            (),
+        );
+        assert_eq!(
+            message.references,
+            vec!["<a@example.com>", "<b@example.com>"]
+        );
+
+        // already the last reference, so it isn't added again
+        let message = ThreadMessage::new(
+            Some("<c@example.com>"),
+            Some("<b@example.com>"),
+            Some("<a@example.com> <b@example.com>"),
+            None,
+            (),
+        );
+        assert_eq!(
+            message.references,
+            vec!["<a@example.com>", "<b@example.com>"]
+        );
+    }
+
+    #[test]
+    fn test_parent_child() {
+        let messages = vec![
+            message("a", "", "hello"),
+            message("b", "<a@example.com>", "Re: hello"),
+            message("c", "<a@example.com> <b@example.com>", "Re: hello"),
+            message("d", "<a@example.com>", "Re: hello"),
+            message("e", "", "something else"),
+        ];
+        assert_eq!(shapes(messages), vec!["a(b(c) d)", "e"]);
+    }
+
+    #[test]
+    fn test_replies_before_parent() {
+        let messages = vec![
+            message("c", "<a@example.com> <b@example.com>", "Re: hello"),
+            message("b", "<a@example.com>", "Re: hello"),
+            message("a", "", "hello"),
+        ];
+        assert_eq!(shapes(messages), vec!["a(b(c))"]);
+    }
+
+    #[test]
+    fn test_missing_parent() {
+        // a is never seen, but it still keeps its two replies together
+        let messages = vec![
+            message("b", "<a@example.com>", "Re: hello"),
+            message("c", "<a@example.com>", "Re: hello"),
+        ];
+        assert_eq!(shapes(messages), vec!["-(b c)"]);
+
+        // with only one reply there's nothing to keep together
+        let messages = vec![
+            message("b", "<a@example.com>", "Re: hello"),
+            message("c", "<a@example.com> <b@example.com>", "Re: hello"),
+        ];
+        assert_eq!(shapes(messages), vec!["b(c)"]);
+    }
+
+    #[test]
+    fn test_reference_loop() {
+        let messages = vec![
+            message("a", "<b@example.com>", "hello"),
+            message("b", "<a@example.com>", "Re: hello"),
+        ];
+        let roots = thread(messages);
+        let count = roots
+            .iter()
+            .map(|root| root.messages().len())
+            .sum::<usize>();
+        assert_eq!(count, 2);
+    }
+
+    #[test]
+    fn test_group_by_subject() {
+        // the reply lost its References, so the subject is all that's left to go on
+        let messages = vec![
+            message("a", "", "hello"),
+            message("b", "", "Re: Hello"),
+            message("c", "", "Fwd: hello"),
+        ];
+        assert_eq!(shapes(messages), vec!["a(b c)"]);
+
+        // two messages that aren't replies stay apart even with the same subject
+        let messages = vec![
+            message("a", "", "Your daily report"),
+            message("b", "", "Your daily report"),
+        ];
+        assert_eq!(shapes(messages), vec!["a", "b"]);
+
+        // two replies to a message that isn't there are put side by side
+        let messages = vec![message("a", "", "Re: hello"), message("b", "", "Re: hello")];
+        assert_eq!(shapes(messages), vec!["-(a b)"]);
+    }
+}
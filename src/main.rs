@@ -1,12 +1,13 @@
 use std::path::{Path, PathBuf};
+use std::str::FromStr;
 use std::thread;
 
-use anyhow::Result;
+use anyhow::{bail, Context, Result};
 use fern::colors::{Color, ColoredLevelConfig};
 use futures::future::TryFutureExt;
 use panorama::{
-    config::{spawn_config_watcher_system, ConfigWatcher},
-    mail::{self, MailEvent, MailStore},
+    config::{spawn_config_watcher_system, ConfigWatcher, MailSource},
+    mail::{self, MailCommand, MailEvent, MailStore},
     report_err,
     ui::{self, UiParams},
 };
@@ -33,14 +34,114 @@ struct Opt {
     // TODO: implement this or decide if it's useless
     #[structopt(long = "no-watch-config")]
     _no_watch_config: bool,
+
+    #[structopt(subcommand)]
+    command: Option<Command>,
+}
+
+#[derive(Debug, StructOpt)]
+enum Command {
+    /// Write mail from the local store out to an mbox file or a Maildir
+    Export {
+        /// The account to export from
+        #[structopt(long)]
+        account: String,
+
+        /// The folder to export. This is needed for mbox, while Maildir always gets every folder
+        #[structopt(long)]
+        folder: Option<String>,
+
+        /// Either mbox or maildir
+        #[structopt(long, default_value = "mbox")]
+        format: ArchiveFormat,
+
+        /// The mbox file or Maildir directory to write to
+        #[structopt(long, short)]
+        output: PathBuf,
+    },
+
+    /// Read mail from an mbox file or a Maildir into the local store
+    Import {
+        /// The account to import into
+        #[structopt(long)]
+        account: String,
+
+        /// The folder to import into. This is needed for mbox, while Maildir folders are imported
+        /// into the folders of the same name
+        #[structopt(long)]
+        folder: Option<String>,
+
+        /// Either mbox or maildir
+        #[structopt(long, default_value = "mbox")]
+        format: ArchiveFormat,
+
+        /// The mbox file or Maildir directory to read from
+        #[structopt(long, short)]
+        input: PathBuf,
+
+        /// Also upload the imported messages to the server. This is needed for IMAP accounts,
+        /// since anything that isn't on the server is removed when the folder is next synced.
+        #[structopt(long)]
+        upload: bool,
+    },
+
+    /// Permanently remove the messages marked as deleted in a folder. Servers that can't remove a
+    /// single message (no UIDPLUS) keep deleted messages around until this is done.
+    Expunge {
+        /// The account the folder is in
+        #[structopt(long)]
+        account: String,
+
+        /// The folder to expunge
+        #[structopt(long)]
+        folder: String,
+    },
+
+    /// Look after the database that the local store is indexed in
+    Db(DbCommand),
+}
+
+#[derive(Debug, StructOpt)]
+enum DbCommand {
+    /// Check the database for damage, and fix anything in it that doesn't agree with the mail
+    /// directory
+    Check,
+
+    /// Compact the database so it takes up less space
+    Vacuum,
+
+    /// Start the database over from the bodies in the mail directory, keeping the old one next to
+    /// it with the time in its name. Anything that wasn't synced to the server yet is lost.
+    Rebuild,
+}
+
+#[derive(Clone, Copy, Debug)]
+enum ArchiveFormat {
+    Mbox,
+    Maildir,
+}
+
+impl FromStr for ArchiveFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "mbox" => Ok(ArchiveFormat::Mbox),
+            "maildir" => Ok(ArchiveFormat::Maildir),
+            _ => Err(format!("unknown format {:?}, expected mbox or maildir", s)),
+        }
+    }
 }
 
 fn main() -> Result<()> {
     // parse command line arguments into options struct
-    let opt = Opt::from_args();
+    let mut opt = Opt::from_args();
     setup_logger(opt.log_file.as_ref())?;
 
     let rt = Runtime::new().unwrap();
+    if let Some(command) = opt.command.take() {
+        return rt.block_on(run_command(command));
+    }
     rt.block_on(run(opt)).unwrap();
 
     Ok(())
@@ -51,12 +152,13 @@ async fn run(opt: Opt) -> Result<()> {
     let _xdg = BaseDirectories::new()?;
     let (_config_thread, config_update) = spawn_config_watcher_system()?;
     let mail_store = MailStore::new(config_update.clone());
+    mail_store.start_compactor();
 
     // used to notify the runtime that the process should exit
     let (exit_tx, mut exit_rx) = mpsc::channel::<()>(1);
 
     // send messages from the UI thread to the mail thread
-    let (_ui2mail_tx, ui2mail_rx) = mpsc::unbounded_channel();
+    let (ui2mail_tx, ui2mail_rx) = mpsc::unbounded_channel();
 
     // send messages from the mail thread to the UI thread
     let (mail2ui_tx, mail2ui_rx) = mpsc::unbounded_channel();
@@ -79,6 +181,7 @@ async fn run(opt: Opt) -> Result<()> {
             mail_store.clone(),
             exit_tx,
             mail2ui_rx,
+            ui2mail_tx,
             ui2vm_tx,
         );
     }
@@ -92,12 +195,164 @@ async fn run(opt: Opt) -> Result<()> {
     // Ok(())
 }
 
+// Runs a single command against the local store and exits, without connecting to anything or
+// starting the UI
+async fn run_command(command: Command) -> Result<()> {
+    let (_config_thread, config_update) = spawn_config_watcher_system()?;
+    let mail_store = MailStore::new(config_update.clone());
+    // a damaged database can't be set up, and starting it over is the way to fix that
+    match mail_store.wait_until_ready().await {
+        Err(_) if matches!(command, Command::Db(DbCommand::Rebuild)) => {}
+        result => result?,
+    }
+    let config = config_update.borrow().clone();
+
+    match command {
+        Command::Export {
+            account,
+            folder,
+            format,
+            output,
+        } => {
+            if !config.mail_accounts.contains_key(&account) {
+                bail!("there's no account named {:?}", account);
+            }
+            let exported = match (format, folder) {
+                (ArchiveFormat::Mbox, Some(folder)) => {
+                    mail_store.export_mbox(&account, &folder, &output).await?
+                }
+                (ArchiveFormat::Mbox, None) => bail!("exporting to mbox needs a --folder"),
+                (ArchiveFormat::Maildir, None) => {
+                    mail_store.export_maildir(&account, &output).await?
+                }
+                (ArchiveFormat::Maildir, Some(_)) => {
+                    bail!("exporting to maildir always includes every folder")
+                }
+            };
+            println!("exported {} messages to {:?}", exported, output);
+        }
+
+        Command::Import {
+            account,
+            folder,
+            format,
+            input,
+            upload,
+        } => {
+            let acct = config
+                .mail_accounts
+                .get(&account)
+                .with_context(|| format!("there's no account named {:?}", account))?;
+            if !upload && matches!(acct.source, MailSource::Imap(_)) {
+                bail!(
+                    "{:?} is an IMAP account, so the messages have to be uploaded with --upload \
+                     or they'd be removed the next time the folder is synced",
+                    account
+                );
+            }
+            let imported = match (format, folder) {
+                (ArchiveFormat::Mbox, Some(folder)) => {
+                    mail_store
+                        .import_mbox(&account, &folder, &input, upload)
+                        .await?
+                }
+                (ArchiveFormat::Mbox, None) => bail!("importing from mbox needs a --folder"),
+                (ArchiveFormat::Maildir, None) => {
+                    mail_store.import_maildir(&account, &input, upload).await?
+                }
+                (ArchiveFormat::Maildir, Some(_)) => {
+                    bail!("importing from maildir always uses the folders in the maildir")
+                }
+            };
+            println!("imported {} messages from {:?}", imported, input);
+
+            if upload && imported > 0 {
+                mail::upload_operations(&mail_store, &account, acct)
+                    .await
+                    .context(
+                    "error uploading, the messages will be uploaded the next time panorama runs",
+                )?;
+                println!("uploaded {} messages to the server", imported);
+            }
+        }
+
+        Command::Expunge { account, folder } => {
+            let acct = config
+                .mail_accounts
+                .get(&account)
+                .with_context(|| format!("there's no account named {:?}", account))?;
+            mail_store.expunge_folder(&account, &folder).await?;
+            mail::upload_operations(&mail_store, &account, acct)
+                .await
+                .context("error expunging, it will be done the next time panorama runs")?;
+            println!("expunged {}", folder);
+        }
+
+        Command::Db(DbCommand::Check) => {
+            let check = mail_store.check_database().await?;
+            if !check.errors.is_empty() {
+                for err in check.errors.iter() {
+                    println!("{}", err);
+                }
+                bail!("the database is damaged, run `panorama db rebuild` to start it over");
+            }
+            if check.recounted > 0 {
+                println!(
+                    "fixed the reference counts of {} message bodies",
+                    check.recounted
+                );
+            }
+            if check.missing > 0 {
+                println!(
+                    "{} message bodies were missing, and will be downloaded again when they're \
+                     opened",
+                    check.missing
+                );
+            }
+            if check.unreferenced > 0 {
+                println!(
+                    "{} files in the mail directory aren't used by any message",
+                    check.unreferenced
+                );
+            }
+            if check.recounted == 0 && check.missing == 0 && check.unreferenced == 0 {
+                println!("the database is fine");
+            }
+        }
+
+        Command::Db(DbCommand::Vacuum) => {
+            let (before, after) = mail_store.vacuum_database().await?;
+            println!(
+                "the database went from {} to {}",
+                format_size(before),
+                format_size(after)
+            );
+        }
+
+        Command::Db(DbCommand::Rebuild) => {
+            let recovered = mail_store.rebuild_database().await?;
+            println!(
+                "rebuilt the database with {} message bodies, which will be matched up with \
+                 messages as they're synced",
+                recovered
+            );
+        }
+    }
+
+    Ok(())
+}
+
+fn format_size(bytes: u64) -> String {
+    format!("{:.1} MiB", bytes as f64 / 1024.0 / 1024.0)
+}
+
 // Spawns the entire UI in a different thread, since it must be thread-local
 fn run_ui(
     config_update: ConfigWatcher,
     mail_store: MailStore,
     exit_tx: mpsc::Sender<()>,
     mail2ui_rx: mpsc::UnboundedReceiver<MailEvent>,
+    ui2mail_tx: mpsc::UnboundedSender<MailCommand>,
     _ui2vm_tx: mpsc::UnboundedSender<()>,
 ) {
     let stdout = std::io::stdout();
@@ -115,6 +370,7 @@ fn run_ui(
             stdout,
             exit_tx,
             mail2ui_rx,
+            ui2mail_tx,
         };
 
         localset.spawn_local(async {
@@ -19,7 +19,10 @@ use panorama_tui::{
         widgets::*,
     },
 };
-use tokio::{sync::RwLock, task::JoinHandle};
+use tokio::{
+    sync::{broadcast::error::RecvError, RwLock},
+    task::JoinHandle,
+};
 
 use crate::mail::{
     store::{AccountRef, MailStoreUpdate},
@@ -28,6 +31,12 @@ use crate::mail::{
 
 use super::{FrameType, HandlesInput, InputResult, MailStore, TermType, Window, UI};
 
+/// The most messages that are kept around for the message list
+const MESSAGE_LIST_SIZE: usize = 200;
+
+/// (total, unread) message counts, by account and folder
+type FolderCounts = HashMap<(String, String), (u32, u32)>;
+
 #[derive(Debug)]
 /// A singular UI view of a list of mail
 pub struct MailView {
@@ -36,13 +45,72 @@ pub struct MailView {
     pub selected: Arc<AtomicU32>,
     pub change: Arc<AtomicI8>,
     current: Arc<RwLock<Option<Current>>>,
+    counts: Arc<RwLock<FolderCounts>>,
     mail_store_listener: JoinHandle<()>,
 }
 
 #[derive(Debug)]
 struct Current {
+    acct_name: String,
     account: Arc<AccountRef>,
-    folder: Option<String>,
+    folder: String,
+
+    /// The newest messages in the folder, newest first
+    messages: Vec<EmailMetadata>,
+}
+
+impl Current {
+    fn is_showing(&self, acct: &str, folder: &str) -> bool {
+        self.acct_name == acct && self.folder == folder
+    }
+
+    async fn reload(&mut self) -> Result<()> {
+        self.messages = self
+            .account
+            .get_messages(&self.folder, 0, MESSAGE_LIST_SIZE)
+            .await?;
+        Ok(())
+    }
+
+    /// Applies a change from the mail store to the message list
+    async fn apply(&mut self, update: MailStoreUpdate) -> Result<()> {
+        match update {
+            MailStoreUpdate::MessageAdded { acct, folder, uid }
+                if self.is_showing(&acct, &folder) =>
+            {
+                let meta = match self.account.get_message(&folder, uid).await? {
+                    Some(meta) => meta,
+                    None => return Ok(()),
+                };
+                let pos = self
+                    .messages
+                    .iter()
+                    .position(|other| other.date <= meta.date)
+                    .unwrap_or(self.messages.len());
+                self.messages.insert(pos, meta);
+                self.messages.truncate(MESSAGE_LIST_SIZE);
+            }
+            MailStoreUpdate::MessageUpdated { acct, folder, uid }
+                if self.is_showing(&acct, &folder) =>
+            {
+                if let Some(pos) = self.messages.iter().position(|meta| meta.uid == Some(uid)) {
+                    if let Some(meta) = self.account.get_message(&folder, uid).await? {
+                        self.messages[pos] = meta;
+                    }
+                }
+            }
+            MailStoreUpdate::MessageRemoved { acct, folder, uid }
+                if self.is_showing(&acct, &folder) =>
+            {
+                self.messages.retain(|meta| meta.uid != Some(uid));
+            }
+            MailStoreUpdate::FolderChanged { acct, folder } if self.is_showing(&acct, &folder) => {
+                self.reload().await?;
+            }
+            _ => {}
+        }
+        Ok(())
+    }
 }
 
 impl HandlesInput for MailView {
@@ -77,6 +145,7 @@ impl Window for MailView {
             .split(area);
 
         let accts = self.mail_store.list_accounts().await;
+        let counts = self.counts.read().await;
 
         // folder list
         let mut items = vec![];
@@ -84,7 +153,16 @@ impl Window for MailView {
             let folders = acct_ref.get_folders().await;
             items.push(ListItem::new(acct_name.to_owned()));
             for folder in folders {
-                items.push(ListItem::new(format!(" {}", folder)));
+                let icon = folder.role.map(|role| role.icon()).unwrap_or(" ");
+                let unread = counts
+                    .get(&(acct_name.to_owned(), folder.name.clone()))
+                    .map(|(_, unread)| *unread)
+                    .unwrap_or(0);
+                items.push(ListItem::new(if unread > 0 {
+                    format!(" {} {} ({})", icon, folder.name, unread)
+                } else {
+                    format!(" {} {}", icon, folder.name)
+                }));
             }
         }
 
@@ -99,11 +177,7 @@ impl Window for MailView {
 
         let mut rows = vec![];
         if let Some(current) = self.current.read().await.as_ref() {
-            let messages = current
-                .account
-                .get_newest_n_messages("INBOX", chunks[1].height as usize)
-                .await?;
-            for meta in messages.iter() {
+            for meta in current.messages.iter().take(chunks[1].height as usize) {
                 let mut row = Row::new(vec![
                     String::from(if meta.unread { "\u{2b24}" } else { "" }),
                     meta.uid.map(|u| u.to_string()).unwrap_or_default(),
@@ -182,31 +256,68 @@ fn humanize_timestamp(date: DateTime<Local>) -> String {
 
 impl MailView {
     pub fn new(mail_store: MailStore) -> Self {
-        let current = Arc::new(RwLock::new(None));
+        let current = Arc::new(RwLock::new(None::<Current>));
         let current2 = current.clone();
+        let counts = Arc::new(RwLock::new(HashMap::new()));
+        let counts2 = counts.clone();
 
-        let mut listener = mail_store.store_out_rx.clone();
+        let mut listener = mail_store.subscribe();
         let mail_store2 = mail_store.clone();
         let mail_store_listener = tokio::spawn(async move {
-            while let Ok(()) = listener.changed().await {
-                let updated = listener.borrow().clone();
-                debug!("new update from mail store: {:?}", updated);
-
-                // TODO: maybe do the processing of updates somewhere else?
-                // in case events get missed
-                match updated {
-                    Some(MailStoreUpdate::AccountListUpdate(_)) => {
+            loop {
+                let update = match listener.recv().await {
+                    Ok(update) => update,
+                    // some changes were missed, so the only way to catch up is to start over
+                    Err(RecvError::Lagged(skipped)) => {
+                        warn!("mail view missed {} updates from the mail store", skipped);
+                        if let Some(current) = current2.write().await.as_mut() {
+                            if let Err(err) = current.reload().await {
+                                error!("error reloading messages: {}", err);
+                            }
+                        }
+                        continue;
+                    }
+                    Err(RecvError::Closed) => break,
+                };
+                debug!("new update from mail store: {:?}", update);
+
+                match update {
+                    MailStoreUpdate::AccountListUpdate(_) => {
                         // TODO: maybe have a default account?
                         let accounts = mail_store2.list_accounts().await;
+                        let mut write = current2.write().await;
+                        *write = None;
                         if let Some((acct_name, acct_ref)) = accounts.iter().next() {
-                            let mut write = current2.write().await;
-                            *write = Some(Current {
+                            let mut new_current = Current {
+                                acct_name: acct_name.to_owned(),
                                 account: acct_ref.clone(),
-                                folder: None,
-                            })
+                                folder: String::from("INBOX"),
+                                messages: Vec::new(),
+                            };
+                            if let Err(err) = new_current.reload().await {
+                                error!("error loading messages: {}", err);
+                            }
+                            *write = Some(new_current);
+                        }
+                    }
+                    MailStoreUpdate::FolderCounts {
+                        acct,
+                        folder,
+                        total,
+                        unread,
+                    } => {
+                        counts2
+                            .write()
+                            .await
+                            .insert((acct, folder), (total, unread));
+                    }
+                    update => {
+                        if let Some(current) = current2.write().await.as_mut() {
+                            if let Err(err) = current.apply(update).await {
+                                error!("error updating message list: {}", err);
+                            }
                         }
                     }
-                    _ => {}
                 }
             }
         });
@@ -214,6 +325,7 @@ impl MailView {
         MailView {
             mail_store,
             current,
+            counts,
             message_list: TableState::default(),
             selected: Arc::new(AtomicU32::default()),
             change: Arc::new(AtomicI8::default()),
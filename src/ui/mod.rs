@@ -40,7 +40,7 @@ use panorama_tui::{
 use tokio::{sync::mpsc, time};
 
 use crate::config::ConfigWatcher;
-use crate::mail::{EmailMetadata, MailEvent, MailStore};
+use crate::mail::{EmailMetadata, MailCommand, MailEvent, MailStore};
 
 use self::colon_prompt::ColonPrompt;
 use self::input::{BaseInputHandler, HandlesInput, InputResult};
@@ -68,6 +68,9 @@ pub struct UiParams {
 
     /// All the events coming in from the mail thread
     pub mail2ui_rx: mpsc::UnboundedReceiver<MailEvent>,
+
+    /// A channel for sending commands to the mail thread
+    pub ui2mail_tx: mpsc::UnboundedSender<MailCommand>,
 }
 
 /// Main entrypoint for the UI
@@ -92,6 +95,7 @@ pub async fn run_ui2(params: UiParams) -> Result<()> {
         windows: HashMap::new(),
         page_names: HashMap::new(),
         mail_store: mail_store.clone(),
+        ui2mail_tx: params.ui2mail_tx,
     };
 
     ui.open_window(MailView::new(mail_store));
@@ -145,6 +149,7 @@ pub struct UI {
     windows: HashMap<LayoutId, Box<dyn Window>>,
     page_names: HashMap<PageId, String>,
     mail_store: MailStore,
+    ui2mail_tx: mpsc::UnboundedSender<MailCommand>,
 }
 
 impl UI {
@@ -172,7 +177,32 @@ impl UI {
             .map(Spans::from)
             .collect();
         let tabs = Tabs::new(titles).style(Style::default().bg(Color::DarkGray));
-        f.render_widget(tabs, chunks[1]);
+
+        // show the state and storage usage of each account next to the tabs
+        let mut statuses = Vec::new();
+        for (acct_name, acct_ref) in self.mail_store.list_accounts().await {
+            let mut parts = Vec::new();
+            if let Some(state) = acct_ref.get_state().await {
+                parts.push(state.to_string());
+            }
+            if let Some((usage, limit)) = acct_ref.get_storage_usage().await {
+                parts.push(format_storage_usage(usage, limit));
+            }
+            if !parts.is_empty() {
+                statuses.push(format!("{}: {}", acct_name, parts.join(", ")));
+            }
+        }
+        let usage_text = statuses.join(" | ");
+        let status_chunks = Layout::default()
+            .direction(Direction::Horizontal)
+            .constraints([
+                Constraint::Min(0),
+                Constraint::Length(usage_text.chars().count() as u16),
+            ])
+            .split(chunks[1]);
+        f.render_widget(tabs, status_chunks[0]);
+        let usage = Paragraph::new(usage_text).style(Style::default().bg(Color::DarkGray));
+        f.render_widget(usage, status_chunks[1]);
         debug!("drew chunks");
 
         // render all other windows
@@ -206,6 +236,14 @@ impl UI {
                 self.should_exit.store(true, Ordering::Relaxed);
             }
 
+            if let KeyEvent {
+                code: KeyCode::Char('r'),
+                ..
+            } = evt
+            {
+                let _ = self.ui2mail_tx.send(MailCommand::Refresh);
+            }
+
             // handle states in the state stack
             // although this is written in a for loop, every case except one should break
             let should_pop = false;
@@ -235,3 +273,17 @@ impl UI {
         Ok(())
     }
 }
+
+/// Formats the storage usage of an account for the status bar, where usage and limit are in KiB
+fn format_storage_usage(usage: u64, limit: u64) -> String {
+    fn human(kib: u64) -> String {
+        match kib {
+            n if n >= 1024 * 1024 => format!("{:.1}G", n as f64 / (1024.0 * 1024.0)),
+            n if n >= 1024 => format!("{:.1}M", n as f64 / 1024.0),
+            n => format!("{}K", n),
+        }
+    }
+
+    let percent = if limit == 0 { 100 } else { usage * 100 / limit };
+    format!("{} / {} ({}%)", human(usage), human(limit), percent)
+}